@@ -0,0 +1,671 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use candle_core::{DType, Device, Tensor};
+use serde::Serialize;
+
+use crate::file::{LayerKind, LoRAFile};
+use crate::header::{read_header, HeaderIndex};
+use crate::network::NetworkType;
+use crate::parser::{parse_key, Component};
+use crate::subspace::top_left_singular_vectors;
+use crate::svd::flatten_to_2d;
+use crate::{InspectorError, Result};
+
+/// Most singular directions `alignment` compares.
+const DIRECTIONS: usize = 8;
+/// Subspace iterations for the top singular directions.
+const ITERATIONS: usize = 16;
+
+/// A base model checkpoint, indexed by its header. Tensors are only read when
+/// asked for, so `reader` can be a `File` as well as a `Cursor` over a buffer
+/// or a memory map.
+pub struct BaseModel<R> {
+    reader: R,
+    index: HeaderIndex,
+    data_start: usize,
+    key_map: KeyMap,
+}
+
+impl<R: Read + Seek> BaseModel<R> {
+    pub fn new(mut reader: R) -> Result<BaseModel<R>> {
+        reader.seek(SeekFrom::Start(0))?;
+        let (index, _metadata, data_start) = read_header(&mut reader)?;
+        let key_map = KeyMap::new(index.keys());
+
+        Ok(BaseModel {
+            reader,
+            index,
+            data_start,
+            key_map,
+        })
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.index.keys()
+    }
+
+    pub fn shape(&self, key: &str) -> Option<&[usize]> {
+        self.index.shape(key)
+    }
+
+    /// Key of the base weight `base_name` applies to.
+    pub fn base_key(&self, base_name: &str, kind: LayerKind) -> Option<String> {
+        self.key_map.base_key(base_name, kind)
+    }
+
+    /// Reads `key` from the checkpoint.
+    pub fn tensor(&mut self, key: &str, device: &Device) -> Result<Tensor> {
+        let entry = self.index.entry(key).ok_or(InspectorError::NotFound)?;
+        let dtype = entry.dtype.to_candle().ok_or_else(|| {
+            InspectorError::Msg(format!("{key} is {}, which can not be loaded", entry.dtype))
+        })?;
+        let (start, end) = entry.data_offsets;
+        let shape = entry.shape.clone();
+
+        let mut data = vec![0u8; end - start];
+        self.reader
+            .seek(SeekFrom::Start((self.data_start + start) as u64))?;
+        self.reader.read_exact(&mut data)?;
+
+        Ok(Tensor::from_raw_buffer(&data, dtype, &shape, device)?)
+    }
+}
+
+/// Finds the base model key for a LoRA base name.
+///
+/// kohya names are the module path with `.` replaced by `_`, so the keys are
+/// compared the same way: `lora_unet_double_blocks_0_img_attn_qkv` matches
+/// `model.diffusion_model.double_blocks.0.img_attn.qkv.weight`. PEFT names
+/// keep their dots and match the same way. SD1/SD2 U-Net names fall back to
+/// their LDM name, for single file checkpoints.
+#[derive(Debug, Clone)]
+pub struct KeyMap {
+    /// `(key with . replaced by _, key)`
+    keys: Vec<(String, String)>,
+}
+
+impl KeyMap {
+    pub fn new<I: IntoIterator<Item = String>>(keys: I) -> KeyMap {
+        KeyMap {
+            keys: keys
+                .into_iter()
+                .map(|key| (key.replace('.', "_"), key))
+                .collect(),
+        }
+    }
+
+    pub fn base_key(&self, base_name: &str, kind: LayerKind) -> Option<String> {
+        let (component, module) = match parse_key(base_name) {
+            Ok(key) => {
+                let module = match key.prefix.as_ref() {
+                    Some(prefix) => base_name.get(prefix.len() + 1..).unwrap_or(base_name),
+                    None => base_name,
+                };
+                (key.component, module)
+            }
+            Err(_) => (None, base_name),
+        };
+
+        let param = if kind == LayerKind::Bias {
+            "bias"
+        } else {
+            "weight"
+        };
+        let target = format!("{}_{param}", module.replace('.', "_"));
+
+        let hints = match component {
+            Some(Component::TextEncoder(index)) => text_encoder_hints(index),
+            _ => &[],
+        };
+
+        self.find(&target, hints).or_else(|| {
+            // SD1/SD2 LoRAs name U-Net modules the diffusers way, single file
+            // checkpoints keep the LDM names
+            let module = ldm_unet_module(&module.replace('.', "_"))?;
+            self.find(&format!("{module}_{param}"), &[])
+        })
+    }
+
+    fn find(&self, target: &str, hints: &[&str]) -> Option<String> {
+        let suffix = format!("_{target}");
+        self.keys
+            .iter()
+            .filter(|(normalized, _)| normalized == target || normalized.ends_with(&suffix))
+            .min_by_key(|(normalized, _)| {
+                (
+                    !hints.iter().any(|hint| normalized.contains(hint)),
+                    normalized.len(),
+                )
+            })
+            .map(|(_, key)| key.clone())
+    }
+}
+
+/// LDM name of a diffusers SD1/SD2 U-Net module, both with `.` replaced by
+/// `_`: `down_blocks_1_attentions_0_proj_in` is `input_blocks_4_1_proj_in`.
+/// The reverse of kohya's `convert_ldm_unet_checkpoint`.
+fn ldm_unet_module(module: &str) -> Option<String> {
+    let parts: Vec<&str> = module.split('_').collect();
+    let index = |i: usize| parts.get(i).and_then(|p| p.parse::<usize>().ok());
+    let rest = |from: usize| parts.get(from..).map(|rest| rest.join("_"));
+    // Resnet layers are renamed too
+    let resnet = |from: usize| {
+        let rest = rest(from)?;
+        [
+            ("norm1", "in_layers_0"),
+            ("conv1", "in_layers_2"),
+            ("time_emb_proj", "emb_layers_1"),
+            ("norm2", "out_layers_0"),
+            ("conv2", "out_layers_3"),
+            ("conv_shortcut", "skip_connection"),
+        ]
+        .into_iter()
+        .find(|(name, _)| rest == *name)
+        .map(|(_, tail)| tail.to_string())
+    };
+
+    let (block, layer, tail) = match parts.as_slice() {
+        ["down", "blocks", _, "attentions", _, ..] => {
+            let n = 3 * index(2)? + index(4)? + 1;
+            ("input_blocks", format!("{n}_1"), rest(5)?)
+        }
+        ["down", "blocks", _, "resnets", _, ..] => {
+            let n = 3 * index(2)? + index(4)? + 1;
+            ("input_blocks", format!("{n}_0"), resnet(5)?)
+        }
+        ["down", "blocks", _, "downsamplers", "0", "conv"] => {
+            let n = 3 * (index(2)? + 1);
+            ("input_blocks", format!("{n}_0"), "op".to_string())
+        }
+        ["mid", "block", "attentions", "0", ..] => ("middle_block", "1".to_string(), rest(4)?),
+        ["mid", "block", "resnets", _, ..] => {
+            ("middle_block", (2 * index(3)?).to_string(), resnet(4)?)
+        }
+        ["up", "blocks", _, "attentions", _, ..] => {
+            let n = 3 * index(2)? + index(4)?;
+            ("output_blocks", format!("{n}_1"), rest(5)?)
+        }
+        ["up", "blocks", _, "resnets", _, ..] => {
+            let n = 3 * index(2)? + index(4)?;
+            ("output_blocks", format!("{n}_0"), resnet(5)?)
+        }
+        ["up", "blocks", _, "upsamplers", "0", "conv"] => {
+            // The first up block has no attentions, so its upsampler comes
+            // right after the resnet
+            let block = index(2)?;
+            let n = 3 * block + 2;
+            let slot = if block == 0 { 1 } else { 2 };
+            ("output_blocks", format!("{n}_{slot}"), "conv".to_string())
+        }
+        ["time", "embedding", "linear", "1"] => ("time_embed", "0".to_string(), String::new()),
+        ["time", "embedding", "linear", "2"] => ("time_embed", "2".to_string(), String::new()),
+        ["conv", "in"] => ("input_blocks", "0_0".to_string(), String::new()),
+        ["conv", "norm", "out"] => ("out", "0".to_string(), String::new()),
+        ["conv", "out"] => ("out", "2".to_string(), String::new()),
+        _ => return None,
+    };
+
+    Some(if tail.is_empty() {
+        format!("{block}_{layer}")
+    } else {
+        format!("{block}_{layer}_{tail}")
+    })
+}
+
+/// SDXL and SD3 checkpoints hold several text encoders with the same module
+/// names, `lora_te2` is the second one.
+fn text_encoder_hints(index: Option<usize>) -> &'static [&'static str] {
+    match index {
+        Some(2) => &["clip_g", "embedders_1", "text_encoder_2"],
+        Some(3) => &["t5xxl", "text_encoder_3"],
+        _ => &[
+            "clip_l",
+            "embedders_0",
+            "cond_stage_model",
+            "text_encoder_text_model",
+        ],
+    }
+}
+
+/// Shapes match, up to trailing unit dims: a linear LoRA on a 1x1 conv
+/// `[out, in, 1, 1]` is still compatible. So is a conv update kept flattened
+/// as `[out, in * kh * kw]`, like LoHa's.
+pub fn shapes_compatible(delta: &[usize], base: &[usize]) -> bool {
+    fn trimmed(shape: &[usize]) -> &[usize] {
+        let end = shape.iter().rposition(|d| *d != 1).map_or(0, |i| i + 1);
+        &shape[..end.max(1).min(shape.len())]
+    }
+
+    let flattened = match (delta, base) {
+        ([out, rest], [base_out, base_rest @ ..]) if base_rest.len() > 1 => {
+            out == base_out && *rest == base_rest.iter().product::<usize>()
+        }
+        _ => false,
+    };
+
+    delta == base || trimmed(delta) == trimmed(base) || flattened
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum LayerIssue {
+    /// The base model has no tensor for this layer
+    NotInBase,
+    ShapeMismatch {
+        delta: Vec<usize>,
+        base: Vec<usize>,
+    },
+    /// The update could not be rebuilt or the base tensor read
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelativeMetrics {
+    pub delta_norm: f64,
+    pub base_norm: f64,
+    /// `||dW|| / ||W||`
+    pub relative_magnitude: f64,
+    /// Overlap of the top singular directions of dW with the top directions
+    /// of W, `||U_W^T U_dW||^2 / k`. 1 when the update amplifies directions
+    /// the base weight already has, around `k / out` for unrelated ones.
+    pub alignment: Option<f64>,
+    /// `k`, the number of directions compared
+    pub directions: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BaseLayer {
+    pub base_name: String,
+    pub base_key: Option<String>,
+    pub delta_shape: Option<Vec<usize>>,
+    pub base_shape: Option<Vec<usize>>,
+    pub metrics: Option<RelativeMetrics>,
+    pub issue: Option<LayerIssue>,
+}
+
+/// Compares every layer of `file` with the base weight it applies to.
+pub fn analyze<R: Read + Seek>(file: &LoRAFile, base: &mut BaseModel<R>) -> Vec<BaseLayer> {
+    let mut base_names = file.base_names();
+    base_names.sort();
+
+    base_names
+        .iter()
+        .map(|base_name| analyze_layer(file, base, base_name))
+        .collect()
+}
+
+pub fn analyze_layer<R: Read + Seek>(
+    file: &LoRAFile,
+    base: &mut BaseModel<R>,
+    base_name: &str,
+) -> BaseLayer {
+    let mut layer = BaseLayer {
+        base_name: base_name.to_string(),
+        base_key: None,
+        delta_shape: None,
+        base_shape: None,
+        metrics: None,
+        issue: None,
+    };
+
+    let Some(base_key) = base.base_key(base_name, file.layer_kind(base_name)) else {
+        layer.issue = Some(LayerIssue::NotInBase);
+        return layer;
+    };
+    layer.base_shape = base.shape(&base_key).map(|shape| shape.to_vec());
+    layer.base_key = Some(base_key.clone());
+
+    if let Err(e) = relative_to_base(file, base, base_name, &base_key, &mut layer) {
+        layer.issue = Some(LayerIssue::Failed(e.to_string()));
+    }
+
+    layer
+}
+
+fn relative_to_base<R: Read + Seek>(
+    file: &LoRAFile,
+    base: &mut BaseModel<R>,
+    base_name: &str,
+    base_key: &str,
+    layer: &mut BaseLayer,
+) -> Result<()> {
+    let network_type = file.metadata().and_then(|m| m.network_type());
+    if matches!(
+        network_type,
+        Some(NetworkType::OFT | NetworkType::BOFT | NetworkType::DiagOFT)
+    ) {
+        return Err(InspectorError::Msg(
+            "OFT rotations are not compared with the base weight".to_string(),
+        ));
+    }
+
+    let base_shape = layer.base_shape.clone().unwrap_or_default();
+
+    // IA3 rescales the base weight, so its update needs the base first
+    let (delta, weight) = if network_type == Some(NetworkType::IA3) {
+        let device = file.device().ok_or(InspectorError::NotFound)?;
+        let weight = base.tensor(base_key, device)?;
+        let delta = file
+            .ia3_delta(base_name, &weight)?
+            .ok_or(InspectorError::NotFound)?;
+        (delta, weight)
+    } else {
+        let delta = file.scale_weight(base_name)?;
+        layer.delta_shape = Some(delta.dims().to_vec());
+        if !shapes_compatible(delta.dims(), &base_shape) {
+            layer.issue = Some(LayerIssue::ShapeMismatch {
+                delta: delta.dims().to_vec(),
+                base: base_shape,
+            });
+            return Ok(());
+        }
+        let weight = base.tensor(base_key, delta.device())?;
+        (delta.reshape(weight.shape())?, weight)
+    };
+    layer.delta_shape = Some(delta.dims().to_vec());
+
+    let delta = delta.to_dtype(DType::F32)?;
+    let weight = weight.to_dtype(DType::F32)?;
+
+    let base_norm = frobenius(&weight)?;
+    // DoRA's magnitude changes the weight on top of the directional update
+    let delta_norm = match file.dora_scale(base_name) {
+        Some(_) => file
+            .effective_scale_with_base(base_name, &weight)?
+            .unwrap_or(frobenius(&delta)?),
+        None => frobenius(&delta)?,
+    };
+
+    let (alignment, directions) = match weight.rank() {
+        0 | 1 => (None, 0),
+        _ => {
+            let k = file
+                .rank(base_name)
+                .unwrap_or(DIRECTIONS)
+                .min(DIRECTIONS)
+                .min(base_shape.first().copied().unwrap_or(0));
+            (alignment(&delta, &weight, k)?, k)
+        }
+    };
+
+    layer.metrics = Some(RelativeMetrics {
+        delta_norm,
+        base_norm,
+        relative_magnitude: if base_norm > 0.0 {
+            delta_norm / base_norm
+        } else {
+            f64::INFINITY
+        },
+        alignment,
+        directions,
+    });
+
+    Ok(())
+}
+
+fn frobenius(t: &Tensor) -> Result<f64> {
+    Ok(t.sqr()?
+        .sum_all()?
+        .to_dtype(DType::F64)?
+        .to_scalar::<f64>()?
+        .sqrt())
+}
+
+/// `||U_W^T U_dW||^2` over the directions dW has, `None` for an empty update.
+fn alignment(delta: &Tensor, weight: &Tensor, k: usize) -> Result<Option<f64>> {
+    if k == 0 {
+        return Ok(None);
+    }
+
+    let delta = flatten_to_2d(delta)?;
+    let weight = flatten_to_2d(weight)?;
+    let delta_directions = top_left_singular_vectors(&delta, k, ITERATIONS)?;
+    let weight_directions = top_left_singular_vectors(&weight, k, ITERATIONS)?;
+
+    // Columns for directions beyond the rank of dW are left at zero
+    let found = frobenius(&delta_directions)?.powi(2);
+    if found < 0.5 {
+        return Ok(None);
+    }
+    let overlap = weight_directions.t()?.matmul(&delta_directions)?;
+
+    Ok(Some(frobenius(&overlap)?.powi(2) / found))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::writer::SafetensorsWriter;
+
+    #[test]
+    fn key_map_matches_kohya_and_peft_names() {
+        let keys = KeyMap::new(
+            [
+                "model.diffusion_model.double_blocks.0.img_attn.qkv.weight",
+                "model.diffusion_model.double_blocks.0.img_attn.qkv.bias",
+                "conditioner.embedders.0.transformer.text_model.encoder.layers.0.self_attn.q_proj.weight",
+                "text_encoders.clip_g.transformer.text_model.encoder.layers.0.self_attn.q_proj.weight",
+                "transformer_blocks.0.attn.to_q.weight",
+            ]
+            .map(String::from),
+        );
+
+        assert_eq!(
+            keys.base_key("lora_unet_double_blocks_0_img_attn_qkv", LayerKind::LowRank)
+                .as_deref(),
+            Some("model.diffusion_model.double_blocks.0.img_attn.qkv.weight")
+        );
+        assert_eq!(
+            keys.base_key("lora_unet_double_blocks_0_img_attn_qkv", LayerKind::Bias)
+                .as_deref(),
+            Some("model.diffusion_model.double_blocks.0.img_attn.qkv.bias")
+        );
+        assert_eq!(
+            keys.base_key(
+                "lora_te1_text_model_encoder_layers_0_self_attn_q_proj",
+                LayerKind::LowRank
+            )
+            .as_deref(),
+            Some("conditioner.embedders.0.transformer.text_model.encoder.layers.0.self_attn.q_proj.weight")
+        );
+        assert_eq!(
+            keys.base_key(
+                "lora_te2_text_model_encoder_layers_0_self_attn_q_proj",
+                LayerKind::LowRank
+            )
+            .as_deref(),
+            Some("text_encoders.clip_g.transformer.text_model.encoder.layers.0.self_attn.q_proj.weight")
+        );
+        assert_eq!(
+            keys.base_key(
+                "transformer.transformer_blocks.0.attn.to_q",
+                LayerKind::LowRank
+            )
+            .as_deref(),
+            Some("transformer_blocks.0.attn.to_q.weight")
+        );
+        assert_eq!(
+            keys.base_key("lora_unet_double_blocks_1_img_attn_qkv", LayerKind::LowRank),
+            None
+        );
+    }
+
+    #[test]
+    fn key_map_matches_ldm_unet_keys() {
+        // SD1.5 single file checkpoint
+        let keys = KeyMap::new(
+            [
+                "model.diffusion_model.input_blocks.1.1.proj_in.weight",
+                "model.diffusion_model.input_blocks.4.1.transformer_blocks.0.attn1.to_k.weight",
+                "model.diffusion_model.input_blocks.5.0.in_layers.2.weight",
+                "model.diffusion_model.input_blocks.6.0.op.weight",
+                "model.diffusion_model.middle_block.1.transformer_blocks.0.ff.net.0.proj.weight",
+                "model.diffusion_model.middle_block.2.emb_layers.1.weight",
+                "model.diffusion_model.output_blocks.2.1.conv.weight",
+                "model.diffusion_model.output_blocks.3.0.skip_connection.weight",
+                "model.diffusion_model.output_blocks.8.2.conv.weight",
+                "model.diffusion_model.output_blocks.11.1.proj_out.weight",
+                "model.diffusion_model.time_embed.2.weight",
+            ]
+            .map(String::from),
+        );
+
+        for (base_name, key) in [
+            (
+                "lora_unet_down_blocks_0_attentions_0_proj_in",
+                "input_blocks.1.1.proj_in",
+            ),
+            (
+                "lora_unet_down_blocks_1_attentions_0_transformer_blocks_0_attn1_to_k",
+                "input_blocks.4.1.transformer_blocks.0.attn1.to_k",
+            ),
+            (
+                "lora_unet_down_blocks_1_resnets_1_conv1",
+                "input_blocks.5.0.in_layers.2",
+            ),
+            (
+                "lora_unet_down_blocks_1_downsamplers_0_conv",
+                "input_blocks.6.0.op",
+            ),
+            (
+                "lora_unet_mid_block_attentions_0_transformer_blocks_0_ff_net_0_proj",
+                "middle_block.1.transformer_blocks.0.ff.net.0.proj",
+            ),
+            (
+                "lora_unet_mid_block_resnets_1_time_emb_proj",
+                "middle_block.2.emb_layers.1",
+            ),
+            (
+                "lora_unet_up_blocks_0_upsamplers_0_conv",
+                "output_blocks.2.1.conv",
+            ),
+            (
+                "lora_unet_up_blocks_1_resnets_0_conv_shortcut",
+                "output_blocks.3.0.skip_connection",
+            ),
+            (
+                "lora_unet_up_blocks_2_upsamplers_0_conv",
+                "output_blocks.8.2.conv",
+            ),
+            (
+                "lora_unet_up_blocks_3_attentions_2_proj_out",
+                "output_blocks.11.1.proj_out",
+            ),
+            ("lora_unet_time_embedding_linear_2", "time_embed.2"),
+        ] {
+            assert_eq!(
+                keys.base_key(base_name, LayerKind::LowRank),
+                Some(format!("model.diffusion_model.{key}.weight")),
+                "{base_name}"
+            );
+        }
+        assert_eq!(
+            keys.base_key(
+                "lora_unet_down_blocks_0_resnets_0_conv3",
+                LayerKind::LowRank
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn compatible_shapes() {
+        assert!(shapes_compatible(&[4, 8], &[4, 8]));
+        assert!(shapes_compatible(&[4, 8], &[4, 8, 1, 1]));
+        assert!(!shapes_compatible(&[4, 8], &[8, 4]));
+        assert!(!shapes_compatible(&[4, 8, 3, 3], &[4, 8, 1, 1]));
+        assert!(shapes_compatible(&[4, 72], &[4, 8, 3, 3]));
+    }
+
+    fn lora_layer(writer: &mut SafetensorsWriter, name: &str, up: &Tensor, down: &Tensor) {
+        writer
+            .insert_tensor(&format!("{name}.lora_up.weight"), up)
+            .unwrap();
+        writer
+            .insert_tensor(&format!("{name}.lora_down.weight"), down)
+            .unwrap();
+        writer
+            .insert_tensor(
+                &format!("{name}.alpha"),
+                &Tensor::new(1f32, &Device::Cpu).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn relative_to_base_weight() -> Result<()> {
+        let device = Device::Cpu;
+        let e0 = Tensor::new(&[[1f32], [0.], [0.], [0.]], &device)?;
+        let e3 = Tensor::new(&[[0f32], [0.], [0.], [1.]], &device)?;
+
+        let mut writer = SafetensorsWriter::new();
+        // Along the largest direction of the base weight
+        lora_layer(
+            &mut writer,
+            "lora_unet_down_blocks_0_attentions_0_proj_in",
+            &e0,
+            &(e0.t()? * 0.5)?,
+        );
+        // Along the smallest one
+        lora_layer(
+            &mut writer,
+            "lora_unet_down_blocks_0_attentions_0_proj_out",
+            &e3,
+            &e3.t()?,
+        );
+        lora_layer(
+            &mut writer,
+            "lora_unet_down_blocks_0_attentions_0_norm",
+            &e0,
+            &e0.t()?,
+        );
+        lora_layer(&mut writer, "lora_unet_conv_in", &e0, &e0.t()?);
+        let lora_file =
+            LoRAFile::new_from_buffer(&writer.serialize()?, "lora.safetensors", &device);
+
+        let weight = Tensor::new(
+            &[
+                [4f32, 0., 0., 0.],
+                [0., 3., 0., 0.],
+                [0., 0., 2., 0.],
+                [0., 0., 0., 1.],
+            ],
+            &device,
+        )?;
+        let mut writer = SafetensorsWriter::new();
+        writer.insert_tensor("down_blocks.0.attentions.0.proj_in.weight", &weight)?;
+        writer.insert_tensor(
+            "down_blocks.0.attentions.0.proj_out.weight",
+            &weight.reshape((4, 4, 1, 1))?,
+        )?;
+        writer.insert_tensor(
+            "down_blocks.0.attentions.0.norm.weight",
+            &Tensor::ones((8, 4), DType::F32, &device)?,
+        )?;
+        let mut base = BaseModel::new(Cursor::new(writer.serialize()?))?;
+
+        let layers = analyze(&lora_file, &mut base);
+        let layer = |name: &str| layers.iter().find(|l| l.base_name.ends_with(name)).unwrap();
+
+        let proj_in = layer("proj_in").metrics.as_ref().unwrap();
+        assert!((proj_in.relative_magnitude - 0.5 / 30f64.sqrt()).abs() < 1e-6);
+        assert!((proj_in.alignment.unwrap() - 1.0).abs() < 1e-4);
+        assert_eq!(proj_in.directions, 1);
+
+        let proj_out = layer("proj_out");
+        assert!(proj_out.issue.is_none());
+        assert!(proj_out.metrics.as_ref().unwrap().alignment.unwrap() < 1e-4);
+
+        assert!(matches!(
+            layer("norm").issue,
+            Some(LayerIssue::ShapeMismatch { .. })
+        ));
+        assert!(matches!(
+            layer("conv_in").issue,
+            Some(LayerIssue::NotInBase)
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,725 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+use crate::file::LoRAFile;
+use crate::network::{
+    Architecture, BlockLrWeights, BlockUsizeSeq, NUM_OF_BLOCKS, NUM_OF_BLOCKS_SDXL,
+};
+use crate::InspectorError;
+
+/// The block of the base model a layer belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BlockId {
+    /// U-Net down block in kohya's numbering, `0..12`.
+    Down(usize),
+    Mid,
+    /// U-Net up block, `0..12`.
+    Up(usize),
+    /// Flux double stream block.
+    Double(usize),
+    /// Flux single stream block.
+    Single(usize),
+    /// Any other `blocks_N` layer, e.g. SD3 or Lumina.
+    Block(usize),
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockId::Down(i) => write!(f, "down_{i:02}"),
+            BlockId::Mid => write!(f, "mid"),
+            BlockId::Up(i) => write!(f, "up_{i:02}"),
+            BlockId::Double(i) => write!(f, "double_{i:02}"),
+            BlockId::Single(i) => write!(f, "single_{i:02}"),
+            BlockId::Block(i) => write!(f, "block_{i:02}"),
+        }
+    }
+}
+
+/// The inverse of `Display`: `down_03`, `mid`, `double_07`, ...
+impl FromStr for BlockId {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        if s == "mid" {
+            return Ok(BlockId::Mid);
+        }
+
+        let block = s.split_once('_').and_then(|(kind, index)| {
+            let index = index.parse::<usize>().ok()?;
+            match kind {
+                "down" => Some(BlockId::Down(index)),
+                "up" => Some(BlockId::Up(index)),
+                "double" => Some(BlockId::Double(index)),
+                "single" => Some(BlockId::Single(index)),
+                "block" => Some(BlockId::Block(index)),
+                _ => None,
+            }
+        });
+        block.ok_or_else(|| {
+            InspectorError::Msg(format!(
+                "Unknown block {s}. Valid blocks: down_NN, mid, up_NN, double_NN, single_NN, block_NN"
+            ))
+        })
+    }
+}
+
+impl Serialize for BlockId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl BlockId {
+    pub fn from_base_name(base_name: &str) -> Option<BlockId> {
+        // SDXL input and output blocks keep their own number, `IN04` is
+        // `input_blocks_4`
+        if let Some(index) = kohya_sdxl_block_index(base_name) {
+            return BlockId::from_kohya_sdxl_index(index);
+        }
+        if let Some(index) = kohya_block_index(base_name) {
+            return Some(BlockId::from_kohya_index(index));
+        }
+
+        let name = base_name.replace('.', "_");
+        let number_after = |marker: &str| {
+            let start = name.find(marker)? + marker.len();
+            name[start..]
+                .split('_')
+                .next()
+                .and_then(|n| n.parse::<usize>().ok())
+        };
+
+        if let Some(i) = number_after("double_blocks_") {
+            Some(BlockId::Double(i))
+        } else if let Some(i) = number_after("single_blocks_") {
+            Some(BlockId::Single(i))
+        } else {
+            number_after("blocks_").map(BlockId::Block)
+        }
+    }
+
+    pub fn from_kohya_index(index: usize) -> BlockId {
+        match index {
+            i if i < NUM_OF_BLOCKS => BlockId::Down(i),
+            NUM_OF_BLOCKS => BlockId::Mid,
+            i => BlockId::Up(i - NUM_OF_BLOCKS - 1),
+        }
+    }
+
+    /// Block for an index of kohya's SDXL numbering. `time_embed`, `label_emb`
+    /// (0) and `out` (20) are not in any block.
+    pub fn from_kohya_sdxl_index(index: usize) -> Option<BlockId> {
+        match index {
+            i if (1..=NUM_OF_BLOCKS_SDXL).contains(&i) => Some(BlockId::Down(i - 1)),
+            i if i == NUM_OF_BLOCKS_SDXL + 1 => Some(BlockId::Mid),
+            i if i > NUM_OF_BLOCKS_SDXL + 1 && i <= 2 * NUM_OF_BLOCKS_SDXL + 1 => {
+                Some(BlockId::Up(i - NUM_OF_BLOCKS_SDXL - 2))
+            }
+            _ => None,
+        }
+    }
+
+    /// Index into kohya's 25 block weights, for U-Net blocks. SDXL uses
+    /// kohya's SDXL numbering, the inverse of `from_kohya_sdxl_index`.
+    pub fn kohya_index(&self, sdxl: bool) -> Option<usize> {
+        let (first, blocks) = if sdxl {
+            (1, NUM_OF_BLOCKS_SDXL)
+        } else {
+            (0, NUM_OF_BLOCKS)
+        };
+        match self {
+            BlockId::Down(i) => Some(first + i),
+            BlockId::Mid => Some(first + blocks),
+            BlockId::Up(i) => Some(first + blocks + 1 + i),
+            _ => None,
+        }
+    }
+}
+
+fn number(part: Option<&str>) -> Option<usize> {
+    part.and_then(|p| p.parse().ok())
+}
+
+/// Block index as kohya's `lora.py` `get_block_index` computes it: down blocks
+/// are `1 + 3 * block + layer` (samplers take the third slot), the mid block is
+/// 12 and up blocks start at 13.
+///
+/// SDXL names use kohya's SDXL numbering instead, see `kohya_sdxl_block_index`.
+pub fn kohya_block_index(base_name: &str) -> Option<usize> {
+    if let Some(index) = kohya_sdxl_block_index(base_name) {
+        return Some(index);
+    }
+
+    let name = base_name.replace('.', "_");
+    let parts: Vec<&str> = name.split('_').collect();
+    let find = |word: &str| {
+        parts
+            .windows(2)
+            .position(|w| w[0] == word && w[1] == "blocks")
+    };
+
+    for (direction, offset) in [("down", 1), ("up", NUM_OF_BLOCKS + 1)] {
+        if let Some(at) = find(direction) {
+            let block = number(parts.get(at + 2).copied())?;
+            let layer = match parts.get(at + 3).copied() {
+                Some("resnets") | Some("attentions") => number(parts.get(at + 4).copied())?,
+                Some("upsamplers") | Some("downsamplers") => 2,
+                _ => return None,
+            };
+            return Some(offset + 3 * block + layer);
+        }
+    }
+
+    if find("mid").is_some() || name.contains("mid_block_") {
+        return Some(NUM_OF_BLOCKS);
+    }
+
+    None
+}
+
+/// Block index of an SDXL layer as kohya's `get_block_index` computes it with
+/// `is_sdxl`: `time_embed` and `label_emb` are 0, `input_blocks_N` is `1 + N`,
+/// `middle_block` is 10, `output_blocks_N` is `11 + N` and `out` is 20.
+pub fn kohya_sdxl_block_index(base_name: &str) -> Option<usize> {
+    let name = base_name.replace('.', "_");
+    let name = name.strip_prefix("lora_unet_")?;
+    let block = |prefix: &str| {
+        name.strip_prefix(prefix)
+            .and_then(|rest| number(rest.split('_').next()))
+    };
+
+    if name.starts_with("time_embed_") || name.starts_with("label_emb_") {
+        Some(0)
+    } else if let Some(n) = block("input_blocks_") {
+        Some(1 + n)
+    } else if name.starts_with("middle_block_") {
+        Some(NUM_OF_BLOCKS_SDXL + 1)
+    } else if let Some(n) = block("output_blocks_") {
+        Some(NUM_OF_BLOCKS_SDXL + 2 + n)
+    } else if name.starts_with("out_") {
+        Some(2 * NUM_OF_BLOCKS_SDXL + 2)
+    } else {
+        None
+    }
+}
+
+/// Whether block indices of `file` follow kohya's SDXL numbering, from the
+/// architecture or the layer names.
+pub fn is_sdxl(file: &LoRAFile) -> bool {
+    file.metadata().and_then(|m| m.architecture()) == Some(Architecture::SDXL)
+        || file
+            .base_names()
+            .iter()
+            .any(|b| kohya_sdxl_block_index(b).is_some())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Block {
+    pub id: BlockId,
+    pub layers: Vec<String>,
+    /// Learning rate multiplier the block was trained with, when the network
+    /// args set block learning rate weights.
+    pub lr_weight: Option<f64>,
+}
+
+/// Groups layers by block, in block order. Layers outside any block are left
+/// out.
+pub fn block_map(base_names: &[String], lr_weights: Option<&BlockLrWeights>) -> Vec<Block> {
+    let mut blocks: BTreeMap<BlockId, Vec<String>> = BTreeMap::new();
+    for base_name in base_names {
+        if let Some(id) = BlockId::from_base_name(base_name) {
+            blocks.entry(id).or_default().push(base_name.clone());
+        }
+    }
+
+    blocks
+        .into_iter()
+        .map(|(id, mut layers)| {
+            layers.sort();
+            Block {
+                lr_weight: lr_weights.and_then(|w| id.kohya_index(w.sdxl).map(|i| w.weight(i))),
+                id,
+                layers,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum BlockDimStatus {
+    /// Every layer has the declared rank and alpha.
+    Match,
+    /// Some layers differ from the declared rank or alpha.
+    Mismatch,
+    /// Declared rank 0, so kohya skipped the block.
+    Dropped,
+    /// Declared a rank but no layers were saved for the block.
+    NoLayers,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockDimLayer {
+    pub base_name: String,
+    pub rank: Option<usize>,
+    /// Only known when the tensors are loaded.
+    pub alpha: Option<f32>,
+    pub matches: bool,
+}
+
+/// One entry of `block_dims` or `conv_block_dims` next to the layers saved for
+/// that block.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockDims {
+    pub index: usize,
+    /// `None` for the SDXL slots outside the U-Net blocks.
+    pub id: Option<BlockId>,
+    /// Entry of `conv_block_dims`, which covers the 3x3 conv layers.
+    pub conv: bool,
+    pub declared_dim: usize,
+    pub declared_alpha: f32,
+    pub status: BlockDimStatus,
+    pub layers: Vec<BlockDimLayer>,
+}
+
+/// 3x3 conv layers take their rank from `conv_block_dims`, everything else
+/// from `block_dims`.
+fn is_conv3x3(file: &LoRAFile, base_name: &str) -> bool {
+    file.shape(&format!("{base_name}.lora_down.weight"))
+        .map(|shape| shape.len() == 4 && shape[2..] != [1, 1])
+        .unwrap_or(false)
+}
+
+/// Compares `block_dims`/`block_alphas` and `conv_block_dims`/
+/// `conv_block_alphas` against the rank and alpha of the saved layers.
+///
+/// Missing alphas default like kohya's `get_block_dims_and_alphas`: to
+/// `ss_network_alpha` for `block_alphas` and to `conv_alpha` for
+/// `conv_block_alphas`, then 1.
+pub fn block_dims(file: &LoRAFile) -> Vec<BlockDims> {
+    let Some(network_args) = file.metadata().and_then(|m| m.network_args()) else {
+        return vec![];
+    };
+    let network_alpha = file
+        .metadata()
+        .and_then(|m| m.metadata.as_ref())
+        .and_then(|m| m.get("ss_network_alpha"))
+        .and_then(|alpha| alpha.parse::<f32>().ok())
+        .unwrap_or(1.0);
+    let conv_alpha = network_args.conv_alpha.map_or(1.0, |a| a as f32);
+
+    let base_names = file.base_names();
+    let sdxl = is_sdxl(file);
+
+    let mut layers: BTreeMap<(bool, usize), Vec<String>> = BTreeMap::new();
+    for base_name in base_names {
+        if let Some(index) = kohya_block_index(&base_name) {
+            layers
+                .entry((is_conv3x3(file, &base_name), index))
+                .or_default()
+                .push(base_name);
+        }
+    }
+
+    let lists = [
+        (
+            false,
+            &network_args.block_dims,
+            &network_args.block_alphas,
+            network_alpha,
+        ),
+        (
+            true,
+            &network_args.conv_block_dims,
+            &network_args.conv_block_alphas,
+            conv_alpha,
+        ),
+    ];
+
+    let mut report = vec![];
+    for (conv, dims, alphas, default_alpha) in lists {
+        let Some(BlockUsizeSeq(dims)) = dims else {
+            continue;
+        };
+
+        for (index, &declared_dim) in dims.iter().enumerate() {
+            let declared_alpha = alphas
+                .as_ref()
+                .and_then(|a| a.0.get(index).map(|&a| a as f32))
+                .unwrap_or(default_alpha);
+
+            let mut block_layers: Vec<BlockDimLayer> = layers
+                .get(&(conv, index))
+                .into_iter()
+                .flatten()
+                .map(|base_name| {
+                    let rank = file.rank(base_name);
+                    let alpha = file.alpha(base_name).map(|a| a.0);
+                    BlockDimLayer {
+                        base_name: base_name.clone(),
+                        rank,
+                        alpha,
+                        matches: rank == Some(declared_dim)
+                            && alpha.is_none_or(|a| a == declared_alpha),
+                    }
+                })
+                .collect();
+            block_layers.sort_by(|a, b| a.base_name.cmp(&b.base_name));
+
+            let status = if declared_dim == 0 && block_layers.is_empty() {
+                BlockDimStatus::Dropped
+            } else if block_layers.is_empty() {
+                BlockDimStatus::NoLayers
+            } else if block_layers.iter().all(|l| l.matches) {
+                BlockDimStatus::Match
+            } else {
+                BlockDimStatus::Mismatch
+            };
+
+            report.push(BlockDims {
+                index,
+                id: if sdxl {
+                    BlockId::from_kohya_sdxl_index(index)
+                } else {
+                    Some(BlockId::from_kohya_index(index))
+                },
+                conv,
+                declared_dim,
+                declared_alpha,
+                status,
+                layers: block_layers,
+            });
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{LrWeight, Op};
+
+    #[test]
+    fn kohya_block_indices() {
+        for (name, index) in [
+            ("lora_unet_down_blocks_0_attentions_0_proj_in", Some(1)),
+            ("lora_unet_down_blocks_1_attentions_1_proj_out", Some(5)),
+            ("lora_unet_down_blocks_2_downsamplers_0_conv", Some(9)),
+            ("lora_unet_down_blocks_3_resnets_1_conv2", Some(11)),
+            ("lora_unet_mid_block_attentions_0_proj_in", Some(12)),
+            ("lora_unet_up_blocks_0_resnets_2_conv1", Some(15)),
+            ("lora_unet_up_blocks_3_attentions_2_proj_out", Some(24)),
+            ("lora_te_text_model_encoder_layers_0_mlp_fc1", None),
+        ] {
+            assert_eq!(kohya_block_index(name), index, "{name}");
+        }
+    }
+
+    #[test]
+    fn kohya_sdxl_block_indices() {
+        // Keys of a kohya SDXL LoCon
+        for (name, index, id) in [
+            ("lora_unet_time_embed_0", 0, None),
+            ("lora_unet_label_emb_0_0", 0, None),
+            (
+                "lora_unet_input_blocks_1_0_in_layers_2",
+                2,
+                Some(BlockId::Down(1)),
+            ),
+            (
+                "lora_unet_input_blocks_4_1_transformer_blocks_0_attn1_to_k",
+                5,
+                Some(BlockId::Down(4)),
+            ),
+            (
+                "lora_unet_input_blocks_8_1_transformer_blocks_9_ff_net_2",
+                9,
+                Some(BlockId::Down(8)),
+            ),
+            (
+                "lora_unet_middle_block_1_transformer_blocks_0_attn2_to_out_0",
+                10,
+                Some(BlockId::Mid),
+            ),
+            (
+                "lora_unet_middle_block_2_out_layers_3",
+                10,
+                Some(BlockId::Mid),
+            ),
+            (
+                "lora_unet_output_blocks_0_1_transformer_blocks_0_attn1_to_q",
+                11,
+                Some(BlockId::Up(0)),
+            ),
+            (
+                "lora_unet_output_blocks_5_1_proj_out",
+                16,
+                Some(BlockId::Up(5)),
+            ),
+            (
+                "lora_unet_output_blocks_8_0_skip_connection",
+                19,
+                Some(BlockId::Up(8)),
+            ),
+            ("lora_unet_out_2", 20, None),
+        ] {
+            assert_eq!(kohya_block_index(name), Some(index), "{name}");
+            assert_eq!(BlockId::from_kohya_sdxl_index(index), id, "{name}");
+            assert_eq!(BlockId::from_base_name(name), id, "{name}");
+        }
+        assert_eq!(
+            kohya_sdxl_block_index("lora_te1_text_model_encoder_layers_0_mlp_fc1"),
+            None
+        );
+    }
+
+    #[test]
+    fn kohya_index_round_trips() {
+        for index in 0..2 * NUM_OF_BLOCKS + 1 {
+            let id = BlockId::from_kohya_index(index);
+            assert_eq!(id.kohya_index(false), Some(index), "{id}");
+        }
+        for index in 1..2 * NUM_OF_BLOCKS_SDXL + 2 {
+            let id = BlockId::from_kohya_sdxl_index(index).unwrap();
+            assert_eq!(id.kohya_index(true), Some(index), "{id}");
+        }
+    }
+
+    #[test]
+    fn block_id_parses_its_display() {
+        for id in [
+            BlockId::Down(3),
+            BlockId::Mid,
+            BlockId::Up(11),
+            BlockId::Double(7),
+            BlockId::Single(37),
+            BlockId::Block(0),
+        ] {
+            assert_eq!(id.to_string().parse::<BlockId>().unwrap(), id);
+        }
+        assert_eq!("up_3".parse::<BlockId>().unwrap(), BlockId::Up(3));
+        for unknown in ["middle", "down", "down_x", "input_03", ""] {
+            assert!(unknown.parse::<BlockId>().is_err(), "{unknown}");
+        }
+    }
+
+    #[test]
+    fn block_ids() {
+        assert_eq!(
+            BlockId::from_base_name("lora_unet_double_blocks_3_img_attn_proj"),
+            Some(BlockId::Double(3))
+        );
+        assert_eq!(
+            BlockId::from_base_name("lora_unet_single_blocks_10_linear1"),
+            Some(BlockId::Single(10))
+        );
+        assert_eq!(
+            BlockId::from_base_name("lora_unet_up_blocks_1_attentions_0_proj_in"),
+            Some(BlockId::Up(3))
+        );
+        assert_eq!(BlockId::Up(3).to_string(), "up_03");
+    }
+
+    #[test]
+    fn block_map_with_lr_weights() {
+        let names = [
+            "lora_unet_up_blocks_0_resnets_0_conv1",
+            "lora_unet_mid_block_attentions_0_proj_in",
+            "lora_unet_down_blocks_0_attentions_0_proj_in",
+            "lora_unet_down_blocks_0_attentions_0_proj_out",
+            "lora_te_text_model_encoder_layers_0_mlp_fc1",
+        ]
+        .map(String::from);
+
+        let weights = BlockLrWeights::new(
+            Some(&LrWeight::Zeros(Op::Plus, 0.5)),
+            Some(0.0),
+            None,
+            0.0,
+            false,
+        );
+        let blocks = block_map(&names, Some(&weights));
+
+        let summary: Vec<(String, usize, Option<f64>)> = blocks
+            .iter()
+            .map(|b| (b.id.to_string(), b.layers.len(), b.lr_weight))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("down_01".to_string(), 2, Some(0.5)),
+                ("mid".to_string(), 1, Some(0.0)),
+                ("up_00".to_string(), 1, Some(1.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_map_with_sdxl_lr_weights() {
+        let names = [
+            "lora_unet_input_blocks_4_1_transformer_blocks_0_attn1_to_k",
+            "lora_unet_input_blocks_8_1_proj_in",
+            "lora_unet_middle_block_1_proj_in",
+            "lora_unet_output_blocks_0_1_proj_out",
+            "lora_unet_output_blocks_8_0_skip_connection",
+            "lora_unet_out_2",
+        ]
+        .map(String::from);
+
+        let down = LrWeight::BlockF64Seq("0,0.1,0.2,0.3,0.4,0.5,0.6,0.7,0.8,0.9".parse().unwrap());
+        let up = LrWeight::BlockF64Seq("0.25".parse().unwrap());
+        let weights = BlockLrWeights::new(Some(&down), Some(0.75), Some(&up), 0.0, true);
+        assert_eq!(
+            weights.down.as_ref().map(|d| d.len()),
+            Some(NUM_OF_BLOCKS_SDXL)
+        );
+        assert_eq!(weights.weights().len(), 21);
+
+        let blocks = block_map(&names, Some(&weights));
+        let summary: Vec<(String, Option<f64>)> = blocks
+            .iter()
+            .map(|b| (b.id.to_string(), b.lr_weight))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("down_04".to_string(), Some(0.4)),
+                ("down_08".to_string(), Some(0.8)),
+                ("mid".to_string(), Some(0.75)),
+                ("up_00".to_string(), Some(0.25)),
+                ("up_08".to_string(), Some(1.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_dims_against_tensors() {
+        use crate::writer::SafetensorsWriter;
+        use candle_core::{DType, Device, Tensor};
+
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        for (base, rank, alpha, kernel) in [
+            ("lora_unet_down_blocks_0_attentions_0_proj_in", 4, 2., 1),
+            ("lora_unet_down_blocks_0_resnets_1_conv1", 2, 1., 3),
+            ("lora_unet_mid_block_attentions_0_proj_in", 8, 2., 1),
+        ] {
+            writer
+                .insert_tensor(
+                    &format!("{base}.lora_down.weight"),
+                    &Tensor::ones((rank, 8, kernel, kernel), DType::F32, &device).unwrap(),
+                )
+                .unwrap();
+            writer
+                .insert_tensor(
+                    &format!("{base}.lora_up.weight"),
+                    &Tensor::ones((8, rank, 1, 1), DType::F32, &device).unwrap(),
+                )
+                .unwrap();
+            writer
+                .insert_tensor(
+                    &format!("{base}.alpha"),
+                    &Tensor::new(alpha, &device).unwrap(),
+                )
+                .unwrap();
+        }
+
+        let mut dims = vec!["0"; 25];
+        dims[1] = "4";
+        dims[12] = "4";
+        dims[13] = "4";
+        let mut conv_dims = vec!["0"; 25];
+        conv_dims[2] = "2";
+        let args = format!(
+            r#"{{"block_dims": "{}", "block_alphas": "{}", "conv_block_dims": "{}"}}"#,
+            dims.join(","),
+            vec!["2"; 25].join(","),
+            conv_dims.join(",")
+        );
+        writer.insert_metadata("ss_network_args", &args);
+        let buffer = writer.serialize().unwrap();
+        let file = LoRAFile::new_from_buffer(&buffer, "test.safetensors", &device);
+
+        let report = block_dims(&file);
+        let status = |conv: bool, index: usize| {
+            report
+                .iter()
+                .find(|b| b.conv == conv && b.index == index)
+                .map(|b| b.status)
+        };
+
+        assert_eq!(report.len(), 50);
+        assert_eq!(status(false, 0), Some(BlockDimStatus::Dropped));
+        assert_eq!(status(false, 1), Some(BlockDimStatus::Match));
+        assert_eq!(status(false, 12), Some(BlockDimStatus::Mismatch));
+        assert_eq!(status(false, 13), Some(BlockDimStatus::NoLayers));
+        assert_eq!(status(true, 2), Some(BlockDimStatus::Match));
+
+        // conv_block_alphas defaults to 1
+        let conv = report.iter().find(|b| b.conv && b.index == 2).unwrap();
+        assert_eq!(conv.declared_alpha, 1.0);
+        assert_eq!(conv.layers[0].rank, Some(2));
+    }
+
+    #[test]
+    fn block_alphas_default_to_network_alpha() {
+        use crate::writer::SafetensorsWriter;
+        use candle_core::{DType, Device, Tensor};
+
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        for (base, rank, alpha, kernel) in [
+            ("lora_unet_down_blocks_0_attentions_0_proj_in", 4, 2., 1),
+            ("lora_unet_down_blocks_0_resnets_1_conv1", 2, 0.5, 3),
+        ] {
+            writer
+                .insert_tensor(
+                    &format!("{base}.lora_down.weight"),
+                    &Tensor::ones((rank, 8, kernel, kernel), DType::F32, &device).unwrap(),
+                )
+                .unwrap();
+            writer
+                .insert_tensor(
+                    &format!("{base}.lora_up.weight"),
+                    &Tensor::ones((8, rank, 1, 1), DType::F32, &device).unwrap(),
+                )
+                .unwrap();
+            writer
+                .insert_tensor(
+                    &format!("{base}.alpha"),
+                    &Tensor::new(alpha, &device).unwrap(),
+                )
+                .unwrap();
+        }
+
+        let mut dims = vec!["0"; 25];
+        dims[1] = "4";
+        let mut conv_dims = vec!["0"; 25];
+        conv_dims[2] = "2";
+        let args = format!(
+            r#"{{"block_dims": "{}", "conv_block_dims": "{}", "conv_alpha": "0.5"}}"#,
+            dims.join(","),
+            conv_dims.join(",")
+        );
+        writer.insert_metadata("ss_network_args", &args);
+        writer.insert_metadata("ss_network_alpha", "2.0");
+        let buffer = writer.serialize().unwrap();
+        let file = LoRAFile::new_from_buffer(&buffer, "test.safetensors", &device);
+
+        let report = block_dims(&file);
+        let block = |conv: bool, index: usize| {
+            report
+                .iter()
+                .find(|b| b.conv == conv && b.index == index)
+                .unwrap()
+        };
+        assert_eq!(block(false, 1).declared_alpha, 2.0);
+        assert_eq!(block(false, 1).status, BlockDimStatus::Match);
+        assert_eq!(block(true, 2).declared_alpha, 0.5);
+        assert_eq!(block(true, 2).status, BlockDimStatus::Match);
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use serde::Serialize;
+
+use crate::base::{shapes_compatible, KeyMap};
+use crate::file::{LayerKind, LoRAFile};
+use crate::header::read_header;
+use crate::network::Architecture;
+use crate::Result;
+
+/// A release inside an architecture that LoRAs do not always carry over to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Variant {
+    FluxDev,
+    FluxSchnell,
+    /// Pony Diffusion, an SDXL finetune far enough from the base that SDXL
+    /// LoRAs work poorly on it
+    Pony,
+}
+
+impl Variant {
+    /// Looks for the variant in a model name or modelspec architecture,
+    /// `flux1-schnell.safetensors` or `ponyDiffusionV6XL`.
+    pub fn from_name(name: &str) -> Option<Variant> {
+        let name = name.to_lowercase();
+        if name.contains("schnell") {
+            Some(Variant::FluxSchnell)
+        } else if name.contains("flux") && name.contains("dev") {
+            Some(Variant::FluxDev)
+        } else if name.contains("pony") {
+            Some(Variant::Pony)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ModelTarget {
+    pub architecture: Option<Architecture>,
+    pub variant: Option<Variant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShapeMismatch {
+    pub base_name: String,
+    pub base_key: String,
+    /// Shape of `up @ down`
+    pub lora_shape: Vec<usize>,
+    pub base_shape: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Verdict {
+    /// Every layer has a base weight of the right shape
+    Compatible,
+    /// Fits, but was trained against another variant of the architecture
+    OtherVariant,
+    /// The layers that are in the base model fit, others are missing, like
+    /// text encoder layers against a checkpoint without text encoders
+    Partial,
+    /// Another architecture, mismatched shapes or no layer in the base model
+    Incompatible,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompatibilityReport {
+    pub lora: ModelTarget,
+    pub base: ModelTarget,
+    /// Layers with a base weight of the right shape
+    pub matched: usize,
+    /// Layers without a base weight
+    pub missing: Vec<String>,
+    pub shape_mismatches: Vec<ShapeMismatch>,
+    pub verdict: Verdict,
+}
+
+/// Metadata keys that name the model a LoRA was trained on or a checkpoint is.
+const MODEL_NAME_KEYS: [&str; 4] = [
+    "modelspec.architecture",
+    "modelspec.title",
+    "ss_sd_model_name",
+    "ss_base_model_version",
+];
+
+/// Checks whether `file` can be applied to the base model. Only the header
+/// is read from `base`, and only the header of `file` is needed.
+pub fn check<R: Read>(file: &LoRAFile, base: &mut R) -> Result<CompatibilityReport> {
+    let (index, base_metadata, _) = read_header(base)?;
+    let base_shapes: HashMap<String, Vec<usize>> = index
+        .tensor_info()
+        .into_iter()
+        .map(|(name, _, shape)| (name, shape))
+        .collect();
+    let lora_shapes: HashMap<String, Vec<usize>> = file
+        .tensor_info()
+        .into_iter()
+        .map(|info| (info.name, info.shape))
+        .collect();
+
+    let key_map = KeyMap::new(base_shapes.keys().cloned());
+
+    let mut base_names = file.base_names();
+    base_names.sort();
+
+    let mut matched = 0;
+    let mut missing = vec![];
+    let mut shape_mismatches = vec![];
+    for base_name in base_names {
+        let kind = file.layer_kind(&base_name);
+        let Some(base_key) = key_map.base_key(&base_name, kind) else {
+            missing.push(base_name);
+            continue;
+        };
+
+        let base_shape = &base_shapes[&base_key];
+        match delta_shape(&lora_shapes, &base_name, kind) {
+            Some(lora_shape) if !shapes_compatible(&lora_shape, base_shape) => shape_mismatches
+                .push(ShapeMismatch {
+                    base_name,
+                    base_key,
+                    lora_shape,
+                    base_shape: base_shape.clone(),
+                }),
+            _ => matched += 1,
+        }
+    }
+
+    let lora_names = lora_shapes.keys().cloned().collect::<Vec<_>>();
+    let lora = ModelTarget {
+        architecture: file
+            .metadata()
+            .and_then(|m| m.architecture())
+            .or_else(|| architecture_from_keys(&lora_names)),
+        variant: file
+            .metadata()
+            .and_then(|m| m.metadata.as_ref())
+            .and_then(variant_from_metadata),
+    };
+
+    let base_names = base_shapes.keys().cloned().collect::<Vec<_>>();
+    let base_architecture = base_metadata
+        .as_ref()
+        .and_then(|m| m.get("modelspec.architecture"))
+        .and_then(|a| Architecture::from_model_version(a))
+        .or_else(|| architecture_from_keys(&base_names));
+    let base = ModelTarget {
+        architecture: base_architecture,
+        variant: base_metadata
+            .as_ref()
+            .and_then(variant_from_metadata)
+            .or_else(|| flux_variant(base_architecture, &base_names)),
+    };
+
+    let verdict = if matched == 0
+        || !shape_mismatches.is_empty()
+        || differ(lora.architecture, base.architecture)
+    {
+        Verdict::Incompatible
+    } else if !missing.is_empty() {
+        Verdict::Partial
+    } else if differ(lora.variant, base.variant) {
+        Verdict::OtherVariant
+    } else {
+        Verdict::Compatible
+    };
+
+    Ok(CompatibilityReport {
+        lora,
+        base,
+        matched,
+        missing,
+        shape_mismatches,
+        verdict,
+    })
+}
+
+/// Both are known and not the same.
+fn differ<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+    matches!((a, b), (Some(a), Some(b)) if a != b)
+}
+
+fn variant_from_metadata(metadata: &HashMap<String, String>) -> Option<Variant> {
+    MODEL_NAME_KEYS
+        .iter()
+        .filter_map(|key| metadata.get(*key))
+        .find_map(|name| Variant::from_name(name))
+}
+
+/// Flux dev is guidance distilled and keeps the guidance embedder that
+/// schnell does not have.
+fn flux_variant(architecture: Option<Architecture>, keys: &[String]) -> Option<Variant> {
+    if architecture != Some(Architecture::Flux) {
+        return None;
+    }
+
+    if keys
+        .iter()
+        .any(|k| k.contains("guidance_in") || k.contains("guidance_embedder"))
+    {
+        Some(Variant::FluxDev)
+    } else {
+        Some(Variant::FluxSchnell)
+    }
+}
+
+/// Architecture from the module names of a checkpoint or a LoRA. SD1 and SD2
+/// LoRAs share their module names, so those only come from checkpoints.
+pub fn architecture_from_keys(keys: &[String]) -> Option<Architecture> {
+    let has = |part: &str| keys.iter().any(|k| k.replace('.', "_").contains(part));
+
+    if has("double_blocks_") || has("single_transformer_blocks_") {
+        Some(Architecture::Flux)
+    } else if has("joint_blocks_") {
+        Some(Architecture::SD3)
+    } else if has("noise_refiner_") || has("context_refiner_") {
+        Some(Architecture::Lumina)
+    } else if has("conditioner_embedders_1") || has("lora_te2_") || has("lora_unet_input_blocks_") {
+        Some(Architecture::SDXL)
+    } else if has("cond_stage_model_model_") {
+        Some(Architecture::SD2)
+    } else if has("cond_stage_model_transformer_") {
+        Some(Architecture::SD1)
+    } else {
+        None
+    }
+}
+
+/// Shape of the update of `base_name`, from the header shapes of its
+/// factors. `None` when the network type keeps its shape elsewhere.
+fn delta_shape(
+    shapes: &HashMap<String, Vec<usize>>,
+    base_name: &str,
+    kind: LayerKind,
+) -> Option<Vec<usize>> {
+    let get = |suffix: &str| shapes.get(&format!("{base_name}.{suffix}"));
+
+    match kind {
+        LayerKind::FullDiff => return get("diff").cloned(),
+        LayerKind::Norm => return get("w_norm").cloned(),
+        LayerKind::Bias => return get("diff_b").or_else(|| get("b_norm")).cloned(),
+        LayerKind::LowRank => (),
+    }
+
+    // LoRA, LoCon and PEFT: [out, rank, ...] @ [rank, in, kh, kw]
+    for (up, down) in [
+        ("lora_up.weight", "lora_down.weight"),
+        ("lora_B.weight", "lora_A.weight"),
+        ("lora_B.default.weight", "lora_A.default.weight"),
+    ] {
+        if let (Some(up), Some(down)) = (get(up), get(down)) {
+            let mut shape = vec![*up.first()?];
+            shape.extend_from_slice(down.get(1..)?);
+            return Some(shape);
+        }
+    }
+
+    // LoHa keeps conv updates flattened to [out, in * kh * kw]
+    if get("hada_t1").is_none() {
+        if let (Some(w1_a), Some(w1_b)) = (get("hada_w1_a"), get("hada_w1_b")) {
+            return Some(vec![*w1_a.first()?, *w1_b.get(1)?]);
+        }
+    }
+
+    // LoKr: kron(w1, w2), either factor possibly split in two. Skipped for
+    // Tucker factors, whose kernel dims are in `lokr_t*`.
+    let factor = |w: &str, t: &str| match get(w) {
+        Some(shape) => Some(shape.clone()),
+        None if get(t).is_none() => {
+            let a = get(&format!("{w}_a"))?;
+            let b = get(&format!("{w}_b"))?;
+            let mut shape = vec![*a.first()?];
+            shape.extend_from_slice(b.get(1..)?);
+            Some(shape)
+        }
+        None => None,
+    };
+    let w1 = factor("lokr_w1", "lokr_t1")?;
+    let w2 = factor("lokr_w2", "lokr_t2")?;
+    let mut shape: Vec<usize> = w2
+        .iter()
+        .enumerate()
+        .map(|(i, d)| d * w1.get(i).copied().unwrap_or(1))
+        .collect();
+    if w1.len() > shape.len() {
+        shape.extend_from_slice(&w1[shape.len()..]);
+    }
+    Some(shape)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::*;
+    use crate::writer::SafetensorsWriter;
+
+    fn lora_file(layers: &[(&str, &[usize], &[usize])], metadata: &[(&str, &str)]) -> LoRAFile {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        for (name, up, down) in layers {
+            let up = Tensor::zeros(*up, DType::F32, &device).unwrap();
+            let down = Tensor::zeros(*down, DType::F32, &device).unwrap();
+            writer
+                .insert_tensor(&format!("{name}.lora_up.weight"), &up)
+                .unwrap();
+            writer
+                .insert_tensor(&format!("{name}.lora_down.weight"), &down)
+                .unwrap();
+        }
+        for (key, value) in metadata {
+            writer.insert_metadata(key, value);
+        }
+        LoRAFile::new_from_header_buffer(&writer.serialize().unwrap(), "lora.safetensors").unwrap()
+    }
+
+    fn checkpoint(tensors: &[(&str, &[usize])]) -> Vec<u8> {
+        let mut writer = SafetensorsWriter::new();
+        for (name, shape) in tensors {
+            let tensor = Tensor::zeros(*shape, DType::F16, &Device::Cpu).unwrap();
+            writer.insert_tensor(name, &tensor).unwrap();
+        }
+        writer.serialize().unwrap()
+    }
+
+    #[test]
+    fn flux_dev_lora_on_schnell() -> crate::Result<()> {
+        let file = lora_file(
+            &[("lora_unet_double_blocks_0_img_attn_qkv", &[12, 2], &[2, 4])],
+            &[("modelspec.architecture", "flux-1-dev/lora")],
+        );
+
+        let dev = checkpoint(&[
+            ("double_blocks.0.img_attn.qkv.weight", &[12, 4]),
+            ("guidance_in.in_layer.weight", &[4, 4]),
+        ]);
+        let report = check(&file, &mut Cursor::new(dev))?;
+        assert_eq!(report.lora.architecture, Some(Architecture::Flux));
+        assert_eq!(report.base.variant, Some(Variant::FluxDev));
+        assert_eq!(report.matched, 1);
+        assert_eq!(report.verdict, Verdict::Compatible);
+
+        let schnell = checkpoint(&[("double_blocks.0.img_attn.qkv.weight", &[12, 4])]);
+        let report = check(&file, &mut Cursor::new(schnell))?;
+        assert_eq!(report.base.variant, Some(Variant::FluxSchnell));
+        assert_eq!(report.verdict, Verdict::OtherVariant);
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_and_mismatched_layers() -> crate::Result<()> {
+        let file = lora_file(
+            &[
+                (
+                    "lora_unet_input_blocks_4_1_proj_in",
+                    &[8, 2, 1, 1],
+                    &[2, 4, 1, 1],
+                ),
+                ("lora_unet_input_blocks_4_1_proj_out", &[8, 2], &[2, 4]),
+                (
+                    "lora_te1_text_model_encoder_layers_0_mlp_fc1",
+                    &[8, 2],
+                    &[2, 4],
+                ),
+            ],
+            &[("ss_sd_model_name", "ponyDiffusionV6XL.safetensors")],
+        );
+        assert_eq!(file.metadata().unwrap().architecture(), None);
+
+        let sdxl = checkpoint(&[
+            (
+                "model.diffusion_model.input_blocks.4.1.proj_in.weight",
+                &[8, 4, 1, 1],
+            ),
+            (
+                "model.diffusion_model.input_blocks.4.1.proj_out.weight",
+                &[4, 8],
+            ),
+            ("conditioner.embedders.1.model.ln_final.weight", &[4]),
+        ]);
+        let report = check(&file, &mut Cursor::new(sdxl))?;
+        assert_eq!(report.lora.architecture, Some(Architecture::SDXL));
+        assert_eq!(report.lora.variant, Some(Variant::Pony));
+        assert_eq!(report.base.architecture, Some(Architecture::SDXL));
+        assert_eq!(report.matched, 1);
+        assert_eq!(
+            report.missing,
+            vec!["lora_te1_text_model_encoder_layers_0_mlp_fc1"]
+        );
+        assert_eq!(report.shape_mismatches.len(), 1);
+        assert_eq!(report.shape_mismatches[0].lora_shape, vec![8, 4]);
+        assert_eq!(report.verdict, Verdict::Incompatible);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lokr_and_loha_shapes() {
+        let shapes: HashMap<String, Vec<usize>> = [
+            ("a.lokr_w1", vec![4, 2]),
+            ("a.lokr_w2_a", vec![8, 1]),
+            ("a.lokr_w2_b", vec![1, 8, 3, 3]),
+            ("b.hada_w1_a", vec![16, 2]),
+            ("b.hada_w1_b", vec![2, 72]),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+
+        assert_eq!(
+            delta_shape(&shapes, "a", LayerKind::LowRank),
+            Some(vec![32, 16, 3, 3])
+        );
+        assert_eq!(
+            delta_shape(&shapes, "b", LayerKind::LowRank),
+            Some(vec![16, 72])
+        );
+        assert!(shapes_compatible(&[16, 72], &[16, 8, 3, 3]));
+    }
+}
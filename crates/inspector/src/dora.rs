@@ -0,0 +1,305 @@
+use std::io::{Read, Seek};
+
+use candle_core::{DType, Tensor};
+use serde::Serialize;
+
+use crate::base::BaseModel;
+use crate::file::LoRAFile;
+use crate::{InspectorError, Result};
+
+/// Distribution of the values in a `dora_scale` magnitude vector.
+#[derive(Debug, Clone, Serialize)]
+pub struct MagnitudeStats {
+    pub len: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+    pub median: f64,
+    /// `min / max`, how uneven the column magnitudes are.
+    pub min_max_ratio: f64,
+}
+
+impl MagnitudeStats {
+    pub fn new(values: &[f64]) -> Option<MagnitudeStats> {
+        if values.is_empty() {
+            return None;
+        }
+
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0
+        } else {
+            sorted[sorted.len() / 2]
+        };
+        let min = sorted[0];
+        let max = sorted[sorted.len() - 1];
+
+        Some(MagnitudeStats {
+            len: values.len(),
+            mean,
+            std_dev: variance.sqrt(),
+            min,
+            max,
+            median,
+            min_max_ratio: if max.abs() > f64::EPSILON {
+                min / max
+            } else {
+                0.0
+            },
+        })
+    }
+}
+
+/// How the magnitude compares to the base weight it rescales.
+#[derive(Debug, Clone, Serialize)]
+pub struct BaseComparison {
+    /// `dora_scale / ||W0||` per column.
+    pub ratio: MagnitudeStats,
+    /// Largest `|dora_scale / ||W0|| - 1|`.
+    pub max_deviation: f64,
+    /// `||dora_scale - ||W0 + dW|| ||`, how far the magnitude moves the column
+    /// lengths of the merged weight.
+    pub magnitude_update: f64,
+    /// `||W' - W0||` with the magnitude applied.
+    pub effective_scale: f64,
+    /// The magnitude changes the weight more than the directional update.
+    pub magnitude_dominates: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DoRALayer {
+    pub base_name: String,
+    /// LyCORIS `wd_on_out`: one magnitude per output row instead of per
+    /// input column.
+    pub on_out: bool,
+    pub magnitude: MagnitudeStats,
+    /// `||dW||` of the directional update, scaled by `alpha / rank`.
+    pub direction_update: f64,
+    /// Only when the base weight is supplied.
+    pub base: Option<BaseComparison>,
+}
+
+/// Lays `t` out as `(magnitudes, rest)`: rows for `wd_on_out`, input columns
+/// otherwise.
+fn by_magnitude(t: &Tensor, on_out: bool) -> Result<Tensor> {
+    let t = t.to_dtype(DType::F64)?;
+    let t = if on_out { t } else { t.transpose(0, 1)? };
+    let rows = t.dim(0)?;
+    Ok(t.contiguous()?.reshape((rows, ()))?)
+}
+
+fn row_norms(t: &Tensor) -> Result<Tensor> {
+    Ok(t.sqr()?.sum_keepdim(1)?.sqrt()?)
+}
+
+fn frobenius(t: &Tensor) -> Result<f64> {
+    Ok(t.sqr()?.sum_all()?.to_scalar::<f64>()?.sqrt())
+}
+
+/// Analyzes a DoRA magnitude against its directional update `delta`, and
+/// against the base weight when one is given.
+///
+/// Follows LyCORIS' `apply_weight_decompose`:
+/// `W' = dora_scale * (W0 + dW) / ||W0 + dW||`.
+pub fn analyze(
+    base_name: &str,
+    dora_scale: &Tensor,
+    delta: &Tensor,
+    base_weight: Option<&Tensor>,
+) -> Result<DoRALayer> {
+    let on_out = dora_scale.dims().first().is_some_and(|d| *d > 1);
+    let magnitude = dora_scale.flatten_all()?.to_dtype(DType::F64)?;
+    let values: Vec<f64> = magnitude.to_vec1()?;
+    let stats = MagnitudeStats::new(&values)
+        .ok_or_else(|| InspectorError::Msg(format!("{base_name} has an empty dora_scale")))?;
+
+    let shape = delta.dims().to_vec();
+    let delta = by_magnitude(delta, on_out)?;
+    if delta.dim(0)? != values.len() {
+        return Err(InspectorError::Msg(format!(
+            "{base_name} dora_scale has {} values but the update has {} columns",
+            values.len(),
+            delta.dim(0)?
+        )));
+    }
+    let direction_update = frobenius(&delta)?;
+
+    let base = match base_weight {
+        Some(base_weight) => {
+            if base_weight.elem_count() != delta.elem_count() {
+                return Err(InspectorError::Msg(format!(
+                    "{base_name} base weight {:?} does not match the update {:?}",
+                    base_weight.dims(),
+                    shape
+                )));
+            }
+            let base_weight = by_magnitude(&base_weight.reshape(shape)?, on_out)?;
+            let magnitude = magnitude.reshape((values.len(), 1))?;
+
+            let base_norms = row_norms(&base_weight)?;
+            let ratios: Vec<f64> = magnitude.div(&base_norms)?.flatten_all()?.to_vec1()?;
+            let ratio = MagnitudeStats::new(&ratios).unwrap_or(stats.clone());
+            let max_deviation = ratios.iter().map(|r| (r - 1.0).abs()).fold(0.0, f64::max);
+
+            let merged = (&base_weight + &delta)?;
+            let merged_norms = row_norms(&merged)?;
+            let magnitude_update = frobenius(&(&magnitude - &merged_norms)?)?;
+            let decomposed = merged.broadcast_mul(&magnitude.div(&merged_norms)?)?;
+            let effective_scale = frobenius(&(&decomposed - &base_weight)?)?;
+
+            Some(BaseComparison {
+                ratio,
+                max_deviation,
+                magnitude_update,
+                effective_scale,
+                magnitude_dominates: magnitude_update > direction_update,
+            })
+        }
+        None => None,
+    };
+
+    Ok(DoRALayer {
+        base_name: base_name.to_string(),
+        on_out,
+        magnitude: stats,
+        direction_update,
+        base,
+    })
+}
+
+/// Every layer with a `dora_scale`, without base weights.
+pub fn layers(file: &LoRAFile) -> Vec<DoRALayer> {
+    file.base_names()
+        .iter()
+        .filter_map(|base_name| {
+            let dora_scale = file.dora_scale(base_name)?;
+            let delta = file.scale_weight(base_name).ok()?;
+            analyze(base_name, &dora_scale, &delta, None).ok()
+        })
+        .collect()
+}
+
+/// Every layer with a `dora_scale`, compared with the weight it applies to in
+/// `base`. Layers whose weight is not in `base` are left without `base`.
+pub fn layers_with_base<R: Read + Seek>(
+    file: &LoRAFile,
+    base: &mut BaseModel<R>,
+) -> Vec<DoRALayer> {
+    let mut base_names = file.base_names();
+    base_names.sort();
+
+    base_names
+        .iter()
+        .filter_map(|base_name| {
+            let dora_scale = file.dora_scale(base_name)?;
+            let delta = file.scale_weight(base_name).ok()?;
+            let base_weight = base
+                .base_key(base_name, file.layer_kind(base_name))
+                .and_then(|key| base.tensor(&key, delta.device()).ok());
+            analyze(base_name, &dora_scale, &delta, base_weight.as_ref()).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use candle_core::Device;
+
+    use super::*;
+    use crate::writer::SafetensorsWriter;
+
+    #[test]
+    fn magnitude_against_base_weight() {
+        let device = Device::Cpu;
+        // Two input columns with norms 5 and 1
+        let base = Tensor::new(&[[3f64, 0.], [4., 1.]], &device).unwrap();
+        let delta = Tensor::new(&[[0f64, 0.], [0., 0.]], &device).unwrap();
+
+        // Magnitude left at the base column norms changes nothing
+        let dora_scale = Tensor::new(&[[5f64, 1.]], &device).unwrap();
+        let layer = analyze("layer", &dora_scale, &delta, Some(&base)).unwrap();
+        assert!(!layer.on_out);
+        let comparison = layer.base.unwrap();
+        assert!(comparison.effective_scale < 1e-12);
+        assert!(comparison.max_deviation < 1e-12);
+        assert!(!comparison.magnitude_dominates);
+
+        // Doubling the second column is all magnitude
+        let dora_scale = Tensor::new(&[[5f64, 2.]], &device).unwrap();
+        let layer = analyze("layer", &dora_scale, &delta, Some(&base)).unwrap();
+        let comparison = layer.base.unwrap();
+        assert!((comparison.effective_scale - 1.0).abs() < 1e-12);
+        assert!((comparison.max_deviation - 1.0).abs() < 1e-12);
+        assert!(comparison.magnitude_dominates);
+        assert_eq!(layer.magnitude.min_max_ratio, 0.4);
+    }
+
+    #[test]
+    fn magnitude_stats_without_base() {
+        let device = Device::Cpu;
+        let delta = Tensor::new(&[[1f32, 0.], [0., 0.], [0., 0.]], &device).unwrap();
+        // wd_on_out keeps one magnitude per output row
+        let dora_scale = Tensor::new(&[[1f32], [2.], [3.]], &device).unwrap();
+
+        let layer = analyze("layer", &dora_scale, &delta, None).unwrap();
+        assert!(layer.on_out);
+        assert!(layer.base.is_none());
+        assert_eq!(layer.magnitude.median, 2.0);
+        assert_eq!(layer.direction_update, 1.0);
+
+        let wrong = Tensor::new(&[[1f32, 2., 3.]], &device).unwrap();
+        assert!(analyze("layer", &wrong, &delta, None).is_err());
+    }
+
+    #[test]
+    fn layers_against_base_model() -> Result<()> {
+        let device = Device::Cpu;
+        let e0 = Tensor::new(&[[1f32], [0.], [0.], [0.]], &device)?;
+        let mut writer = SafetensorsWriter::new();
+        for name in [
+            "lora_unet_down_blocks_0_attentions_0_proj_in",
+            "lora_unet_down_blocks_0_attentions_0_proj_out",
+        ] {
+            writer.insert_tensor(&format!("{name}.lora_up.weight"), &e0)?;
+            writer.insert_tensor(&format!("{name}.lora_down.weight"), &(e0.t()? * 0.5)?)?;
+            writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(1f32, &device)?)?;
+            // The last column doubled
+            writer.insert_tensor(
+                &format!("{name}.dora_scale"),
+                &Tensor::new(&[[4f32, 3., 2., 2.]], &device)?,
+            )?;
+        }
+        let lora_file = LoRAFile::new_from_buffer(&writer.serialize()?, "lora", &device);
+
+        let weight = Tensor::new(
+            &[
+                [4f32, 0., 0., 0.],
+                [0., 3., 0., 0.],
+                [0., 0., 2., 0.],
+                [0., 0., 0., 1.],
+            ],
+            &device,
+        )?;
+        let mut writer = SafetensorsWriter::new();
+        writer.insert_tensor("down_blocks.0.attentions.0.proj_in.weight", &weight)?;
+        let mut base = BaseModel::new(Cursor::new(writer.serialize()?))?;
+
+        let layers = layers_with_base(&lora_file, &mut base);
+        assert_eq!(layers.len(), 2);
+        let comparison = layers[0].base.as_ref().unwrap();
+        assert!((comparison.max_deviation - 1.0).abs() < 1e-6);
+        assert!(comparison.magnitude_dominates);
+        // Not in the base model
+        assert!(layers[1].base.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+
+use candle_core::{DType, Tensor};
+use serde::Serialize;
+
+use crate::file::LoRAFile;
+use crate::metadata::Metadata;
+use crate::svd::flatten_to_2d;
+use crate::writer::SafetensorsWriter;
+use crate::{InspectorError, Result};
+
+/// DyLoRA rank step from `unit` in the network args. kohya defaults to 1.
+pub fn unit(metadata: &Metadata) -> usize {
+    metadata
+        .network_args()
+        .and_then(|args| args.unit)
+        .filter(|unit| *unit > 0)
+        .unwrap_or(1)
+}
+
+/// A layer using only its first `rank` ranks, the way DyLoRA trained it.
+#[derive(Debug, Clone, Serialize)]
+pub struct RankSlice {
+    pub rank: usize,
+    /// Frobenius norm of the sliced delta weight, scaled by `alpha / rank`.
+    pub effective_scale: f64,
+    /// `||delta_rank - delta_full|| / ||delta_full||`
+    pub relative_error: f64,
+    /// Share of the unscaled `up @ down` energy carried by the slice. Can go
+    /// over 1 when later ranks cancel earlier ones.
+    pub energy: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DyLoRALayer {
+    pub base_name: String,
+    pub rank: usize,
+    pub alpha: f64,
+    pub slices: Vec<RankSlice>,
+}
+
+/// Slices `up`/`down` at every multiple of `unit`.
+///
+/// Works from the `rank x rank` Gram matrices: with `P = (up^T up) * (down
+/// down^T)` elementwise, `<delta_r, delta_c>` is the sum of the top left
+/// `r x c` block of `P` scaled by `alpha / r * alpha / c`.
+pub fn rank_slices(up: &Tensor, down: &Tensor, alpha: f64, unit: usize) -> Result<Vec<RankSlice>> {
+    let up = flatten_to_2d(up)?.to_dtype(DType::F64)?;
+    let down = flatten_to_2d(down)?.to_dtype(DType::F64)?;
+    let rank = down.dim(0)?;
+
+    let products: Vec<f64> = up
+        .t()?
+        .matmul(&up)?
+        .mul(&down.matmul(&down.t()?)?)?
+        .flatten_all()?
+        .to_vec1()?;
+
+    // sums[r][c] is the sum of products[..r][..c]
+    let mut sums = vec![vec![0.0; rank + 1]; rank + 1];
+    for r in 0..rank {
+        for c in 0..rank {
+            sums[r + 1][c + 1] =
+                products[r * rank + c] + sums[r][c + 1] + sums[r + 1][c] - sums[r][c];
+        }
+    }
+
+    let scale = |r: usize| alpha / r as f64;
+    let full = sums[rank][rank];
+    let full_scaled = scale(rank).powi(2) * full;
+
+    let mut ranks: Vec<usize> = (unit.max(1)..=rank).step_by(unit.max(1)).collect();
+    if ranks.last() != Some(&rank) {
+        ranks.push(rank);
+    }
+
+    Ok(ranks
+        .into_iter()
+        .map(|r| {
+            let sliced = scale(r).powi(2) * sums[r][r];
+            let cross = scale(r) * scale(rank) * sums[r][rank];
+            let error = (sliced - 2.0 * cross + full_scaled).max(0.0);
+
+            RankSlice {
+                rank: r,
+                effective_scale: sliced.max(0.0).sqrt(),
+                relative_error: if full_scaled > f64::EPSILON {
+                    (error / full_scaled).sqrt()
+                } else {
+                    0.0
+                },
+                energy: if full > f64::EPSILON {
+                    sums[r][r] / full
+                } else {
+                    0.0
+                },
+            }
+        })
+        .collect())
+}
+
+/// Rank slices of `base_name`, `None` when it is not an up/down layer.
+pub fn layer(file: &LoRAFile, base_name: &str, unit: usize) -> Result<Option<DyLoRALayer>> {
+    let Some((up, down)) = file.up_down(base_name)? else {
+        return Ok(None);
+    };
+
+    let rank = down.dims()[0];
+    let alpha = file
+        .alpha(base_name)
+        .map(|a| a.0 as f64)
+        .unwrap_or(rank as f64);
+
+    Ok(Some(DyLoRALayer {
+        base_name: base_name.to_string(),
+        rank,
+        alpha,
+        slices: rank_slices(&up, &down, alpha, unit)?,
+    }))
+}
+
+/// Rank slices of every up/down layer, using the `unit` from the metadata.
+pub fn layers(file: &LoRAFile) -> Vec<DyLoRALayer> {
+    let unit = file.metadata().map(unit).unwrap_or(1);
+    file.base_names()
+        .iter()
+        .filter_map(|base_name| layer(file, base_name, unit).ok().flatten())
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SliceSummary {
+    pub rank: usize,
+    pub layers: usize,
+    pub mean_relative_error: f64,
+    pub max_relative_error: f64,
+    pub mean_energy: f64,
+}
+
+/// Slices averaged over the layers that have them, to pick a rank to keep.
+pub fn summary(layers: &[DyLoRALayer]) -> Vec<SliceSummary> {
+    let mut by_rank: BTreeMap<usize, Vec<&RankSlice>> = BTreeMap::new();
+    for slice in layers.iter().flat_map(|l| &l.slices) {
+        by_rank.entry(slice.rank).or_default().push(slice);
+    }
+
+    by_rank
+        .into_iter()
+        .map(|(rank, slices)| {
+            let n = slices.len() as f64;
+            SliceSummary {
+                rank,
+                layers: slices.len(),
+                mean_relative_error: slices.iter().map(|s| s.relative_error).sum::<f64>() / n,
+                max_relative_error: slices.iter().map(|s| s.relative_error).fold(0.0, f64::max),
+                mean_energy: slices.iter().map(|s| s.energy).sum::<f64>() / n,
+            }
+        })
+        .collect()
+}
+
+/// Rewrites a DyLoRA file keeping only the first `rank` ranks of every layer.
+///
+/// Alphas are kept: DyLoRA scales a slice by `alpha / rank`, which is what the
+/// truncated layer gets when loaded as a plain LoRA.
+pub fn truncate(buffer: &[u8], rank: usize, unit: usize) -> Result<Vec<u8>> {
+    if rank == 0 || !rank.is_multiple_of(unit.max(1)) {
+        return Err(InspectorError::Msg(format!(
+            "rank {rank} is not a multiple of the DyLoRA unit {unit}"
+        )));
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    for name in writer.names() {
+        let dim = if name.ends_with(".lora_down.weight") {
+            0
+        } else if name.ends_with(".lora_up.weight") {
+            1
+        } else {
+            continue;
+        };
+
+        let Some(tensor) = writer.get(&name) else {
+            continue;
+        };
+        if tensor.shape.get(dim).is_some_and(|size| *size > rank) {
+            let narrowed = tensor.narrow(dim, rank)?;
+            writer.insert(&name, narrowed);
+        }
+    }
+
+    if writer
+        .metadata()
+        .is_some_and(|m| m.contains_key("ss_network_dim"))
+    {
+        writer.insert_metadata("ss_network_dim", &rank.to_string());
+    }
+
+    writer.serialize()
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::*;
+
+    fn dylora_buffer() -> Vec<u8> {
+        let device = Device::Cpu;
+        let base = "lora_unet_down_blocks_0_attentions_0_proj_in";
+        let up = Tensor::new(
+            &[[1f32, 0., 0.5, 0.], [0., 1., 0., 0.25], [1., 1., 0., 0.]],
+            &device,
+        )
+        .unwrap();
+        let down = Tensor::new(&[[1f32, 0.], [0., 1.], [1., 1.], [0., 2.]], &device).unwrap();
+
+        let mut writer = SafetensorsWriter::new();
+        writer
+            .insert_tensor(&format!("{base}.lora_up.weight"), &up)
+            .unwrap();
+        writer
+            .insert_tensor(&format!("{base}.lora_down.weight"), &down)
+            .unwrap();
+        writer
+            .insert_tensor(
+                &format!("{base}.alpha"),
+                &Tensor::new(2f32, &device).unwrap(),
+            )
+            .unwrap();
+        writer.insert_metadata("ss_network_module", "networks.dylora");
+        writer.insert_metadata("ss_network_dim", "4");
+        writer.insert_metadata("ss_network_args", r#"{"unit": "2"}"#);
+        writer.serialize().unwrap()
+    }
+
+    fn delta(up: &Tensor, down: &Tensor, rank: usize) -> Tensor {
+        up.narrow(1, 0, rank)
+            .unwrap()
+            .matmul(&down.narrow(0, 0, rank).unwrap())
+            .unwrap()
+            .affine(2.0 / rank as f64, 0.0)
+            .unwrap()
+    }
+
+    fn norm(t: &Tensor) -> f64 {
+        t.sqr()
+            .unwrap()
+            .sum_all()
+            .unwrap()
+            .to_dtype(DType::F64)
+            .unwrap()
+            .to_scalar::<f64>()
+            .unwrap()
+            .sqrt()
+    }
+
+    #[test]
+    fn slices_match_direct_reconstruction() {
+        let buffer = dylora_buffer();
+        let file = LoRAFile::new_from_buffer(&buffer, "test.safetensors", &Device::Cpu);
+        let layers = layers(&file);
+        assert_eq!(layers.len(), 1);
+
+        let layer = &layers[0];
+        assert_eq!(
+            layer.slices.iter().map(|s| s.rank).collect::<Vec<_>>(),
+            vec![2, 4]
+        );
+
+        let (up, down) = file.up_down(&layer.base_name).unwrap().unwrap();
+        let full = delta(&up, &down, 4);
+        let half = delta(&up, &down, 2);
+
+        let slice = &layer.slices[0];
+        assert!((slice.effective_scale - norm(&half)).abs() < 1e-5);
+        let error = norm(&(&half - &full).unwrap()) / norm(&full);
+        assert!((slice.relative_error - error).abs() < 1e-5);
+
+        let last = &layer.slices[1];
+        assert!(last.relative_error < 1e-6);
+        assert!((last.energy - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn truncate_keeps_leading_ranks() {
+        let buffer = dylora_buffer();
+        assert!(truncate(&buffer, 3, 2).is_err());
+
+        let truncated = truncate(&buffer, 2, 2).unwrap();
+        let file = LoRAFile::new_from_buffer(&truncated, "test.safetensors", &Device::Cpu);
+        let base = "lora_unet_down_blocks_0_attentions_0_proj_in";
+
+        let (up, down) = file.up_down(base).unwrap().unwrap();
+        assert_eq!(up.dims(), &[3, 2]);
+        assert_eq!(down.dims(), &[2, 2]);
+        assert_eq!(
+            down.to_vec2::<f32>().unwrap(),
+            vec![vec![1., 0.], vec![0., 1.]]
+        );
+        assert_eq!(up.to_vec2::<f32>().unwrap()[2], vec![1., 1.]);
+        assert_eq!(
+            file.metadata()
+                .and_then(|m| m.metadata.as_ref())
+                .and_then(|m| m.get("ss_network_dim"))
+                .map(|v| v.as_str()),
+            Some("2")
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use std::io::{Read, Seek};
+
+use candle_core::{DType, Device, Tensor};
+use serde::Serialize;
+
+use crate::base::BaseModel;
+use crate::compat::architecture_from_keys;
+use crate::modelspec::architecture_name;
+use crate::subspace::truncated_svd;
+use crate::svd::flatten_to_2d;
+use crate::writer::SafetensorsWriter;
+use crate::{InspectorError, Result};
+
+/// Directions found beyond the rank, so the ones kept converge.
+const OVERSAMPLE: usize = 8;
+/// Subspace iterations of the randomized SVD.
+const ITERATIONS: usize = 4;
+
+/// Checkpoint prefixes and the kohya prefix of the modules under them.
+const PREFIXES: [(&str, &str); 9] = [
+    ("model.diffusion_model.", "lora_unet"),
+    ("cond_stage_model.transformer.", "lora_te"),
+    ("conditioner.embedders.0.transformer.", "lora_te1"),
+    ("text_encoders.clip_l.transformer.", "lora_te1"),
+    ("text_encoders.clip_g.transformer.", "lora_te2"),
+    ("text_encoder.", "lora_te1"),
+    ("text_encoder_2.", "lora_te2"),
+    ("unet.", "lora_unet"),
+    ("transformer.", "lora_unet"),
+];
+
+/// Parts of a checkpoint kohya LoRAs do not train: the VAE, and the OpenCLIP
+/// and T5 text encoders whose module names differ from the ones the LoRA
+/// loaders expect.
+const UNSUPPORTED_PREFIXES: [&str; 6] = [
+    "first_stage_model.",
+    "vae.",
+    "conditioner.embedders.1.model.",
+    "cond_stage_model.model.",
+    "text_encoders.t5xxl.",
+    "text_encoder_3.",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum RankSelection {
+    /// The same rank for every layer
+    Fixed(usize),
+    /// The smallest rank that keeps `threshold` of the layer's energy
+    /// `||dW||^2`, up to `max_rank`
+    Energy { threshold: f64, max_rank: usize },
+}
+
+impl RankSelection {
+    pub fn max_rank(&self) -> usize {
+        match self {
+            RankSelection::Fixed(rank) => *rank,
+            RankSelection::Energy { max_rank, .. } => *max_rank,
+        }
+    }
+
+    /// Rank for singular values `s` of an update with `total_energy`.
+    fn rank(&self, s: &[f64], total_energy: f64) -> usize {
+        let rank = match self {
+            RankSelection::Fixed(rank) => *rank,
+            RankSelection::Energy {
+                threshold,
+                max_rank,
+            } => {
+                let mut kept = 0.0;
+                s.iter()
+                    .take(*max_rank)
+                    .position(|s| {
+                        kept += s * s;
+                        kept >= threshold * total_energy
+                    })
+                    .map_or(*max_rank, |i| i + 1)
+            }
+        };
+        rank.clamp(1, s.len().max(1))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtractOptions {
+    pub rank: RankSelection,
+    /// Rank of conv layers with a kernel larger than 1x1, which are left out
+    /// when `None`
+    pub conv_rank: Option<RankSelection>,
+    /// Layers with `||dW|| / ||W||` below this are left out as unchanged
+    pub min_relative_diff: f64,
+    /// Dtype the factors are saved in
+    pub dtype: DType,
+    /// Recorded in the metadata
+    pub base_model: Option<String>,
+    pub tuned_model: Option<String>,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        ExtractOptions {
+            rank: RankSelection::Fixed(32),
+            conv_rank: None,
+            min_relative_diff: 1e-4,
+            dtype: DType::F16,
+            base_model: None,
+            tuned_model: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtractedLayer {
+    pub key: String,
+    pub lora_name: String,
+    pub rank: usize,
+    /// Fraction of `||dW||^2` the factors keep
+    pub energy: f64,
+    /// `||dW|| / ||W||`
+    pub relative_diff: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum SkipReason {
+    Unchanged,
+    NotInTuned,
+    ShapeMismatch,
+    /// Conv layer without a `conv_rank`
+    Conv,
+    /// No kohya module name for the key
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedLayer {
+    pub key: String,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtractReport {
+    pub layers: Vec<ExtractedLayer>,
+    pub skipped: Vec<SkippedLayer>,
+}
+
+/// kohya module name of a checkpoint weight key,
+/// `model.diffusion_model.input_blocks.4.1.proj_in.weight` is
+/// `lora_unet_input_blocks_4_1_proj_in`. Keys without a known prefix, like
+/// those of a standalone Flux transformer, are taken as the unet.
+pub fn kohya_name(key: &str) -> Option<String> {
+    let module = key.strip_suffix(".weight")?;
+    if UNSUPPORTED_PREFIXES.iter().any(|p| module.starts_with(p)) {
+        return None;
+    }
+
+    let (prefix, module) = PREFIXES
+        .iter()
+        .find_map(|(prefix, lora)| module.strip_prefix(prefix).map(|m| (*lora, m)))
+        .unwrap_or(("lora_unet", module));
+
+    Some(format!("{prefix}_{}", module.replace('.', "_")))
+}
+
+/// Extracts a kohya LoRA from the difference of `tuned` and `base`, one
+/// layer at a time so only two tensors are in memory at once. Returns the
+/// safetensors file and what was extracted.
+pub fn extract<B: Read + Seek, T: Read + Seek>(
+    base: &mut BaseModel<B>,
+    tuned: &mut BaseModel<T>,
+    options: &ExtractOptions,
+) -> Result<(Vec<u8>, ExtractReport)> {
+    let mut keys = base.keys();
+    keys.sort();
+
+    let mut writer = SafetensorsWriter::new();
+    let mut report = ExtractReport {
+        layers: vec![],
+        skipped: vec![],
+    };
+
+    for key in keys {
+        let Some(shape) = base.shape(&key).map(|s| s.to_vec()) else {
+            continue;
+        };
+        // Linear and conv weights, which are what LoRA modules wrap
+        if !matches!(shape.len(), 2 | 4)
+            || key.contains("embedding")
+            || key.contains("embed_tokens")
+        {
+            continue;
+        }
+
+        let mut skip = |reason| {
+            report.skipped.push(SkippedLayer {
+                key: key.clone(),
+                reason,
+            })
+        };
+
+        let Some(lora_name) = kohya_name(&key) else {
+            skip(SkipReason::Unsupported);
+            continue;
+        };
+        let selection = match shape.as_slice() {
+            [_, _, kh, kw] if kh * kw > 1 => match options.conv_rank {
+                Some(selection) => selection,
+                None => {
+                    skip(SkipReason::Conv);
+                    continue;
+                }
+            },
+            _ => options.rank,
+        };
+        match tuned.shape(&key) {
+            None => {
+                skip(SkipReason::NotInTuned);
+                continue;
+            }
+            Some(tuned_shape) if tuned_shape != shape.as_slice() => {
+                skip(SkipReason::ShapeMismatch);
+                continue;
+            }
+            Some(_) => (),
+        }
+
+        let weight = base.tensor(&key, &Device::Cpu)?.to_dtype(DType::F32)?;
+        let delta = (tuned.tensor(&key, &Device::Cpu)?.to_dtype(DType::F32)? - &weight)?;
+        let total_energy = energy(&delta)?;
+        let relative_diff = (total_energy / energy(&weight)?.max(f64::MIN_POSITIVE)).sqrt();
+        if relative_diff < options.min_relative_diff || total_energy == 0.0 {
+            skip(SkipReason::Unchanged);
+            continue;
+        }
+
+        let matrix = flatten_to_2d(&delta)?;
+        let (rows, cols) = matrix.dims2()?;
+        let k = (selection.max_rank() + OVERSAMPLE).min(rows).min(cols);
+        let svd = truncated_svd(&matrix, k, ITERATIONS)?;
+        let rank = selection.rank(&svd.s, total_energy).min(k);
+
+        // Split the singular values evenly between the factors
+        let root: Vec<f32> = svd.s[..rank].iter().map(|s| s.sqrt() as f32).collect();
+        let root = Tensor::from_vec(root, rank, &Device::Cpu)?;
+        let up = svd
+            .u
+            .narrow(1, 0, rank)?
+            .broadcast_mul(&root.unsqueeze(0)?)?;
+        let down = svd
+            .vt
+            .narrow(0, 0, rank)?
+            .broadcast_mul(&root.unsqueeze(1)?)?;
+        let (up, down) = match shape.as_slice() {
+            [out, input, kh, kw] => (
+                up.reshape((*out, rank, 1, 1))?,
+                down.reshape((rank, *input, *kh, *kw))?,
+            ),
+            _ => (up, down),
+        };
+
+        // alpha = rank, so the factors are applied as they are
+        let alpha = Tensor::new(rank as f32, &Device::Cpu)?;
+        for (suffix, tensor) in [
+            ("lora_up.weight", up),
+            ("lora_down.weight", down),
+            ("alpha", alpha),
+        ] {
+            writer.insert_tensor(
+                &format!("{lora_name}.{suffix}"),
+                &tensor.to_dtype(options.dtype)?,
+            )?;
+        }
+
+        report.layers.push(ExtractedLayer {
+            key,
+            lora_name,
+            rank,
+            energy: svd.s[..rank].iter().map(|s| s * s).sum::<f64>() / total_energy,
+            relative_diff,
+        });
+    }
+
+    if report.layers.is_empty() {
+        return Err(InspectorError::Msg(
+            "no layer differs between the checkpoints".to_string(),
+        ));
+    }
+
+    let max_rank = report.layers.iter().map(|l| l.rank).max().unwrap_or(0);
+    writer.insert_metadata("ss_network_module", "networks.lora");
+    writer.insert_metadata("ss_network_dim", &max_rank.to_string());
+    writer.insert_metadata("ss_network_alpha", &max_rank.to_string());
+    if let Some(architecture) = architecture_from_keys(&base.keys()) {
+        writer.insert_metadata(
+            "modelspec.architecture",
+            &format!("{}/lora", architecture_name(architecture)),
+        );
+    }
+    if let Some(base_model) = options.base_model.as_ref() {
+        writer.insert_metadata("ss_sd_model_name", base_model);
+        writer.insert_metadata("extract.base_model", base_model);
+    }
+    if let Some(tuned_model) = options.tuned_model.as_ref() {
+        writer.insert_metadata("extract.tuned_model", tuned_model);
+    }
+    writer.insert_metadata("extract.rank", &serde_json::to_string(&options.rank)?);
+    if let Some(conv_rank) = options.conv_rank {
+        writer.insert_metadata("extract.conv_rank", &serde_json::to_string(&conv_rank)?);
+    }
+    let mean_energy =
+        report.layers.iter().map(|l| l.energy).sum::<f64>() / report.layers.len() as f64;
+    writer.insert_metadata("extract.energy", &format!("{mean_energy:.6}"));
+
+    Ok((writer.serialize()?, report))
+}
+
+fn energy(t: &Tensor) -> Result<f64> {
+    Ok(t.sqr()?
+        .sum_all()?
+        .to_dtype(DType::F64)?
+        .to_scalar::<f64>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::file::LoRAFile;
+    use crate::metadata::Metadata;
+
+    fn checkpoint(tensors: &[(&str, &Tensor)]) -> BaseModel<Cursor<Vec<u8>>> {
+        let mut writer = SafetensorsWriter::new();
+        for (name, tensor) in tensors {
+            writer.insert_tensor(name, tensor).unwrap();
+        }
+        BaseModel::new(Cursor::new(writer.serialize().unwrap())).unwrap()
+    }
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar()
+            .unwrap()
+    }
+
+    #[test]
+    fn names_follow_kohya() {
+        assert_eq!(
+            kohya_name("model.diffusion_model.input_blocks.4.1.proj_in.weight").as_deref(),
+            Some("lora_unet_input_blocks_4_1_proj_in")
+        );
+        assert_eq!(
+            kohya_name(
+                "conditioner.embedders.0.transformer.text_model.encoder.layers.0.mlp.fc1.weight"
+            )
+            .as_deref(),
+            Some("lora_te1_text_model_encoder_layers_0_mlp_fc1")
+        );
+        assert_eq!(
+            kohya_name("double_blocks.0.img_attn.qkv.weight").as_deref(),
+            Some("lora_unet_double_blocks_0_img_attn_qkv")
+        );
+        assert_eq!(kohya_name("first_stage_model.encoder.conv_in.weight"), None);
+        assert_eq!(kohya_name("double_blocks.0.img_attn.qkv.bias"), None);
+    }
+
+    #[test]
+    fn extract_linear_and_conv_layers() -> Result<()> {
+        let device = Device::Cpu;
+        let weight = Tensor::arange(0f32, 30., &device)?.reshape((6, 5))?.sin()?;
+        // Rank 2 update with 90% of its energy in the first direction
+        let u = Tensor::new(
+            &[[1f32, 0.], [0., 0.], [0., 1.], [0., 0.], [0., 0.], [0., 0.]],
+            &device,
+        )?;
+        let v = Tensor::new(&[[0f32, 0., 0., 1., 0.], [0., 1., 0., 0., 0.]], &device)?;
+        let delta = u
+            .matmul(&Tensor::new(&[[3f32, 0.], [0., 1.]], &device)?)?
+            .matmul(&v)?;
+
+        let conv = Tensor::arange(0f32, 108., &device)?
+            .reshape((4, 3, 3, 3))?
+            .cos()?;
+        let conv_delta = Tensor::new(&[1f32, -1., 0.5, 2.], &device)?
+            .reshape((4, 1))?
+            .matmul(&Tensor::arange(0f32, 27., &device)?.reshape((1, 27))?)?
+            .reshape((4, 3, 3, 3))?;
+
+        let unchanged = Tensor::ones((3, 3), DType::F32, &device)?;
+
+        let mut base = checkpoint(&[
+            ("model.diffusion_model.out.0.weight", &weight),
+            ("model.diffusion_model.input_blocks.0.0.weight", &conv),
+            (
+                "model.diffusion_model.middle_block.1.proj_in.weight",
+                &unchanged,
+            ),
+        ]);
+        let mut tuned = checkpoint(&[
+            ("model.diffusion_model.out.0.weight", &(&weight + &delta)?),
+            (
+                "model.diffusion_model.input_blocks.0.0.weight",
+                &(&conv + &conv_delta)?,
+            ),
+            (
+                "model.diffusion_model.middle_block.1.proj_in.weight",
+                &unchanged,
+            ),
+        ]);
+
+        let options = ExtractOptions {
+            rank: RankSelection::Fixed(2),
+            conv_rank: Some(RankSelection::Fixed(1)),
+            dtype: DType::F32,
+            tuned_model: Some("tuned.safetensors".to_string()),
+            ..Default::default()
+        };
+        let (buffer, report) = extract(&mut base, &mut tuned, &options)?;
+        assert_eq!(report.layers.len(), 2);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].reason, SkipReason::Unchanged);
+
+        let lora_file = LoRAFile::new_from_buffer(&buffer, "extracted.safetensors", &device);
+        let linear = lora_file.scale_weight("lora_unet_out_0")?;
+        assert!(max_abs_diff(&linear, &delta) < 1e-4);
+        let conv_layer = lora_file.scale_weight("lora_unet_input_blocks_0_0")?;
+        assert_eq!(conv_layer.dims(), &[4, 3, 3, 3]);
+        assert!(max_abs_diff(&conv_layer, &conv_delta) < 1e-3);
+
+        let metadata = Metadata::new_from_buffer(&buffer)?.metadata.unwrap();
+        assert_eq!(metadata["ss_network_dim"], "2");
+        assert_eq!(metadata["extract.tuned_model"], "tuned.safetensors");
+
+        // 90% of the energy only needs the first direction
+        let options = ExtractOptions {
+            rank: RankSelection::Energy {
+                threshold: 0.85,
+                max_rank: 4,
+            },
+            ..options
+        };
+        let (_, report) = extract(&mut base, &mut tuned, &options)?;
+        let linear = &report.layers[1];
+        assert_eq!(linear.key, "model.diffusion_model.out.0.weight");
+        assert_eq!(linear.rank, 1);
+        assert!((linear.energy - 0.9).abs() < 1e-4);
+
+        Ok(())
+    }
+}
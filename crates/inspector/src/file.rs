@@ -4,10 +4,11 @@ use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
 use crate::{
+    blocks,
     metadata::Metadata,
     network::NetworkType,
     norms::{l1, l2, matrix_norm},
-    svd,
+    oft, svd,
     weight::{self, BufferedLoRAWeight, Weight},
     InspectorError, Result,
 };
@@ -15,10 +16,55 @@ use crate::{
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LayerScale {
     pub base_name: String,
+    pub kind: LayerKind,
     pub eff_scale: f64,
+    /// Compared against the layers of the same kind only.
     pub is_outlier: bool,
 }
 
+/// How a layer stores its update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LayerKind {
+    /// Factors that rebuild the delta weight: LoRA, LoHa, LoKr, OFT, ...
+    LowRank,
+    /// LyCORIS `full`, the whole delta weight as `diff`.
+    FullDiff,
+    /// `train_norm` norm layer weight update, `w_norm`.
+    Norm,
+    /// Only a bias update, `diff_b` or `b_norm`.
+    Bias,
+}
+
+impl LayerKind {
+    /// Kind of `base_name` from the keys saved for it.
+    pub fn from_keys<F>(base_name: &str, has_key: F) -> LayerKind
+    where
+        F: Fn(&str) -> bool,
+    {
+        let has = |suffix: &str| has_key(&format!("{base_name}.{suffix}"));
+        if has("diff") {
+            LayerKind::FullDiff
+        } else if has("w_norm") {
+            LayerKind::Norm
+        } else if has("diff_b") || has("b_norm") {
+            LayerKind::Bias
+        } else {
+            LayerKind::LowRank
+        }
+    }
+}
+
+/// A layer stored without a low-rank decomposition.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffLayer {
+    pub base_name: String,
+    pub kind: LayerKind,
+    pub shape: Vec<usize>,
+    /// Norm of the weight update, `None` for bias-only layers.
+    pub eff_scale: Option<f64>,
+    pub bias_scale: Option<f64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TensorInfo {
     pub name: String,
@@ -38,6 +84,14 @@ pub struct LoRAFile {
 
 // const WEIGHT_NOT_LOADED: &str = "Weight not loaded properly";
 
+/// Loads the tensors with candle, widening fp8 tensors to F16 first since
+/// candle has no fp8. The header keeps the stored dtypes.
+fn load_weights(buffer: &[u8], device: &Device) -> Option<BufferedLoRAWeight> {
+    let decoded = crate::precision::decode_fp8(buffer).ok()?;
+    let buffer = decoded.as_deref().unwrap_or(buffer);
+    BufferedLoRAWeight::new(buffer.to_vec(), device).ok()
+}
+
 impl LoRAFile {
     pub fn new_from_buffer(buffer: &[u8], filename: &str, device: &Device) -> LoRAFile {
         let metadata = Metadata::new_from_buffer(buffer).map_err(|e| e.to_string());
@@ -45,9 +99,7 @@ impl LoRAFile {
 
         LoRAFile {
             filename: filename.to_string(),
-            weights: BufferedLoRAWeight::new(buffer.to_vec(), device)
-                .map(Some)
-                .unwrap_or_else(|_| None),
+            weights: load_weights(buffer, device),
             scaled_weights: HashMap::new(),
             metadata: metadata.map(Some).unwrap_or_else(|_| None),
             header,
@@ -76,19 +128,26 @@ impl LoRAFile {
     }
 
     pub fn reload_weights(&mut self, buffer: &[u8], device: &Device) {
-        self.weights = BufferedLoRAWeight::new(buffer.to_vec(), device)
-            .map(Some)
-            .unwrap_or_else(|_| None);
+        self.weights = load_weights(buffer, device);
     }
 
     pub fn is_tensors_loaded(&self) -> bool {
         self.weights.is_some()
     }
 
+    /// The device the tensors are loaded on, once they are.
+    pub fn device(&self) -> Option<&Device> {
+        self.weights.as_ref().map(|weights| weights.device())
+    }
+
     pub fn filename(&self) -> String {
         self.filename.clone()
     }
 
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
     pub fn unet_keys(&self) -> Vec<String> {
         self.header
             .as_ref()
@@ -128,6 +187,33 @@ impl LoRAFile {
         self.header.as_ref().map(|h| h.dims()).unwrap_or_default()
     }
 
+    /// Alpha stored for `base_name`; `None` when the tensors are not loaded or
+    /// the layer has no alpha.
+    pub fn alpha(&self, base_name: &str) -> Option<weight::Alpha> {
+        self.weights
+            .as_ref()
+            .and_then(|weights| weights.alpha(base_name).ok())
+    }
+
+    /// Rank of `base_name`, read from the header shape of its down weight.
+    pub fn rank(&self, base_name: &str) -> Option<usize> {
+        let header = self.header.as_ref()?;
+        [
+            "lora_down.weight",
+            "hada_w1_b",
+            "lora_A.weight",
+            "lora_A.default.weight",
+        ]
+        .iter()
+        .find_map(|suffix| header.shape(&format!("{base_name}.{suffix}")))
+        .and_then(|shape| shape.first().copied())
+    }
+
+    /// Shape of `key` from the header.
+    pub fn shape(&self, key: &str) -> Option<&[usize]> {
+        self.header.as_ref().and_then(|h| h.shape(key))
+    }
+
     pub fn precision(&self) -> Option<weight::DType> {
         self.header.as_ref().and_then(|h| h.precision())
     }
@@ -143,6 +229,17 @@ impl LoRAFile {
             .unwrap_or_default()
     }
 
+    /// Layers grouped by block, with the block learning rate weights from the
+    /// network args attached.
+    pub fn block_map(&self) -> Vec<blocks::Block> {
+        let lr_weights = self
+            .metadata
+            .as_ref()
+            .and_then(|m| m.network_args())
+            .and_then(|args| args.block_lr_weights(blocks::is_sdxl(self)));
+        blocks::block_map(&self.base_names(), lr_weights.as_ref())
+    }
+
     pub fn tensor_info(&self) -> Vec<TensorInfo> {
         self.header
             .as_ref()
@@ -203,6 +300,13 @@ impl LoRAFile {
         // }
 
         match self.weights.as_ref() {
+            // Full diffs and norm layers can be saved next to any network type
+            Some(weights) if self.layer_kind(base_name) == LayerKind::Bias => {
+                Ok(weights.bias_diff(base_name)?)
+            }
+            Some(weights) if self.layer_kind(base_name) != LayerKind::LowRank => {
+                Ok(weights.scale_full_weight(base_name)?)
+            }
             Some(weights) => match self
                 .metadata
                 .as_ref()
@@ -212,10 +316,22 @@ impl LoRAFile {
                 Some(NetworkType::LoRAFA) => Ok(weights.scale_lora_weight(base_name)?),
                 Some(NetworkType::DyLoRA) => Ok(weights.scale_lora_weight(base_name)?),
                 Some(NetworkType::GLoRA) => Ok(weights.scale_glora_weights(base_name)?),
-                Some(NetworkType::LoKr) => Ok(weights.scale_lokr_weight(base_name)?),
+                // GLoKr keeps LoKr's factors, only the training differs
+                Some(NetworkType::LoKr | NetworkType::GLoKr) => {
+                    Ok(weights.scale_lokr_weight(base_name)?)
+                }
+                Some(NetworkType::IA3) => Ok(weights.scale_ia3_weight(base_name)?),
+                // Every layer is a full diff, handled above
+                Some(NetworkType::Full) => Err(InspectorError::NotFound),
                 Some(NetworkType::LoHA) => Ok(weights.scale_hada_weight(base_name)?),
-                Some(NetworkType::BOFT) => Ok(weights.scale_boft_weight(base_name)?),
-                Some(NetworkType::DiagOFT) => Ok(weights.scale_diag_oft_weight(base_name)?),
+                // The rotation minus identity, so the norm measures how far it
+                // moves the weight instead of the raw skew parameters
+                Some(NetworkType::BOFT | NetworkType::DiagOFT | NetworkType::OFT) => {
+                    match oft::rotation(self, base_name)? {
+                        Some(rotation) => rotation.deviation_blocks(weights.device()),
+                        None => Err(InspectorError::NotFound),
+                    }
+                }
                 Some(_) => Err(InspectorError::UnsupportedNetworkType),
                 None => Ok(weights.scale_lora_weight(base_name)?),
             },
@@ -225,6 +341,11 @@ impl LoRAFile {
         }
     }
 
+    /// Frobenius norm of the scaled update `||dW||`.
+    ///
+    /// For DoRA layers this is the directional update only. The magnitude
+    /// rescales the columns of `W0 + dW`, so how much it changes the weight
+    /// depends on the base weight; see `effective_scale_with_base`.
     pub fn effective_scale(&self, base_name: &str) -> Result<Option<f64>> {
         match self.weights.as_ref() {
             None => Ok(None),
@@ -245,6 +366,126 @@ impl LoRAFile {
         }
     }
 
+    /// Effective scale against the base weight it applies to. For DoRA layers
+    /// this includes the magnitude, `||W' - W0||`, and IA3 layers rescale the
+    /// base weight; other layers are the same as `effective_scale`.
+    pub fn effective_scale_with_base(
+        &self,
+        base_name: &str,
+        base_weight: &candle_core::Tensor,
+    ) -> Result<Option<f64>> {
+        if let Some(delta) = self.ia3_delta(base_name, base_weight)? {
+            return Ok(Some(
+                self.l2_norm::<f64>(&delta.to_dtype(candle_core::DType::F64)?)?,
+            ));
+        }
+
+        match self.dora_scale(base_name) {
+            Some(dora_scale) => {
+                let delta = self.scale_weight(base_name)?;
+                let layer =
+                    crate::dora::analyze(base_name, &dora_scale, &delta, Some(base_weight))?;
+                Ok(layer.base.map(|base| base.effective_scale))
+            }
+            None => self.effective_scale(base_name),
+        }
+    }
+
+    /// IA3 delta weight `W0 * weight` of `base_name`, `None` when the file is
+    /// not an IA3 network.
+    pub fn ia3_delta(
+        &self,
+        base_name: &str,
+        base_weight: &candle_core::Tensor,
+    ) -> Result<Option<candle_core::Tensor>> {
+        let Some(weights) = self.weights.as_ref() else {
+            return Ok(None);
+        };
+        if self.metadata.as_ref().and_then(|m| m.network_type()) != Some(NetworkType::IA3) {
+            return Ok(None);
+        }
+
+        let scale = weights.scale_ia3_weight(base_name)?;
+        let on_input = weights.ia3_on_input(base_name)?;
+        let expected = if on_input {
+            base_weight.dims().get(1).copied().unwrap_or(1)
+        } else {
+            base_weight.dims()[0]
+        };
+        if scale.elem_count() != expected {
+            return Err(InspectorError::Msg(format!(
+                "{base_name} IA3 weight has {} values but the base weight {:?} needs {expected}",
+                scale.elem_count(),
+                base_weight.dims()
+            )));
+        }
+
+        Ok(Some(weight::apply_ia3(&scale, on_input, base_weight)?))
+    }
+
+    pub fn layer_kind(&self, base_name: &str) -> LayerKind {
+        LayerKind::from_keys(base_name, |key| self.shape(key).is_some())
+    }
+
+    /// Norm of the bias update of a `full` or norm layer.
+    pub fn bias_scale(&self, base_name: &str) -> Result<Option<f64>> {
+        let Some(weights) = self.weights.as_ref() else {
+            return Ok(None);
+        };
+
+        match weights.bias_diff(base_name) {
+            Ok(bias) => Ok(Some(
+                self.l2_norm::<f64>(&bias.to_dtype(candle_core::DType::F64)?)?,
+            )),
+            Err(candle_core::Error::SafeTensor(SafeTensorError::TensorNotFound(_))) => Ok(None),
+            Err(e) => Err(InspectorError::from(e)),
+        }
+    }
+
+    /// Full diff, norm and bias layers, reported apart from the low-rank ones.
+    pub fn diff_layers(&self) -> Vec<DiffLayer> {
+        let mut layers: Vec<DiffLayer> = self
+            .base_names()
+            .into_iter()
+            .filter_map(|base_name| {
+                let kind = self.layer_kind(&base_name);
+                let shape = ["diff", "w_norm", "diff_b", "b_norm"]
+                    .iter()
+                    .find_map(|suffix| self.shape(&format!("{base_name}.{suffix}")))?
+                    .to_vec();
+                let eff_scale = match kind {
+                    LayerKind::LowRank => return None,
+                    LayerKind::Bias => None,
+                    _ => self.effective_scale(&base_name).ok().flatten(),
+                };
+
+                Some(DiffLayer {
+                    bias_scale: self.bias_scale(&base_name).ok().flatten(),
+                    base_name,
+                    kind,
+                    shape,
+                    eff_scale,
+                })
+            })
+            .collect();
+        layers.sort_by(|a, b| a.base_name.cmp(&b.base_name));
+        layers
+    }
+
+    /// Any tensor of the file by its full key.
+    pub fn tensor(&self, key: &str) -> Option<candle_core::Tensor> {
+        self.weights
+            .as_ref()
+            .and_then(|weights| weights.get(key).ok())
+    }
+
+    /// DoRA magnitude vector of `base_name`, if it has one.
+    pub fn dora_scale(&self, base_name: &str) -> Option<candle_core::Tensor> {
+        self.weights
+            .as_ref()
+            .and_then(|weights| weights.dora_scale(base_name).ok())
+    }
+
     /// Frobenius norm of the reconstructed `up @ down` delta weight, computed from the
     /// low-rank factors directly instead of materializing the full `m x n` product.
     ///
@@ -295,6 +536,26 @@ impl LoRAFile {
         Ok(Some(frob_sq.max(0.0).sqrt() * scale.abs()))
     }
 
+    /// The `up` and `down` tensors of a LoRA layer, `None` when the tensors are
+    /// not loaded or the layer has no up/down pair.
+    pub fn up_down(
+        &self,
+        base_name: &str,
+    ) -> Result<Option<(candle_core::Tensor, candle_core::Tensor)>> {
+        let Some(weights) = self.weights.as_ref() else {
+            return Ok(None);
+        };
+
+        match (weights.up(base_name), weights.down(base_name)) {
+            (Ok(up), Ok(down)) => Ok(Some((up, down))),
+            (Err(candle_core::Error::SafeTensor(SafeTensorError::TensorNotFound(_))), _)
+            | (_, Err(candle_core::Error::SafeTensor(SafeTensorError::TensorNotFound(_)))) => {
+                Ok(None)
+            }
+            (Err(e), _) | (_, Err(e)) => Err(InspectorError::from(e)),
+        }
+    }
+
     pub fn factorization_balance(&self, base_name: &str) -> Result<Option<f64>> {
         match self.weights.as_ref() {
             None => Ok(None),
@@ -347,14 +608,15 @@ impl LoRAFile {
     }
 
     pub fn effective_scales_all(&self) -> Vec<LayerScale> {
-        let scales: Vec<(String, f64)> = self
+        let scales: Vec<(String, LayerKind, f64)> = self
             .base_names()
             .into_iter()
             .filter_map(|name| {
                 self.effective_scale(&name)
                     .ok()
                     .flatten()
-                    .map(|s| (name, s))
+                    .map(|s| (self.layer_kind(&name), s))
+                    .map(|(kind, s)| (name, kind, s))
             })
             .collect();
 
@@ -362,24 +624,36 @@ impl LoRAFile {
             return vec![];
         }
 
-        let mut sorted_vals: Vec<f64> = scales.iter().map(|(_, s)| *s).collect();
-        sorted_vals.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
-        let median = {
-            let n = sorted_vals.len();
-            if n % 2 == 0 {
-                (sorted_vals[n / 2 - 1] + sorted_vals[n / 2]) / 2.0
-            } else {
-                sorted_vals[n / 2]
-            }
-        };
-        // If median is near zero, use a small absolute floor to avoid flagging everything
-        let threshold = if median < 1e-10 { 1e-10 } else { 1.5 * median };
+        // Full diffs and norm layers are on a different scale than the
+        // low-rank layers, so each kind gets its own median
+        let mut by_kind: HashMap<LayerKind, Vec<f64>> = HashMap::new();
+        for (_, kind, scale) in &scales {
+            by_kind.entry(*kind).or_default().push(*scale);
+        }
+        let thresholds: HashMap<LayerKind, f64> = by_kind
+            .into_iter()
+            .map(|(kind, mut sorted_vals)| {
+                sorted_vals.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                let median = {
+                    let n = sorted_vals.len();
+                    if n.is_multiple_of(2) {
+                        (sorted_vals[n / 2 - 1] + sorted_vals[n / 2]) / 2.0
+                    } else {
+                        sorted_vals[n / 2]
+                    }
+                };
+                // If median is near zero, use a small absolute floor to avoid flagging everything
+                let threshold = if median < 1e-10 { 1e-10 } else { 1.5 * median };
+                (kind, threshold)
+            })
+            .collect();
 
         scales
             .into_iter()
-            .map(|(base_name, eff_scale)| LayerScale {
-                is_outlier: eff_scale > threshold,
+            .map(|(base_name, kind, eff_scale)| LayerScale {
+                is_outlier: eff_scale > thresholds[&kind],
                 base_name,
+                kind,
                 eff_scale,
             })
             .collect()
@@ -407,7 +681,7 @@ mod tests {
 
     use crate::weight::{self, Alpha};
 
-    use super::LoRAFile;
+    use super::{LayerKind, LoRAFile};
 
     fn load_test_file() -> Result<Vec<u8>, io::Error> {
         let filename = "boo.safetensors";
@@ -748,6 +1022,78 @@ mod tests {
         assert!((threshold - 1.5).abs() < 1e-10);
     }
 
+    #[test]
+    fn full_diff_and_norm_layers_are_reported_apart() -> crate::Result<()> {
+        use crate::writer::SafetensorsWriter;
+        use candle_core::Tensor;
+
+        let device = Device::Cpu;
+        let lora = "lora_unet_mid_block_attentions_0_proj_in";
+        let full = "lora_unet_mid_block_attentions_0_proj_out";
+        let norm = "lora_unet_mid_block_attentions_0_norm";
+
+        let mut writer = SafetensorsWriter::new();
+        writer.insert_tensor(
+            &format!("{lora}.lora_up.weight"),
+            &Tensor::new(&[[1f32], [0.]], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{lora}.lora_down.weight"),
+            &Tensor::new(&[[1f32, 0.]], &device)?,
+        )?;
+        writer.insert_tensor(&format!("{lora}.alpha"), &Tensor::new(1f32, &device)?)?;
+        writer.insert_tensor(
+            &format!("{full}.diff"),
+            &Tensor::new(&[[3f32, 0.], [0., 4.]], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{full}.diff_b"),
+            &Tensor::new(&[0f32, 2.], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{norm}.w_norm"),
+            &Tensor::new(&[0.5f32, 0.], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{norm}.b_norm"),
+            &Tensor::new(&[0f32, 0.], &device)?,
+        )?;
+        writer.insert_metadata("ss_network_module", "lycoris.kohya");
+        writer.insert_metadata(
+            "ss_network_args",
+            r#"{"algo": "lora", "train_norm": "True"}"#,
+        );
+        let buffer = writer.serialize()?;
+
+        let lora_file = LoRAFile::new_from_buffer(&buffer, "test.safetensors", &device);
+
+        let mut base_names = lora_file.base_names();
+        base_names.sort();
+        assert_eq!(base_names, vec![norm, lora, full]);
+        assert_eq!(lora_file.layer_kind(lora), LayerKind::LowRank);
+        assert_eq!(lora_file.layer_kind(full), LayerKind::FullDiff);
+        assert_eq!(lora_file.layer_kind(norm), LayerKind::Norm);
+
+        // Full diffs are not scaled by alpha
+        assert_eq!(lora_file.effective_scale(full)?, Some(5.0));
+        assert_eq!(lora_file.bias_scale(full)?, Some(2.0));
+        assert_eq!(lora_file.bias_scale(lora)?, None);
+
+        let diff_layers = lora_file.diff_layers();
+        assert_eq!(diff_layers.len(), 2);
+        assert_eq!(diff_layers[0].base_name, norm);
+        assert_eq!(diff_layers[0].eff_scale, Some(0.5));
+        assert_eq!(diff_layers[1].shape, vec![2, 2]);
+
+        // Each kind is its own population, so the big full diff is not an
+        // outlier against the LoRA layer
+        let scales = lora_file.effective_scales_all();
+        assert_eq!(scales.len(), 3);
+        assert!(scales.iter().all(|s| !s.is_outlier));
+
+        Ok(())
+    }
+
     fn header_only_bytes(buffer: &[u8]) -> &[u8] {
         let mut len_bytes = [0u8; 8];
         len_bytes.copy_from_slice(&buffer[0..8]);
@@ -0,0 +1,302 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::blocks::BlockId;
+use crate::file::LoRAFile;
+use crate::parser::{parse_key, Component};
+use crate::writer::SafetensorsWriter;
+use crate::{InspectorError, Result};
+
+/// Which layers a filter pattern matches.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Selector {
+    /// `unet`: the U-Net or the diffusion transformer
+    Unet,
+    /// `te` for every text encoder, `te1`, `te2`, ... for one of them
+    TextEncoder(Option<usize>),
+    /// `block:down_03`, a block by its name in the block map
+    Block(String),
+    /// Any other pattern, a glob over base names where `*` matches any run of
+    /// characters and `?` a single one
+    Glob(String),
+}
+
+impl FromStr for Selector {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(InspectorError::Msg("Empty filter pattern".to_string()));
+        }
+
+        match s {
+            "unet" | "transformer" => Ok(Selector::Unet),
+            "te" => Ok(Selector::TextEncoder(None)),
+            _ => {
+                if let Some(block) = s.strip_prefix("block:") {
+                    Ok(Selector::Block(block.to_string()))
+                } else if let Some(index) =
+                    s.strip_prefix("te").and_then(|i| i.parse::<usize>().ok())
+                {
+                    Ok(Selector::TextEncoder(Some(index)))
+                } else {
+                    Ok(Selector::Glob(s.to_string()))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Unet => write!(f, "unet"),
+            Selector::TextEncoder(None) => write!(f, "te"),
+            Selector::TextEncoder(Some(i)) => write!(f, "te{i}"),
+            Selector::Block(block) => write!(f, "block:{block}"),
+            Selector::Glob(pattern) => write!(f, "{pattern}"),
+        }
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, base_name: &str) -> bool {
+        match self {
+            Selector::Unet => matches!(
+                component(base_name),
+                Some(Component::Unet | Component::Transformer)
+            ),
+            Selector::TextEncoder(index) => match (component(base_name), index) {
+                (Some(Component::TextEncoder(_)), None) => true,
+                // `lora_te` and `text_encoder` are the first text encoder
+                (Some(Component::TextEncoder(te)), Some(index)) => te.unwrap_or(1) == *index,
+                _ => false,
+            },
+            Selector::Block(block) => {
+                BlockId::from_base_name(base_name).is_some_and(|id| id.to_string() == *block)
+            }
+            Selector::Glob(pattern) => glob_match(pattern, base_name),
+        }
+    }
+}
+
+fn component(base_name: &str) -> Option<Component> {
+    parse_key(base_name).ok().and_then(|key| key.component)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FilterOptions {
+    /// Layers to keep. Every layer when empty.
+    pub include: Vec<Selector>,
+    /// Layers to drop, even if included
+    pub exclude: Vec<Selector>,
+}
+
+impl FilterOptions {
+    pub fn keeps(&self, base_name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|s| s.matches(base_name)))
+            && !self.exclude.iter().any(|s| s.matches(base_name))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FilterReport {
+    pub kept: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// Writes a file with only the layers `options` keeps, every tensor of a
+/// layer including its alpha. The patterns are saved in the metadata as
+/// `filter.include` and `filter.exclude`.
+pub fn filter(buffer: &[u8], options: &FilterOptions) -> Result<(Vec<u8>, FilterReport)> {
+    let file = LoRAFile::new_from_header_buffer(buffer, "")?;
+    let mut base_names = file.base_names();
+    base_names.sort();
+
+    let (kept, removed): (Vec<String>, Vec<String>) = base_names
+        .into_iter()
+        .partition(|base_name| options.keeps(base_name));
+    if kept.is_empty() {
+        return Err(InspectorError::Msg(
+            "The filter does not match any layer".to_string(),
+        ));
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    for name in writer.names() {
+        if removed
+            .iter()
+            .any(|base_name| name.starts_with(&format!("{base_name}.")))
+        {
+            writer.remove(&name);
+        }
+    }
+
+    let patterns = |selectors: &[Selector]| {
+        selectors
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    writer.insert_metadata("filter.include", &patterns(&options.include));
+    writer.insert_metadata("filter.exclude", &patterns(&options.exclude));
+    writer.insert_metadata("filter.removed", &removed.len().to_string());
+
+    let has_text_encoder = kept.iter().any(|b| Selector::TextEncoder(None).matches(b));
+    let has_unet = kept.iter().any(|b| Selector::Unet.matches(b));
+    if has_text_encoder != has_unet {
+        let flag = |b: bool| if b { "True" } else { "False" };
+        writer.insert_metadata("ss_network_train_unet_only", flag(has_unet));
+        writer.insert_metadata("ss_network_train_text_encoder_only", flag(has_text_encoder));
+    }
+
+    Ok((writer.serialize()?, FilterReport { kept, removed }))
+}
+
+/// Glob match over the whole of `text`. `*` matches any run of characters,
+/// `?` any one character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text it was tried against
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` take one more character
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(
+            "lora_unet_*",
+            "lora_unet_mid_block_attentions_0_proj_in"
+        ));
+        assert!(glob_match(
+            "*attn?_to_k",
+            "lora_unet_down_blocks_1_attn1_to_k"
+        ));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match(
+            "*attn?_to_k",
+            "lora_unet_down_blocks_1_attn1_to_q"
+        ));
+        assert!(!glob_match(
+            "lora_te_*",
+            "lora_te1_text_model_encoder_layers_0_mlp_fc1"
+        ));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+    }
+
+    #[test]
+    fn selectors() -> Result<()> {
+        let te1 = "lora_te1_text_model_encoder_layers_0_mlp_fc1";
+        let te2 = "lora_te2_text_model_encoder_layers_0_mlp_fc1";
+        let unet = "lora_unet_down_blocks_1_attentions_0_proj_in";
+        let peft = "transformer.single_transformer_blocks.0.attn.to_q";
+
+        assert_eq!("te".parse::<Selector>()?, Selector::TextEncoder(None));
+        assert!("te".parse::<Selector>()?.matches(te2));
+        assert!("te1".parse::<Selector>()?.matches(te1));
+        assert!(!"te1".parse::<Selector>()?.matches(te2));
+        assert!("te1"
+            .parse::<Selector>()?
+            .matches("lora_te_text_model_encoder_layers_0_mlp_fc1"));
+        assert!("unet".parse::<Selector>()?.matches(unet));
+        assert!("unet".parse::<Selector>()?.matches(peft));
+        assert!(!"unet".parse::<Selector>()?.matches(te1));
+        assert!("block:down_04".parse::<Selector>()?.matches(unet));
+        assert!(!"block:down_05".parse::<Selector>()?.matches(unet));
+        assert!("*proj_in".parse::<Selector>()?.matches(unet));
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_unet_only() -> Result<()> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        let names = [
+            "lora_te1_text_model_encoder_layers_0_mlp_fc1",
+            "lora_unet_down_blocks_1_attentions_0_proj_in",
+            "lora_unet_down_blocks_1_attentions_0_proj_out",
+            "lora_unet_up_blocks_0_attentions_0_proj_in",
+        ];
+        for name in names {
+            writer.insert_tensor(
+                &format!("{name}.lora_up.weight"),
+                &Tensor::ones((4, 2), candle_core::DType::F32, &device)?,
+            )?;
+            writer.insert_tensor(
+                &format!("{name}.lora_down.weight"),
+                &Tensor::ones((2, 4), candle_core::DType::F32, &device)?,
+            )?;
+            writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(1f32, &device)?)?;
+        }
+        writer.insert_metadata("ss_network_train_unet_only", "False");
+
+        let options = FilterOptions {
+            include: vec!["unet".parse()?],
+            exclude: vec!["*proj_out".parse()?],
+        };
+        let (output, report) = filter(&writer.serialize()?, &options)?;
+
+        assert_eq!(
+            report.kept,
+            vec![
+                "lora_unet_down_blocks_1_attentions_0_proj_in",
+                "lora_unet_up_blocks_0_attentions_0_proj_in"
+            ]
+        );
+        assert_eq!(report.removed.len(), 2);
+
+        let file = LoRAFile::new_from_buffer(&output, "", &device);
+        assert_eq!(file.keys().len(), 6);
+        assert!(file
+            .alpha("lora_unet_up_blocks_0_attentions_0_proj_in")
+            .is_some());
+        let metadata = file.metadata().and_then(|m| m.metadata.as_ref()).unwrap();
+        assert_eq!(metadata["ss_network_train_unet_only"], "True");
+        assert_eq!(metadata["filter.include"], "unet");
+        assert_eq!(metadata["filter.exclude"], "*proj_out");
+        assert_eq!(metadata["filter.removed"], "2");
+
+        let none = FilterOptions {
+            include: vec!["block:mid".parse()?],
+            exclude: vec![],
+        };
+        assert!(filter(&writer.serialize()?, &none).is_err());
+
+        Ok(())
+    }
+}
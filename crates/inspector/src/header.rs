@@ -1,6 +1,8 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::weight::{get_base_name, is_peft, DType, LoRAFormat};
+use serde::Deserialize;
+
+use crate::weight::{get_base_name, is_full_key, is_peft, DType, LoRAFormat};
 use crate::InspectorError;
 
 /// Matches the upstream `safetensors` crate's own sanity bound on header size
@@ -14,6 +16,8 @@ const MAX_HEADER_SIZE: usize = 100_000_000;
 pub struct TensorEntry {
     pub dtype: DType,
     pub shape: Vec<usize>,
+    /// Byte range of the payload, relative to the end of the header.
+    pub data_offsets: (usize, usize),
 }
 
 /// An index of every tensor's name, dtype and shape parsed from a safetensors
@@ -22,6 +26,7 @@ pub struct TensorEntry {
 pub struct HeaderIndex {
     tensors: HashMap<String, TensorEntry>,
     format: LoRAFormat,
+    data_start: usize,
 }
 
 impl HeaderIndex {
@@ -29,6 +34,14 @@ impl HeaderIndex {
         self.tensors.keys().cloned().collect()
     }
 
+    pub fn shape(&self, key: &str) -> Option<&[usize]> {
+        self.tensors.get(key).map(|e| e.shape.as_slice())
+    }
+
+    pub fn entry(&self, key: &str) -> Option<&TensorEntry> {
+        self.tensors.get(key)
+    }
+
     pub fn keys_by_key(&self, key: &str) -> Vec<String> {
         self.tensors
             .keys()
@@ -46,6 +59,12 @@ impl HeaderIndex {
         let glora_b1 = &mut self.keys_by_key("b1");
         let glora_a2 = &mut self.keys_by_key("a2");
         let glora_b2 = &mut self.keys_by_key("b1");
+        let full = &mut self
+            .tensors
+            .keys()
+            .filter(|k| is_full_key(k))
+            .cloned()
+            .collect();
         let mut keys = self.keys_by_key("weight");
         keys.append(hada);
         keys.append(lokr);
@@ -55,6 +74,7 @@ impl HeaderIndex {
         keys.append(glora_b1);
         keys.append(glora_a2);
         keys.append(glora_b2);
+        keys.append(full);
 
         keys
     }
@@ -113,6 +133,21 @@ impl HeaderIndex {
         self.format
     }
 
+    /// Offset of the first payload byte in the file, which
+    /// `TensorEntry::data_offsets` are relative to.
+    pub fn data_start(&self) -> usize {
+        self.data_start
+    }
+
+    /// Bytes of payload the header describes, the end of the last tensor.
+    pub fn data_len(&self) -> usize {
+        self.tensors
+            .values()
+            .map(|e| e.data_offsets.1)
+            .max()
+            .unwrap_or(0)
+    }
+
     /// `(name, dtype, shape)` for every tensor in the file, sorted by name for a
     /// stable UI ordering.
     pub fn tensor_info(&self) -> Vec<(String, DType, Vec<usize>)> {
@@ -160,30 +195,81 @@ pub fn parse_header(
 
     let json_str = std::str::from_utf8(&buffer[8..stop])
         .map_err(|_| InspectorError::Msg("safetensors header is not valid UTF-8".to_string()))?;
-    let metadata: safetensors::tensor::Metadata = serde_json::from_str(json_str)?;
+    let mut entries: HashMap<String, serde_json::Value> = serde_json::from_str(json_str)?;
+    let metadata: HeaderMetadata = entries
+        .remove("__metadata__")
+        .map(serde_json::from_value)
+        .transpose()?;
+
+    // Read the entries ourselves instead of through `safetensors::tensor::Metadata`,
+    // whose `Dtype` has no fp8 variants.
+    let tensors = entries
+        .into_iter()
+        .map(|(name, value)| {
+            let info: RawTensorInfo = serde_json::from_value(value)?;
+            let dtype = DType::from_safetensors_name(&info.dtype).ok_or_else(|| {
+                InspectorError::Msg(format!("unknown dtype {} for tensor {name}", info.dtype))
+            })?;
+            Ok((
+                name,
+                TensorEntry {
+                    dtype,
+                    shape: info.shape,
+                    data_offsets: info.data_offsets,
+                },
+            ))
+        })
+        .collect::<crate::Result<HashMap<_, _>>>()?;
 
-    let sample_keys: Vec<String> = metadata.tensors().keys().take(10).cloned().collect();
+    let sample_keys: Vec<String> = tensors.keys().take(10).cloned().collect();
     let format = if is_peft(sample_keys) {
         LoRAFormat::Peft
     } else {
         LoRAFormat::Kohya
     };
 
-    let tensors = metadata
-        .tensors()
-        .into_iter()
-        .map(|(name, info)| {
-            (
-                name,
-                TensorEntry {
-                    dtype: info.dtype.into(),
-                    shape: info.shape.clone(),
-                },
-            )
-        })
-        .collect();
+    Ok((
+        HeaderIndex {
+            tensors,
+            format,
+            data_start: stop,
+        },
+        metadata,
+    ))
+}
+
+/// A tensor entry of the header JSON, with the dtype still as its tag.
+#[derive(Deserialize)]
+struct RawTensorInfo {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: (usize, usize),
+}
+
+type HeaderMetadata = Option<HashMap<String, String>>;
+
+/// Reads just the header from the start of `reader`, for checkpoints too large
+/// to hold in memory. Returns the index, the metadata and the offset of the
+/// first payload byte, which `TensorEntry::data_offsets` are relative to.
+pub fn read_header<R: std::io::Read>(
+    reader: &mut R,
+) -> crate::Result<(HeaderIndex, HeaderMetadata, usize)> {
+    let mut len_bytes = [0u8; 8];
+    reader.read_exact(&mut len_bytes)?;
+    let header_len = u64::from_le_bytes(len_bytes) as usize;
+
+    if header_len > MAX_HEADER_SIZE {
+        return Err(InspectorError::Msg(format!(
+            "safetensors header length {header_len} exceeds maximum of {MAX_HEADER_SIZE} bytes"
+        )));
+    }
+
+    let mut buffer = vec![0u8; 8 + header_len];
+    buffer[0..8].copy_from_slice(&len_bytes);
+    reader.read_exact(&mut buffer[8..])?;
 
-    Ok((HeaderIndex { tensors, format }, metadata.metadata().clone()))
+    let (index, metadata) = parse_header(&buffer)?;
+    Ok((index, metadata, buffer.len()))
 }
 
 #[cfg(test)]
@@ -0,0 +1,546 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use candle_core::{DType, Device};
+use serde::{Serialize, Serializer};
+
+use crate::blocks::BlockId;
+use crate::file::{LayerKind, LoRAFile};
+use crate::network::Architecture;
+use crate::parser::{parse_key, Component};
+use crate::rescale::{self, ScaleMethod};
+use crate::writer::SafetensorsWriter;
+use crate::{norms, InspectorError, Result};
+
+/// A weight of a LoRA Block Weight string.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LbwSlot {
+    /// The text encoders
+    Base,
+    Block(BlockId),
+}
+
+impl fmt::Display for LbwSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LbwSlot::Base => write!(f, "BASE"),
+            LbwSlot::Block(BlockId::Down(i)) => write!(f, "IN{i:02}"),
+            LbwSlot::Block(BlockId::Mid) => write!(f, "M00"),
+            LbwSlot::Block(BlockId::Up(i)) => write!(f, "OUT{i:02}"),
+            LbwSlot::Block(BlockId::Double(i)) => write!(f, "D{i:02}"),
+            LbwSlot::Block(BlockId::Single(i)) => write!(f, "S{i:02}"),
+            LbwSlot::Block(BlockId::Block(i)) => write!(f, "B{i:02}"),
+        }
+    }
+}
+
+impl Serialize for LbwSlot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl LbwSlot {
+    pub fn from_base_name(base_name: &str) -> Option<LbwSlot> {
+        let text_encoder = parse_key(base_name)
+            .ok()
+            .and_then(|key| key.component)
+            .is_some_and(|c| matches!(c, Component::TextEncoder(_)));
+        if text_encoder {
+            Some(LbwSlot::Base)
+        } else {
+            BlockId::from_base_name(base_name).map(LbwSlot::Block)
+        }
+    }
+}
+
+/// Block layouts of the A1111 LoRA Block Weight extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LbwLayout {
+    /// 17 weights: BASE, the six attention blocks IN01-IN08, M00 and
+    /// OUT03-OUT11
+    Sd1,
+    /// 26 weights, every block, for LyCORIS and LoCon
+    Sd1Full,
+    /// 12 weights: BASE, IN04-IN08, M00 and OUT00-OUT05
+    Sdxl,
+    /// 58 weights: BASE, the 19 double blocks and the 38 single blocks
+    Flux,
+}
+
+impl FromStr for LbwLayout {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sd1" => Ok(LbwLayout::Sd1),
+            "sd1-full" => Ok(LbwLayout::Sd1Full),
+            "sdxl" => Ok(LbwLayout::Sdxl),
+            "flux" => Ok(LbwLayout::Flux),
+            _ => Err(InspectorError::Msg(format!(
+                "Unknown block weight layout {s}. Valid layouts: sd1, sd1-full, sdxl, flux"
+            ))),
+        }
+    }
+}
+
+impl LbwLayout {
+    pub fn slots(&self) -> Vec<LbwSlot> {
+        let blocks: Vec<BlockId> = match self {
+            LbwLayout::Sd1 => [1, 2, 4, 5, 7, 8]
+                .into_iter()
+                .map(BlockId::Down)
+                .chain([BlockId::Mid])
+                .chain((3..12).map(BlockId::Up))
+                .collect(),
+            LbwLayout::Sd1Full => (0..12)
+                .map(BlockId::Down)
+                .chain([BlockId::Mid])
+                .chain((0..12).map(BlockId::Up))
+                .collect(),
+            LbwLayout::Sdxl => [4, 5, 7, 8]
+                .into_iter()
+                .map(BlockId::Down)
+                .chain([BlockId::Mid])
+                .chain((0..6).map(BlockId::Up))
+                .collect(),
+            LbwLayout::Flux => (0..19)
+                .map(BlockId::Double)
+                .chain((0..38).map(BlockId::Single))
+                .collect(),
+        };
+
+        std::iter::once(LbwSlot::Base)
+            .chain(blocks.into_iter().map(LbwSlot::Block))
+            .collect()
+    }
+
+    /// The layout with `len` weights.
+    pub fn from_len(len: usize) -> Option<LbwLayout> {
+        [
+            LbwLayout::Sd1,
+            LbwLayout::Sd1Full,
+            LbwLayout::Sdxl,
+            LbwLayout::Flux,
+        ]
+        .into_iter()
+        .find(|layout| layout.slots().len() == len)
+    }
+
+    /// Layout for the layers of `file`, from its architecture or else its
+    /// block names. SD1 LoRAs with layers outside the 17 weights, like LoCon,
+    /// get the full layout.
+    pub fn detect(file: &LoRAFile) -> Option<LbwLayout> {
+        let base_names = file.base_names();
+        let slots: Vec<LbwSlot> = base_names
+            .iter()
+            .filter_map(|b| LbwSlot::from_base_name(b))
+            .collect();
+        let has = |f: fn(&BlockId) -> bool| {
+            slots
+                .iter()
+                .any(|s| matches!(s, LbwSlot::Block(id) if f(id)))
+        };
+
+        let sd1 = || {
+            if slots.iter().all(|s| LbwLayout::Sd1.slots().contains(s)) {
+                LbwLayout::Sd1
+            } else {
+                LbwLayout::Sd1Full
+            }
+        };
+
+        match file.metadata().and_then(|m| m.architecture()) {
+            Some(Architecture::SD1 | Architecture::SD2) => Some(sd1()),
+            Some(Architecture::SDXL) => Some(LbwLayout::Sdxl),
+            Some(Architecture::Flux) => Some(LbwLayout::Flux),
+            _ if has(|id| matches!(id, BlockId::Double(_) | BlockId::Single(_))) => {
+                Some(LbwLayout::Flux)
+            }
+            _ if base_names
+                .iter()
+                .any(|b| b.contains("input_blocks") || b.contains("output_blocks")) =>
+            {
+                Some(LbwLayout::Sdxl)
+            }
+            _ if has(|id| matches!(id, BlockId::Down(_) | BlockId::Mid | BlockId::Up(_))) => {
+                Some(sd1())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Average L2 norm of the scaled updates in each slot, the block averages
+/// `block-weights` charts. Only low rank layers count.
+pub fn slot_norms(file: &LoRAFile) -> Result<BTreeMap<LbwSlot, f64>> {
+    let mut sums: BTreeMap<LbwSlot, (f64, usize)> = BTreeMap::new();
+    for base_name in file.base_names() {
+        if file.layer_kind(&base_name) != LayerKind::LowRank {
+            continue;
+        }
+        let Some(slot) = LbwSlot::from_base_name(&base_name) else {
+            continue;
+        };
+        let weight = file.scale_weight(&base_name)?.to_dtype(DType::F64)?;
+        let entry = sums.entry(slot).or_default();
+        entry.0 += norms::l2::<f64>(&weight)?;
+        entry.1 += 1;
+    }
+
+    Ok(sums
+        .into_iter()
+        .map(|(slot, (sum, count))| (slot, sum / count as f64))
+        .collect())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PresetOptions {
+    /// Blocks with a norm below this percentile (0-100) of the block norms
+    /// get 0
+    pub percentile: Option<f64>,
+    /// Weigh blocks by their norm over the strongest block's. Every block
+    /// left gets 1 otherwise.
+    pub normalize: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LbwPreset {
+    pub layout: LbwLayout,
+    pub slots: Vec<LbwSlot>,
+    pub weights: Vec<f64>,
+}
+
+impl fmt::Display for LbwPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let weights: Vec<String> = self.weights.iter().map(|w| format_weight(*w)).collect();
+        write!(f, "{}", weights.join(","))
+    }
+}
+
+/// `0.50` as `0.5` and `1.00` as `1`, the way presets are written.
+fn format_weight(weight: f64) -> String {
+    let s = format!("{weight:.2}");
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" {
+        "0".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/// Builds a block weight preset for `layout` from per slot norms. Slots
+/// without layers get 0.
+pub fn preset(
+    layout: LbwLayout,
+    norms: &BTreeMap<LbwSlot, f64>,
+    options: &PresetOptions,
+) -> LbwPreset {
+    let slots = layout.slots();
+    let present: Vec<f64> = slots.iter().filter_map(|s| norms.get(s).copied()).collect();
+    let strongest = present.iter().copied().fold(0.0, f64::max);
+    let cutoff = options
+        .percentile
+        .map(|p| percentile(present.clone(), p))
+        .unwrap_or(f64::NEG_INFINITY);
+
+    let weights = slots
+        .iter()
+        .map(|slot| match norms.get(slot) {
+            Some(norm) if *norm >= cutoff && *norm > 0.0 => {
+                if options.normalize {
+                    norm / strongest
+                } else {
+                    1.0
+                }
+            }
+            _ => 0.0,
+        })
+        .collect();
+
+    LbwPreset {
+        layout,
+        slots,
+        weights,
+    }
+}
+
+/// Linear interpolation between the closest ranks, `p` in 0-100.
+fn percentile(mut values: Vec<f64>, p: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let rank = (p.clamp(0.0, 100.0) / 100.0) * (values.len() - 1) as f64;
+    let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+    values[low] + (values[high] - values[low]) * (rank - low as f64)
+}
+
+/// Parses a block weight string like `1,0,0.5,...`.
+pub fn parse_weights(s: &str) -> Result<Vec<f64>> {
+    s.split(',')
+        .map(|w| {
+            w.trim()
+                .parse::<f64>()
+                .map_err(|_| InspectorError::Msg(format!("Invalid block weight {w}")))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LbwLayer {
+    pub base_name: String,
+    pub slot: LbwSlot,
+    pub weight: f64,
+    pub method: ScaleMethod,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApplyReport {
+    pub layout: LbwLayout,
+    pub layers: Vec<LbwLayer>,
+    /// Layers whose update does not scale linearly, left as they are
+    pub skipped: Vec<String>,
+    /// Layers outside the slots of the layout, left as they are
+    pub unmatched: Vec<String>,
+}
+
+/// Bakes the block `weights` into the file by scaling each layer by the
+/// weight of its slot. `layout` is picked from the number of weights when
+/// `None`.
+pub fn apply(
+    buffer: &[u8],
+    weights: &[f64],
+    layout: Option<LbwLayout>,
+    method: ScaleMethod,
+) -> Result<(Vec<u8>, ApplyReport)> {
+    let layout = layout
+        .or_else(|| LbwLayout::from_len(weights.len()))
+        .ok_or_else(|| {
+            InspectorError::Msg(format!(
+                "No block weight layout has {} weights",
+                weights.len()
+            ))
+        })?;
+    let slots = layout.slots();
+    if slots.len() != weights.len() {
+        return Err(InspectorError::Msg(format!(
+            "{layout:?} takes {} block weights, got {}",
+            slots.len(),
+            weights.len()
+        )));
+    }
+
+    let file = LoRAFile::new_from_buffer(buffer, "", &Device::Cpu);
+    if !file.is_tensors_loaded() {
+        return Err(InspectorError::Msg(
+            "Could not load the tensors".to_string(),
+        ));
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    let mut layers = vec![];
+    let mut skipped = vec![];
+    let mut unmatched = vec![];
+    let mut base_names = file.base_names();
+    base_names.sort();
+    for base_name in base_names {
+        let Some((slot, weight)) = LbwSlot::from_base_name(&base_name)
+            .and_then(|slot| Some((slots.iter().position(|s| *s == slot)?, slot)))
+            .map(|(i, slot)| (slot, weights[i]))
+        else {
+            unmatched.push(base_name);
+            continue;
+        };
+        if weight == 1.0 {
+            continue;
+        }
+
+        match rescale::scale_layer(&file, &mut writer, &base_name, weight, method)? {
+            Some(method) => layers.push(LbwLayer {
+                base_name,
+                slot,
+                weight,
+                method,
+            }),
+            None => skipped.push(base_name),
+        }
+    }
+
+    let preset = LbwPreset {
+        layout,
+        slots,
+        weights: weights.to_vec(),
+    };
+    writer.insert_metadata("lbw.weights", &preset.to_string());
+
+    Ok((
+        writer.serialize()?,
+        ApplyReport {
+            layout,
+            layers,
+            skipped,
+            unmatched,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Tensor;
+
+    use super::*;
+
+    #[test]
+    fn layouts() {
+        assert_eq!(LbwLayout::Sd1.slots().len(), 17);
+        assert_eq!(LbwLayout::Sd1Full.slots().len(), 26);
+        assert_eq!(LbwLayout::Sdxl.slots().len(), 12);
+        assert_eq!(LbwLayout::Flux.slots().len(), 58);
+        assert_eq!(LbwLayout::from_len(12), Some(LbwLayout::Sdxl));
+
+        let names: Vec<String> = LbwLayout::Sdxl
+            .slots()
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            names.join(","),
+            "BASE,IN04,IN05,IN07,IN08,M00,OUT00,OUT01,OUT02,OUT03,OUT04,OUT05"
+        );
+
+        assert_eq!(
+            LbwSlot::from_base_name("lora_unet_output_blocks_2_1_transformer_blocks_0_attn1_to_k"),
+            Some(LbwSlot::Block(BlockId::Up(2)))
+        );
+        assert_eq!(
+            LbwSlot::from_base_name("lora_te2_text_model_encoder_layers_0_mlp_fc1"),
+            Some(LbwSlot::Base)
+        );
+    }
+
+    #[test]
+    fn preset_from_norms() {
+        let mut norms = BTreeMap::new();
+        norms.insert(LbwSlot::Base, 1.0);
+        norms.insert(LbwSlot::Block(BlockId::Down(4)), 4.0);
+        norms.insert(LbwSlot::Block(BlockId::Mid), 2.0);
+        norms.insert(LbwSlot::Block(BlockId::Up(0)), 0.6);
+
+        let normalized = preset(
+            LbwLayout::Sdxl,
+            &norms,
+            &PresetOptions {
+                percentile: None,
+                normalize: true,
+            },
+        );
+        assert_eq!(normalized.to_string(), "0.25,1,0,0,0,0.5,0.15,0,0,0,0,0");
+
+        let cut = preset(
+            LbwLayout::Sdxl,
+            &norms,
+            &PresetOptions {
+                percentile: Some(50.0),
+                normalize: false,
+            },
+        );
+        assert_eq!(cut.to_string(), "0,1,0,0,0,1,0,0,0,0,0,0");
+    }
+
+    #[test]
+    fn apply_scales_blocks() -> Result<()> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        let names = [
+            "lora_te1_text_model_encoder_layers_0_mlp_fc1",
+            "lora_unet_input_blocks_4_1_proj_in",
+            "lora_unet_middle_block_1_proj_in",
+            "lora_unet_output_blocks_0_1_proj_in",
+        ];
+        for name in names {
+            writer.insert_tensor(
+                &format!("{name}.lora_up.weight"),
+                &Tensor::new(&[[1f32], [0.]], &device)?,
+            )?;
+            writer.insert_tensor(
+                &format!("{name}.lora_down.weight"),
+                &Tensor::new(&[[1f32, 0.]], &device)?,
+            )?;
+            writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(1f32, &device)?)?;
+        }
+        let buffer = writer.serialize()?;
+
+        let file = LoRAFile::new_from_buffer(&buffer, "", &device);
+        assert_eq!(LbwLayout::detect(&file), Some(LbwLayout::Sdxl));
+
+        let weights = parse_weights("0, 1, 1, 1, 1, 0.5, -1, 1, 1, 1, 1, 1")?;
+        let (output, report) = apply(&buffer, &weights, None, ScaleMethod::Factors)?;
+
+        assert_eq!(report.layout, LbwLayout::Sdxl);
+        assert_eq!(report.layers.len(), 3);
+        let written = LoRAFile::new_from_buffer(&output, "", &device);
+        let scale = |name: &str| -> Result<f64> {
+            let weight = written.scale_weight(name)?;
+            Ok(weight.sum_all()?.to_dtype(DType::F64)?.to_scalar::<f64>()?)
+        };
+        assert!(scale(names[0])?.abs() < 1e-6);
+        assert!((scale(names[1])? - 1.0).abs() < 1e-6);
+        assert!((scale(names[2])? - 0.5).abs() < 1e-6);
+        assert!((scale(names[3])? + 1.0).abs() < 1e-6);
+
+        assert!(apply(&buffer, &[1.0, 0.0], None, ScaleMethod::Alpha).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_zero_weight_with_alpha() -> Result<()> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        let names = [
+            "lora_unet_input_blocks_4_1_proj_in",
+            "lora_unet_input_blocks_5_1_proj_in",
+        ];
+        for name in names {
+            writer.insert_tensor(
+                &format!("{name}.lora_up.weight"),
+                &Tensor::new(&[[1f32], [0.]], &device)?,
+            )?;
+            writer.insert_tensor(
+                &format!("{name}.lora_down.weight"),
+                &Tensor::new(&[[1f32, 0.]], &device)?,
+            )?;
+            writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(1f32, &device)?)?;
+        }
+
+        let weights = parse_weights("1,0,0.5,1,1,1,1,1,1,1,1,1")?;
+        let (output, report) = apply(&writer.serialize()?, &weights, None, ScaleMethod::Alpha)?;
+
+        let method = |name: &str| {
+            report
+                .layers
+                .iter()
+                .find(|l| l.base_name == name)
+                .map(|l| l.method)
+        };
+        assert_eq!(method(names[0]), Some(ScaleMethod::Factors));
+        assert_eq!(method(names[1]), Some(ScaleMethod::Alpha));
+
+        let written = LoRAFile::new_from_buffer(&output, "", &device);
+        // An alpha of 0 would load as alpha = rank
+        assert_eq!(written.alpha(names[0]).map(|a| a.0), Some(1.0));
+        assert_eq!(written.alpha(names[1]).map(|a| a.0), Some(0.5));
+        let weight = written.scale_weight(names[0])?;
+        assert_eq!(weight.abs()?.sum_all()?.to_scalar::<f32>()?, 0.0);
+
+        let metadata = written
+            .metadata()
+            .and_then(|m| m.metadata.as_ref())
+            .unwrap();
+        assert_eq!(parse_weights(&metadata["lbw.weights"])?, weights);
+
+        Ok(())
+    }
+}
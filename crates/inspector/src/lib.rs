@@ -10,16 +10,36 @@ use wasm_bindgen::JsValue;
 #[grammar = "key.pest"]
 pub struct KeyParser;
 
+pub mod base;
+pub mod blocks;
+pub mod compat;
+pub mod dora;
+pub mod dylora;
+pub mod extract;
 pub mod file;
+pub mod filter;
 mod header;
+pub mod lbw;
+pub mod lint;
 pub mod metadata;
+pub mod modelspec;
 pub mod network;
 pub mod norms;
-mod parser;
+pub mod oft;
+pub mod parser;
+pub mod precision;
+pub mod prune;
+pub mod rescale;
+pub mod scrub;
 pub mod statistic;
+pub mod subspace;
 pub mod svd;
+pub mod thumbnail;
+pub mod training;
+pub mod writer;
 
 pub use crate::file::LayerScale;
+pub use crate::parser::{parse_key, ModuleKey};
 pub use crate::svd::RankMetrics;
 mod tensor;
 mod weight;
@@ -126,6 +146,13 @@ pub fn get_base_name(name: &str) -> String {
                     | "oft_diag"
                     // BOFT
                     | "oft_blocks"
+                    // IA3
+                    | "on_input"
+                    // Full diffs and norm layers
+                    | "diff"
+                    | "diff_b"
+                    | "w_norm"
+                    | "b_norm"
                     // GLoRA
                     | "a1"
                     | "b1"
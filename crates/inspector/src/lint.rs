@@ -0,0 +1,729 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::file::LoRAFile;
+use crate::network::{Architecture, NetworkArgs, NetworkModule, NetworkType, WeightDecomposition};
+use crate::{InspectorError, Result};
+
+/// kohya's `lora.py` takes one entry per U-Net block: 12 down, 1 mid, 12 up.
+const KOHYA_UNET_BLOCKS: usize = 25;
+/// For SDXL: `time_embed`, 9 input blocks, the middle block, 9 output blocks
+/// and `out`.
+const KOHYA_SDXL_BLOCKS: usize = 21;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum LintRule {
+    NetworkDimMismatch,
+    NetworkAlphaMismatch,
+    ConvDimWithoutConvLayers,
+    DoraWithoutScale,
+    BlockDimsLength,
+    AlphaExceedsRank,
+    MissingBaseModelVersion,
+    InvalidNetworkMetadata,
+    TrainNormWithoutNormLayers,
+}
+
+impl LintRule {
+    pub const ALL: [LintRule; 9] = [
+        LintRule::NetworkDimMismatch,
+        LintRule::NetworkAlphaMismatch,
+        LintRule::ConvDimWithoutConvLayers,
+        LintRule::DoraWithoutScale,
+        LintRule::BlockDimsLength,
+        LintRule::AlphaExceedsRank,
+        LintRule::MissingBaseModelVersion,
+        LintRule::InvalidNetworkMetadata,
+        LintRule::TrainNormWithoutNormLayers,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            LintRule::NetworkDimMismatch => "network-dim-mismatch",
+            LintRule::NetworkAlphaMismatch => "network-alpha-mismatch",
+            LintRule::ConvDimWithoutConvLayers => "conv-dim-without-conv-layers",
+            LintRule::DoraWithoutScale => "dora-without-scale",
+            LintRule::BlockDimsLength => "block-dims-length",
+            LintRule::AlphaExceedsRank => "alpha-exceeds-rank",
+            LintRule::MissingBaseModelVersion => "missing-base-model-version",
+            LintRule::InvalidNetworkMetadata => "invalid-network-metadata",
+            LintRule::TrainNormWithoutNormLayers => "train-norm-without-norm-layers",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            LintRule::NetworkDimMismatch => Severity::Warning,
+            LintRule::NetworkAlphaMismatch => Severity::Warning,
+            LintRule::ConvDimWithoutConvLayers => Severity::Warning,
+            LintRule::DoraWithoutScale => Severity::Error,
+            LintRule::BlockDimsLength => Severity::Error,
+            LintRule::AlphaExceedsRank => Severity::Warning,
+            LintRule::MissingBaseModelVersion => Severity::Info,
+            LintRule::InvalidNetworkMetadata => Severity::Warning,
+            LintRule::TrainNormWithoutNormLayers => Severity::Warning,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            LintRule::NetworkDimMismatch => "ss_network_dim does not match the tensor ranks",
+            LintRule::NetworkAlphaMismatch => "ss_network_alpha does not match the stored alphas",
+            LintRule::ConvDimWithoutConvLayers => "conv_dim is set but no conv layers were saved",
+            LintRule::DoraWithoutScale => "dora_wd is set but no dora_scale tensors were saved",
+            LintRule::BlockDimsLength => {
+                "block_dims/block_alphas length does not match the architecture"
+            }
+            LintRule::AlphaExceedsRank => "alpha is larger than the rank of the layer",
+            LintRule::MissingBaseModelVersion => "ss_base_model_version is missing",
+            LintRule::InvalidNetworkMetadata => {
+                "ss_network_args or the LyCORIS algo could not be parsed"
+            }
+            LintRule::TrainNormWithoutNormLayers => {
+                "train_norm is set but no norm layers were saved"
+            }
+        }
+    }
+
+    /// Rules to run for the given ids. No ids selects every rule; ids in
+    /// `skip` are removed afterwards.
+    pub fn select(ids: &[String], skip: &[String]) -> Result<Vec<LintRule>> {
+        let parse = |ids: &[String]| {
+            ids.iter()
+                .map(|id| id.parse::<LintRule>())
+                .collect::<Result<Vec<_>>>()
+        };
+
+        let selected = if ids.is_empty() {
+            LintRule::ALL.to_vec()
+        } else {
+            parse(ids)?
+        };
+        let skip = parse(skip)?;
+
+        Ok(selected
+            .into_iter()
+            .filter(|rule| !skip.contains(rule))
+            .collect())
+    }
+}
+
+impl FromStr for LintRule {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        LintRule::ALL
+            .into_iter()
+            .find(|rule| rule.id() == s.trim())
+            .ok_or_else(|| InspectorError::Msg(format!("Unknown lint rule {s}")))
+    }
+}
+
+impl fmt::Display for LintRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LintFinding {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// Layers the finding applies to, when it is about specific layers.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LintSkipped {
+    pub rule: &'static str,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LintReport {
+    pub findings: Vec<LintFinding>,
+    /// Rules that could not run, e.g. tensor rules on a header-only file.
+    pub skipped: Vec<LintSkipped>,
+}
+
+impl LintReport {
+    pub fn max_severity(&self) -> Option<Severity> {
+        self.findings.iter().map(|f| f.severity).max()
+    }
+}
+
+/// Outcome of a single rule.
+enum Check {
+    Pass,
+    Fail(String, Vec<String>),
+    Skip(String),
+}
+
+/// Runs `rules` over the metadata and tensors of `file`.
+pub fn lint(file: &LoRAFile, rules: &[LintRule]) -> LintReport {
+    let mut report = LintReport::default();
+
+    for rule in rules {
+        match check(file, *rule) {
+            Check::Pass => (),
+            Check::Fail(message, layers) => report.findings.push(LintFinding {
+                rule: rule.id(),
+                severity: rule.severity(),
+                message,
+                layers,
+            }),
+            Check::Skip(reason) => report.skipped.push(LintSkipped {
+                rule: rule.id(),
+                reason,
+            }),
+        }
+    }
+
+    report
+}
+
+fn check(file: &LoRAFile, rule: LintRule) -> Check {
+    let Some(metadata) = file.metadata().filter(|m| m.metadata.is_some()) else {
+        return Check::Skip("no metadata".to_string());
+    };
+    let network_args = metadata.network_args().unwrap_or_default();
+
+    match rule {
+        LintRule::NetworkDimMismatch => check_network_dim(file, &network_args),
+        LintRule::NetworkAlphaMismatch => check_network_alpha(file, &network_args),
+        LintRule::ConvDimWithoutConvLayers => check_conv_dim(file, &network_args),
+        LintRule::DoraWithoutScale => check_dora_scale(file),
+        LintRule::TrainNormWithoutNormLayers => check_train_norm(file, &network_args),
+        LintRule::BlockDimsLength => check_block_dims(file, &network_args),
+        LintRule::AlphaExceedsRank => check_alpha_rank(file),
+        LintRule::InvalidNetworkMetadata => match metadata.parse_warnings().as_slice() {
+            [] => Check::Pass,
+            warnings => Check::Fail(
+                warnings
+                    .iter()
+                    .map(|w| w.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+                vec![],
+            ),
+        },
+        LintRule::MissingBaseModelVersion => {
+            match get(file, "ss_base_model_version").filter(|v| !v.is_empty()) {
+                Some(_) => Check::Pass,
+                None => Check::Fail(
+                    match file.metadata().and_then(|m| m.architecture()) {
+                        Some(arch) => {
+                            format!("ss_base_model_version is missing, other keys suggest {arch:?}")
+                        }
+                        None => "ss_base_model_version is missing".to_string(),
+                    },
+                    vec![],
+                ),
+            }
+        }
+    }
+}
+
+fn get(file: &LoRAFile, key: &str) -> Option<String> {
+    file.metadata()
+        .and_then(|m| m.metadata.as_ref())
+        .and_then(|m| m.get(key).cloned())
+}
+
+/// Network types whose `dims()` are LoRA ranks. LoKr and OFT store factors and
+/// block sizes there instead.
+fn has_rank_dims(file: &LoRAFile) -> bool {
+    matches!(
+        file.metadata().and_then(|m| m.network_type()),
+        Some(
+            NetworkType::LoRA
+                | NetworkType::LoRAC3Lier
+                | NetworkType::LoRAFA
+                | NetworkType::LoCon
+                | NetworkType::LoHA
+                | NetworkType::DyLoRA
+                | NetworkType::GLoRA
+        )
+    )
+}
+
+fn block_values(seq: &Option<crate::network::BlockUsizeSeq>) -> Vec<usize> {
+    seq.as_ref().map(|s| s.0.clone()).unwrap_or_default()
+}
+
+fn block_f64_values(seq: &Option<crate::network::BlockF64Seq>) -> Vec<f64> {
+    seq.as_ref().map(|s| s.0.clone()).unwrap_or_default()
+}
+
+fn sorted<T: Ord>(values: impl IntoIterator<Item = T>) -> Vec<T> {
+    let mut values: Vec<T> = values.into_iter().collect();
+    values.sort();
+    values
+}
+
+fn check_network_dim(file: &LoRAFile, network_args: &NetworkArgs) -> Check {
+    let Some(dim) = get(file, "ss_network_dim") else {
+        return Check::Skip("ss_network_dim is not set".to_string());
+    };
+    let Ok(dim) = dim.parse::<usize>() else {
+        return Check::Fail(format!("ss_network_dim {dim} is not a number"), vec![]);
+    };
+    if !has_rank_dims(file) {
+        return Check::Skip("network type does not store ranks".to_string());
+    }
+
+    let dims = file.dims();
+    if dims.is_empty() {
+        return Check::Skip("no rank tensors found".to_string());
+    }
+
+    let mut expected: HashSet<usize> = HashSet::from([dim]);
+    expected.extend(network_args.conv_dim);
+    expected.extend(block_values(&network_args.block_dims));
+    expected.extend(block_values(&network_args.conv_block_dims));
+
+    let unexpected = sorted(dims.difference(&expected).copied());
+    let uses_block_dims = network_args.block_dims.is_some();
+
+    if !unexpected.is_empty() {
+        Check::Fail(
+            format!("ss_network_dim is {dim} but tensors have ranks {unexpected:?}"),
+            vec![],
+        )
+    } else if !uses_block_dims && !dims.contains(&dim) {
+        Check::Fail(
+            format!(
+                "ss_network_dim is {dim} but no layer has that rank, found {:?}",
+                sorted(dims)
+            ),
+            vec![],
+        )
+    } else {
+        Check::Pass
+    }
+}
+
+fn check_network_alpha(file: &LoRAFile, network_args: &NetworkArgs) -> Check {
+    let Some(alpha) = get(file, "ss_network_alpha") else {
+        return Check::Skip("ss_network_alpha is not set".to_string());
+    };
+    let Ok(alpha) = alpha.parse::<f32>() else {
+        return Check::Fail(format!("ss_network_alpha {alpha} is not a number"), vec![]);
+    };
+    if !file.is_tensors_loaded() {
+        return Check::Skip("tensors are not loaded".to_string());
+    }
+
+    let alphas = file.alphas();
+    if alphas.is_empty() {
+        return Check::Skip("no alpha tensors found".to_string());
+    }
+
+    let mut expected = vec![alpha];
+    expected.extend(network_args.conv_alpha.map(|a| a as f32));
+    expected.extend(
+        block_f64_values(&network_args.block_alphas)
+            .into_iter()
+            .chain(block_f64_values(&network_args.conv_block_alphas))
+            .map(|a| a as f32),
+    );
+
+    let mut unexpected: Vec<f32> = alphas
+        .iter()
+        .map(|a| a.0)
+        .filter(|a| {
+            !expected
+                .iter()
+                .any(|e| (e - a).abs() <= 1e-3 * e.abs().max(1.))
+        })
+        .collect();
+    unexpected.sort_by(|a, b| a.total_cmp(b));
+
+    if unexpected.is_empty() {
+        Check::Pass
+    } else {
+        Check::Fail(
+            format!("ss_network_alpha is {alpha} but tensors have alphas {unexpected:?}"),
+            vec![],
+        )
+    }
+}
+
+/// 3x3 convolutions, the layers `conv_dim` enables. 1x1 convolutions are
+/// trained with the regular `network_dim`.
+fn conv_layers(file: &LoRAFile) -> Vec<String> {
+    sorted(
+        file.tensor_info()
+            .into_iter()
+            .filter(|t| {
+                (t.shape.len() == 4 && t.shape[2] * t.shape[3] > 1)
+                    || t.name.contains("lora_mid")
+                    || t.name.contains("hada_t1")
+                    || t.name.contains("lokr_t2")
+            })
+            .map(|t| crate::weight::get_base_name(&t.name))
+            .collect::<HashSet<_>>(),
+    )
+}
+
+fn check_conv_dim(file: &LoRAFile, network_args: &NetworkArgs) -> Check {
+    match network_args.conv_dim {
+        Some(conv_dim) if conv_dim > 0 => {
+            if conv_layers(file).is_empty() {
+                Check::Fail(
+                    format!("conv_dim is {conv_dim} but the file has no conv layers"),
+                    vec![],
+                )
+            } else {
+                Check::Pass
+            }
+        }
+        _ => Check::Pass,
+    }
+}
+
+fn check_dora_scale(file: &LoRAFile) -> Check {
+    let dora = file.metadata().and_then(|m| m.weight_decomposition());
+    if dora != Some(WeightDecomposition::DoRA) {
+        return Check::Pass;
+    }
+
+    if file.keys().iter().any(|k| k.contains("dora_scale")) {
+        Check::Pass
+    } else {
+        Check::Fail(
+            "dora_wd is set but no dora_scale tensors were saved".to_string(),
+            vec![],
+        )
+    }
+}
+
+fn check_train_norm(file: &LoRAFile, network_args: &NetworkArgs) -> Check {
+    if network_args.train_norm != Some(true) {
+        return Check::Pass;
+    }
+
+    if file
+        .keys()
+        .iter()
+        .any(|k| k.ends_with(".w_norm") || k.ends_with(".b_norm"))
+    {
+        Check::Pass
+    } else {
+        Check::Fail(
+            "train_norm is set but no w_norm/b_norm tensors were saved".to_string(),
+            vec![],
+        )
+    }
+}
+
+fn check_block_dims(file: &LoRAFile, network_args: &NetworkArgs) -> Check {
+    let lists: Vec<(&str, usize)> = [
+        (
+            "block_dims",
+            network_args.block_dims.as_ref().map(|s| s.0.len()),
+        ),
+        (
+            "block_alphas",
+            network_args.block_alphas.as_ref().map(|s| s.0.len()),
+        ),
+        (
+            "conv_block_dims",
+            network_args.conv_block_dims.as_ref().map(|s| s.0.len()),
+        ),
+        (
+            "conv_block_alphas",
+            network_args.conv_block_alphas.as_ref().map(|s| s.0.len()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, len)| len.map(|len| (name, len)))
+    .collect();
+
+    if lists.is_empty() {
+        return Check::Pass;
+    }
+
+    let metadata = file.metadata();
+    if metadata.and_then(|m| m.network_module()) != Some(NetworkModule::KohyaSSLoRA) {
+        return Check::Fail(
+            format!(
+                "{} set but only networks.lora reads them",
+                lists
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            vec![],
+        );
+    }
+
+    match metadata.and_then(|m| m.architecture()) {
+        arch @ (Some(Architecture::SD1 | Architecture::SD2 | Architecture::SDXL) | None) => {
+            let expected = if arch == Some(Architecture::SDXL) {
+                KOHYA_SDXL_BLOCKS
+            } else {
+                KOHYA_UNET_BLOCKS
+            };
+            let wrong: Vec<String> = lists
+                .iter()
+                .filter(|(_, len)| *len != expected)
+                .map(|(name, len)| format!("{name} has {len}"))
+                .collect();
+
+            if wrong.is_empty() {
+                Check::Pass
+            } else {
+                Check::Fail(
+                    format!("expected {expected} U-Net blocks but {}", wrong.join(", ")),
+                    vec![],
+                )
+            }
+        }
+        Some(arch) => Check::Fail(format!("block dims are not supported for {arch:?}"), vec![]),
+    }
+}
+
+fn check_alpha_rank(file: &LoRAFile) -> Check {
+    if !file.is_tensors_loaded() {
+        return Check::Skip("tensors are not loaded".to_string());
+    }
+
+    let layers = sorted(file.base_names().into_iter().filter(|base_name| {
+        match (file.alpha(base_name), file.rank(base_name)) {
+            (Some(alpha), Some(rank)) => alpha.0 > rank as f32,
+            _ => false,
+        }
+    }));
+
+    if layers.is_empty() {
+        Check::Pass
+    } else {
+        Check::Fail(
+            format!(
+                "{} layers have an alpha larger than their rank",
+                layers.len()
+            ),
+            layers,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::*;
+    use crate::writer::SafetensorsWriter;
+
+    fn lora_buffer(metadata: &[(&str, &str)], rank: usize, alpha: f32, dora: bool) -> Vec<u8> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        let base = "lora_unet_down_blocks_0_attentions_0_proj_in";
+        writer
+            .insert_tensor(
+                &format!("{base}.lora_down.weight"),
+                &Tensor::ones((rank, 8), candle_core::DType::F32, &device).unwrap(),
+            )
+            .unwrap();
+        writer
+            .insert_tensor(
+                &format!("{base}.lora_up.weight"),
+                &Tensor::ones((8, rank), candle_core::DType::F32, &device).unwrap(),
+            )
+            .unwrap();
+        writer
+            .insert_tensor(
+                &format!("{base}.alpha"),
+                &Tensor::new(alpha, &device).unwrap(),
+            )
+            .unwrap();
+        if dora {
+            writer
+                .insert_tensor(
+                    &format!("{base}.dora_scale"),
+                    &Tensor::ones((1, 8), candle_core::DType::F32, &device).unwrap(),
+                )
+                .unwrap();
+        }
+        for (k, v) in metadata {
+            writer.insert_metadata(k, v);
+        }
+
+        writer.serialize().unwrap()
+    }
+
+    fn lora(metadata: &[(&str, &str)], rank: usize, alpha: f32, dora: bool) -> LoRAFile {
+        let buffer = lora_buffer(metadata, rank, alpha, dora);
+        LoRAFile::new_from_buffer(&buffer, "test.safetensors", &Device::Cpu)
+    }
+
+    fn rule_ids(report: &LintReport) -> Vec<&'static str> {
+        report.findings.iter().map(|f| f.rule).collect()
+    }
+
+    #[test]
+    fn consistent_file_has_no_findings() {
+        let file = lora(
+            &[
+                ("ss_network_module", "networks.lora"),
+                ("ss_network_dim", "4"),
+                ("ss_network_alpha", "2.0"),
+                ("ss_base_model_version", "sd_v1"),
+            ],
+            4,
+            2.,
+            false,
+        );
+
+        let report = lint(&file, &LintRule::ALL);
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+    }
+
+    #[test]
+    fn flags_metadata_that_disagrees_with_tensors() {
+        let file = lora(
+            &[
+                ("ss_network_module", "networks.lora"),
+                ("ss_network_dim", "8"),
+                ("ss_network_alpha", "16"),
+                (
+                    "ss_network_args",
+                    r#"{"conv_dim": "3", "dora_wd": "True", "block_dims": "2,2,2"}"#,
+                ),
+            ],
+            4,
+            16.,
+            false,
+        );
+
+        let report = lint(&file, &LintRule::ALL);
+        assert_eq!(
+            rule_ids(&report),
+            vec![
+                "network-dim-mismatch",
+                "conv-dim-without-conv-layers",
+                "dora-without-scale",
+                "block-dims-length",
+                "alpha-exceeds-rank",
+                "missing-base-model-version",
+            ]
+        );
+        assert_eq!(report.max_severity(), Some(Severity::Error));
+        assert_eq!(
+            report.findings[4].layers,
+            vec!["lora_unet_down_blocks_0_attentions_0_proj_in"]
+        );
+    }
+
+    #[test]
+    fn dora_scale_present_passes() {
+        let file = lora(
+            &[
+                ("ss_network_module", "networks.lora"),
+                ("ss_network_args", r#"{"dora_wd": "True"}"#),
+            ],
+            4,
+            4.,
+            true,
+        );
+
+        let report = lint(&file, &[LintRule::DoraWithoutScale]);
+        assert!(report.findings.is_empty());
+    }
+
+    #[test]
+    fn train_norm_without_norm_layers() {
+        let file = lora(
+            &[
+                ("ss_network_module", "lycoris.kohya"),
+                (
+                    "ss_network_args",
+                    r#"{"algo": "lora", "train_norm": "True"}"#,
+                ),
+            ],
+            4,
+            4.,
+            false,
+        );
+
+        let report = lint(&file, &[LintRule::TrainNormWithoutNormLayers]);
+        assert_eq!(rule_ids(&report), vec!["train-norm-without-norm-layers"]);
+    }
+
+    #[test]
+    fn header_only_skips_tensor_rules() {
+        let buffer = lora_buffer(&[("ss_network_alpha", "1")], 4, 2., false);
+
+        let header_only = LoRAFile::new_from_header_buffer(&buffer, "test.safetensors").unwrap();
+        let report = lint(&header_only, &[LintRule::NetworkAlphaMismatch]);
+        assert!(report.findings.is_empty());
+        assert_eq!(report.skipped[0].rule, "network-alpha-mismatch");
+
+        let file = LoRAFile::new_from_buffer(&buffer, "test.safetensors", &Device::Cpu);
+        let report = lint(&file, &[LintRule::NetworkAlphaMismatch]);
+        assert_eq!(rule_ids(&report), vec!["network-alpha-mismatch"]);
+    }
+
+    #[test]
+    fn malformed_network_metadata_is_a_warning() {
+        let file = lora(
+            &[
+                ("ss_network_module", "lycoris.kohya"),
+                ("ss_network_args", r#"{"algo": "mystery"}"#),
+            ],
+            4,
+            4.,
+            false,
+        );
+        let report = lint(&file, &[LintRule::InvalidNetworkMetadata]);
+        assert_eq!(report.findings[0].message, "Unknown LyCORIS algo mystery");
+        assert_eq!(report.max_severity(), Some(Severity::Warning));
+
+        let file = lora(
+            &[("ss_network_args", r#"{"down_lr_weight": "cosine*2"}"#)],
+            4,
+            4.,
+            false,
+        );
+        let report = lint(&file, &[LintRule::InvalidNetworkMetadata]);
+        assert!(report.findings[0]
+            .message
+            .starts_with("Invalid ss_network_args: Invalid lr weight cosine*2"));
+    }
+
+    #[test]
+    fn select_rules_by_id() {
+        let rules = LintRule::select(&[], &["missing-base-model-version".to_string()]).unwrap();
+        assert_eq!(rules.len(), LintRule::ALL.len() - 1);
+
+        let rules = LintRule::select(&["alpha-exceeds-rank".to_string()], &[]).unwrap();
+        assert_eq!(rules, vec![LintRule::AlphaExceedsRank]);
+
+        assert!(LintRule::select(&["nope".to_string()], &[]).is_err());
+    }
+}
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
-use safetensors::SafeTensors;
 use serde::{Deserialize, Serialize};
 
-use crate::network::{NetworkArgs, NetworkModule, NetworkType, WeightDecomposition};
+use crate::network::{
+    Architecture, NetworkArgs, NetworkModule, NetworkParseError, NetworkType, WeightDecomposition,
+};
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Metadata {
@@ -11,12 +12,16 @@ pub struct Metadata {
 }
 
 impl Metadata {
+    /// Reads the `__metadata__` block of a full safetensors buffer, failing
+    /// when the buffer is shorter than the payload its header describes.
     pub fn new_from_buffer(buffer: &[u8]) -> crate::Result<Metadata> {
-        Ok(
-            SafeTensors::read_metadata(buffer).map(|(_size, metadata)| Metadata {
-                metadata: metadata.metadata().clone(),
-            })?,
-        )
+        let (header, metadata) = crate::header::parse_header(buffer)?;
+        if header.data_start() + header.data_len() > buffer.len() {
+            return Err(crate::InspectorError::Msg(
+                "buffer does not contain the full safetensors payload".to_string(),
+            ));
+        }
+        Ok(Metadata { metadata })
     }
 
     /// Builds `Metadata` from just the safetensors header (see
@@ -30,21 +35,60 @@ impl Metadata {
         self.metadata.as_ref().map_or(0, |m| m.len())
     }
     pub fn network_args(&self) -> Option<NetworkArgs> {
-        match &self.metadata.as_ref().map(|v| v.get("ss_network_args")) {
-            Some(Some(network_args)) => match serde_json::from_str::<NetworkArgs>(network_args) {
-                Ok(network_args) => Some(network_args),
-                Err(e) => {
-                    println!("{:#?}", e);
-                    None
+        self.try_network_args().ok().flatten()
+    }
+
+    /// Parses `ss_network_args`, failing when it is set but malformed.
+    /// Malformed block learning rate weights are left out instead, see
+    /// `parse_warnings`.
+    pub fn try_network_args(&self) -> Result<Option<NetworkArgs>, NetworkParseError> {
+        Ok(self
+            .parse_network_args()?
+            .map(|(network_args, _)| network_args))
+    }
+
+    /// `ss_network_args` along with the block learning rate weights that could
+    /// not be parsed. These are parsed one at a time and dropped on failure,
+    /// so a bad `down_lr_weight` does not lose the `algo` or `conv_dim`.
+    fn parse_network_args(
+        &self,
+    ) -> Result<Option<(NetworkArgs, Vec<NetworkParseError>)>, NetworkParseError> {
+        let Some(network_args) = self
+            .metadata
+            .as_ref()
+            .and_then(|v| v.get("ss_network_args"))
+        else {
+            return Ok(None);
+        };
+
+        let invalid = |e: serde_json::Error| NetworkParseError::InvalidNetworkArgs(e.to_string());
+        let mut value: serde_json::Value = serde_json::from_str(network_args).map_err(invalid)?;
+        let mut warnings = vec![];
+        if let Some(args) = value.as_object_mut() {
+            for key in ["down_lr_weight", "mid_lr_weight", "up_lr_weight"] {
+                let Some(arg) = args.get(key) else {
+                    continue;
+                };
+                let single = serde_json::Map::from_iter([(key.to_string(), arg.clone())]);
+                if let Err(e) = serde_json::from_value::<NetworkArgs>(single.into()) {
+                    warnings.push(invalid(e));
+                    args.remove(key);
                 }
-            },
-            _ => None,
+            }
         }
+
+        serde_json::from_value::<NetworkArgs>(value)
+            .map(|network_args| Some((network_args, warnings)))
+            .map_err(invalid)
     }
 
     pub fn network_type(&self) -> Option<NetworkType> {
-        // try to discover the network type
-        match self.network_module() {
+        self.try_network_type().ok().flatten()
+    }
+
+    /// Discovers the network type, failing on an unknown LyCORIS `algo`.
+    pub fn try_network_type(&self) -> Result<Option<NetworkType>, NetworkParseError> {
+        Ok(match self.network_module() {
             Some(NetworkModule::KohyaSSLoRA) => match self.network_args() {
                 Some(network_args) => match network_args.conv_dim {
                     // We need to make the name for LoCon/Lo-Curious
@@ -53,21 +97,26 @@ impl Metadata {
                 },
                 None => Some(NetworkType::LoRA),
             },
-            Some(NetworkModule::Lycoris) => self.network_args().map(|network_args| {
-                match network_args.algo.as_ref().map(|algo| algo.as_ref()) {
-                    Some("diag-oft") => NetworkType::DiagOFT,
-                    Some("boft") => NetworkType::BOFT,
-                    Some("loha") => NetworkType::LoHA,
-                    Some("lokr") => NetworkType::LoKr,
-                    Some("glora") => NetworkType::GLoRA,
-                    Some("glokr") => NetworkType::GLoKr,
-                    Some("locon") => NetworkType::LoCon,
-                    Some("lora") => NetworkType::LoRA,
-                    Some(algo) => panic!("Invalid algo {}", algo),
-                    // Defaults to LoRA
-                    None => NetworkType::LoRA,
+            Some(NetworkModule::Lycoris) => match self.network_args() {
+                Some(network_args) => {
+                    Some(match network_args.algo.as_ref().map(|algo| algo.as_ref()) {
+                        Some("diag-oft") => NetworkType::DiagOFT,
+                        Some("boft") => NetworkType::BOFT,
+                        Some("loha") => NetworkType::LoHA,
+                        Some("lokr") => NetworkType::LoKr,
+                        Some("glora") => NetworkType::GLoRA,
+                        Some("glokr") => NetworkType::GLoKr,
+                        Some("ia3") => NetworkType::IA3,
+                        Some("full") => NetworkType::Full,
+                        Some("locon") => NetworkType::LoCon,
+                        Some("lora") => NetworkType::LoRA,
+                        Some(algo) => return Err(NetworkParseError::UnknownAlgo(algo.to_string())),
+                        // Defaults to LoRA
+                        None => NetworkType::LoRA,
+                    })
                 }
-            }),
+                None => None,
+            },
             Some(NetworkModule::KohyaSSLoRAFA) => Some(NetworkType::LoRAFA),
             Some(NetworkModule::KohyaSSDyLoRA) => Some(NetworkType::DyLoRA),
             Some(NetworkModule::KohyaSSOFT) => Some(NetworkType::OFT),
@@ -77,7 +126,20 @@ impl Metadata {
             Some(NetworkModule::KohyaSSLoRASD3) => Some(NetworkType::LoRA),
             Some(NetworkModule::MusubiTunerLoRAKrea2) => Some(NetworkType::LoRA),
             None => None,
-        }
+        })
+    }
+
+    /// Network metadata that could not be parsed, so it can be reported
+    /// instead of silently ignored.
+    pub fn parse_warnings(&self) -> Vec<NetworkParseError> {
+        let mut warnings = match self.parse_network_args() {
+            Ok(network_args) => network_args
+                .map(|(_, warnings)| warnings)
+                .unwrap_or_default(),
+            Err(e) => vec![e],
+        };
+        warnings.extend(self.try_network_type().err());
+        warnings
     }
 
     pub fn weight_decomposition(&self) -> Option<WeightDecomposition> {
@@ -96,6 +158,22 @@ impl Metadata {
         })
     }
 
+    /// Base model family from `ss_base_model_version`, falling back to
+    /// `modelspec.architecture` and then the legacy `ss_v2` flag.
+    pub fn architecture(&self) -> Option<Architecture> {
+        let metadata = self.metadata.as_ref()?;
+
+        ["ss_base_model_version", "modelspec.architecture"]
+            .iter()
+            .filter_map(|key| metadata.get(*key))
+            .find_map(|version| Architecture::from_model_version(version))
+            .or_else(|| match metadata.get("ss_v2").map(|v| v.as_str()) {
+                Some("True") => Some(Architecture::SD2),
+                Some("False") => Some(Architecture::SD1),
+                _ => None,
+            })
+    }
+
     pub fn network_module(&self) -> Option<NetworkModule> {
         match self.metadata.as_ref().map(|v| v.get("ss_network_module")) {
             Some(Some(network_module)) => match network_module.as_str() {
@@ -253,6 +331,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn load_from_truncated_buffer() -> crate::Result<()> {
+        let mut writer = crate::writer::SafetensorsWriter::new();
+        writer.set_metadata(Some(HashMap::from([(
+            "ss_network_dim".to_string(),
+            "4".to_string(),
+        )])));
+        writer.insert_tensor(
+            "lora_unet_mid_block_attentions_0_proj_in.alpha",
+            &candle_core::Tensor::new(&[1f32, 2.], &candle_core::Device::Cpu)?,
+        )?;
+        let buffer = writer.serialize()?;
+        let header_only = &buffer[..buffer.len() - 4];
+
+        // The payload is cut short, only the header can be read
+        assert_err!(
+            Metadata::new_from_buffer(header_only),
+            Err(crate::InspectorError::Msg(_))
+        );
+        let metadata = Metadata::new_from_header_buffer(header_only)?;
+        assert_eq!(
+            metadata.metadata.unwrap().get("ss_network_dim"),
+            Some(&"4".to_string())
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn load_from_buffer() -> crate::Result<()> {
         let buffer = load_test_file()?;
@@ -535,4 +641,34 @@ mod tests {
         assert!(diff.removed.is_empty());
         assert!(diff.changed.is_empty());
     }
+
+    #[test]
+    fn malformed_lr_weight_keeps_network_args() {
+        let metadata = Metadata {
+            metadata: Some(HashMap::from([
+                (
+                    "ss_network_module".to_string(),
+                    "lycoris.kohya".to_string(),
+                ),
+                (
+                    "ss_network_args".to_string(),
+                    r#"{"algo": "loha", "conv_dim": "4", "down_lr_weight": "cosine*2", "up_lr_weight": "1,1,1,1,1,1,1,1,1,1,1,0.5"}"#
+                        .to_string(),
+                ),
+            ])),
+        };
+
+        let network_args = metadata.network_args().unwrap();
+        assert_eq!(network_args.algo.as_deref(), Some("loha"));
+        assert_eq!(network_args.conv_dim, Some(4));
+        assert!(network_args.down_lr_weight.is_none());
+        assert!(network_args.up_lr_weight.is_some());
+        assert_eq!(metadata.network_type(), Some(NetworkType::LoHA));
+
+        let warnings = metadata.parse_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .to_string()
+            .starts_with("Invalid ss_network_args: Invalid lr weight cosine*2"));
+    }
 }
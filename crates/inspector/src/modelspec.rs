@@ -0,0 +1,641 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::Metadata;
+use crate::network::Architecture;
+use crate::{InspectorError, Result};
+
+pub const PREFIX: &str = "modelspec.";
+pub const SPEC_VERSION: &str = "1.0.0";
+
+/// Keys the modelspec requires on every file.
+const REQUIRED: [&str; 4] = ["sai_model_spec", "architecture", "implementation", "title"];
+
+/// Every key defined by the 1.0 modelspec, without the `modelspec.` prefix.
+const KNOWN: [&str; 20] = [
+    "sai_model_spec",
+    "architecture",
+    "implementation",
+    "title",
+    "description",
+    "author",
+    "date",
+    "hash_sha256",
+    "license",
+    "usage_hint",
+    "thumbnail",
+    "tags",
+    "merged_from",
+    "trigger_phrase",
+    "prediction_type",
+    "timestep_range",
+    "encoder_layer",
+    "resolution",
+    "preprocessor",
+    "is_negative_embedding",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PredictionType {
+    Epsilon,
+    V,
+}
+
+impl FromStr for PredictionType {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "epsilon" => Ok(PredictionType::Epsilon),
+            "v" => Ok(PredictionType::V),
+            _ => Err(InspectorError::Msg(format!("Invalid prediction type {s}"))),
+        }
+    }
+}
+
+/// Stability's model metadata spec, as stored in the `modelspec.*` keys.
+///
+/// Free text fields are kept as written; fields with a defined format are
+/// parsed, and are `None` when missing or malformed (see [`ModelSpec::validate`]).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelSpec {
+    pub sai_model_spec: Option<String>,
+    /// `<architecture>/<adapter>`, e.g. `stable-diffusion-xl-v1-base/lora`.
+    pub architecture: Option<String>,
+    pub implementation: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub date: Option<String>,
+    pub hash_sha256: Option<String>,
+    pub license: Option<String>,
+    pub usage_hint: Option<String>,
+    /// Data URI of the preview image.
+    pub thumbnail: Option<String>,
+    pub tags: Vec<String>,
+    pub merged_from: Option<String>,
+    pub trigger_phrase: Option<String>,
+    pub prediction_type: Option<PredictionType>,
+    pub timestep_range: Option<(usize, usize)>,
+    pub encoder_layer: Option<usize>,
+    pub resolution: Option<(usize, usize)>,
+    pub preprocessor: Option<String>,
+    pub is_negative_embedding: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelSpecIssue {
+    pub key: String,
+    pub message: String,
+}
+
+/// A modelspec key and the `ss_*` key that records the same thing.
+#[derive(Debug, Clone, Serialize)]
+pub struct Reconciliation {
+    pub key: String,
+    pub ss_key: String,
+    pub modelspec: String,
+    pub ss: String,
+    pub agrees: bool,
+}
+
+/// Everything the UI shows about the modelspec of a file.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelSpecReport {
+    pub spec: Option<ModelSpec>,
+    pub issues: Vec<ModelSpecIssue>,
+    pub reconciliation: Vec<Reconciliation>,
+}
+
+/// Parses, validates and reconciles the modelspec of `metadata`.
+pub fn report(metadata: &Metadata) -> ModelSpecReport {
+    let spec = ModelSpec::from_metadata(metadata);
+    ModelSpecReport {
+        issues: spec
+            .as_ref()
+            .map(|_| ModelSpec::validate(metadata))
+            .unwrap_or_default(),
+        reconciliation: spec
+            .as_ref()
+            .map(|s| s.reconcile(metadata))
+            .unwrap_or_default(),
+        spec,
+    }
+}
+
+fn parse_pair(value: &str, sep: char) -> Option<(usize, usize)> {
+    let (a, b) = value.split_once(sep)?;
+    Some((a.trim().parse().ok()?, b.trim().parse().ok()?))
+}
+
+/// `(512, 512)`, `[512, 512]`, `512,512` or `512` as kohya writes `ss_resolution`.
+fn parse_ss_resolution(value: &str) -> Option<(usize, usize)> {
+    let value = value.trim_matches(|c| "()[] ".contains(c));
+    parse_pair(value, ',').or_else(|| value.parse().ok().map(|n| (n, n)))
+}
+
+/// `YYYY-MM-DD` followed by an optional `THH:MM[:SS]...` time.
+fn is_iso_date(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    let digits = |range: std::ops::Range<usize>| {
+        bytes
+            .get(range)
+            .is_some_and(|b| b.iter().all(u8::is_ascii_digit))
+    };
+
+    digits(0..4)
+        && bytes.get(4) == Some(&b'-')
+        && digits(5..7)
+        && bytes.get(7) == Some(&b'-')
+        && digits(8..10)
+        && (bytes.len() == 10 || bytes.get(10) == Some(&b'T'))
+}
+
+/// Calendar date of a unix timestamp, as `YYYY-MM-DD`.
+fn unix_to_date(timestamp: i64) -> String {
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = timestamp.div_euclid(86_400) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// modelspec architecture name for a base model family.
+pub fn architecture_name(architecture: Architecture) -> &'static str {
+    match architecture {
+        Architecture::SD1 => "stable-diffusion-v1",
+        Architecture::SD2 => "stable-diffusion-v2-512",
+        Architecture::SDXL => "stable-diffusion-xl-v1-base",
+        Architecture::SD3 => "stable-diffusion-3-medium",
+        Architecture::Flux => "flux-1-dev",
+        Architecture::Lumina => "lumina-2",
+    }
+}
+
+impl ModelSpec {
+    /// Reads the `modelspec.*` keys. Returns `None` when the file has none.
+    pub fn from_metadata(metadata: &Metadata) -> Option<ModelSpec> {
+        let map = metadata.metadata.as_ref()?;
+        if !map.keys().any(|k| k.starts_with(PREFIX)) {
+            return None;
+        }
+
+        let get = |key: &str| {
+            map.get(&format!("{PREFIX}{key}"))
+                .filter(|v| !v.is_empty())
+                .cloned()
+        };
+
+        Some(ModelSpec {
+            sai_model_spec: get("sai_model_spec"),
+            architecture: get("architecture"),
+            implementation: get("implementation"),
+            title: get("title"),
+            description: get("description"),
+            author: get("author"),
+            date: get("date"),
+            hash_sha256: get("hash_sha256"),
+            license: get("license"),
+            usage_hint: get("usage_hint"),
+            thumbnail: get("thumbnail"),
+            tags: get("tags")
+                .map(|tags| {
+                    tags.split(',')
+                        .map(|t| t.trim().to_string())
+                        .filter(|t| !t.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            merged_from: get("merged_from"),
+            trigger_phrase: get("trigger_phrase"),
+            prediction_type: get("prediction_type").and_then(|v| v.parse().ok()),
+            timestep_range: get("timestep_range").and_then(|v| parse_pair(&v, ',')),
+            encoder_layer: get("encoder_layer").and_then(|v| v.parse().ok()),
+            resolution: get("resolution").and_then(|v| parse_pair(&v, 'x')),
+            preprocessor: get("preprocessor"),
+            is_negative_embedding: get("is_negative_embedding").map(|v| v == "true"),
+        })
+    }
+
+    /// Fills in what the kohya `ss_*` keys know, for files trained before
+    /// sd-scripts wrote a modelspec.
+    pub fn from_ss_metadata(metadata: &Metadata) -> ModelSpec {
+        let empty = HashMap::new();
+        let map = metadata.metadata.as_ref().unwrap_or(&empty);
+        let get = |key: &str| {
+            map.get(key)
+                .filter(|v| !v.is_empty() && *v != "None")
+                .cloned()
+        };
+
+        let architecture = metadata.architecture();
+        let prediction_type = match get("ss_v_parameterization").as_deref() {
+            Some("True") => Some(PredictionType::V),
+            Some("False") => Some(PredictionType::Epsilon),
+            _ => None,
+        };
+
+        ModelSpec {
+            sai_model_spec: Some(SPEC_VERSION.to_string()),
+            architecture: architecture.map(|a| format!("{}/lora", architecture_name(a))),
+            implementation: architecture.map(|a| {
+                match a {
+                    Architecture::SD1 | Architecture::SD2 => "diffusers",
+                    Architecture::SDXL | Architecture::SD3 => {
+                        "https://github.com/Stability-AI/generative-models"
+                    }
+                    Architecture::Flux => "https://github.com/black-forest-labs/flux",
+                    Architecture::Lumina => "https://github.com/Alpha-VLLM/Lumina-Image-2.0",
+                }
+                .to_string()
+            }),
+            title: get("ss_output_name"),
+            date: get("ss_training_finished_at")
+                .or_else(|| get("ss_training_started_at"))
+                .and_then(|t| t.parse::<f64>().ok())
+                .map(|t| unix_to_date(t as i64)),
+            resolution: get("ss_resolution").and_then(|r| parse_ss_resolution(&r)),
+            encoder_layer: get("ss_clip_skip").and_then(|c| c.parse().ok()),
+            prediction_type,
+            ..ModelSpec::default()
+        }
+    }
+
+    /// Base model family named by `architecture`.
+    pub fn base_architecture(&self) -> Option<Architecture> {
+        self.architecture
+            .as_deref()
+            .and_then(Architecture::from_model_version)
+    }
+
+    /// Checks the raw keys against the spec: required keys, unknown keys and
+    /// the format of typed values.
+    pub fn validate(metadata: &Metadata) -> Vec<ModelSpecIssue> {
+        let empty = HashMap::new();
+        let map = metadata.metadata.as_ref().unwrap_or(&empty);
+        let mut issues = vec![];
+        let mut issue = |key: &str, message: String| {
+            issues.push(ModelSpecIssue {
+                key: format!("{PREFIX}{key}"),
+                message,
+            })
+        };
+
+        for key in REQUIRED {
+            if map
+                .get(&format!("{PREFIX}{key}"))
+                .is_none_or(|v| v.is_empty())
+            {
+                issue(key, "required key is missing".to_string());
+            }
+        }
+
+        let mut keys: Vec<_> = map
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix(PREFIX).map(|k| (k, v)))
+            .collect();
+        keys.sort();
+
+        for (key, value) in keys {
+            let valid = match key {
+                "sai_model_spec" => value.starts_with("1."),
+                "architecture" => value.contains('/'),
+                "date" => is_iso_date(value),
+                "prediction_type" => value.parse::<PredictionType>().is_ok(),
+                "timestep_range" => parse_pair(value, ',').is_some_and(|(a, b)| a <= b),
+                "encoder_layer" => value.parse::<usize>().is_ok(),
+                "resolution" => parse_pair(value, 'x').is_some(),
+                "thumbnail" => value.starts_with("data:image/"),
+                "hash_sha256" => {
+                    let hash = value.strip_prefix("0x").unwrap_or(value);
+                    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+                }
+                "is_negative_embedding" => value == "true" || value == "false",
+                k if KNOWN.contains(&k) => true,
+                _ => {
+                    issue(key, "not a modelspec key".to_string());
+                    continue;
+                }
+            };
+
+            if !valid {
+                let expected = match key {
+                    "sai_model_spec" => "a 1.x spec version",
+                    "architecture" => "<architecture>/<adapter>",
+                    "date" => "an ISO-8601 date",
+                    "prediction_type" => "epsilon or v",
+                    "timestep_range" => "<min>,<max>",
+                    "encoder_layer" => "a layer number",
+                    "resolution" => "<width>x<height>",
+                    "thumbnail" => "an image data URI",
+                    "hash_sha256" => "a sha256 hex digest",
+                    _ => "true or false",
+                };
+                let shown: String = value.chars().take(40).collect();
+                issue(key, format!("expected {expected}, found {shown:?}"));
+            }
+        }
+
+        issues
+    }
+
+    /// Compares the modelspec with the `ss_*` keys that record the same
+    /// information.
+    pub fn reconcile(&self, metadata: &Metadata) -> Vec<Reconciliation> {
+        let ss = ModelSpec::from_ss_metadata(metadata);
+        let mut out = vec![];
+        let mut compare = |key: &str,
+                           ss_key: &str,
+                           modelspec: Option<String>,
+                           value: Option<String>,
+                           agrees: bool| {
+            if let (Some(modelspec), Some(ss)) = (modelspec, value) {
+                out.push(Reconciliation {
+                    key: format!("{PREFIX}{key}"),
+                    ss_key: ss_key.to_string(),
+                    modelspec,
+                    ss,
+                    agrees,
+                })
+            }
+        };
+
+        let raw = |key: &str| metadata.metadata.as_ref().and_then(|m| m.get(key).cloned());
+
+        compare(
+            "architecture",
+            "ss_base_model_version",
+            self.architecture.clone(),
+            raw("ss_base_model_version"),
+            self.base_architecture() == ss.base_architecture(),
+        );
+        compare(
+            "title",
+            "ss_output_name",
+            self.title.clone(),
+            ss.title.clone(),
+            self.title == ss.title,
+        );
+        compare(
+            "resolution",
+            "ss_resolution",
+            self.resolution.map(|(w, h)| format!("{w}x{h}")),
+            raw("ss_resolution"),
+            self.resolution == ss.resolution,
+        );
+        compare(
+            "encoder_layer",
+            "ss_clip_skip",
+            self.encoder_layer.map(|l| l.to_string()),
+            ss.encoder_layer.map(|l| l.to_string()),
+            self.encoder_layer == ss.encoder_layer,
+        );
+        compare(
+            "prediction_type",
+            "ss_v_parameterization",
+            self.prediction_type
+                .map(|p| format!("{p:?}").to_lowercase()),
+            raw("ss_v_parameterization"),
+            self.prediction_type == ss.prediction_type,
+        );
+        compare(
+            "date",
+            "ss_training_finished_at",
+            self.date.clone(),
+            ss.date.clone(),
+            match (&self.date, &ss.date) {
+                (Some(date), Some(ss_date)) => date.starts_with(ss_date.as_str()),
+                _ => false,
+            },
+        );
+
+        out
+    }
+
+    /// The spec as `modelspec.*` key/value pairs. Unset fields are left out.
+    pub fn to_metadata(&self) -> HashMap<String, String> {
+        let pair = |v: (usize, usize), sep: &str| format!("{}{sep}{}", v.0, v.1);
+        let tags = (!self.tags.is_empty()).then(|| self.tags.join(","));
+
+        [
+            ("sai_model_spec", self.sai_model_spec.clone()),
+            ("architecture", self.architecture.clone()),
+            ("implementation", self.implementation.clone()),
+            ("title", self.title.clone()),
+            ("description", self.description.clone()),
+            ("author", self.author.clone()),
+            ("date", self.date.clone()),
+            ("hash_sha256", self.hash_sha256.clone()),
+            ("license", self.license.clone()),
+            ("usage_hint", self.usage_hint.clone()),
+            ("thumbnail", self.thumbnail.clone()),
+            ("tags", tags),
+            ("merged_from", self.merged_from.clone()),
+            ("trigger_phrase", self.trigger_phrase.clone()),
+            (
+                "prediction_type",
+                self.prediction_type
+                    .map(|p| format!("{p:?}").to_lowercase()),
+            ),
+            ("timestep_range", self.timestep_range.map(|r| pair(r, ","))),
+            ("encoder_layer", self.encoder_layer.map(|l| l.to_string())),
+            ("resolution", self.resolution.map(|r| pair(r, "x"))),
+            ("preprocessor", self.preprocessor.clone()),
+            (
+                "is_negative_embedding",
+                self.is_negative_embedding.map(|b| b.to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (format!("{PREFIX}{k}"), v)))
+        .collect()
+    }
+
+    /// Writes the set fields into `metadata`, replacing existing values.
+    pub fn apply(&self, metadata: &mut Metadata) {
+        metadata
+            .metadata
+            .get_or_insert_with(HashMap::new)
+            .extend(self.to_metadata());
+    }
+
+    /// Fields missing here are taken from `other`.
+    pub fn or(self, other: ModelSpec) -> ModelSpec {
+        ModelSpec {
+            sai_model_spec: self.sai_model_spec.or(other.sai_model_spec),
+            architecture: self.architecture.or(other.architecture),
+            implementation: self.implementation.or(other.implementation),
+            title: self.title.or(other.title),
+            description: self.description.or(other.description),
+            author: self.author.or(other.author),
+            date: self.date.or(other.date),
+            hash_sha256: self.hash_sha256.or(other.hash_sha256),
+            license: self.license.or(other.license),
+            usage_hint: self.usage_hint.or(other.usage_hint),
+            thumbnail: self.thumbnail.or(other.thumbnail),
+            tags: if self.tags.is_empty() {
+                other.tags
+            } else {
+                self.tags
+            },
+            merged_from: self.merged_from.or(other.merged_from),
+            trigger_phrase: self.trigger_phrase.or(other.trigger_phrase),
+            prediction_type: self.prediction_type.or(other.prediction_type),
+            timestep_range: self.timestep_range.or(other.timestep_range),
+            encoder_layer: self.encoder_layer.or(other.encoder_layer),
+            resolution: self.resolution.or(other.resolution),
+            preprocessor: self.preprocessor.or(other.preprocessor),
+            is_negative_embedding: self.is_negative_embedding.or(other.is_negative_embedding),
+        }
+    }
+}
+
+/// Sets `modelspec.<key>` in `metadata` after checking the key is part of the
+/// spec. `key` may be given with or without the `modelspec.` prefix.
+pub fn set_key(metadata: &mut Metadata, key: &str, value: &str) -> Result<()> {
+    let key = key.strip_prefix(PREFIX).unwrap_or(key);
+    if !KNOWN.contains(&key) {
+        return Err(InspectorError::Msg(format!("{key} is not a modelspec key")));
+    }
+
+    metadata
+        .metadata
+        .get_or_insert_with(HashMap::new)
+        .insert(format!("{PREFIX}{key}"), value.to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(entries: &[(&str, &str)]) -> Metadata {
+        Metadata {
+            metadata: Some(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn parses_typed_fields() {
+        let spec = ModelSpec::from_metadata(&metadata(&[
+            ("modelspec.sai_model_spec", "1.0.0"),
+            ("modelspec.architecture", "stable-diffusion-xl-v1-base/lora"),
+            ("modelspec.title", "boo"),
+            ("modelspec.resolution", "1024x768"),
+            ("modelspec.timestep_range", "0,1000"),
+            ("modelspec.prediction_type", "v"),
+            ("modelspec.tags", "anime, style"),
+        ]))
+        .unwrap();
+
+        assert_eq!(spec.resolution, Some((1024, 768)));
+        assert_eq!(spec.timestep_range, Some((0, 1000)));
+        assert_eq!(spec.prediction_type, Some(PredictionType::V));
+        assert_eq!(spec.tags, vec!["anime", "style"]);
+        assert_eq!(spec.base_architecture(), Some(Architecture::SDXL));
+
+        assert!(ModelSpec::from_metadata(&metadata(&[("ss_network_dim", "4")])).is_none());
+    }
+
+    #[test]
+    fn validates_against_spec() {
+        let issues = ModelSpec::validate(&metadata(&[
+            ("modelspec.sai_model_spec", "1.0.0"),
+            ("modelspec.architecture", "flux-1-dev"),
+            ("modelspec.title", "boo"),
+            ("modelspec.date", "yesterday"),
+            ("modelspec.resolution", "1024"),
+            ("modelspec.thumbnail", "https://example.com/boo.png"),
+            ("modelspec.colour", "blue"),
+        ]));
+
+        let keys: Vec<&str> = issues.iter().map(|i| i.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "modelspec.implementation",
+                "modelspec.architecture",
+                "modelspec.colour",
+                "modelspec.date",
+                "modelspec.resolution",
+                "modelspec.thumbnail",
+            ]
+        );
+    }
+
+    #[test]
+    fn reconciles_with_ss_keys() {
+        let m = metadata(&[
+            ("modelspec.architecture", "stable-diffusion-v1/lora"),
+            ("modelspec.title", "boo"),
+            ("modelspec.resolution", "512x512"),
+            ("modelspec.date", "2023-02-20T09:22:16"),
+            ("ss_base_model_version", "sdxl_base_v1-0"),
+            ("ss_output_name", "boo"),
+            ("ss_resolution", "(512, 512)"),
+            ("ss_training_finished_at", "1676884936.5"),
+        ]);
+
+        let spec = ModelSpec::from_metadata(&m).unwrap();
+        let reconciled: Vec<(String, bool)> = spec
+            .reconcile(&m)
+            .into_iter()
+            .map(|r| (r.key, r.agrees))
+            .collect();
+
+        assert_eq!(
+            reconciled,
+            vec![
+                ("modelspec.architecture".to_string(), false),
+                ("modelspec.title".to_string(), true),
+                ("modelspec.resolution".to_string(), true),
+                ("modelspec.date".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_and_updates_keys() {
+        let mut m = metadata(&[
+            ("ss_base_model_version", "sd_v1"),
+            ("ss_output_name", "boo"),
+            ("modelspec.title", "old"),
+        ]);
+
+        let spec = ModelSpec::from_metadata(&m)
+            .unwrap()
+            .or(ModelSpec::from_ss_metadata(&m));
+        spec.apply(&mut m);
+        set_key(&mut m, "trigger_phrase", "cp-ede").unwrap();
+        assert!(set_key(&mut m, "colour", "blue").is_err());
+
+        let map = m.metadata.as_ref().unwrap();
+        assert_eq!(map["modelspec.title"], "old");
+        assert_eq!(map["modelspec.architecture"], "stable-diffusion-v1/lora");
+        assert_eq!(map["modelspec.implementation"], "diffusers");
+        assert_eq!(map["modelspec.trigger_phrase"], "cp-ede");
+        assert!(ModelSpec::validate(&m).is_empty());
+    }
+
+    #[test]
+    fn unix_timestamps_to_dates() {
+        assert_eq!(unix_to_date(0), "1970-01-01");
+        assert_eq!(unix_to_date(1676884936), "2023-02-20");
+        assert_eq!(unix_to_date(951782400), "2000-02-29");
+    }
+}
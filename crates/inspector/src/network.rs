@@ -47,6 +47,45 @@ pub enum NetworkType {
     OFT,
     DiagOFT,
     BOFT,
+    Full,
+}
+
+/// Base model family the network was trained against.
+#[wasm_bindgen]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Architecture {
+    SD1,
+    SD2,
+    SDXL,
+    SD3,
+    Flux,
+    Lumina,
+}
+
+impl Architecture {
+    /// Parses either kohya's `ss_base_model_version` (`sd_v1`, `sdxl_base_v1-0`,
+    /// `flux1`, ...) or a modelspec architecture (`stable-diffusion-xl-v1-base`,
+    /// `flux-1-dev`, ...).
+    pub fn from_model_version(version: &str) -> Option<Architecture> {
+        let version = version.to_lowercase();
+        let version = version.split('/').next().unwrap_or_default();
+
+        if version.starts_with("sdxl") || version.starts_with("stable-diffusion-xl") {
+            Some(Architecture::SDXL)
+        } else if version.starts_with("sd3") || version.starts_with("stable-diffusion-3") {
+            Some(Architecture::SD3)
+        } else if version.starts_with("sd_v2") || version.starts_with("stable-diffusion-v2") {
+            Some(Architecture::SD2)
+        } else if version.starts_with("sd_v1") || version.starts_with("stable-diffusion-v1") {
+            Some(Architecture::SD1)
+        } else if version.starts_with("flux") {
+            Some(Architecture::Flux)
+        } else if version.starts_with("lumina") {
+            Some(Architecture::Lumina)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default)]
@@ -85,17 +124,17 @@ pub struct NetworkArgs {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub block_dims: Option<BlockUsizeSeq>,
 
-    #[serde(deserialize_with = "de_optional_vec_sequence_usize_from_str")]
+    #[serde(deserialize_with = "de_optional_vec_sequence_f64_from_str")]
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub block_alphas: Option<BlockUsizeSeq>,
+    pub block_alphas: Option<BlockF64Seq>,
 
     #[serde(deserialize_with = "de_optional_vec_sequence_usize_from_str")]
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub conv_block_dims: Option<BlockUsizeSeq>,
 
-    #[serde(deserialize_with = "de_optional_vec_sequence_usize_from_str")]
+    #[serde(deserialize_with = "de_optional_vec_sequence_f64_from_str")]
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub conv_block_alphas: Option<BlockUsizeSeq>,
+    pub conv_block_alphas: Option<BlockF64Seq>,
 
     // #[serde(deserialize_with = "de_optional_vec_sequence_f64_from_str")]
     // #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -229,6 +268,37 @@ pub struct NetworkArgs {
     pub ggpo_sigma: Option<f64>,
 
     pub initalize: Option<String>,
+
+    #[serde(deserialize_with = "de_optional_f64_from_str")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_lr_zero_threshold: Option<f64>,
+
+    /// DyLoRA rank step
+    #[serde(deserialize_with = "de_optional_usize_from_str")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<usize>,
+}
+
+impl NetworkArgs {
+    /// Per-block learning rate weights from `down_lr_weight`, `mid_lr_weight`
+    /// and `up_lr_weight`, or `None` when none of them were set. SDXL
+    /// networks have 9 blocks per side instead of 12.
+    pub fn block_lr_weights(&self, sdxl: bool) -> Option<BlockLrWeights> {
+        if self.down_lr_weight.is_none()
+            && self.mid_lr_weight.is_none()
+            && self.up_lr_weight.is_none()
+        {
+            return None;
+        }
+
+        Some(BlockLrWeights::new(
+            self.down_lr_weight.as_ref(),
+            self.mid_lr_weight,
+            self.up_lr_weight.as_ref(),
+            self.block_lr_zero_threshold.unwrap_or(0.0),
+            sdxl,
+        ))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -237,16 +307,42 @@ pub enum Op {
     Minus,
 }
 
-impl From<&str> for Op {
-    fn from(value: &str) -> Self {
-        match value {
-            "+" => Op::Plus,
-            "-" => Op::Minus,
-            v => panic!("Could not convert {} to Op", v),
+impl FromStr for Op {
+    type Err = NetworkParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "+" => Ok(Op::Plus),
+            "-" => Ok(Op::Minus),
+            v => Err(NetworkParseError::InvalidOp(v.to_string())),
+        }
+    }
+}
+
+/// Why part of the network metadata could not be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkParseError {
+    InvalidOp(String),
+    InvalidLrWeight { value: String, reason: String },
+    UnknownAlgo(String),
+    InvalidNetworkArgs(String),
+}
+
+impl std::fmt::Display for NetworkParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkParseError::InvalidOp(op) => write!(f, "Invalid op {op}, expected + or -"),
+            NetworkParseError::InvalidLrWeight { value, reason } => {
+                write!(f, "Invalid lr weight {value}: {reason}")
+            }
+            NetworkParseError::UnknownAlgo(algo) => write!(f, "Unknown LyCORIS algo {algo}"),
+            NetworkParseError::InvalidNetworkArgs(e) => write!(f, "Invalid ss_network_args: {e}"),
         }
     }
 }
 
+impl std::error::Error for NetworkParseError {}
+
 #[derive(Debug, Clone)]
 pub enum LrWeight {
     // sine, cosine, linear, reverse_linear, zeros
@@ -279,6 +375,129 @@ impl LrWeight {
     }
 }
 
+impl LrWeight {
+    /// Expands the weight into a list over `num_blocks` blocks, following
+    /// `get_block_lr_weight` in kohya's `lora.py`. Curves use `num_blocks`
+    /// points, explicit lists are returned as given.
+    pub fn evaluate(&self, num_blocks: usize) -> Vec<f64> {
+        let base = |op: &Op, amount: &f64| match op {
+            Op::Plus => *amount,
+            Op::Minus => -amount,
+        };
+        let step = |i: usize| i as f64 / (num_blocks.max(2) - 1) as f64;
+        let sine = |i: usize| (std::f64::consts::PI * step(i) / 2.0).sin();
+
+        match self {
+            LrWeight::Cosine(op, amount) => (0..num_blocks)
+                .rev()
+                .map(|i| sine(i) + base(op, amount))
+                .collect(),
+            LrWeight::Sine(op, amount) => (0..num_blocks)
+                .map(|i| sine(i) + base(op, amount))
+                .collect(),
+            LrWeight::Linear(op, amount) => (0..num_blocks)
+                .map(|i| step(i) + base(op, amount))
+                .collect(),
+            LrWeight::ReverseLinear(op, amount) => (0..num_blocks)
+                .rev()
+                .map(|i| step(i) + base(op, amount))
+                .collect(),
+            LrWeight::Zeros(op, amount) => vec![base(op, amount); num_blocks],
+            LrWeight::Block(weight) => vec![*weight],
+            LrWeight::BlockF64Seq(weights) => weights.0.clone(),
+            LrWeight::BlockUsizeSeq(weights) => weights.0.iter().map(|w| *w as f64).collect(),
+        }
+    }
+}
+
+/// Number of down (and up) blocks kohya's `lora.py` weights separately.
+pub const NUM_OF_BLOCKS: usize = 12;
+
+/// Number of input (and output) blocks kohya's `lora.py` numbers for SDXL.
+pub const NUM_OF_BLOCKS_SDXL: usize = 9;
+
+/// Learning rate multipliers per U-Net block as kohya's `lora.py` applies
+/// them: 12 down blocks, the mid block and 12 up blocks, or 9 of each for
+/// SDXL.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BlockLrWeights {
+    pub down: Option<Vec<f64>>,
+    pub mid: Option<f64>,
+    pub up: Option<Vec<f64>>,
+    /// Indexed by kohya's SDXL numbering, see
+    /// `crate::blocks::kohya_sdxl_block_index`
+    pub sdxl: bool,
+}
+
+impl BlockLrWeights {
+    pub fn new(
+        down: Option<&LrWeight>,
+        mid: Option<f64>,
+        up: Option<&LrWeight>,
+        zero_threshold: f64,
+        sdxl: bool,
+    ) -> BlockLrWeights {
+        let blocks = if sdxl {
+            NUM_OF_BLOCKS_SDXL
+        } else {
+            NUM_OF_BLOCKS
+        };
+        // Lists are padded with 1.0 or truncated to the number of blocks, and
+        // weights at or below the threshold are zeroed.
+        let expand = |weight: &LrWeight| {
+            let mut weights = weight.evaluate(blocks);
+            weights.resize(blocks, 1.0);
+            weights
+                .into_iter()
+                .map(|w| if w > zero_threshold { w } else { 0.0 })
+                .collect()
+        };
+
+        BlockLrWeights {
+            down: down.map(expand),
+            mid: mid.map(|w| if w > zero_threshold { w } else { 0.0 }),
+            up: up.map(expand),
+            sdxl,
+        }
+    }
+
+    /// Weight for a kohya block index (`crate::blocks::kohya_block_index`):
+    /// `0..12` are down blocks, `12` is the mid block and `13..25` are up
+    /// blocks. For SDXL `1..10` are down blocks, `10` is the mid block and
+    /// `11..20` are up blocks, the other slots are not in any block.
+    pub fn weight(&self, block_index: usize) -> f64 {
+        let (first, blocks) = if self.sdxl {
+            (1, NUM_OF_BLOCKS_SDXL)
+        } else {
+            (0, NUM_OF_BLOCKS)
+        };
+        let mid = first + blocks;
+        let at = |weights: &Option<Vec<f64>>, i: usize| {
+            weights
+                .as_ref()
+                .and_then(|w| w.get(i).copied())
+                .unwrap_or(1.0)
+        };
+
+        match block_index {
+            i if i < first => 1.0,
+            i if i < mid => at(&self.down, i - first),
+            i if i == mid => self.mid.unwrap_or(1.0),
+            i => at(&self.up, i - mid - 1),
+        }
+    }
+
+    /// Every slot of the numbering, 25 or 21 for SDXL, down blocks first.
+    pub fn weights(&self) -> Vec<f64> {
+        let slots = if self.sdxl {
+            2 * NUM_OF_BLOCKS_SDXL + 3
+        } else {
+            2 * NUM_OF_BLOCKS + 1
+        };
+        (0..slots).map(|i| self.weight(i)).collect()
+    }
+}
+
 use pest_derive::Parser;
 
 #[derive(Parser)]
@@ -321,59 +540,93 @@ impl Serialize for LrWeight {
     }
 }
 
+impl FromStr for LrWeight {
+    type Err = NetworkParseError;
+
+    /// Parses a curve with an optional base, `cosine+.5`, or a comma separated
+    /// list of weights, `0,0.5,1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| NetworkParseError::InvalidLrWeight {
+            value: s.to_string(),
+            reason,
+        };
+
+        let f = LrWeightParser::parse(Rule::f, s.trim())
+            .map_err(|e| invalid(e.variant.message().to_string()))?
+            .next()
+            .ok_or_else(|| invalid("empty".to_string()))?;
+
+        let weight = f
+            .into_inner()
+            .next()
+            .ok_or_else(|| invalid("empty".to_string()))?;
+
+        match weight.as_rule() {
+            Rule::curve_weight => {
+                let mut curve = "";
+                let mut op = Op::Plus;
+                let mut amount = 0.0;
+                for pair in weight.into_inner() {
+                    match pair.as_rule() {
+                        Rule::curve => curve = pair.as_str(),
+                        Rule::op => op = pair.as_str().parse()?,
+                        Rule::amount => {
+                            amount = pair
+                                .as_str()
+                                .parse()
+                                .map_err(|e| invalid(format!("{}: {e}", pair.as_str())))?
+                        }
+                        _ => continue,
+                    };
+                }
+
+                match curve {
+                    "cosine" => Ok(LrWeight::Cosine(op, amount)),
+                    "sine" => Ok(LrWeight::Sine(op, amount)),
+                    "linear" => Ok(LrWeight::Linear(op, amount)),
+                    "reverse_linear" => Ok(LrWeight::ReverseLinear(op, amount)),
+                    "zeros" => Ok(LrWeight::Zeros(op, amount)),
+                    curve => Err(invalid(format!("unknown curve {curve}"))),
+                }
+            }
+            Rule::numbers => weight
+                .into_inner()
+                .map(|n| {
+                    n.as_str()
+                        .parse::<f64>()
+                        .map_err(|e| invalid(format!("{}: {e}", n.as_str())))
+                })
+                .collect::<Result<Vec<f64>, _>>()
+                .map(|weights| LrWeight::BlockF64Seq(BlockF64Seq(weights))),
+            rule => Err(invalid(format!("unexpected {rule:?}"))),
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for LrWeight {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        // let s = String::deserialize(deserializer)?;
         let v = Value::deserialize(deserializer)?;
-        Ok(match v {
-            Value::String(s) => match s.split('+').collect::<Vec<&str>>() {
-                parts if parts.len() > 1 => {
-                    let pairs = LrWeightParser::parse(Rule::f, &s).unwrap();
-                    let mut curve = "";
-                    let mut op = "";
-                    let mut amount = "";
-                    for pair in pairs {
-                        match pair.as_rule() {
-                            Rule::curve => curve = pair.as_str(),
-                            Rule::op => op = pair.as_str(),
-                            Rule::amount => amount = pair.as_str(),
-                            _ => continue,
-                        };
-                    }
-
-                    match curve {
-                        "cosine" => {
-                            LrWeight::Cosine(op.into(), amount.parse().map_err(de::Error::custom)?)
-                        }
-                        "sine" => {
-                            LrWeight::Sine(op.into(), amount.parse().map_err(de::Error::custom)?)
-                        }
-                        "linear" => {
-                            LrWeight::Linear(op.into(), amount.parse().map_err(de::Error::custom)?)
-                        }
-                        "reverse_linear" => LrWeight::ReverseLinear(
-                            op.into(),
-                            amount.parse().map_err(de::Error::custom)?,
-                        ),
-                        "zeros" => {
-                            LrWeight::Zeros(op.into(), amount.parse().map_err(de::Error::custom)?)
-                        }
-                        curve => panic!("Invalid curve found for {}", curve),
-                    }
-                }
-                single => LrWeight::BlockF64Seq(
-                    single.first().unwrap().parse().map_err(de::Error::custom)?,
-                ),
-            },
-            Value::Number(num) => LrWeight::Block(
-                num.as_f64()
-                    .ok_or_else(|| de::Error::custom("Invalid number"))?,
-            ),
-            value => panic!("Could not extract value: {:#?}", value),
-        })
+        match v {
+            Value::String(s) => s.parse().map_err(de::Error::custom),
+            Value::Number(num) => num
+                .as_f64()
+                .map(LrWeight::Block)
+                .ok_or_else(|| de::Error::custom("Invalid number")),
+            Value::Array(values) => values
+                .iter()
+                .map(|v| {
+                    v.as_f64()
+                        .ok_or_else(|| de::Error::custom(format!("Invalid lr weight {v}")))
+                })
+                .collect::<Result<Vec<f64>, _>>()
+                .map(|weights| LrWeight::BlockF64Seq(BlockF64Seq(weights))),
+            value => Err(de::Error::custom(format!(
+                "Could not extract lr weight from {value}"
+            ))),
+        }
     }
 }
 
@@ -388,7 +641,7 @@ impl std::fmt::Display for ParseSeqError {
 
 // TODO: Probably could merge these 2 unto 1 struct
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
-pub struct BlockF64Seq(Vec<f64>);
+pub struct BlockF64Seq(pub Vec<f64>);
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct BlockUsizeSeq(pub Vec<usize>);
@@ -421,17 +674,17 @@ impl FromStr for BlockUsizeSeq {
     }
 }
 
-// fn de_optional_vec_sequence_f64_from_str<'de, D>(
-//     deserializer: D,
-// ) -> Result<Option<BlockF64Seq>, D::Error>
-// where
-//     D: Deserializer<'de>,
-// {
-//     let s = String::deserialize(deserializer)?;
-//     BlockF64Seq::from_str(&s)
-//         .map(Some)
-//         .map_err(de::Error::custom)
-// }
+fn de_optional_vec_sequence_f64_from_str<'de, D>(
+    deserializer: D,
+) -> Result<Option<BlockF64Seq>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    BlockF64Seq::from_str(&s)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
 
 fn de_optional_vec_sequence_usize_from_str<'de, D>(
     deserializer: D,
@@ -586,4 +839,98 @@ mod tests {
         assert!(network_args.is_ok());
         Ok(())
     }
+
+    #[test]
+    fn evaluate_lr_weight_curves() {
+        let close = |a: &[f64], b: &[f64]| {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-9)
+        };
+
+        let cosine = LrWeight::Cosine(Op::Plus, 0.5).evaluate(NUM_OF_BLOCKS);
+        assert_eq!(cosine.len(), 12);
+        assert!((cosine[0] - 1.5).abs() < 1e-9);
+        assert!((cosine[11] - 0.5).abs() < 1e-9);
+
+        let sine = LrWeight::Sine(Op::Plus, 0.0).evaluate(3);
+        assert!(close(
+            &sine,
+            &[0.0, (std::f64::consts::PI / 4.0).sin(), 1.0]
+        ));
+
+        let linear = LrWeight::Linear(Op::Minus, 0.5).evaluate(3);
+        assert!(close(&linear, &[-0.5, 0.0, 0.5]));
+
+        let reverse = LrWeight::ReverseLinear(Op::Plus, 0.0).evaluate(3);
+        assert!(close(&reverse, &[1.0, 0.5, 0.0]));
+
+        assert!(close(
+            &LrWeight::Zeros(Op::Plus, 0.25).evaluate(2),
+            &[0.25, 0.25]
+        ));
+    }
+
+    #[test]
+    fn block_lr_weights_pad_and_threshold() {
+        let down = LrWeight::BlockF64Seq("0.05,0.5".parse().unwrap());
+        let weights = BlockLrWeights::new(Some(&down), None, None, 0.1, false);
+
+        let all = weights.weights();
+        assert_eq!(all.len(), 25);
+        assert_eq!(&all[0..3], &[0.0, 0.5, 1.0]);
+        assert_eq!(all[12], 1.0);
+        assert_eq!(all[24], 1.0);
+
+        let args: NetworkArgs =
+            serde_json::from_str(r#"{"mid_lr_weight": "0.5", "block_lr_zero_threshold": "0.6"}"#)
+                .unwrap();
+        assert_eq!(args.block_lr_weights(false).unwrap().weight(12), 0.0);
+        assert!(NetworkArgs::default().block_lr_weights(false).is_none());
+    }
+
+    #[test]
+    fn parse_lr_weights() {
+        for (value, expected) in [
+            ("cosine", LrWeight::Cosine(Op::Plus, 0.0)),
+            ("sine+.5", LrWeight::Sine(Op::Plus, 0.5)),
+            ("linear-1", LrWeight::Linear(Op::Minus, 1.0)),
+            (
+                "reverse_linear+0.25",
+                LrWeight::ReverseLinear(Op::Plus, 0.25),
+            ),
+            ("zeros + 1.5", LrWeight::Zeros(Op::Plus, 1.5)),
+        ] {
+            let weight: LrWeight = value.parse().unwrap();
+            assert_eq!(weight.evaluate(4), expected.evaluate(4), "{value}");
+        }
+
+        let weight: LrWeight = "0, 0.5,1,-1,.25".parse().unwrap();
+        assert_eq!(weight.evaluate(5), vec![0.0, 0.5, 1.0, -1.0, 0.25]);
+
+        for value in ["cosine*2", "square+1", "1,,2", ""] {
+            assert!(
+                matches!(
+                    value.parse::<LrWeight>(),
+                    Err(NetworkParseError::InvalidLrWeight { .. })
+                ),
+                "{value}"
+            );
+        }
+        assert_eq!(
+            "*".parse::<Op>().unwrap_err(),
+            NetworkParseError::InvalidOp("*".to_string())
+        );
+    }
+
+    #[test]
+    fn deserialize_lr_weights_without_panicking() {
+        let args: NetworkArgs =
+            serde_json::from_str(r#"{"down_lr_weight": [0.5, 1], "up_lr_weight": 2}"#).unwrap();
+        assert_eq!(
+            args.block_lr_weights(false).unwrap().weights()[0..2],
+            [0.5, 1.0]
+        );
+
+        assert!(serde_json::from_str::<NetworkArgs>(r#"{"down_lr_weight": {}}"#).is_err());
+        assert!(serde_json::from_str::<NetworkArgs>(r#"{"up_lr_weight": "cosine/2"}"#).is_err());
+    }
 }
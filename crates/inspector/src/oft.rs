@@ -0,0 +1,587 @@
+use candle_core::{DType, Device, Tensor};
+use serde::Serialize;
+
+use crate::file::LoRAFile;
+use crate::network::NetworkModule;
+use crate::{InspectorError, Result};
+
+/// Square `n x n` matrix in row-major order.
+#[derive(Debug, Clone, PartialEq)]
+struct Matrix {
+    n: usize,
+    data: Vec<f64>,
+}
+
+impl Matrix {
+    fn identity(n: usize) -> Matrix {
+        let mut data = vec![0.0; n * n];
+        for i in 0..n {
+            data[i * n + i] = 1.0;
+        }
+        Matrix { n, data }
+    }
+
+    fn at(&self, i: usize, j: usize) -> f64 {
+        self.data[i * self.n + j]
+    }
+
+    fn transpose(&self) -> Matrix {
+        let n = self.n;
+        Matrix {
+            n,
+            data: (0..n * n).map(|i| self.at(i % n, i / n)).collect(),
+        }
+    }
+
+    fn matmul(&self, other: &Matrix) -> Matrix {
+        let n = self.n;
+        let mut data = vec![0.0; n * n];
+        for i in 0..n {
+            for k in 0..n {
+                let a = self.at(i, k);
+                for j in 0..n {
+                    data[i * n + j] += a * other.at(k, j);
+                }
+            }
+        }
+        Matrix { n, data }
+    }
+
+    fn zip(&self, other: &Matrix, f: impl Fn(f64, f64) -> f64) -> Matrix {
+        Matrix {
+            n: self.n,
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(a, b)| f(*a, *b))
+                .collect(),
+        }
+    }
+
+    fn frobenius_sq(&self) -> f64 {
+        self.data.iter().map(|v| v * v).sum()
+    }
+
+    /// Gauss-Jordan elimination with partial pivoting.
+    fn inverse(&self) -> Result<Matrix> {
+        let n = self.n;
+        let mut a = self.data.clone();
+        let mut inv = Matrix::identity(n).data;
+
+        for col in 0..n {
+            let pivot = (col..n)
+                .max_by(|x, y| a[x * n + col].abs().total_cmp(&a[y * n + col].abs()))
+                .unwrap_or(col);
+            if a[pivot * n + col].abs() < 1e-12 {
+                return Err(InspectorError::Msg("singular matrix".to_string()));
+            }
+            for j in 0..n {
+                a.swap(col * n + j, pivot * n + j);
+                inv.swap(col * n + j, pivot * n + j);
+            }
+
+            let p = a[col * n + col];
+            for j in 0..n {
+                a[col * n + j] /= p;
+                inv[col * n + j] /= p;
+            }
+            for row in (0..n).filter(|row| *row != col) {
+                let factor = a[row * n + col];
+                if factor != 0.0 {
+                    for j in 0..n {
+                        a[row * n + j] -= factor * a[col * n + j];
+                        inv[row * n + j] -= factor * inv[col * n + j];
+                    }
+                }
+            }
+        }
+
+        Ok(Matrix { n, data: inv })
+    }
+}
+
+/// Cayley transform of each `b x b` block: `Q = B - B^T`, scaled down to
+/// `constraint` when its norm is larger, then `R = (I + Q)(I - Q)^-1`.
+///
+/// The norm is taken over all blocks together, like LyCORIS and kohya do.
+fn cayley(blocks: &[Matrix], constraint: Option<f64>) -> Result<Vec<Matrix>> {
+    let skew: Vec<Matrix> = blocks
+        .iter()
+        .map(|b| b.zip(&b.transpose(), |x, y| x - y))
+        .collect();
+
+    let norm = skew.iter().map(|q| q.frobenius_sq()).sum::<f64>().sqrt();
+    let shrink = match constraint {
+        Some(c) if c > 0.0 && norm > c => c / norm,
+        _ => 1.0,
+    };
+
+    skew.iter()
+        .map(|q| {
+            let identity = Matrix::identity(q.n);
+            let q = q.zip(&identity, |x, _| x * shrink);
+            let plus = identity.zip(&q, |i, x| i + x);
+            let minus = identity.zip(&q, |i, x| i - x);
+            Ok(plus.matmul(&minus.inverse()?))
+        })
+        .collect()
+}
+
+fn to_blocks(t: &Tensor) -> Result<Vec<Matrix>> {
+    let dims = t.dims();
+    let n = *dims.last().unwrap_or(&0);
+    if dims.len() < 2 || dims[dims.len() - 2] != n || n == 0 {
+        return Err(InspectorError::Msg(format!(
+            "expected square OFT blocks, got {dims:?}"
+        )));
+    }
+
+    let data: Vec<f64> = t.flatten_all()?.to_dtype(DType::F64)?.to_vec1()?;
+    Ok(data
+        .chunks(n * n)
+        .map(|chunk| Matrix {
+            n,
+            data: chunk.to_vec(),
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum OftKind {
+    /// Block diagonal rotation, kohya OFT and LyCORIS diag-oft.
+    Diagonal,
+    /// Product of butterfly stages of block diagonal rotations, LyCORIS BOFT.
+    Butterfly,
+}
+
+/// The orthogonal rotation of an OFT or BOFT layer, rebuilt from its
+/// skew-symmetric parameters.
+#[derive(Debug, Clone)]
+pub struct OftRotation {
+    pub kind: OftKind,
+    pub block_size: usize,
+    pub blocks: usize,
+    /// One stage of `blocks` rotations for diagonal OFT, `m` for BOFT.
+    stages: Vec<Vec<Matrix>>,
+    /// Per output row multiplier when trained with `rescaled`.
+    rescale: Option<Vec<f64>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OftMetrics {
+    pub kind: OftKind,
+    pub block_size: usize,
+    pub blocks: usize,
+    pub stages: usize,
+    /// `||R - I||` per stage.
+    pub stage_deviation: Vec<f64>,
+    /// `||R - I||` of the whole rotation. For BOFT this is the sum over the
+    /// stages, an upper bound on the deviation of their product.
+    pub identity_deviation: f64,
+    /// Largest `||R_k - I||` of a single block.
+    pub max_block_deviation: f64,
+    /// Largest `||R_k^T R_k - I||`; should be 0 up to rounding.
+    pub orthogonality_error: f64,
+    pub rescaled: bool,
+}
+
+impl OftRotation {
+    /// `oft_blocks`/`oft_diag` of shape `(blocks, b, b)`.
+    pub fn diagonal(
+        blocks: &Tensor,
+        constraint: Option<f64>,
+        rescale: Option<&Tensor>,
+    ) -> Result<OftRotation> {
+        if blocks.dims().len() != 3 {
+            return Err(InspectorError::Msg(format!(
+                "expected (blocks, b, b) OFT blocks, got {:?}",
+                blocks.dims()
+            )));
+        }
+        OftRotation::new(
+            OftKind::Diagonal,
+            vec![to_blocks(blocks)?],
+            constraint,
+            rescale,
+        )
+    }
+
+    /// BOFT `oft_blocks` of shape `(m, blocks, b, b)`.
+    pub fn butterfly(
+        blocks: &Tensor,
+        constraint: Option<f64>,
+        rescale: Option<&Tensor>,
+    ) -> Result<OftRotation> {
+        let dims = blocks.dims();
+        if dims.len() != 4 || !dims[2].is_multiple_of(2) {
+            return Err(InspectorError::Msg(format!(
+                "expected (m, blocks, b, b) BOFT blocks with an even b, got {dims:?}"
+            )));
+        }
+        let per_stage = dims[1];
+        let all = to_blocks(blocks)?;
+        let stages = all.chunks(per_stage).map(|c| c.to_vec()).collect();
+        OftRotation::new(OftKind::Butterfly, stages, constraint, rescale)
+    }
+
+    fn new(
+        kind: OftKind,
+        stages: Vec<Vec<Matrix>>,
+        constraint: Option<f64>,
+        rescale: Option<&Tensor>,
+    ) -> Result<OftRotation> {
+        // The constraint applies to all the parameters of the layer at once
+        let flat: Vec<Matrix> = stages.iter().flatten().cloned().collect();
+        let rotated = cayley(&flat, constraint)?;
+        let blocks = stages.first().map_or(0, |s| s.len());
+        let block_size = flat.first().map_or(0, |m| m.n);
+
+        let rescale = match rescale {
+            Some(t) => Some(t.flatten_all()?.to_dtype(DType::F64)?.to_vec1()?),
+            None => None,
+        };
+
+        Ok(OftRotation {
+            kind,
+            block_size,
+            blocks,
+            stages: rotated.chunks(blocks.max(1)).map(|c| c.to_vec()).collect(),
+            rescale,
+        })
+    }
+
+    /// Size of the rotated dimension, the output features of the layer.
+    pub fn dim(&self) -> usize {
+        self.blocks * self.block_size
+    }
+
+    pub fn metrics(&self) -> OftMetrics {
+        let deviation = |r: &Matrix| r.zip(&Matrix::identity(r.n), |a, b| a - b).frobenius_sq();
+
+        let stage_deviation: Vec<f64> = self
+            .stages
+            .iter()
+            .map(|stage| stage.iter().map(deviation).sum::<f64>().sqrt())
+            .collect();
+        let all = || self.stages.iter().flatten();
+
+        OftMetrics {
+            kind: self.kind,
+            block_size: self.block_size,
+            blocks: self.blocks,
+            stages: self.stages.len(),
+            identity_deviation: stage_deviation.iter().sum(),
+            stage_deviation,
+            max_block_deviation: all().map(|r| deviation(r).sqrt()).fold(0.0, f64::max),
+            orthogonality_error: all()
+                .map(|r| {
+                    r.transpose()
+                        .matmul(r)
+                        .zip(&Matrix::identity(r.n), |a, b| a - b)
+                        .frobenius_sq()
+                        .sqrt()
+                })
+                .fold(0.0, f64::max),
+            rescaled: self.rescale.is_some(),
+        }
+    }
+
+    /// Rotates the rows of a `(dim, columns)` row-major weight in place.
+    fn rotate_rows(&self, rows: &mut [f64], columns: usize) {
+        let b = self.block_size;
+        let mut scratch = vec![0.0; b * columns];
+
+        let mut apply = |rows: &mut [f64], blocks: &[Matrix], transpose: bool| {
+            for (k, r) in blocks.iter().enumerate() {
+                let block = &mut rows[k * b * columns..(k + 1) * b * columns];
+                scratch.iter_mut().for_each(|v| *v = 0.0);
+                for i in 0..b {
+                    for j in 0..b {
+                        let w = if transpose { r.at(j, i) } else { r.at(i, j) };
+                        if w == 0.0 {
+                            continue;
+                        }
+                        for c in 0..columns {
+                            scratch[i * columns + c] += w * block[j * columns + c];
+                        }
+                    }
+                }
+                block.copy_from_slice(&scratch);
+            }
+        };
+
+        match self.kind {
+            // einsum("k n m, k n ... -> k m ...", R, W): R_k^T W_k
+            OftKind::Diagonal => apply(rows, &self.stages[0], true),
+            // Each stage interleaves rows 2^i * b/2 apart into the blocks,
+            // rotates them and puts them back.
+            OftKind::Butterfly => {
+                for (i, stage) in self.stages.iter().enumerate() {
+                    let k = (1 << i) * (b / 2);
+                    let permutation: Vec<usize> = (0..self.dim())
+                        .map(|new| {
+                            let (chunk, offset) = (new / (2 * k), new % (2 * k));
+                            chunk * 2 * k + (offset % 2) * k + offset / 2
+                        })
+                        .collect();
+
+                    let mut permuted = vec![0.0; rows.len()];
+                    for (new, old) in permutation.iter().enumerate() {
+                        permuted[new * columns..(new + 1) * columns]
+                            .copy_from_slice(&rows[old * columns..(old + 1) * columns]);
+                    }
+                    apply(&mut permuted, stage, false);
+                    for (new, old) in permutation.iter().enumerate() {
+                        rows[old * columns..(old + 1) * columns]
+                            .copy_from_slice(&permuted[new * columns..(new + 1) * columns]);
+                    }
+                }
+            }
+        }
+
+        if let Some(rescale) = &self.rescale {
+            for (row, scale) in rows.chunks_mut(columns).zip(rescale) {
+                row.iter_mut().for_each(|v| *v *= scale);
+            }
+        }
+    }
+
+    /// `R_k - I` of every block stacked as `(stages * blocks, b, b)`, the part
+    /// of the rotation that changes a weight.
+    pub fn deviation_blocks(&self, device: &Device) -> Result<Tensor> {
+        let data: Vec<f64> = self
+            .stages
+            .iter()
+            .flatten()
+            .flat_map(|r| r.zip(&Matrix::identity(r.n), |a, b| a - b).data)
+            .collect();
+        let count = data.len() / (self.block_size * self.block_size).max(1);
+        Ok(Tensor::from_vec(
+            data,
+            (count, self.block_size, self.block_size),
+            device,
+        )?)
+    }
+
+    /// The rotated weight `R W0` for a base weight whose first dim is `dim()`.
+    pub fn apply(&self, base_weight: &Tensor) -> Result<Tensor> {
+        let dims = base_weight.dims().to_vec();
+        if dims.first() != Some(&self.dim()) {
+            return Err(InspectorError::Msg(format!(
+                "OFT rotates {} features but the base weight is {dims:?}",
+                self.dim()
+            )));
+        }
+        let columns = base_weight.elem_count() / self.dim();
+
+        let mut rows: Vec<f64> = base_weight.flatten_all()?.to_dtype(DType::F64)?.to_vec1()?;
+        self.rotate_rows(&mut rows, columns);
+
+        Ok(Tensor::from_vec(rows, dims, base_weight.device())?)
+    }
+
+    /// Equivalent weight delta `R W0 - W0`.
+    pub fn delta(&self, base_weight: &Tensor) -> Result<Tensor> {
+        let base = base_weight.to_dtype(DType::F64)?;
+        Ok((self.apply(&base)? - base)?)
+    }
+
+    /// The rotation as a dense `dim x dim` matrix, the delta of an identity
+    /// base weight plus `I`.
+    pub fn matrix(&self, device: &Device) -> Result<Tensor> {
+        self.apply(&Tensor::eye(self.dim(), DType::F64, device)?)
+    }
+}
+
+/// Rebuilds the rotation of an OFT, diag-oft or BOFT layer using the
+/// `constrain`/`rescaled` network args, or kohya's alpha as the constraint.
+///
+/// Returns `None` for layers without OFT blocks.
+pub fn rotation(file: &LoRAFile, base_name: &str) -> Result<Option<OftRotation>> {
+    let blocks = file
+        .tensor(&format!("{base_name}.oft_blocks"))
+        .or_else(|| file.tensor(&format!("{base_name}.oft_diag")));
+    let Some(blocks) = blocks else {
+        return Ok(None);
+    };
+
+    let metadata = file.metadata();
+    let network_args = metadata.and_then(|m| m.network_args()).unwrap_or_default();
+    let rescale = file.tensor(&format!("{base_name}.rescale"));
+    if network_args.rescaled == Some(true) && rescale.is_none() {
+        return Err(InspectorError::Msg(format!(
+            "{base_name} was trained with rescaled but has no rescale tensor"
+        )));
+    }
+
+    let dims = blocks.dims();
+    let out_dim = match dims {
+        [m, n, b, _] if *m > 0 => n * b,
+        [n, b, _] => n * b,
+        _ => 0,
+    };
+    // kohya stores its constraint as alpha, LyCORIS uses `constrain`. Both
+    // multiply it by the output features.
+    let constraint =
+        if metadata.and_then(|m| m.network_module()) == Some(NetworkModule::KohyaSSOFT) {
+            file.alpha(base_name).map(|a| a.0 as f64)
+        } else {
+            network_args.constrain
+        }
+        .map(|c| c * out_dim as f64);
+
+    let rotation = match dims.len() {
+        4 => OftRotation::butterfly(&blocks, constraint, rescale.as_ref())?,
+        _ => OftRotation::diagonal(&blocks, constraint, rescale.as_ref())?,
+    };
+
+    if let Some(block_size) = network_args.block_size.filter(|b| *b > 0) {
+        if rotation.kind == OftKind::Diagonal && block_size != rotation.block_size {
+            return Err(InspectorError::Msg(format!(
+                "{base_name} has {} sized blocks but block_size is {block_size}",
+                rotation.block_size
+            )));
+        }
+    }
+
+    Ok(Some(rotation))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OftLayer {
+    pub base_name: String,
+    pub metrics: OftMetrics,
+}
+
+/// Rotation metrics of every OFT layer.
+pub fn layers(file: &LoRAFile) -> Vec<OftLayer> {
+    file.base_names()
+        .into_iter()
+        .filter_map(|base_name| {
+            let rotation = rotation(file, &base_name).ok().flatten()?;
+            Some(OftLayer {
+                metrics: rotation.metrics(),
+                base_name,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &[f64], b: &[f64]) -> bool {
+        a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-9)
+    }
+
+    #[test]
+    fn diagonal_rotation_is_orthogonal() {
+        let device = Device::Cpu;
+        // Q = [[0, 0.5], [-0.5, 0]] gives a rotation with cos = 0.6, sin = 0.8
+        let blocks =
+            Tensor::new(&[[[0f64, 0.5], [0., 0.]], [[0., 0.], [0., 0.]]], &device).unwrap();
+        let rotation = OftRotation::diagonal(&blocks, None, None).unwrap();
+        assert_eq!(rotation.dim(), 4);
+
+        let matrix: Vec<f64> = rotation
+            .matrix(&device)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        assert!(close(
+            &matrix,
+            &[
+                0.6, -0.8, 0., 0., //
+                0.8, 0.6, 0., 0., //
+                0., 0., 1., 0., //
+                0., 0., 0., 1.,
+            ]
+        ));
+
+        let metrics = rotation.metrics();
+        assert!(metrics.orthogonality_error < 1e-12);
+        assert!(
+            (metrics.identity_deviation - (0.4f64.powi(2) * 2. + 0.8f64.powi(2) * 2.).sqrt()).abs()
+                < 1e-12
+        );
+
+        // A constraint shrinks Q before the transform
+        let constrained = OftRotation::diagonal(&blocks, Some(0.1), None).unwrap();
+        assert!(constrained.metrics().identity_deviation < metrics.identity_deviation);
+
+        let base = Tensor::new(&[[1f64], [0.], [2.], [0.]], &device).unwrap();
+        let delta: Vec<f64> = rotation
+            .delta(&base)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        assert!(close(&delta, &[-0.4, 0.8, 0., 0.]));
+    }
+
+    #[test]
+    fn butterfly_matches_dense_product() {
+        let device = Device::Cpu;
+        // Two stages of two 2x2 blocks over 4 features
+        let blocks = Tensor::new(
+            &[
+                [[[0f64, 0.5], [0., 0.]], [[0., 0.], [0.25, 0.]]],
+                [[[0., 0.1], [0., 0.]], [[0., -0.3], [0., 0.]]],
+            ],
+            &device,
+        )
+        .unwrap();
+        let rotation = OftRotation::butterfly(&blocks, None, None).unwrap();
+        let matrix = rotation.matrix(&device).unwrap();
+
+        // Orthogonal as a whole
+        let product: Vec<f64> = matrix
+            .t()
+            .unwrap()
+            .matmul(&matrix)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        let identity: Vec<f64> = Tensor::eye(4, DType::F64, &device)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        assert!(close(&product, &identity));
+
+        // Stage 0 pairs neighbouring rows, stage 1 rows two apart
+        let r0 = &rotation.stages[0];
+        let r1 = &rotation.stages[1];
+        let expected = [
+            [r1[0].at(0, 0), 0., r1[0].at(0, 1), 0.],
+            [0., r1[1].at(0, 0), 0., r1[1].at(0, 1)],
+            [r1[0].at(1, 0), 0., r1[0].at(1, 1), 0.],
+            [0., r1[1].at(1, 0), 0., r1[1].at(1, 1)],
+        ];
+        let stage0 = [
+            [r0[0].at(0, 0), r0[0].at(0, 1), 0., 0.],
+            [r0[0].at(1, 0), r0[0].at(1, 1), 0., 0.],
+            [0., 0., r0[1].at(0, 0), r0[1].at(0, 1)],
+            [0., 0., r0[1].at(1, 0), r0[1].at(1, 1)],
+        ];
+        let dense = Tensor::new(&expected, &device)
+            .unwrap()
+            .matmul(&Tensor::new(&stage0, &device).unwrap())
+            .unwrap();
+        let dense: Vec<f64> = dense.flatten_all().unwrap().to_vec1().unwrap();
+        let matrix: Vec<f64> = matrix.flatten_all().unwrap().to_vec1().unwrap();
+        assert!(close(&matrix, &dense));
+
+        let metrics = rotation.metrics();
+        assert_eq!(metrics.stages, 2);
+        assert!(metrics.orthogonality_error < 1e-12);
+    }
+}
@@ -1,82 +1,340 @@
-// struct {
-// prefix:
-// block
-// block_num
-//     down_block
-//     up_block
-//         num
-// block_type
-//     attentions
-// block_num
-// sub_block
-//     transformer_block
-// sub_block_num
-//     attn2
-// to
-//     num
-//
-// }
-
-// use std::collections::HashMap;
-
-// pub fn parse(key: &str) -> Result<Pairs<Rule>, Box<pest::error::Error<Rule>>> {
-//     let successful_parse = KeyParser::parse(Rule::key, key);
-//     Ok(successful_parse?)
-//     // if let Ok(pairs) = successful_parse {
-//     //     console::log_1(&format!("{:#?}", pairs).into());
-//     // }
-// }
-
-// fn process(pair: Pair<Rule>) {}
-
-// fn process_ids() {
-//     let x = parse("lora_unet_down_blocks_0_attentions_0_proj_in").unwrap();
-//
-//     let mut properties: HashMap<&str, u8> = HashMap::new();
-//
-//     for pair in x {
-//         match pair.as_rule() {
-//             crate::Rule::block_id => {
-//                 properties.insert("block_id", pair.as_str().parse::<u8>().unwrap());
-//             }
-//             crate::Rule::block_type_id => todo!(),
-//             crate::Rule::out_id => todo!(),
-//             crate::Rule::attn_id => todo!(),
-//             crate::Rule::layer_id => todo!(),
-//             _ => (),
-//         }
-//     }
-// }
+use pest::iterators::Pair;
+use pest::Parser;
+use serde::Serialize;
+
+use crate::{InspectorError, KeyParser, Result, Rule};
+
+/// Model a key trains.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Component {
+    /// kohya's `lora_unet`, also used for DiT models trained with kohya
+    Unet,
+    /// diffusers `transformer`/`diffusion_model`
+    Transformer,
+    /// `lora_te`, `lora_te1`, `text_encoder_2`, ...
+    TextEncoder(Option<usize>),
+}
+
+/// A block along the path of a key, `input_blocks_4_1` is `input_blocks` with
+/// indices `[4, 1]`. Blocks without an index, like `mid_block`, have none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockSegment {
+    pub name: String,
+    pub indices: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ParamKind {
+    LoraUp,
+    LoraDown,
+    LoraMid,
+    Alpha,
+    DoraScale,
+    Hada,
+    LoKr,
+    Oft,
+    OftRescale,
+    GLoRA,
+    IA3,
+    IA3OnInput,
+    Diff,
+    DiffBias,
+    NormWeight,
+    NormBias,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Param {
+    pub kind: ParamKind,
+    /// As written in the key, `lora_up.weight` or `hada_w1_a`
+    pub name: String,
+    /// PEFT adapter name, `default` in `lora_A.default.weight`
+    pub adapter: Option<String>,
+}
+
+/// A tensor key split into its parts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModuleKey {
+    pub prefix: Option<String>,
+    pub component: Option<Component>,
+    pub blocks: Vec<BlockSegment>,
+    /// Module inside the innermost block, `attn1_to_k` or `self_attn.q_proj`
+    pub sub_module: String,
+    /// `None` for a base name without the tensor part
+    pub param: Option<Param>,
+}
+
+/// Parses kohya (`lora_unet_down_blocks_0_attentions_0_proj_in.lora_up.weight`)
+/// and PEFT (`transformer.transformer_blocks.0.attn.to_q.lora_A.weight`) keys.
+pub fn parse_key(key: &str) -> Result<ModuleKey> {
+    let mut pairs = KeyParser::parse(Rule::key, key)
+        .map_err(|e| InspectorError::Msg(format!("Invalid key {key}: {e}")))?;
+
+    let mut module_key = ModuleKey {
+        prefix: None,
+        component: None,
+        blocks: vec![],
+        sub_module: String::new(),
+        param: None,
+    };
+
+    let key_pair = pairs.next().expect("the key rule");
+    for pair in key_pair.into_inner().flat_map(|p| p.into_inner()) {
+        match pair.as_rule() {
+            Rule::prefix | Rule::peft_prefix => {
+                module_key.component = Some(component(pair.as_str()));
+                module_key.prefix = Some(pair.as_str().to_string());
+            }
+            Rule::block | Rule::dot_block => module_key.blocks.push(block(pair)),
+            Rule::sub_module | Rule::dot_sub_module => {
+                module_key.sub_module = pair.as_str().to_string()
+            }
+            Rule::param => module_key.param = Some(param(pair)),
+            _ => (),
+        }
+    }
+
+    Ok(module_key)
+}
+
+fn component(prefix: &str) -> Component {
+    match prefix {
+        "lora_unet" | "unet" => Component::Unet,
+        "lora_transformer" | "transformer" | "diffusion_model" => Component::Transformer,
+        te => Component::TextEncoder(
+            te.trim_start_matches("lora_te")
+                .trim_start_matches("text_encoder")
+                .trim_start_matches('_')
+                .parse()
+                .ok(),
+        ),
+    }
+}
+
+fn block(pair: Pair<'_, Rule>) -> BlockSegment {
+    let mut segment = BlockSegment {
+        name: String::new(),
+        indices: vec![],
+    };
+
+    for inner in pair.into_inner() {
+        match inner.as_rule() {
+            Rule::block_name | Rule::scope => segment.name = inner.as_str().to_string(),
+            Rule::block_index => segment.indices.extend(inner.as_str().parse::<usize>().ok()),
+            _ => (),
+        }
+    }
+
+    segment
+}
+
+fn param(pair: Pair<'_, Rule>) -> Param {
+    let name = pair.as_str().to_string();
+    let inner = pair.into_inner().next().expect("a param kind");
+
+    let kind = match inner.as_rule() {
+        Rule::lora_up | Rule::lora_b => ParamKind::LoraUp,
+        Rule::lora_down | Rule::lora_a => ParamKind::LoraDown,
+        Rule::lora_mid => ParamKind::LoraMid,
+        Rule::alpha => ParamKind::Alpha,
+        Rule::dora_scale => ParamKind::DoraScale,
+        Rule::hada => ParamKind::Hada,
+        Rule::lokr => ParamKind::LoKr,
+        Rule::oft => ParamKind::Oft,
+        Rule::rescale => ParamKind::OftRescale,
+        Rule::glora => ParamKind::GLoRA,
+        Rule::diff => ParamKind::Diff,
+        Rule::diff_b => ParamKind::DiffBias,
+        Rule::w_norm => ParamKind::NormWeight,
+        Rule::b_norm => ParamKind::NormBias,
+        Rule::on_input => ParamKind::IA3OnInput,
+        _ => ParamKind::IA3,
+    };
+
+    let adapter = inner
+        .into_inner()
+        .find(|p| p.as_rule() == Rule::adapter)
+        .map(|p| p.as_str().to_string());
+
+    Param {
+        kind,
+        name,
+        adapter,
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use pest::{iterators::Pairs, Parser};
-
-    use crate::{KeyParser, Rule};
-    pub fn parse(key: &str) -> Result<Pairs<Rule>, Box<pest::error::Error<Rule>>> {
-        let successful_parse = KeyParser::parse(Rule::key, key);
-        Ok(successful_parse?)
-        // if let Ok(pairs) = successful_parse {
-        //     console::log_1(&format!("{:#?}", pairs).into());
-        // }
+    use std::fs;
+
+    use super::*;
+
+    fn block(name: &str, indices: &[usize]) -> BlockSegment {
+        BlockSegment {
+            name: name.to_string(),
+            indices: indices.to_vec(),
+        }
     }
 
     #[test]
     fn parse_key_test() {
-        let x = parse("lora_unet_down_blocks_0_attentions_0_proj_in").unwrap();
+        let key = parse_key("lora_unet_down_blocks_0_attentions_0_proj_in").unwrap();
+
+        assert_eq!(key.prefix.as_deref(), Some("lora_unet"));
+        assert_eq!(key.component, Some(Component::Unet));
+        assert_eq!(
+            key.blocks,
+            vec![block("down_blocks", &[0]), block("attentions", &[0])]
+        );
+        assert_eq!(key.sub_module, "proj_in");
+        assert_eq!(key.param, None);
+    }
+
+    #[test]
+    fn parse_sd_keys() {
+        let key = parse_key(
+            "lora_unet_input_blocks_4_1_transformer_blocks_0_attn1_to_out_0.lora_down.weight",
+        )
+        .unwrap();
+        assert_eq!(
+            key.blocks,
+            vec![
+                block("input_blocks", &[4, 1]),
+                block("transformer_blocks", &[0])
+            ]
+        );
+        assert_eq!(key.sub_module, "attn1_to_out_0");
+        assert_eq!(key.param.unwrap().kind, ParamKind::LoraDown);
+
+        let key = parse_key("lora_unet_mid_block_attentions_0_proj_out.hada_w1_a").unwrap();
+        assert_eq!(
+            key.blocks,
+            vec![block("mid_block", &[]), block("attentions", &[0])]
+        );
+        assert_eq!(key.param.unwrap().kind, ParamKind::Hada);
+
+        let key =
+            parse_key("lora_te2_text_model_encoder_layers_11_self_attn_q_proj.alpha").unwrap();
+        assert_eq!(key.component, Some(Component::TextEncoder(Some(2))));
+        assert_eq!(
+            key.blocks,
+            vec![block("text_model_encoder", &[]), block("layers", &[11])]
+        );
+        assert_eq!(key.sub_module, "self_attn_q_proj");
+        assert_eq!(key.param.unwrap().kind, ParamKind::Alpha);
+
+        let key = parse_key("lora_unet_conv_in.oft_diag").unwrap();
+        assert!(key.blocks.is_empty());
+        assert_eq!(key.sub_module, "conv_in");
+    }
+
+    #[test]
+    fn parse_dit_keys() {
+        // Flux
+        let key = parse_key("lora_unet_double_blocks_0_img_attn_qkv.lora_up.weight").unwrap();
+        assert_eq!(key.blocks, vec![block("double_blocks", &[0])]);
+        assert_eq!(key.sub_module, "img_attn_qkv");
+
+        let key = parse_key("lora_unet_single_blocks_37_linear1.lokr_w2_b").unwrap();
+        assert_eq!(key.blocks, vec![block("single_blocks", &[37])]);
+        assert_eq!(key.sub_module, "linear1");
+        assert_eq!(key.param.unwrap().name, "lokr_w2_b");
+
+        let key = parse_key("lora_te3_encoder_block_0_layer_1_DenseReluDense_wi_0.alpha").unwrap();
+        assert_eq!(
+            key.blocks,
+            vec![block("encoder_block", &[0]), block("layer", &[1])]
+        );
+        assert_eq!(key.sub_module, "DenseReluDense_wi_0");
+
+        // SD3
+        let key =
+            parse_key("lora_unet_joint_blocks_3_context_block_attn_qkv.lora_down.weight").unwrap();
+        assert_eq!(
+            key.blocks,
+            vec![block("joint_blocks", &[3]), block("context_block", &[])]
+        );
+        assert_eq!(key.sub_module, "attn_qkv");
 
-        // let mut properties: HashMap<&str, HashMap<&str, &str>> = HashMap::new();
+        // Lumina
+        let key = parse_key("lora_unet_layers_5_feed_forward_w1.lora_up.weight").unwrap();
+        assert_eq!(key.blocks, vec![block("layers", &[5])]);
+        assert_eq!(key.sub_module, "feed_forward_w1");
+
+        let key = parse_key("lora_unet_noise_refiner_1_attention_qkv.alpha").unwrap();
+        assert_eq!(key.blocks, vec![block("noise_refiner", &[1])]);
+
+        let key = parse_key("lora_te_model_layers_2_mlp_gate_proj.alpha").unwrap();
+        assert_eq!(key.component, Some(Component::TextEncoder(None)));
+        assert_eq!(key.blocks, vec![block("model", &[]), block("layers", &[2])]);
+    }
+
+    #[test]
+    fn parse_peft_keys() {
+        let key =
+            parse_key("transformer.single_transformer_blocks.0.attn.to_q.lora_A.weight").unwrap();
+        assert_eq!(key.prefix.as_deref(), Some("transformer"));
+        assert_eq!(key.component, Some(Component::Transformer));
+        assert_eq!(key.blocks, vec![block("single_transformer_blocks", &[0])]);
+        assert_eq!(key.sub_module, "attn.to_q");
+        let param = key.param.unwrap();
+        assert_eq!(param.kind, ParamKind::LoraDown);
+        assert_eq!(param.adapter, None);
+
+        let key = parse_key("transformer_blocks.0.attn.to_q.lora_B.default.weight").unwrap();
+        assert_eq!(key.prefix, None);
+        assert_eq!(key.blocks, vec![block("transformer_blocks", &[0])]);
+        let param = key.param.unwrap();
+        assert_eq!(param.kind, ParamKind::LoraUp);
+        assert_eq!(param.adapter.as_deref(), Some("default"));
+
+        let key = parse_key("diffusion_model.double_blocks.4.img_mlp.0.lora_A.weight").unwrap();
+        assert_eq!(key.blocks, vec![block("double_blocks", &[4])]);
+        assert_eq!(key.sub_module, "img_mlp.0");
+
+        let key =
+            parse_key("text_encoder_2.text_model.encoder.layers.3.self_attn.k_proj.lora_A.weight")
+                .unwrap();
+        assert_eq!(key.component, Some(Component::TextEncoder(Some(2))));
+        assert_eq!(
+            key.blocks,
+            vec![
+                block("text_model", &[]),
+                block("encoder", &[]),
+                block("layers", &[3])
+            ]
+        );
+        assert_eq!(key.sub_module, "self_attn.k_proj");
+
+        // A PEFT base name
+        let key = parse_key("unet.up_blocks.1.attentions.2.proj_out").unwrap();
+        assert_eq!(key.param, None);
+        assert_eq!(key.sub_module, "proj_out");
+    }
+
+    #[test]
+    fn parse_key_rejects_garbage() {
+        assert!(parse_key("").is_err());
+        assert!(parse_key("lora_unet_.alpha").is_err());
+    }
+
+    #[test]
+    fn parse_keys_json() {
+        let keys = fs::read_to_string("./keys.json").expect("to read the keys json");
+        let keys: Vec<String> = serde_json::from_str(&keys).unwrap();
 
-        // for pair in x {
-        //     match pair.as_rule() {
-        //         crate::Rule::block_id => todo!(),
-        //         crate::Rule::block_type_id => todo!(),
-        //         crate::Rule::out_id => todo!(),
-        //         crate::Rule::attn_id => todo!(),
-        //         crate::Rule::layer_id => todo!(),
-        //     }
-        // }
+        for key in keys {
+            let module_key = parse_key(&key).unwrap_or_else(|e| panic!("{e}"));
+            assert!(!module_key.sub_module.is_empty(), "{key}");
+            assert!(module_key.component.is_some(), "{key}");
 
-        println!("{:#?}", x.tokens());
+            // Splitting the key and joining the parts back gives the base name
+            let param = module_key.param.as_ref().expect("keys.json has params");
+            assert!(key.ends_with(&format!(".{}", param.name)), "{key}");
+            assert!(
+                key.starts_with(module_key.prefix.as_deref().unwrap()),
+                "{key}"
+            );
+        }
     }
 }
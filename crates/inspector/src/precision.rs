@@ -0,0 +1,534 @@
+use candle_core::{Device, Tensor};
+use serde::Serialize;
+
+use crate::file::LoRAFile;
+use crate::weight::DType;
+use crate::writer::{SafetensorsWriter, TensorBytes};
+use crate::{InspectorError, Result};
+
+/// Precisions a file can be converted to.
+pub const TARGETS: [DType; 5] = [
+    DType::F32,
+    DType::F16,
+    DType::BF16,
+    DType::F8E4M3,
+    DType::F8E5M2,
+];
+
+/// Parses `fp32`, `fp16`, `bf16`, `fp8_e4m3` or `fp8_e5m2`. `fp8` is e4m3,
+/// the format most fp8 checkpoints use.
+pub fn parse_precision(s: &str) -> Result<DType> {
+    match s {
+        "fp32" | "f32" => Ok(DType::F32),
+        "fp16" | "f16" => Ok(DType::F16),
+        "bf16" => Ok(DType::BF16),
+        "fp8" | "fp8_e4m3" | "e4m3" => Ok(DType::F8E4M3),
+        "fp8_e5m2" | "e5m2" => Ok(DType::F8E5M2),
+        _ => Err(InspectorError::Msg(format!(
+            "Unknown precision {s}. Valid precisions: fp32, fp16, bf16, fp8_e4m3, fp8_e5m2"
+        ))),
+    }
+}
+
+/// Bit layout of an 8-bit float.
+#[derive(Debug, Clone, Copy)]
+struct Fp8 {
+    mantissa_bits: i32,
+    bias: i32,
+    max: f64,
+    /// e4m3 spends its top exponent on normal numbers, keeping only NaN
+    has_infinity: bool,
+}
+
+const E4M3: Fp8 = Fp8 {
+    mantissa_bits: 3,
+    bias: 7,
+    max: 448.0,
+    has_infinity: false,
+};
+
+const E5M2: Fp8 = Fp8 {
+    mantissa_bits: 2,
+    bias: 15,
+    max: 57344.0,
+    has_infinity: true,
+};
+
+impl Fp8 {
+    fn for_dtype(dtype: DType) -> Option<Fp8> {
+        match dtype {
+            DType::F8E4M3 => Some(E4M3),
+            DType::F8E5M2 => Some(E5M2),
+            _ => None,
+        }
+    }
+
+    /// Rounds to nearest, ties to even. Values past the largest finite number
+    /// saturate to it rather than becoming NaN or infinity.
+    fn encode(&self, value: f32) -> u8 {
+        let m = self.mantissa_bits;
+        if value.is_nan() {
+            return 0x7f;
+        }
+
+        let sign = if value.is_sign_negative() { 0x80 } else { 0 };
+        let a = (value.abs() as f64).min(self.max);
+        let min_exponent = 1 - self.bias;
+        if a == 0.0 {
+            return sign;
+        }
+
+        // Step between neighbours at the exponent of `a`, subnormals share
+        // the step of the smallest normal
+        let exponent = exponent_of(a).max(min_exponent);
+        let step = 2f64.powi(exponent - m);
+        let q = ((a / step).round_ties_even() * step).min(self.max);
+        if q == 0.0 {
+            return sign;
+        }
+
+        let exponent = exponent_of(q).max(min_exponent);
+        let mantissa = (q / 2f64.powi(exponent - m)) as u32;
+        let bits = if q < 2f64.powi(min_exponent) {
+            mantissa
+        } else {
+            (((exponent + self.bias) as u32) << m) | (mantissa - (1 << m))
+        };
+        sign | bits as u8
+    }
+
+    fn decode(&self, byte: u8) -> f32 {
+        let m = self.mantissa_bits;
+        let sign = if byte & 0x80 != 0 { -1f64 } else { 1f64 };
+        let exponent_bits = ((byte & 0x7f) >> m) as i32;
+        let mantissa = (byte & ((1 << m) - 1)) as f64;
+        let max_exponent = (1 << (7 - m)) - 1;
+
+        if exponent_bits == max_exponent {
+            if self.has_infinity {
+                return if mantissa == 0.0 {
+                    (sign * f64::INFINITY) as f32
+                } else {
+                    f32::NAN
+                };
+            } else if mantissa as i32 == (1 << m) - 1 {
+                return f32::NAN;
+            }
+        }
+
+        let value = if exponent_bits == 0 {
+            mantissa * 2f64.powi(1 - self.bias - m)
+        } else {
+            (1.0 + mantissa / (1 << m) as f64) * 2f64.powi(exponent_bits - self.bias)
+        };
+        (sign * value) as f32
+    }
+}
+
+/// Exponent of a normal f64, `floor(log2(x))` without the rounding of `log2`.
+fn exponent_of(x: f64) -> i32 {
+    ((x.to_bits() >> 52) & 0x7ff) as i32 - 1023
+}
+
+/// Largest finite value of a float dtype.
+fn max_finite(dtype: DType) -> f64 {
+    match dtype {
+        DType::F16 => 65504.0,
+        DType::BF16 | DType::F32 => f32::MAX as f64,
+        DType::F8E4M3 => E4M3.max,
+        DType::F8E5M2 => E5M2.max,
+        _ => f64::MAX,
+    }
+}
+
+fn is_float(dtype: DType) -> bool {
+    matches!(dtype, DType::F16 | DType::BF16 | DType::F32 | DType::F64) || is_fp8(dtype)
+}
+
+fn is_fp8(dtype: DType) -> bool {
+    Fp8::for_dtype(dtype).is_some()
+}
+
+/// Whether `key` is converted to `dtype`. Alphas stay as they are for fp8, as
+/// loaders read them as a plain number and e4m3 cannot hold most of them.
+fn converts(key: &str, tensor: &TensorBytes, dtype: DType) -> bool {
+    is_float(tensor.dtype) && !(Fp8::for_dtype(dtype).is_some() && key.ends_with(".alpha"))
+}
+
+/// Loads `tensor` with candle, decoding fp8 to F32 by hand.
+fn to_tensor(tensor: &TensorBytes) -> Result<Tensor> {
+    if let Some(fp8) = Fp8::for_dtype(tensor.dtype) {
+        let values: Vec<f32> = tensor.data.iter().map(|b| fp8.decode(*b)).collect();
+        return Ok(Tensor::from_vec(
+            values,
+            tensor.shape.as_slice(),
+            &Device::Cpu,
+        )?);
+    }
+
+    let dtype = tensor.dtype.to_candle().ok_or_else(|| {
+        InspectorError::Msg(format!("cannot load a {} tensor with candle", tensor.dtype))
+    })?;
+    Ok(Tensor::from_raw_buffer(
+        &tensor.data,
+        dtype,
+        &tensor.shape,
+        &Device::Cpu,
+    )?)
+}
+
+/// Stores `tensor` as `dtype`, encoding fp8 by hand since candle has no fp8.
+/// Values past the range of `dtype` saturate to its largest finite value.
+fn encode(tensor: &Tensor, dtype: DType) -> Result<TensorBytes> {
+    match Fp8::for_dtype(dtype) {
+        Some(fp8) => Ok(TensorBytes {
+            dtype,
+            shape: tensor.dims().to_vec(),
+            data: tensor
+                .to_dtype(candle_core::DType::F32)?
+                .flatten_all()?
+                .to_vec1::<f32>()?
+                .into_iter()
+                .map(|v| fp8.encode(v))
+                .collect(),
+        }),
+        None => {
+            let max = max_finite(dtype);
+            let tensor = if max < f32::MAX as f64 {
+                tensor.clamp(-max, max)?
+            } else {
+                tensor.clone()
+            };
+            TensorBytes::from_tensor_as(&tensor, dtype)
+        }
+    }
+}
+
+/// `tensor` after a round trip through `dtype`, in F32.
+fn round_trip(tensor: &Tensor, dtype: DType) -> Result<Tensor> {
+    let encoded = encode(tensor, dtype)?;
+    match Fp8::for_dtype(dtype) {
+        Some(fp8) => {
+            let values: Vec<f32> = encoded.data.iter().map(|b| fp8.decode(*b)).collect();
+            Ok(Tensor::from_vec(values, encoded.shape, &Device::Cpu)?)
+        }
+        None => Ok(to_tensor(&encoded)?.to_dtype(candle_core::DType::F32)?),
+    }
+}
+
+/// Rewrites every float tensor of the file as `dtype`.
+///
+/// fp8 files are written with the `F8_E4M3` and `F8_E5M2` dtypes of the
+/// safetensors format. candle cannot load those, so `decode_fp8` widens them
+/// again when such a file is opened.
+pub fn convert(buffer: &[u8], dtype: DType) -> Result<Vec<u8>> {
+    if !TARGETS.contains(&dtype) {
+        return Err(InspectorError::Msg(format!("cannot convert to {dtype}")));
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    for key in writer.names() {
+        let Some(tensor) = writer.get(&key) else {
+            continue;
+        };
+        if !converts(&key, tensor, dtype) || tensor.dtype == dtype {
+            continue;
+        }
+        let converted = encode(&to_tensor(tensor)?, dtype)?;
+        writer.insert(&key, converted);
+    }
+
+    writer.serialize()
+}
+
+/// The file with its fp8 tensors stored as F16, which holds every fp8 value
+/// exactly, so candle can load it. `None` when the file has no fp8 tensors.
+pub fn decode_fp8(buffer: &[u8]) -> Result<Option<Vec<u8>>> {
+    let (header, _metadata) = crate::header::parse_header(buffer)?;
+    if !header
+        .tensor_info()
+        .iter()
+        .any(|(_, dtype, _)| is_fp8(*dtype))
+    {
+        return Ok(None);
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    for key in writer.names() {
+        let Some(tensor) = writer.get(&key) else {
+            continue;
+        };
+        if !is_fp8(tensor.dtype) {
+            continue;
+        }
+        let decoded = TensorBytes::from_tensor_as(&to_tensor(tensor)?, DType::F16)?;
+        writer.insert(&key, decoded);
+    }
+
+    writer.serialize().map(Some)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TensorPrecision {
+    pub key: String,
+    pub from: String,
+    /// ‖x' - x‖ / ‖x‖
+    pub error: f64,
+    /// Values past the largest finite value of the target
+    pub overflow: usize,
+    /// Values that are not zero but round to zero
+    pub flush_to_zero: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerPrecision {
+    pub base_name: String,
+    /// ‖ΔW' - ΔW‖ / ‖ΔW‖ of the update rebuilt from the converted tensors
+    pub error: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrecisionReport {
+    pub dtype: String,
+    pub tensors: Vec<TensorPrecision>,
+    pub layers: Vec<LayerPrecision>,
+}
+
+impl PrecisionReport {
+    pub fn overflow(&self) -> usize {
+        self.tensors.iter().map(|t| t.overflow).sum()
+    }
+
+    pub fn flush_to_zero(&self) -> usize {
+        self.tensors.iter().map(|t| t.flush_to_zero).sum()
+    }
+
+    pub fn max_error(&self) -> f64 {
+        self.layers.iter().map(|l| l.error).fold(0.0, f64::max)
+    }
+
+    /// No value overflows and no update moves by more than `tolerance`.
+    pub fn is_safe(&self, tolerance: f64) -> bool {
+        self.overflow() == 0 && self.max_error() <= tolerance
+    }
+}
+
+/// Simulates converting the file to `dtype` without writing it: every tensor
+/// goes through `dtype` and back, then the updates are rebuilt from them and
+/// compared with the original.
+pub fn simulate(buffer: &[u8], dtype: DType) -> Result<PrecisionReport> {
+    let original = LoRAFile::new_from_buffer(buffer, "", &Device::Cpu);
+    if !original.is_tensors_loaded() {
+        return Err(InspectorError::Msg(
+            "Could not load the tensors".to_string(),
+        ));
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    let mut tensors = vec![];
+    for key in writer.names() {
+        let Some(bytes) = writer.get(&key) else {
+            continue;
+        };
+        if !converts(&key, bytes, dtype) {
+            continue;
+        }
+
+        let from = bytes.dtype.to_string();
+        let tensor = to_tensor(bytes)?.to_dtype(candle_core::DType::F64)?;
+        let simulated = round_trip(&tensor, dtype)?.to_dtype(candle_core::DType::F64)?;
+
+        let values: Vec<f64> = tensor.flatten_all()?.to_vec1()?;
+        let rounded: Vec<f64> = simulated.flatten_all()?.to_vec1()?;
+        let max = max_finite(dtype);
+        let overflow = values.iter().filter(|v| v.abs() > max).count();
+        let flush_to_zero = values
+            .iter()
+            .zip(&rounded)
+            .filter(|(v, r)| **v != 0.0 && **r == 0.0)
+            .count();
+        let norm = values.iter().map(|v| v * v).sum::<f64>().sqrt();
+        let diff = values
+            .iter()
+            .zip(&rounded)
+            .map(|(v, r)| (v - r) * (v - r))
+            .sum::<f64>()
+            .sqrt();
+
+        tensors.push(TensorPrecision {
+            key: key.clone(),
+            from,
+            error: if norm > 0.0 { diff / norm } else { diff },
+            overflow,
+            flush_to_zero,
+            len: values.len(),
+        });
+        writer.insert(&key, TensorBytes::from_tensor_as(&simulated, DType::F32)?);
+    }
+
+    let simulated = writer.serialize()?;
+    let converted = LoRAFile::new_from_buffer(&simulated, "", &Device::Cpu);
+    let mut layers = vec![];
+    let mut base_names = original.base_names();
+    base_names.sort();
+    for base_name in base_names {
+        let (Ok(before), Ok(after)) = (
+            original.scale_weight(&base_name),
+            converted.scale_weight(&base_name),
+        ) else {
+            continue;
+        };
+        let before = before.to_dtype(candle_core::DType::F64)?;
+        let after = after.to_dtype(candle_core::DType::F64)?;
+        let norm = before.sqr()?.sum_all()?.to_scalar::<f64>()?.sqrt();
+        let diff = (after - &before)?
+            .sqr()?
+            .sum_all()?
+            .to_scalar::<f64>()?
+            .sqrt();
+        layers.push(LayerPrecision {
+            base_name,
+            error: if norm > 0.0 { diff / norm } else { diff },
+        });
+    }
+
+    Ok(PrecisionReport {
+        dtype: dtype.to_string(),
+        tensors,
+        layers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fp8_round_trip() {
+        // Every finite byte decodes and encodes back to itself
+        for fp8 in [E4M3, E5M2] {
+            for byte in 0..=255u8 {
+                let value = fp8.decode(byte);
+                if value.is_finite() {
+                    assert_eq!(fp8.encode(value), byte, "{byte:#x} {value}");
+                }
+            }
+        }
+
+        assert_eq!(E4M3.decode(E4M3.encode(448.0)), 448.0);
+        assert_eq!(E4M3.decode(E4M3.encode(1000.0)), 448.0);
+        assert_eq!(E4M3.decode(E4M3.encode(-0.3)), -0.3125);
+        // Smallest subnormal is 2^-9, half of it ties to even, zero
+        assert_eq!(E4M3.decode(E4M3.encode(2f32.powi(-9))), 2f32.powi(-9));
+        assert_eq!(E4M3.decode(E4M3.encode(2f32.powi(-10))), 0.0);
+        assert_eq!(E5M2.decode(E5M2.encode(1.0e6)), 57344.0);
+        assert_eq!(E5M2.decode(0x7c), f32::INFINITY);
+    }
+
+    fn lora_buffer() -> Result<Vec<u8>> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        let name = "lora_unet_down_blocks_0_attentions_0_proj_in";
+        writer.insert_tensor(
+            &format!("{name}.lora_up.weight"),
+            &Tensor::new(&[[1e-4f32, 2e-4], [1e-9, 3e-4]], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{name}.lora_down.weight"),
+            &Tensor::new(&[[70000f32, 0.5], [0.25, 1.0]], &device)?,
+        )?;
+        writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(0.7f32, &device)?)?;
+        writer.serialize()
+    }
+
+    #[test]
+    fn simulate_reports_overflow_and_underflow() -> Result<()> {
+        let buffer = lora_buffer()?;
+
+        let f32 = simulate(&buffer, DType::F32)?;
+        assert_eq!(f32.max_error(), 0.0);
+        assert!(f32.is_safe(1e-6));
+
+        let f16 = simulate(&buffer, DType::F16)?;
+        assert_eq!(f16.overflow(), 1);
+        // Saturated, so the update stays finite
+        assert!(f16.max_error().is_finite() && f16.max_error() > 0.0);
+        assert_eq!(f16.flush_to_zero(), 1);
+        assert!(!f16.is_safe(1e-2));
+
+        let bf16 = simulate(&buffer, DType::BF16)?;
+        assert_eq!(bf16.overflow(), 0);
+        assert_eq!(bf16.flush_to_zero(), 0);
+        assert!(bf16.max_error() < 1e-2);
+
+        let e4m3 = simulate(&buffer, DType::F8E4M3)?;
+        // Alphas are not converted to fp8
+        assert_eq!(e4m3.tensors.len(), 2);
+        assert_eq!(e4m3.overflow(), 1);
+        assert!(e4m3.flush_to_zero() >= 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn convert_writes_the_dtype() -> Result<()> {
+        let buffer = lora_buffer()?;
+        let name = "lora_unet_down_blocks_0_attentions_0_proj_in";
+
+        let bf16 = convert(&buffer, DType::BF16)?;
+        let file = LoRAFile::new_from_buffer(&bf16, "", &Device::Cpu);
+        assert_eq!(file.precision(), Some(DType::BF16));
+
+        let fp8 = convert(&buffer, DType::F8E4M3)?;
+        let expected: Vec<u8> = [1e-4f32, 2e-4, 1e-9, 3e-4]
+            .iter()
+            .map(|v| E4M3.encode(*v))
+            .collect();
+        // The header names the fp8 dtype and the payload holds one byte each
+        let header_len = u64::from_le_bytes(fp8[..8].try_into().unwrap()) as usize;
+        let header: serde_json::Value = serde_json::from_slice(&fp8[8..8 + header_len])?;
+        let up = &header[format!("{name}.lora_up.weight")];
+        assert_eq!(up["dtype"], "F8_E4M3");
+        assert_eq!(header[format!("{name}.alpha")]["dtype"], "F32");
+        let start = 8 + header_len + up["data_offsets"][0].as_u64().unwrap() as usize;
+        assert_eq!(&fp8[start..start + 4], expected.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn fp8_files_open_again() -> Result<()> {
+        let buffer = lora_buffer()?;
+        let name = "lora_unet_down_blocks_0_attentions_0_proj_in";
+
+        let fp8 = convert(&buffer, DType::F8E4M3)?;
+        let file = LoRAFile::new_from_buffer(&fp8, "", &Device::Cpu);
+        assert!(file.is_tensors_loaded());
+        assert!(file.metadata().is_some());
+        assert_eq!(file.precision(), Some(DType::F8E4M3));
+
+        // The same update as the file holding the fp8 values in F32
+        let mut widened = SafetensorsWriter::from_buffer(&fp8)?;
+        for key in widened.names() {
+            let tensor = to_tensor(widened.get(&key).unwrap())?;
+            widened.insert(&key, TensorBytes::from_tensor_as(&tensor, DType::F32)?);
+        }
+        let expected = LoRAFile::new_from_buffer(&widened.serialize()?, "", &Device::Cpu);
+        let f32 = candle_core::DType::F32;
+        let diff = (file.scale_weight(name)?.to_dtype(f32)?
+            - expected.scale_weight(name)?.to_dtype(f32)?)?
+        .abs()?
+        .sum_all()?
+        .to_scalar::<f32>()?;
+        assert_eq!(diff, 0.0);
+
+        // And converts back
+        let f16 = convert(&fp8, DType::F16)?;
+        let file = LoRAFile::new_from_buffer(&f16, "", &Device::Cpu);
+        assert!(file.is_tensors_loaded());
+        assert_eq!(file.precision(), Some(DType::F16));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Tensor};
+use serde::Serialize;
+
+use crate::file::LoRAFile;
+use crate::rescale::{delta_error_from, FactorPair};
+use crate::svd::RankHealth;
+use crate::writer::{SafetensorsWriter, TensorBytes};
+use crate::{InspectorError, Result};
+
+#[derive(Debug, Clone, Default)]
+pub struct PruneOptions {
+    /// Drop layers with an effective scale below this
+    pub min_scale: Option<f64>,
+    /// Drop LoRA layers with one of these rank healths
+    pub health: Vec<RankHealth>,
+    /// Truncate LoRA layers to the singular values carrying this fraction
+    /// (0-1) of their energy, dropping the tail
+    pub energy: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum PruneAction {
+    Kept,
+    /// Effective scale under `min_scale`
+    DroppedScale,
+    DroppedHealth(RankHealth),
+    Truncated {
+        from: usize,
+        to: usize,
+    },
+    /// Kept at its rank, with the reason it could not be truncated
+    Skipped(String),
+}
+
+impl PruneAction {
+    pub fn is_dropped(&self) -> bool {
+        matches!(
+            self,
+            PruneAction::DroppedScale | PruneAction::DroppedHealth(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrunedLayer {
+    pub base_name: String,
+    pub action: PruneAction,
+    /// ‖ΔW‖² before and after
+    pub energy: f64,
+    pub retained: f64,
+    /// ‖ΔW' - ΔW‖ / ‖ΔW‖ between the delta of the written file and the
+    /// truncation it should hold, 0 for layers that are not truncated
+    pub error: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PruneReport {
+    pub layers: Vec<PrunedLayer>,
+    /// Size of the file before and after, in bytes
+    pub size_before: usize,
+    pub size_after: usize,
+}
+
+impl PruneReport {
+    /// Fraction of the total ‖ΔW‖² over every layer that is left.
+    pub fn retained_energy(&self) -> f64 {
+        let total: f64 = self.layers.iter().map(|l| l.energy).sum();
+        let retained: f64 = self.layers.iter().map(|l| l.retained).sum();
+        if total > 0.0 {
+            retained / total
+        } else {
+            1.0
+        }
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.layers.iter().filter(|l| l.action.is_dropped()).count()
+    }
+}
+
+/// Parses a rank health as printed by `inspect`, e.g. `collapsed` or `poor`.
+pub fn parse_health(s: &str) -> Result<RankHealth> {
+    match s.to_lowercase().as_str() {
+        "collapsed" => Ok(RankHealth::Collapsed),
+        "poor" => Ok(RankHealth::Poor),
+        "weak" => Ok(RankHealth::Weak),
+        "ok" => Ok(RankHealth::Ok),
+        "good" => Ok(RankHealth::Good),
+        _ => Err(InspectorError::Msg(format!(
+            "Unknown rank health {s}, expected collapsed, poor, weak, ok or good"
+        ))),
+    }
+}
+
+/// Removes weak or collapsed layers and truncates the rank of the others.
+///
+/// Truncated layers keep the top singular directions of `up @ down` and
+/// shrink their alpha with the rank, so the directions left apply as before.
+/// Layers without an alpha are left at their rank. As ranks then differ per
+/// layer, `ss_network_dim` and `ss_network_alpha` are removed.
+///
+/// The deltas of the truncated layers are compared with the directions they
+/// should keep and an error is returned if any differs by more than the
+/// precision of the factors allows.
+pub fn prune(buffer: &[u8], options: &PruneOptions) -> Result<(Vec<u8>, PruneReport)> {
+    let file = LoRAFile::new_from_buffer(buffer, "", &Device::Cpu);
+    if !file.is_tensors_loaded() {
+        return Err(InspectorError::Msg(
+            "Could not load the tensors".to_string(),
+        ));
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    let mut layers = vec![];
+    let mut expected = HashMap::new();
+    let mut base_names = file.base_names();
+    base_names.sort();
+    for base_name in base_names {
+        let Some(scale) = file.effective_scale(&base_name)? else {
+            continue;
+        };
+        let energy = scale * scale;
+
+        let health = file
+            .rank_metrics(&base_name)?
+            .map(|metrics| metrics.health)
+            .filter(|health| options.health.contains(health));
+        let action = if options.min_scale.is_some_and(|min| scale < min) {
+            PruneAction::DroppedScale
+        } else if let Some(health) = health {
+            PruneAction::DroppedHealth(health)
+        } else {
+            PruneAction::Kept
+        };
+
+        if action.is_dropped() {
+            for key in writer.names() {
+                if key.starts_with(&format!("{base_name}.")) {
+                    writer.remove(&key);
+                }
+            }
+            layers.push(PrunedLayer {
+                base_name,
+                action,
+                energy,
+                retained: 0.0,
+                error: 0.0,
+            });
+            continue;
+        }
+
+        let truncated = match options.energy {
+            Some(fraction) => truncate(&file, &mut writer, &base_name, fraction)?,
+            None => None,
+        };
+        let (action, retained) = match truncated {
+            Some(Truncation::Truncated {
+                from,
+                to,
+                kept,
+                delta,
+            }) => {
+                expected.insert(base_name.clone(), delta);
+                (PruneAction::Truncated { from, to }, energy * kept)
+            }
+            Some(Truncation::Skipped(reason)) => (PruneAction::Skipped(reason), energy),
+            None => (PruneAction::Kept, energy),
+        };
+        layers.push(PrunedLayer {
+            base_name,
+            action,
+            energy,
+            retained,
+            error: 0.0,
+        });
+    }
+
+    if !layers.is_empty() && layers.iter().all(|l| l.action.is_dropped()) {
+        return Err(InspectorError::Msg(
+            "Pruning would remove every layer".to_string(),
+        ));
+    }
+
+    let truncated = layers
+        .iter()
+        .any(|l| matches!(l.action, PruneAction::Truncated { .. }));
+    if let Some(mut metadata) = writer.metadata().cloned() {
+        if truncated {
+            metadata.remove("ss_network_dim");
+            metadata.remove("ss_network_alpha");
+        }
+        writer.set_metadata(Some(metadata));
+    }
+    if let Some(min_scale) = options.min_scale {
+        writer.insert_metadata("prune.min_scale", &min_scale.to_string());
+    }
+    if !options.health.is_empty() {
+        writer.insert_metadata("prune.health", &serde_json::to_string(&options.health)?);
+    }
+    if let Some(energy) = options.energy {
+        writer.insert_metadata("prune.energy", &energy.to_string());
+    }
+
+    let mut report = PruneReport {
+        layers,
+        size_before: buffer.len(),
+        size_after: 0,
+    };
+    writer.insert_metadata(
+        "prune.retained_energy",
+        &format!("{:.6}", report.retained_energy()),
+    );
+
+    let output = writer.serialize()?;
+    report.size_after = output.len();
+
+    let written = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+    for layer in report.layers.iter_mut() {
+        if let Some(delta) = expected.get(&layer.base_name) {
+            layer.error = delta_error_from(delta, &written, &layer.base_name)?;
+        }
+    }
+
+    Ok((output, report))
+}
+
+/// What `truncate` did to a layer.
+enum Truncation {
+    Truncated {
+        from: usize,
+        to: usize,
+        /// Fraction of the energy kept
+        kept: f64,
+        /// The delta `scale_weight` should read from the written file
+        delta: Tensor,
+    },
+    Skipped(String),
+}
+
+/// Keeps the singular directions of `base_name` carrying `fraction` of its
+/// energy. `None` when nothing is cut or the layer has no SVD.
+fn truncate(
+    file: &LoRAFile,
+    writer: &mut SafetensorsWriter,
+    base_name: &str,
+    fraction: f64,
+) -> Result<Option<Truncation>> {
+    let Some(pair) = FactorPair::find(file, base_name) else {
+        return Ok(None);
+    };
+    let Some(svd) = pair.svd()? else {
+        return Ok(None);
+    };
+
+    let energies: Vec<f64> = svd.s.iter().map(|s| s * s).collect();
+    let total: f64 = energies.iter().sum();
+    if total <= 0.0 {
+        return Ok(None);
+    }
+    let mut kept = 0.0;
+    let mut keep = 0;
+    for energy in &energies {
+        if keep > 0 && kept / total >= fraction {
+            break;
+        }
+        kept += energy;
+        keep += 1;
+    }
+    let rank = pair.rank();
+    if keep >= rank {
+        return Ok(None);
+    }
+
+    // Without an alpha the scale comes from the rank itself, or from the
+    // adapter config for PEFT, so it cannot follow the new rank
+    let alpha_key = format!("{base_name}.alpha");
+    let Some(alpha) = file.tensor(&alpha_key) else {
+        return Ok(Some(Truncation::Skipped(
+            "no alpha to keep its scale at the new rank".to_string(),
+        )));
+    };
+
+    // alpha / rank stays the same, alpha / √rank for rank-stabilized files,
+    // with the factors making up for the rounding of the stored alpha
+    let rank_stabilized = file.metadata().and_then(|m| m.rank_stabilized()) == Some(true);
+    let ratio = keep as f64 / rank as f64;
+    let before = alpha.to_dtype(DType::F64)?.to_scalar::<f64>()?;
+    let target = if rank_stabilized {
+        before * ratio.sqrt()
+    } else {
+        before * ratio
+    };
+    let stored = Tensor::new(target, &Device::Cpu)?.to_dtype(alpha.dtype())?;
+    let after = stored.to_dtype(DType::F64)?.to_scalar::<f64>()?;
+
+    let (mut up, down) = pair.split(&svd, keep)?;
+    if after > 0.0 {
+        up = (up.to_dtype(DType::F64)? * (target / after))?.to_dtype(pair.up.dtype())?;
+    }
+    writer.insert(&alpha_key, TensorBytes::from_tensor(&stored)?);
+    writer.insert(&pair.up_key, TensorBytes::from_tensor(&up)?);
+    writer.insert(&pair.down_key, TensorBytes::from_tensor(&down)?);
+
+    // The top `keep` directions at alpha / rank of the written file
+    let s = Tensor::from_vec(svd.s[..keep].to_vec(), keep, &Device::Cpu)?;
+    let delta = (svd
+        .u
+        .narrow(1, 0, keep)?
+        .to_dtype(DType::F64)?
+        .broadcast_mul(&s.unsqueeze(0)?)?
+        .matmul(&svd.vt.narrow(0, 0, keep)?.to_dtype(DType::F64)?)?
+        * (target / keep as f64))?;
+
+    Ok(Some(Truncation::Truncated {
+        from: rank,
+        to: keep,
+        kept: kept / total,
+        delta,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lora_buffer() -> Result<Vec<u8>> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        // Singular values 4, 2 and 0.1
+        let up = Tensor::new(&[[4f32, 0., 0.], [0., 2., 0.], [0., 0., 0.1]], &device)?;
+        let down = Tensor::eye(3, candle_core::DType::F32, &device)?;
+        for (name, factor) in [
+            ("lora_unet_down_blocks_0_attentions_0_proj_in", 1f64),
+            ("lora_unet_down_blocks_0_attentions_0_proj_out", 1e-4),
+        ] {
+            writer.insert_tensor(&format!("{name}.lora_up.weight"), &(&up * factor)?)?;
+            writer.insert_tensor(&format!("{name}.lora_down.weight"), &down)?;
+            writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(3f32, &device)?)?;
+        }
+        // Collapsed onto one direction
+        let name = "lora_unet_up_blocks_1_attentions_0_proj_in";
+        writer.insert_tensor(
+            &format!("{name}.lora_up.weight"),
+            &Tensor::new(&[[1f32, 1., 1.], [0., 0., 0.], [0., 0., 0.]], &device)?,
+        )?;
+        writer.insert_tensor(&format!("{name}.lora_down.weight"), &down)?;
+        writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(3f32, &device)?)?;
+        writer.insert_metadata("ss_network_dim", "3");
+        writer.serialize()
+    }
+
+    #[test]
+    fn prune_layers_and_tail() -> Result<()> {
+        let buffer = lora_buffer()?;
+        let (output, report) = prune(
+            &buffer,
+            &PruneOptions {
+                min_scale: Some(0.01),
+                health: vec![RankHealth::Collapsed],
+                energy: Some(0.99),
+            },
+        )?;
+
+        let action = |name: &str| {
+            report
+                .layers
+                .iter()
+                .find(|l| l.base_name == name)
+                .map(|l| l.action.clone())
+        };
+        assert_eq!(
+            action("lora_unet_down_blocks_0_attentions_0_proj_in"),
+            Some(PruneAction::Truncated { from: 3, to: 2 })
+        );
+        assert_eq!(
+            action("lora_unet_down_blocks_0_attentions_0_proj_out"),
+            Some(PruneAction::DroppedScale)
+        );
+        assert_eq!(
+            action("lora_unet_up_blocks_1_attentions_0_proj_in"),
+            Some(PruneAction::DroppedHealth(RankHealth::Collapsed))
+        );
+        assert_eq!(report.dropped(), 2);
+        assert_eq!(parse_health("Poor")?, RankHealth::Poor);
+        assert!(parse_health("bad").is_err());
+        assert!(report.size_after < report.size_before);
+        // 4² + 2² of 4² + 2² + 0.1², plus 3 for the collapsed layer
+        let expected = 20.0 / (16.0 + 4.0 + 0.01 + 3.0);
+        assert!((report.retained_energy() - expected).abs() < 1e-3);
+
+        let file = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+        let name = "lora_unet_down_blocks_0_attentions_0_proj_in";
+        assert_eq!(file.base_names(), vec![name.to_string()]);
+        assert_eq!(file.rank(name), Some(2));
+        assert_eq!(file.alpha(name).map(|a| a.0), Some(2.0));
+        let delta = file.scale_weight(name)?.to_dtype(DType::F64)?;
+        let diagonal: Vec<f64> = delta
+            .to_vec2()?
+            .iter()
+            .enumerate()
+            .map(|(i, r)| r[i])
+            .collect();
+        for (value, expected) in diagonal.iter().zip([4.0, 2.0, 0.0]) {
+            assert!((value - expected).abs() < 1e-4, "{diagonal:?}");
+        }
+        let metadata = file.metadata().and_then(|m| m.metadata.as_ref()).unwrap();
+        assert!(!metadata.contains_key("ss_network_dim"));
+        let layer = report.layers.iter().find(|l| l.base_name == name).unwrap();
+        assert!(layer.error < 1e-5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_rank_stabilized() -> Result<()> {
+        let device = Device::Cpu;
+        let name = "lora_unet_down_blocks_0_attentions_0_proj_in";
+        let mut writer = SafetensorsWriter::new();
+        writer.insert_tensor(
+            &format!("{name}.lora_up.weight"),
+            &Tensor::new(&[[4f32, 0., 0.], [0., 2., 0.], [0., 0., 0.1]], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{name}.lora_down.weight"),
+            &Tensor::eye(3, candle_core::DType::F32, &device)?,
+        )?;
+        writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(3f32, &device)?)?;
+        writer.insert_metadata("ss_network_args", r#"{"rank_stabilized": "True"}"#);
+
+        let options = PruneOptions {
+            energy: Some(0.99),
+            ..Default::default()
+        };
+        let (output, report) = prune(&writer.serialize()?, &options)?;
+        assert_eq!(
+            report.layers[0].action,
+            PruneAction::Truncated { from: 3, to: 2 }
+        );
+
+        // alpha / √rank stays √3
+        let file = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+        let alpha = file.alpha(name).map(|a| a.0).unwrap();
+        assert!((alpha as f64 - 6f64.sqrt()).abs() < 1e-6, "{alpha}");
+        let applied = (file.scale_weight(name)?.to_dtype(DType::F64)? * 2f64.sqrt())?;
+        let diagonal: Vec<f64> = applied
+            .to_vec2()?
+            .iter()
+            .enumerate()
+            .map(|(i, r)| r[i])
+            .collect();
+        for (value, expected) in diagonal.iter().zip([4.0, 2.0, 0.0]) {
+            assert!(
+                (value - expected * 3f64.sqrt()).abs() < 1e-4,
+                "{diagonal:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_peft_without_alpha() -> Result<()> {
+        let device = Device::Cpu;
+        let name = "base_model.model.down_blocks.0.attentions.0.proj_in";
+        let up = Tensor::new(&[[4f32, 0., 0.], [0., 2., 0.], [0., 0., 0.1]], &device)?;
+        let down = Tensor::eye(3, candle_core::DType::F32, &device)?;
+        let mut writer = SafetensorsWriter::new();
+        writer.insert_tensor(&format!("{name}.lora_B.weight"), &up)?;
+        writer.insert_tensor(&format!("{name}.lora_A.weight"), &down)?;
+        let buffer = writer.serialize()?;
+
+        let options = PruneOptions {
+            energy: Some(0.99),
+            ..Default::default()
+        };
+        let (output, report) = prune(&buffer, &options)?;
+        assert!(matches!(report.layers[0].action, PruneAction::Skipped(_)));
+        assert_eq!(report.retained_energy(), 1.0);
+
+        let file = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+        assert_eq!(file.rank(name), Some(3));
+
+        Ok(())
+    }
+}
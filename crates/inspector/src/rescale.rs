@@ -0,0 +1,968 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use candle_core::{DType, Device, Tensor};
+use serde::Serialize;
+
+use crate::blocks::BlockId;
+use crate::file::{LayerKind, LoRAFile};
+use crate::network::NetworkType;
+use crate::subspace;
+use crate::writer::{SafetensorsWriter, TensorBytes};
+use crate::{InspectorError, Result};
+
+/// Tensors the delta weight is linear in, one at a time: scaling one of them
+/// by `c` scales the update by `c`. Holds for LoRA, LoHa, LoKr and full diffs.
+/// The bias updates, `BIAS_SUFFIXES`, are added next to the weight update and
+/// take the whole factor themselves.
+const FACTOR_SUFFIXES: [&str; 24] = [
+    "lora_up.weight",
+    "lora_down.weight",
+    "lora_mid.weight",
+    "lora_A.weight",
+    "lora_B.weight",
+    "lora_A.default.weight",
+    "lora_B.default.weight",
+    "hada_w1_a",
+    "hada_w1_b",
+    "hada_w2_a",
+    "hada_w2_b",
+    "hada_t1",
+    "hada_t2",
+    "lokr_w1",
+    "lokr_w1_a",
+    "lokr_w1_b",
+    "lokr_w2",
+    "lokr_w2_a",
+    "lokr_w2_b",
+    "lokr_t2",
+    "diff",
+    "w_norm",
+    "diff_b",
+    "b_norm",
+];
+
+const BIAS_SUFFIXES: [&str; 2] = ["diff_b", "b_norm"];
+
+/// Which layers `normalize` rescales.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum LayerSelection {
+    /// Layers `effective_scales_all` flags as outliers
+    Outliers,
+    /// The layers of a block, parsed from its name in the block map:
+    /// `down_03`, `mid`, `double_07`, ...
+    Block(BlockId),
+    All,
+}
+
+impl FromStr for LayerSelection {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "outliers" => Ok(LayerSelection::Outliers),
+            "all" => Ok(LayerSelection::All),
+            block => Ok(LayerSelection::Block(block.parse()?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ScaleTarget {
+    /// Median effective scale of the layers of the same kind
+    Median,
+    Value(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ScaleMethod {
+    /// Change `alpha`, for LoRA layers that store one
+    Alpha,
+    /// Scale every factor by the same amount
+    Factors,
+}
+
+impl FromStr for ScaleMethod {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "alpha" => Ok(ScaleMethod::Alpha),
+            "factors" => Ok(ScaleMethod::Factors),
+            _ => Err(InspectorError::Msg(format!(
+                "Unknown scale method {s}. Valid methods: alpha, factors"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NormalizeOptions {
+    pub selection: LayerSelection,
+    pub target: ScaleTarget,
+    /// Layers without an alpha fall back to `Factors`
+    pub method: ScaleMethod,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RescaledLayer {
+    pub base_name: String,
+    /// Effective scale before and after
+    pub before: f64,
+    pub after: f64,
+    pub factor: f64,
+    pub method: ScaleMethod,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NormalizeReport {
+    pub layers: Vec<RescaledLayer>,
+    /// Selected layers whose update does not scale linearly with its
+    /// tensors: DoRA, GLoRA, OFT and IA3 layers
+    pub skipped: Vec<String>,
+}
+
+/// Rewrites `buffer` so the selected layers have the target effective scale.
+pub fn normalize(buffer: &[u8], options: &NormalizeOptions) -> Result<(Vec<u8>, NormalizeReport)> {
+    let file = LoRAFile::new_from_buffer(buffer, "", &Device::Cpu);
+    if !file.is_tensors_loaded() {
+        return Err(InspectorError::Msg(
+            "Could not load the tensors".to_string(),
+        ));
+    }
+
+    let scales = file.effective_scales_all();
+    let mut by_kind: HashMap<LayerKind, Vec<f64>> = HashMap::new();
+    for scale in &scales {
+        by_kind.entry(scale.kind).or_default().push(scale.eff_scale);
+    }
+    let medians: HashMap<LayerKind, f64> = by_kind
+        .into_iter()
+        .map(|(kind, values)| (kind, median(values)))
+        .collect();
+
+    let selected: Vec<_> = scales
+        .iter()
+        .filter(|scale| match &options.selection {
+            LayerSelection::Outliers => scale.is_outlier,
+            LayerSelection::Block(block) => {
+                BlockId::from_base_name(&scale.base_name).as_ref() == Some(block)
+            }
+            LayerSelection::All => true,
+        })
+        .collect();
+    if let LayerSelection::Block(block) = &options.selection {
+        if selected.is_empty() {
+            return Err(InspectorError::Msg(format!("No layers in block {block}")));
+        }
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    let mut rescaled = vec![];
+    let mut skipped = vec![];
+    for scale in selected {
+        let base_name = &scale.base_name;
+        if scale.eff_scale < f64::EPSILON {
+            skipped.push(base_name.clone());
+            continue;
+        }
+
+        let target = match options.target {
+            ScaleTarget::Median => medians[&scale.kind],
+            ScaleTarget::Value(value) => value,
+        };
+        let factor = target / scale.eff_scale;
+        let Some(method) = scale_layer(&file, &mut writer, base_name, factor, options.method)?
+        else {
+            skipped.push(base_name.clone());
+            continue;
+        };
+
+        rescaled.push(RescaledLayer {
+            base_name: base_name.clone(),
+            before: scale.eff_scale,
+            after: 0.0,
+            factor,
+            method,
+        });
+    }
+
+    let output = writer.serialize()?;
+
+    // Measured on the rewritten file, so the report shows what was saved
+    let written = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+    for layer in rescaled.iter_mut() {
+        layer.after = written.effective_scale(&layer.base_name)?.unwrap_or(0.0);
+    }
+
+    Ok((
+        output,
+        NormalizeReport {
+            layers: rescaled,
+            skipped,
+        },
+    ))
+}
+
+/// The alpha `reparameterize_alpha` stores.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum AlphaTarget {
+    Value(f32),
+    /// alpha = rank, a scale of 1
+    Rank,
+    /// alpha = √rank, rsLoRA's scale of 1
+    SqrtRank,
+}
+
+impl AlphaTarget {
+    /// The convention the file was trained with: `SqrtRank` for rank
+    /// stabilized networks, `Rank` otherwise.
+    pub fn for_file(file: &LoRAFile) -> AlphaTarget {
+        match file.metadata().and_then(|m| m.rank_stabilized()) {
+            Some(true) => AlphaTarget::SqrtRank,
+            _ => AlphaTarget::Rank,
+        }
+    }
+
+    fn alpha(&self, rank: usize) -> f32 {
+        match self {
+            AlphaTarget::Value(value) => *value,
+            AlphaTarget::Rank => rank as f32,
+            AlphaTarget::SqrtRank => (rank as f32).sqrt(),
+        }
+    }
+}
+
+impl FromStr for AlphaTarget {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rank" => Ok(AlphaTarget::Rank),
+            "sqrt-rank" => Ok(AlphaTarget::SqrtRank),
+            value => value
+                .parse::<f32>()
+                .ok()
+                .filter(|v| v.is_finite() && *v > 0.0)
+                .map(AlphaTarget::Value)
+                .ok_or_else(|| {
+                    InspectorError::Msg(format!(
+                        "Invalid alpha {s}. Use a positive number, rank or sqrt-rank"
+                    ))
+                }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlphaChange {
+    pub base_name: String,
+    pub rank: usize,
+    pub before: f32,
+    pub after: f32,
+    /// ‖ΔW' - ΔW‖ / ‖ΔW‖ between the delta of the written file and the
+    /// original
+    pub error: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReparameterizeReport {
+    pub layers: Vec<AlphaChange>,
+    /// Layers with an alpha but no lora_up/lora_down pair, or the other way
+    /// around
+    pub skipped: Vec<String>,
+}
+
+/// Stores `target` as the alpha of every LoRA layer, rescaling `lora_up` and
+/// `lora_down` by √(alpha / alpha') each so the delta stays the same.
+///
+/// The deltas of the written file are compared with the original and an
+/// error is returned if any differs by more than the precision of the
+/// factors allows.
+pub fn reparameterize_alpha(
+    buffer: &[u8],
+    target: AlphaTarget,
+) -> Result<(Vec<u8>, ReparameterizeReport)> {
+    let file = LoRAFile::new_from_buffer(buffer, "", &Device::Cpu);
+    if !file.is_tensors_loaded() {
+        return Err(InspectorError::Msg(
+            "Could not load the tensors".to_string(),
+        ));
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    let mut changed = vec![];
+    let mut skipped = vec![];
+    for base_name in file.base_names() {
+        let alpha_key = format!("{base_name}.alpha");
+        let up_key = format!("{base_name}.lora_up.weight");
+        let down_key = format!("{base_name}.lora_down.weight");
+
+        let alpha = file.tensor(&alpha_key);
+        let has_factors = file.shape(&up_key).is_some() && file.shape(&down_key).is_some();
+        let (alpha, rank) = match (alpha, has_factors, file.rank(&base_name)) {
+            (Some(alpha), true, Some(rank)) => (alpha, rank),
+            (None, false, _) => continue,
+            _ => {
+                skipped.push(base_name);
+                continue;
+            }
+        };
+
+        let before = alpha.to_dtype(DType::F32)?.to_scalar::<f32>()?;
+        // Round through the stored dtype so the factors compensate for the
+        // alpha that is actually saved
+        let stored = Tensor::new(target.alpha(rank), &Device::Cpu)?.to_dtype(alpha.dtype())?;
+        let after = stored.to_dtype(DType::F32)?.to_scalar::<f32>()?;
+        if before == 0.0 || after <= 0.0 {
+            skipped.push(base_name);
+            continue;
+        }
+
+        let per_factor = (before as f64 / after as f64).sqrt();
+        scale_tensor(&file, &mut writer, &up_key, per_factor)?;
+        scale_tensor(&file, &mut writer, &down_key, per_factor)?;
+        writer.insert(&alpha_key, TensorBytes::from_tensor(&stored)?);
+
+        changed.push(AlphaChange {
+            base_name,
+            rank,
+            before,
+            after,
+            error: 0.0,
+        });
+    }
+
+    if let Some(first) = changed.first() {
+        if changed.iter().all(|c| c.after == first.after) {
+            writer.insert_metadata("ss_network_alpha", &first.after.to_string());
+        } else if let Some(mut metadata) = writer.metadata().cloned() {
+            // Per layer alphas have no single network alpha
+            metadata.remove("ss_network_alpha");
+            writer.set_metadata(Some(metadata));
+        }
+    }
+
+    let output = writer.serialize()?;
+
+    let written = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+    for change in changed.iter_mut() {
+        change.error = delta_error(&file, &written, &change.base_name)?;
+    }
+
+    Ok((
+        output,
+        ReparameterizeReport {
+            layers: changed,
+            skipped,
+        },
+    ))
+}
+
+/// Layers whose factors `rebalance` rewrote.
+#[derive(Debug, Clone, Serialize)]
+pub struct RebalancedLayer {
+    pub base_name: String,
+    pub rank: usize,
+    /// ‖up‖ / ‖down‖ before and after
+    pub before: f64,
+    pub after: f64,
+    /// ‖ΔW' - ΔW‖ / ‖ΔW‖ between the delta of the written file and the
+    /// original
+    pub error: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RebalanceReport {
+    pub layers: Vec<RebalancedLayer>,
+    /// Layers with a Tucker core or a rank above their dimensions
+    pub skipped: Vec<String>,
+    /// Layers with a zero `up @ down`, like an untrained zero initialised
+    /// `up`. Their factors are kept so they can still be trained.
+    pub zero: Vec<String>,
+}
+
+/// up/down key pairs, kohya then PEFT
+const FACTOR_PAIRS: [(&str, &str); 3] = [
+    ("lora_up.weight", "lora_down.weight"),
+    ("lora_B.weight", "lora_A.weight"),
+    ("lora_B.default.weight", "lora_A.default.weight"),
+];
+
+/// Subspace iterations for the SVD of `up @ down`. The product has exactly
+/// `rank` directions so the first pass already spans them.
+const ITERATIONS: usize = 2;
+
+/// Rewrites the factors of every LoRA layer from the SVD of their product,
+/// `up = U√S` and `down = √SVᵀ`. The delta stays the same but both factors
+/// carry the same norm, with orthogonal columns and rows.
+///
+/// The deltas of the written file are compared with the original and an
+/// error is returned if any differs by more than the precision of the
+/// factors allows.
+pub fn rebalance(buffer: &[u8]) -> Result<(Vec<u8>, RebalanceReport)> {
+    let file = LoRAFile::new_from_buffer(buffer, "", &Device::Cpu);
+    if !file.is_tensors_loaded() {
+        return Err(InspectorError::Msg(
+            "Could not load the tensors".to_string(),
+        ));
+    }
+
+    let mut writer = SafetensorsWriter::from_buffer(buffer)?;
+    let mut changed = vec![];
+    let mut skipped = vec![];
+    let mut zero = vec![];
+    for base_name in file.base_names() {
+        let Some(pair) = FactorPair::find(&file, &base_name) else {
+            continue;
+        };
+        let Some(before) = file.factorization_balance(&base_name)? else {
+            continue;
+        };
+        let Some(svd) = pair.svd()? else {
+            skipped.push(base_name);
+            continue;
+        };
+        // Splitting a zero product would zero both factors
+        if svd.s.first().is_none_or(|s| *s == 0.0) {
+            zero.push(base_name);
+            continue;
+        }
+
+        let rank = pair.rank();
+        let (new_up, new_down) = pair.split(&svd, rank)?;
+        writer.insert(&pair.up_key, TensorBytes::from_tensor(&new_up)?);
+        writer.insert(&pair.down_key, TensorBytes::from_tensor(&new_down)?);
+
+        changed.push(RebalancedLayer {
+            base_name,
+            rank,
+            before,
+            after: 0.0,
+            error: 0.0,
+        });
+    }
+
+    let output = writer.serialize()?;
+
+    let written = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+    for layer in changed.iter_mut() {
+        layer.after = written
+            .factorization_balance(&layer.base_name)?
+            .unwrap_or(0.0);
+        layer.error = delta_error(&file, &written, &layer.base_name)?;
+    }
+
+    Ok((
+        output,
+        RebalanceReport {
+            layers: changed,
+            skipped,
+            zero,
+        },
+    ))
+}
+
+/// The up and down factors of a LoRA layer.
+pub(crate) struct FactorPair {
+    pub up_key: String,
+    pub down_key: String,
+    pub up: Tensor,
+    pub down: Tensor,
+    has_mid: bool,
+}
+
+impl FactorPair {
+    pub fn find(file: &LoRAFile, base_name: &str) -> Option<FactorPair> {
+        let (up_key, down_key) = FACTOR_PAIRS
+            .iter()
+            .map(|(up, down)| (format!("{base_name}.{up}"), format!("{base_name}.{down}")))
+            .find(|(up, down)| file.shape(up).is_some() && file.shape(down).is_some())?;
+
+        Some(FactorPair {
+            up: file.tensor(&up_key)?,
+            down: file.tensor(&down_key)?,
+            up_key,
+            down_key,
+            has_mid: file
+                .shape(&format!("{base_name}.lora_mid.weight"))
+                .is_some(),
+        })
+    }
+
+    pub fn rank(&self) -> usize {
+        self.down.dims().first().copied().unwrap_or_default()
+    }
+
+    /// SVD of `up @ down`, with convolutions flattened to `[out, in * kh * kw]`.
+    /// `None` for Tucker cores, convolutions in `up` and ranks above the
+    /// dimensions of the layer.
+    pub fn svd(&self) -> Result<Option<subspace::TruncatedSvd>> {
+        let rank = self.rank();
+        let pointwise_up = self.up.dims().iter().skip(2).all(|d| *d == 1);
+        if self.has_mid || !pointwise_up || self.up.dims().get(1) != Some(&rank) {
+            return Ok(None);
+        }
+
+        let up = self.up.flatten_from(1)?.to_dtype(DType::F32)?;
+        let down = self.down.flatten_from(1)?.to_dtype(DType::F32)?;
+        let svd = subspace::truncated_svd(&up.matmul(&down)?, rank, ITERATIONS)?;
+        if svd.s.len() != rank || svd.u.dim(1)? != rank {
+            return Ok(None);
+        }
+        Ok(Some(svd))
+    }
+
+    /// New factors from the top `keep` directions of `svd`, `U√S` and `√SVᵀ`,
+    /// in the shapes and dtypes of the current ones.
+    pub fn split(&self, svd: &subspace::TruncatedSvd, keep: usize) -> Result<(Tensor, Tensor)> {
+        let sqrt_s: Vec<f32> = svd.s[..keep].iter().map(|s| s.sqrt() as f32).collect();
+        let sqrt_s = Tensor::from_vec(sqrt_s, keep, &Device::Cpu)?;
+
+        let mut up_shape = self.up.dims().to_vec();
+        up_shape[1] = keep;
+        let mut down_shape = self.down.dims().to_vec();
+        down_shape[0] = keep;
+
+        let up = svd
+            .u
+            .narrow(1, 0, keep)?
+            .broadcast_mul(&sqrt_s.unsqueeze(0)?)?
+            .reshape(up_shape)?
+            .to_dtype(self.up.dtype())?;
+        let down = svd
+            .vt
+            .narrow(0, 0, keep)?
+            .broadcast_mul(&sqrt_s.unsqueeze(1)?)?
+            .reshape(down_shape)?
+            .to_dtype(self.down.dtype())?;
+        Ok((up, down))
+    }
+}
+
+/// ‖ΔW' - ΔW‖ / ‖ΔW‖ of `base_name` between two files, failing when it is
+/// more than the precision of the factors allows.
+fn delta_error(original: &LoRAFile, written: &LoRAFile, base_name: &str) -> Result<f64> {
+    delta_error_from(&original.scale_weight(base_name)?, written, base_name)
+}
+
+/// ‖ΔW' - expected‖ / ‖expected‖ of `base_name` in `written`, failing when it
+/// is more than the precision of the factors allows.
+pub(crate) fn delta_error_from(
+    expected: &Tensor,
+    written: &LoRAFile,
+    base_name: &str,
+) -> Result<f64> {
+    let after = written.scale_weight(base_name)?.to_dtype(DType::F64)?;
+    let before = expected.to_dtype(DType::F64)?.reshape(after.dims())?;
+    let norm = frobenius(&before)?;
+    let diff = frobenius(&(after - &before)?)?;
+    let error = if norm > 0.0 { diff / norm } else { diff };
+
+    let dtype = factor_keys(written, base_name)
+        .first()
+        .and_then(|key| written.tensor(key))
+        .map_or(DType::F32, |t| t.dtype());
+    let tolerance = tolerance(dtype);
+    if error > tolerance {
+        return Err(InspectorError::Msg(format!(
+            "The delta of {base_name} changed by {error:.2e} after rewriting, more than the {tolerance:.0e} {dtype:?} allows"
+        )));
+    }
+
+    Ok(error)
+}
+
+/// Relative error rounding both factors to `dtype` may introduce.
+fn tolerance(dtype: DType) -> f64 {
+    match dtype {
+        DType::F64 => 1e-9,
+        DType::F32 => 1e-5,
+        DType::F16 => 5e-3,
+        _ => 4e-2,
+    }
+}
+
+fn frobenius(t: &Tensor) -> Result<f64> {
+    Ok(t.sqr()?.sum_all()?.to_scalar::<f64>()?.sqrt())
+}
+
+/// Whether multiplying the factors of `base_name` multiplies its update alike.
+/// Not for DoRA, GLoRA, OFT and IA3 layers.
+pub(crate) fn scales_linearly(file: &LoRAFile, base_name: &str) -> bool {
+    let linear_network = matches!(
+        file.metadata().and_then(|m| m.network_type()),
+        None | Some(
+            NetworkType::LoRA
+                | NetworkType::LoRAFA
+                | NetworkType::DyLoRA
+                | NetworkType::LoHA
+                | NetworkType::LoKr
+                | NetworkType::GLoKr
+                | NetworkType::Full
+        )
+    );
+
+    match file.layer_kind(base_name) {
+        LayerKind::LowRank => linear_network && file.dora_scale(base_name).is_none(),
+        LayerKind::FullDiff | LayerKind::Norm | LayerKind::Bias => true,
+    }
+}
+
+/// Multiplies the update of `base_name` by `factor` into `writer`, through the
+/// alpha or spread over the factors. `None` when the layer does not scale
+/// linearly.
+///
+/// A 0 factor always goes on the factors: kohya's `lora.py` and LyCORIS load
+/// an alpha of 0 as `alpha = rank`, which would apply the layer in full.
+pub(crate) fn scale_layer(
+    file: &LoRAFile,
+    writer: &mut SafetensorsWriter,
+    base_name: &str,
+    factor: f64,
+    method: ScaleMethod,
+) -> Result<Option<ScaleMethod>> {
+    let (biases, factors): (Vec<String>, Vec<String>) = factor_keys(file, base_name)
+        .into_iter()
+        .partition(|key| BIAS_SUFFIXES.iter().any(|suffix| key.ends_with(suffix)));
+    if (factors.is_empty() && biases.is_empty()) || !scales_linearly(file, base_name) {
+        return Ok(None);
+    }
+
+    let alpha_key = format!("{base_name}.alpha");
+    let has_lora = factors.iter().any(|k| k.contains("lora_"));
+    if method == ScaleMethod::Alpha
+        && factor != 0.0
+        && has_lora
+        && file.tensor(&alpha_key).is_some()
+    {
+        scale_tensor(file, writer, &alpha_key, factor)?;
+        return Ok(Some(ScaleMethod::Alpha));
+    }
+
+    // The sign goes on the first factor alone
+    let per_factor = factor.abs().powf(1.0 / factors.len() as f64);
+    for (i, key) in factors.iter().enumerate() {
+        let sign = if i == 0 { factor.signum() } else { 1.0 };
+        scale_tensor(file, writer, key, sign * per_factor)?;
+    }
+    for key in &biases {
+        scale_tensor(file, writer, key, factor)?;
+    }
+    Ok(Some(ScaleMethod::Factors))
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len();
+    if n == 0 {
+        0.0
+    } else if n.is_multiple_of(2) {
+        (values[n / 2 - 1] + values[n / 2]) / 2.0
+    } else {
+        values[n / 2]
+    }
+}
+
+/// Keys of the factors of `base_name` that are in the file.
+fn factor_keys(file: &LoRAFile, base_name: &str) -> Vec<String> {
+    FACTOR_SUFFIXES
+        .iter()
+        .map(|suffix| format!("{base_name}.{suffix}"))
+        .filter(|key| file.shape(key).is_some())
+        .collect()
+}
+
+/// Multiplies `key` by `factor`, keeping its dtype.
+fn scale_tensor(
+    file: &LoRAFile,
+    writer: &mut SafetensorsWriter,
+    key: &str,
+    factor: f64,
+) -> Result<()> {
+    let tensor = file
+        .tensor(key)
+        .ok_or_else(|| InspectorError::Msg(format!("{key} could not be loaded")))?;
+    writer.insert(key, TensorBytes::from_tensor(&scaled(&tensor, factor)?)?);
+    Ok(())
+}
+
+fn scaled(t: &Tensor, factor: f64) -> Result<Tensor> {
+    Ok((t.to_dtype(DType::F64)? * factor)?.to_dtype(t.dtype())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lora_buffer() -> Vec<u8> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        // Effective scales 1, 1, 1 and 4
+        for (name, value) in [
+            ("lora_unet_down_blocks_0_attentions_0_proj_in", 1f32),
+            ("lora_unet_down_blocks_0_attentions_0_proj_out", 1.),
+            ("lora_unet_up_blocks_1_attentions_0_proj_in", 1.),
+            ("lora_unet_up_blocks_1_attentions_0_proj_out", 4.),
+        ] {
+            writer
+                .insert_tensor(
+                    &format!("{name}.lora_up.weight"),
+                    &Tensor::new(&[[value], [0.]], &device).unwrap(),
+                )
+                .unwrap();
+            writer
+                .insert_tensor(
+                    &format!("{name}.lora_down.weight"),
+                    &Tensor::new(&[[2f32, 0.]], &device).unwrap(),
+                )
+                .unwrap();
+            writer
+                .insert_tensor(
+                    &format!("{name}.alpha"),
+                    &Tensor::new(0.5f32, &device).unwrap(),
+                )
+                .unwrap();
+        }
+        writer.serialize().unwrap()
+    }
+
+    #[test]
+    fn normalize_outliers_to_median() -> Result<()> {
+        let buffer = lora_buffer();
+        for method in [ScaleMethod::Alpha, ScaleMethod::Factors] {
+            let (output, report) = normalize(
+                &buffer,
+                &NormalizeOptions {
+                    selection: LayerSelection::Outliers,
+                    target: ScaleTarget::Median,
+                    method,
+                },
+            )?;
+
+            assert_eq!(report.layers.len(), 1);
+            let layer = &report.layers[0];
+            assert_eq!(
+                layer.base_name,
+                "lora_unet_up_blocks_1_attentions_0_proj_out"
+            );
+            assert_eq!(layer.method, method);
+            assert!((layer.before - 4.0).abs() < 1e-6);
+            assert!((layer.after - 1.0).abs() < 1e-6);
+            assert!((layer.factor - 0.25).abs() < 1e-9);
+
+            let file = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+            assert!(file.effective_scales_all().iter().all(|s| !s.is_outlier));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_block_to_value() -> Result<()> {
+        let (output, report) = normalize(
+            &lora_buffer(),
+            &NormalizeOptions {
+                selection: "down_01".parse()?,
+                target: ScaleTarget::Value(0.5),
+                method: ScaleMethod::Factors,
+            },
+        )?;
+
+        assert_eq!(report.layers.len(), 2);
+        let file = LoRAFile::new_from_buffer(&output, "", &Device::Cpu);
+        let scale = file.effective_scale("lora_unet_down_blocks_0_attentions_0_proj_in")?;
+        assert!((scale.unwrap() - 0.5).abs() < 1e-6);
+        // Factors are scaled alike, so the balance is kept
+        assert_eq!(
+            file.factorization_balance("lora_unet_down_blocks_0_attentions_0_proj_in")?,
+            Some(0.5)
+        );
+        let untouched = file.effective_scale("lora_unet_up_blocks_1_attentions_0_proj_in")?;
+        assert_eq!(untouched, Some(1.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_unknown_or_empty_block() {
+        assert!("middle".parse::<LayerSelection>().is_err());
+
+        let result = normalize(
+            &lora_buffer(),
+            &NormalizeOptions {
+                selection: "down_05".parse().unwrap(),
+                target: ScaleTarget::Value(0.5),
+                method: ScaleMethod::Factors,
+            },
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn scale_full_layer_with_bias() -> Result<()> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        let full = "lora_unet_down_blocks_0_attentions_0_proj_in";
+        let bias = "lora_unet_down_blocks_0_attentions_0_proj_out";
+        writer.insert_tensor(
+            &format!("{full}.diff"),
+            &Tensor::new(&[[1f32, 2.], [-3., 4.]], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{full}.diff_b"),
+            &Tensor::new(&[1f32, -2.], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{bias}.b_norm"),
+            &Tensor::new(&[0.5f32, 1.], &device)?,
+        )?;
+        let buffer = writer.serialize()?;
+        let file = LoRAFile::new_from_buffer(&buffer, "", &Device::Cpu);
+
+        let mut writer = SafetensorsWriter::from_buffer(&buffer)?;
+        for base_name in [full, bias] {
+            assert_eq!(
+                scale_layer(&file, &mut writer, base_name, 3.0, ScaleMethod::Alpha)?,
+                Some(ScaleMethod::Factors)
+            );
+        }
+        let written = LoRAFile::new_from_buffer(&writer.serialize()?, "", &Device::Cpu);
+
+        // The weight and the bias each take the whole factor
+        assert_eq!(
+            written
+                .tensor(&format!("{full}.diff"))
+                .unwrap()
+                .to_vec2::<f32>()?,
+            vec![vec![3., 6.], vec![-9., 12.]]
+        );
+        assert_eq!(
+            written
+                .tensor(&format!("{full}.diff_b"))
+                .unwrap()
+                .to_vec1::<f32>()?,
+            vec![3., -6.]
+        );
+        assert_eq!(
+            written
+                .tensor(&format!("{bias}.b_norm"))
+                .unwrap()
+                .to_vec1::<f32>()?,
+            vec![1.5, 3.]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rebalance_keeps_the_delta() -> Result<()> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        let linear = "lora_unet_down_blocks_0_attentions_0_proj_in";
+        let conv = "lora_unet_down_blocks_0_resnets_0_conv1";
+        writer.insert_tensor(
+            &format!("{linear}.lora_up.weight"),
+            &Tensor::new(&[[8f32, 4.], [-2., 6.], [4., 0.]], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{linear}.lora_down.weight"),
+            &Tensor::new(&[[0.1f32, -0.2, 0.05], [0.1, 0.0, 0.3]], &device)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{conv}.lora_up.weight"),
+            &(Tensor::arange(1f32, 7., &device)?.reshape((3, 2, 1, 1))? * 10.)?,
+        )?;
+        writer.insert_tensor(
+            &format!("{conv}.lora_down.weight"),
+            &(Tensor::arange(0f32, 36., &device)?
+                .sin()?
+                .reshape((2, 2, 3, 3))?
+                * 0.01)?,
+        )?;
+        // Zero initialised up, as before training
+        let untrained = "lora_unet_mid_block_attentions_0_proj_in";
+        let untrained_down = Tensor::new(&[[0.1f32, -0.2, 0.05], [0.1, 0.0, 0.3]], &device)?;
+        writer.insert_tensor(
+            &format!("{untrained}.lora_up.weight"),
+            &Tensor::zeros((3, 2), DType::F32, &device)?,
+        )?;
+        writer.insert_tensor(&format!("{untrained}.lora_down.weight"), &untrained_down)?;
+        for name in [linear, conv, untrained] {
+            writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(1f32, &device)?)?;
+        }
+
+        let (output, report) = rebalance(&writer.serialize()?)?;
+
+        assert_eq!(report.layers.len(), 2);
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.zero, vec![untrained.to_string()]);
+        let written = LoRAFile::new_from_buffer(&output, "", &device);
+        let down = written
+            .tensor(&format!("{untrained}.lora_down.weight"))
+            .unwrap();
+        assert_eq!(down.to_vec2::<f32>()?, untrained_down.to_vec2::<f32>()?);
+        for layer in &report.layers {
+            assert!(layer.before > 10.0, "{} {}", layer.base_name, layer.before);
+            assert!((layer.after - 1.0).abs() < 1e-4);
+            assert!(layer.error < 1e-5);
+            assert_eq!(
+                written
+                    .shape(&format!("{}.lora_down.weight", layer.base_name))
+                    .map(|s| s.to_vec()),
+                writer
+                    .get(&format!("{}.lora_down.weight", layer.base_name))
+                    .map(|t| t.shape.clone())
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reparameterize_keeps_the_delta() -> Result<()> {
+        let device = Device::Cpu;
+        let mut writer = SafetensorsWriter::new();
+        let name = "lora_unet_down_blocks_0_attentions_0_proj_in";
+        let up = Tensor::new(&[[1f32, 2.], [3., -1.], [0.5, 0.25]], &device)?;
+        let down = Tensor::new(&[[0.5f32, -1., 2.], [1., 0., 0.5]], &device)?;
+        writer.insert(
+            &format!("{name}.lora_up.weight"),
+            TensorBytes::from_tensor_as(&up, crate::weight::DType::F16)?,
+        );
+        writer.insert(
+            &format!("{name}.lora_down.weight"),
+            TensorBytes::from_tensor_as(&down, crate::weight::DType::F16)?,
+        );
+        writer.insert_tensor(&format!("{name}.alpha"), &Tensor::new(1f32, &device)?)?;
+        writer.insert_metadata("ss_network_alpha", "1");
+        writer.insert_metadata("ss_network_args", r#"{"rank_stabilized": "True"}"#);
+        let buffer = writer.serialize()?;
+
+        let file = LoRAFile::new_from_buffer(&buffer, "", &device);
+        let target = AlphaTarget::for_file(&file);
+        assert_eq!(target, AlphaTarget::SqrtRank);
+
+        for target in [target, AlphaTarget::Rank, AlphaTarget::Value(16.)] {
+            let (output, report) = reparameterize_alpha(&buffer, target)?;
+            assert_eq!(report.layers.len(), 1);
+            assert!(report.layers[0].error < 5e-3);
+
+            let written = LoRAFile::new_from_buffer(&output, "", &device);
+            let alpha = written.alpha(name).unwrap().0;
+            assert_eq!(alpha, report.layers[0].after);
+            assert_eq!(
+                written
+                    .metadata()
+                    .and_then(|m| m.metadata.as_ref())
+                    .and_then(|m| m.get("ss_network_alpha").cloned()),
+                Some(alpha.to_string())
+            );
+        }
+
+        let (_, report) = reparameterize_alpha(&buffer, AlphaTarget::SqrtRank)?;
+        assert_eq!(report.layers[0].after, 2f32.sqrt());
+
+        Ok(())
+    }
+}
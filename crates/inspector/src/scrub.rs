@@ -0,0 +1,494 @@
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::metadata::Metadata;
+use crate::InspectorError;
+
+/// How aggressively to clean metadata before a LoRA is published.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ScrubProfile {
+    /// Keep every key, only redact filesystem paths and usernames.
+    Minimal,
+    /// `Minimal`, plus remove keys that list the dataset folders, output name
+    /// and caption tags.
+    ShareSafe,
+    /// Keep only the keys needed to load and interpret the network.
+    StripAll,
+}
+
+impl FromStr for ScrubProfile {
+    type Err = InspectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minimal" => Ok(ScrubProfile::Minimal),
+            "share-safe" | "share_safe" => Ok(ScrubProfile::ShareSafe),
+            "strip-all" | "strip_all" => Ok(ScrubProfile::StripAll),
+            profile => Err(InspectorError::Msg(format!(
+                "Unknown scrub profile {profile}. Valid profiles: minimal, share-safe, strip-all"
+            ))),
+        }
+    }
+}
+
+/// Keys `ShareSafe` removes outright, with the reason reported for each.
+const SHARE_SAFE_REMOVED: &[(&str, &str)] = &[
+    ("ss_dataset_dirs", "lists the local training image folders"),
+    (
+        "ss_reg_dataset_dirs",
+        "lists the local regularization image folders",
+    ),
+    (
+        "ss_tag_frequency",
+        "contains the caption tags of the dataset",
+    ),
+    ("ss_output_name", "contains the original output name"),
+];
+
+/// Keys `StripAll` keeps; everything else is removed.
+const STRIP_ALL_KEPT: &[&str] = &[
+    "format",
+    "ss_base_model_version",
+    "ss_v2",
+    "ss_network_module",
+    "ss_network_dim",
+    "ss_network_alpha",
+    "ss_network_args",
+    "modelspec.sai_model_spec",
+    "modelspec.architecture",
+    "modelspec.implementation",
+];
+
+const REDACTED_USER: &str = "<user>";
+const REDACTED_PATH: &str = "<path>";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ScrubAction {
+    Removed,
+    Redacted,
+}
+
+/// A single metadata key the scrub touched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubFinding {
+    pub key: String,
+    pub action: ScrubAction,
+    pub reason: String,
+    /// The sensitive substrings found in the value (paths, usernames, fields).
+    pub matches: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubReport {
+    pub profile: ScrubProfile,
+    pub findings: Vec<ScrubFinding>,
+}
+
+impl ScrubReport {
+    pub fn removed_keys(&self) -> Vec<&str> {
+        self.findings
+            .iter()
+            .filter(|f| f.action == ScrubAction::Removed)
+            .map(|f| f.key.as_str())
+            .collect()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+/// Returns the cleaned metadata along with a report of everything that was
+/// removed or redacted. The input is left untouched so the report can be
+/// shown as a dry run.
+pub fn scrub(metadata: &Metadata, profile: ScrubProfile) -> (Metadata, ScrubReport) {
+    let mut report = ScrubReport {
+        profile,
+        findings: vec![],
+    };
+
+    let meta = match metadata.metadata.as_ref() {
+        Some(meta) => meta,
+        None => return (Metadata { metadata: None }, report),
+    };
+
+    // Usernames are collected from every path up front so a username that only
+    // appears in one key's path is also redacted from free text in the others.
+    let usernames: BTreeSet<String> = meta
+        .values()
+        .flat_map(|value| find_paths(value))
+        .filter_map(|path| username_from_path(&path))
+        .collect();
+
+    let mut keys: Vec<&String> = meta.keys().collect();
+    keys.sort();
+
+    let mut cleaned = HashMap::new();
+    for key in keys {
+        let value = &meta[key];
+
+        if let Some(reason) = removal_reason(key, profile) {
+            report.findings.push(ScrubFinding {
+                key: key.clone(),
+                action: ScrubAction::Removed,
+                reason,
+                matches: vec![],
+            });
+            continue;
+        }
+
+        let mut matches = vec![];
+        let redacted = match serde_json::from_str::<Value>(value) {
+            Ok(mut json @ (Value::Object(_) | Value::Array(_))) => {
+                if key == "ss_datasets" && profile != ScrubProfile::Minimal {
+                    strip_field(&mut json, "tag_frequency", &mut matches);
+                }
+                redact_json(&mut json, &usernames, &mut matches);
+                if matches.is_empty() {
+                    value.clone()
+                } else {
+                    json.to_string()
+                }
+            }
+            _ => redact_text(value, &usernames, &mut matches),
+        };
+
+        if !matches.is_empty() {
+            matches.sort();
+            matches.dedup();
+            report.findings.push(ScrubFinding {
+                key: key.clone(),
+                action: ScrubAction::Redacted,
+                reason: "contains filesystem paths, usernames or caption tags".to_string(),
+                matches,
+            });
+        }
+
+        cleaned.insert(key.clone(), redacted);
+    }
+
+    (
+        Metadata {
+            metadata: Some(cleaned),
+        },
+        report,
+    )
+}
+
+fn removal_reason(key: &str, profile: ScrubProfile) -> Option<String> {
+    match profile {
+        ScrubProfile::Minimal => None,
+        ScrubProfile::ShareSafe => SHARE_SAFE_REMOVED
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, reason)| reason.to_string()),
+        ScrubProfile::StripAll => {
+            if STRIP_ALL_KEPT.contains(&key) {
+                None
+            } else {
+                Some("not needed to load the network".to_string())
+            }
+        }
+    }
+}
+
+/// Removes every `field` from the objects in `value`, recording what was dropped.
+fn strip_field(value: &mut Value, field: &str, matches: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            if map.remove(field).is_some() {
+                matches.push(field.to_string());
+            }
+            map.values_mut()
+                .for_each(|v| strip_field(v, field, matches));
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|v| strip_field(v, field, matches)),
+        _ => (),
+    }
+}
+
+fn redact_json(value: &mut Value, usernames: &BTreeSet<String>, matches: &mut Vec<String>) {
+    match value {
+        Value::String(s) => *s = redact_string(s, usernames, matches),
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|v| redact_json(v, usernames, matches)),
+        Value::Object(map) => {
+            // Object keys are paths too, e.g. the folder names in `ss_dataset_dirs`.
+            let entries = std::mem::take(map);
+            for (k, mut v) in entries {
+                redact_json(&mut v, usernames, matches);
+                map.insert(redact_string(&k, usernames, matches), v);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// A JSON string that is a path as a whole may contain spaces, so it is
+/// redacted in one piece rather than token by token.
+fn redact_string(s: &str, usernames: &BTreeSet<String>, matches: &mut Vec<String>) -> String {
+    if is_path(s) {
+        matches.push(s.to_string());
+        redact_path(s)
+    } else {
+        redact_text(s, usernames, matches)
+    }
+}
+
+fn redact_text(text: &str, usernames: &BTreeSet<String>, matches: &mut Vec<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    for (token, is_token) in tokens(text) {
+        if is_token && is_path(token) {
+            matches.push(token.to_string());
+            out.push_str(&redact_path(token));
+        } else {
+            out.push_str(token);
+        }
+    }
+
+    for username in usernames {
+        let replaced = replace_word(&out, username, REDACTED_USER);
+        if replaced != out {
+            matches.push(username.clone());
+            out = replaced;
+        }
+    }
+
+    out
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace()
+        || matches!(
+            c,
+            '"' | '\'' | ',' | ';' | '(' | ')' | '[' | ']' | '{' | '}'
+        )
+}
+
+/// Splits `text` into alternating delimiter runs and tokens, flagging tokens.
+fn tokens(text: &str) -> Vec<(&str, bool)> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut in_token = false;
+
+    for (i, c) in text.char_indices() {
+        let delimiter = is_delimiter(c);
+        if delimiter == in_token {
+            if i > start {
+                parts.push((&text[start..i], in_token));
+            }
+            start = i;
+            in_token = !delimiter;
+        }
+    }
+    if start < text.len() {
+        parts.push((&text[start..], in_token));
+    }
+
+    parts
+}
+
+/// Absolute Unix paths with at least two segments, home-relative paths,
+/// Windows drive paths and UNC shares.
+pub fn is_path(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    if s.starts_with("~/") || s.starts_with("\\\\") {
+        return true;
+    }
+    if bytes.len() > 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        return bytes[2] == b'\\' || bytes[2] == b'/';
+    }
+    s.starts_with('/') && s[1..].contains('/') && !s.starts_with("//")
+}
+
+fn path_segments(path: &str) -> Vec<&str> {
+    path.split(['/', '\\']).filter(|s| !s.is_empty()).collect()
+}
+
+fn redact_path(path: &str) -> String {
+    match path_segments(path).last() {
+        Some(basename) => format!("{REDACTED_PATH}/{basename}"),
+        None => REDACTED_PATH.to_string(),
+    }
+}
+
+/// `/home/<user>`, `/Users/<user>` and `C:\Users\<user>`.
+fn username_from_path(path: &str) -> Option<String> {
+    let segments = path_segments(path);
+    segments
+        .windows(2)
+        .find(|pair| pair[0] == "home" || pair[0].eq_ignore_ascii_case("users"))
+        .map(|pair| pair[1].to_string())
+        // Very short names would match inside unrelated words.
+        .filter(|user| user.len() >= 3)
+}
+
+fn find_paths(text: &str) -> Vec<String> {
+    let mut paths: Vec<String> = tokens(text)
+        .into_iter()
+        .filter(|(token, is_token)| *is_token && is_path(token))
+        .map(|(token, _)| token.to_string())
+        .collect();
+
+    if let Ok(json) = serde_json::from_str::<Value>(text) {
+        collect_json_paths(&json, &mut paths);
+    }
+
+    paths
+}
+
+fn collect_json_paths(value: &Value, paths: &mut Vec<String>) {
+    match value {
+        Value::String(s) if is_path(s) => paths.push(s.clone()),
+        Value::Array(values) => values.iter().for_each(|v| collect_json_paths(v, paths)),
+        Value::Object(map) => {
+            for (k, v) in map {
+                if is_path(k) {
+                    paths.push(k.clone());
+                }
+                collect_json_paths(v, paths);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Replaces `word` where it is not part of a longer alphanumeric run.
+fn replace_word(text: &str, word: &str, replacement: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(idx) = rest.find(word) {
+        let before = rest[..idx].chars().next_back();
+        let after = rest[idx + word.len()..].chars().next();
+        let bounded = !before.is_some_and(|c| c.is_alphanumeric())
+            && !after.is_some_and(|c| c.is_alphanumeric());
+
+        out.push_str(&rest[..idx]);
+        out.push_str(if bounded { replacement } else { word });
+        rest = &rest[idx + word.len()..];
+    }
+    out.push_str(rest);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(entries: &[(&str, &str)]) -> Metadata {
+        Metadata {
+            metadata: Some(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn share_safe_removes_dataset_keys_and_redacts_paths() {
+        let metadata = metadata(&[
+            ("ss_network_dim", "16"),
+            ("ss_output_name", "dave-private-project"),
+            (
+                "ss_dataset_dirs",
+                r#"{"10_cat": {"n_repeats": 10, "img_count": 20}}"#,
+            ),
+            (
+                "ss_tag_frequency",
+                r#"{"10_cat": {"cat": 20, "my_house": 3}}"#,
+            ),
+            (
+                "ss_datasets",
+                r#"[{"subsets": [{"image_dir": "/home/dave/datasets/10_cat"}], "tag_frequency": {"10_cat": {"cat": 20}}}]"#,
+            ),
+            (
+                "ss_training_comment",
+                "trained on /home/dave/datasets by dave",
+            ),
+        ]);
+
+        let (cleaned, report) = scrub(&metadata, ScrubProfile::ShareSafe);
+        let cleaned = cleaned.metadata.unwrap();
+
+        let mut removed = report.removed_keys();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec!["ss_dataset_dirs", "ss_output_name", "ss_tag_frequency"]
+        );
+        assert_eq!(cleaned.get("ss_network_dim"), Some(&"16".to_string()));
+
+        let datasets = &cleaned["ss_datasets"];
+        assert!(!datasets.contains("dave"), "{datasets}");
+        assert!(!datasets.contains("tag_frequency"), "{datasets}");
+        assert!(datasets.contains("<path>/10_cat"), "{datasets}");
+
+        assert_eq!(
+            cleaned["ss_training_comment"],
+            "trained on <path>/datasets by <user>"
+        );
+    }
+
+    #[test]
+    fn minimal_keeps_keys_but_redacts_windows_usernames() {
+        let metadata = metadata(&[
+            ("ss_output_name", "cats"),
+            (
+                "ss_sd_model_name",
+                r"C:\Users\someone\models\v1-5.safetensors",
+            ),
+        ]);
+
+        let (cleaned, report) = scrub(&metadata, ScrubProfile::Minimal);
+        let cleaned = cleaned.metadata.unwrap();
+
+        assert!(report.removed_keys().is_empty());
+        assert_eq!(cleaned["ss_output_name"], "cats");
+        assert_eq!(cleaned["ss_sd_model_name"], "<path>/v1-5.safetensors");
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].action, ScrubAction::Redacted);
+    }
+
+    #[test]
+    fn strip_all_keeps_only_network_keys() {
+        let metadata = metadata(&[
+            ("ss_network_module", "networks.lora"),
+            ("ss_network_dim", "16"),
+            ("ss_learning_rate", "0.0001"),
+            ("ss_seed", "42"),
+        ]);
+
+        let (cleaned, report) = scrub(&metadata, ScrubProfile::StripAll);
+        let mut keys: Vec<String> = cleaned.metadata.unwrap().into_keys().collect();
+        keys.sort();
+
+        assert_eq!(keys, vec!["ss_network_dim", "ss_network_module"]);
+        assert_eq!(report.removed_keys().len(), 2);
+    }
+
+    #[test]
+    fn clean_metadata_has_no_findings() {
+        let metadata = metadata(&[("ss_network_dim", "16"), ("ss_learning_rate", "1/2")]);
+        let (_cleaned, report) = scrub(&metadata, ScrubProfile::ShareSafe);
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn profile_from_str() {
+        assert_eq!(
+            "share-safe".parse::<ScrubProfile>().unwrap(),
+            ScrubProfile::ShareSafe
+        );
+        assert!("everything".parse::<ScrubProfile>().is_err());
+    }
+}
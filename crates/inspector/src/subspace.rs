@@ -0,0 +1,157 @@
+/// Truncated SVD of candle tensors by subspace iteration.
+///
+/// The range of the top directions is found on the full matrix, the small
+/// projected problem is then solved with the Jacobi eigendecomposition from
+/// [`crate::svd::jacobi`].
+use candle_core::{DType, Tensor};
+
+use crate::svd::jacobi::jacobi_sym;
+use crate::Result;
+
+/// Orthonormal `[rows, k]` basis of the top `k` left singular vectors of `m`,
+/// by subspace iteration. `m` has to be F32.
+pub(crate) fn top_left_singular_vectors(m: &Tensor, k: usize, iterations: usize) -> Result<Tensor> {
+    let (_, cols) = m.dims2()?;
+    let k = k.min(cols);
+    let mt = m.t()?.contiguous()?;
+
+    let mut x = Tensor::from_vec(start_vectors(cols * k), (cols, k), m.device())?;
+    let mut u = orthonormalize(&m.matmul(&x)?)?;
+    for _ in 0..iterations {
+        x = orthonormalize(&mt.matmul(&u)?)?;
+        u = orthonormalize(&m.matmul(&x)?)?;
+    }
+
+    Ok(u)
+}
+
+/// Fixed pseudo-random start, so the results are reproducible.
+fn start_vectors(len: usize) -> Vec<f32> {
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        })
+        .collect()
+}
+
+/// Modified Gram-Schmidt over the columns of `[rows, k]`. Columns that are
+/// dependent on the previous ones become zero.
+fn orthonormalize(t: &Tensor) -> Result<Tensor> {
+    let (rows, k) = t.dims2()?;
+    let values = t.to_dtype(DType::F64)?.t()?.contiguous()?.flatten_all()?;
+    let mut columns: Vec<f64> = values.to_vec1()?;
+    let scale = columns.iter().map(|v| v * v).sum::<f64>().sqrt().max(1.0);
+
+    for j in 0..k {
+        let (done, rest) = columns.split_at_mut(j * rows);
+        let column = &mut rest[..rows];
+        for previous in done.chunks(rows) {
+            let dot: f64 = previous.iter().zip(column.iter()).map(|(a, b)| a * b).sum();
+            column
+                .iter_mut()
+                .zip(previous)
+                .for_each(|(c, p)| *c -= dot * p);
+        }
+        let norm = column.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if norm > 1e-9 * scale {
+            column.iter_mut().for_each(|v| *v /= norm);
+        } else {
+            column.iter_mut().for_each(|v| *v = 0.0);
+        }
+    }
+
+    Ok(Tensor::from_vec(columns, (k, rows), t.device())?
+        .t()?
+        .contiguous()?
+        .to_dtype(t.dtype())?)
+}
+
+/// Top `k` singular triplets of a matrix, `m ≈ u diag(s) vt`.
+#[derive(Debug, Clone)]
+pub struct TruncatedSvd {
+    /// `[rows, k]`
+    pub u: Tensor,
+    /// Descending
+    pub s: Vec<f64>,
+    /// `[k, cols]`
+    pub vt: Tensor,
+}
+
+/// Randomized SVD of a 2-D F32 `m`: subspace iteration finds the range of the
+/// top `k` directions, then the Jacobi eigendecomposition of the small
+/// `k × k` Gram matrix of the projection gives the singular triplets. Works
+/// for the full size layers of a checkpoint where a Jacobi SVD of `m` itself
+/// would not.
+pub fn truncated_svd(m: &Tensor, k: usize, iterations: usize) -> Result<TruncatedSvd> {
+    let q = top_left_singular_vectors(m, k, iterations)?;
+    let k = q.dim(1)?;
+    // B = Q^T m, B B^T = V S^2 V^T
+    let b = q.t()?.matmul(m)?;
+    let gram: Vec<f64> = b
+        .matmul(&b.t()?)?
+        .to_dtype(DType::F64)?
+        .flatten_all()?
+        .to_vec1()?;
+    let (lambda, vectors) = jacobi_sym(&gram, k);
+    let s: Vec<f64> = lambda.iter().map(|l| l.max(0.0).sqrt()).collect();
+
+    // Column-major eigenvectors read row-major are V^T
+    let v_t = Tensor::from_vec(vectors, (k, k), m.device())?.to_dtype(DType::F32)?;
+    let u = q.matmul(&v_t.t()?)?;
+
+    // vt = S^-1 V^T B, dropping the directions m does not have
+    let tol = s.first().copied().unwrap_or(0.0) * 1e-7;
+    let inv: Vec<f32> = s
+        .iter()
+        .map(|s| if *s > tol { (1.0 / s) as f32 } else { 0.0 })
+        .collect();
+    let vt = v_t
+        .matmul(&b)?
+        .broadcast_mul(&Tensor::from_vec(inv, (k, 1), m.device())?)?;
+
+    Ok(TruncatedSvd { u, s, vt })
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::*;
+
+    #[test]
+    fn truncated_svd_recovers_a_rank_2_matrix() {
+        let dev = &Device::Cpu;
+        // diag(3, 2, 0) with the rows and columns permuted
+        let m = Tensor::from_vec(
+            vec![0.0f32, 0.0, 2.0, 3.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            (3, 3),
+            dev,
+        )
+        .unwrap();
+
+        let svd = truncated_svd(&m, 2, 4).unwrap();
+        assert_eq!(svd.s.len(), 2);
+        assert!((svd.s[0] - 3.0).abs() < 1e-5, "s={:?}", svd.s);
+        assert!((svd.s[1] - 2.0).abs() < 1e-5, "s={:?}", svd.s);
+
+        let s = Tensor::from_vec(svd.s.iter().map(|s| *s as f32).collect(), (2, 1), dev).unwrap();
+        let rebuilt = svd.u.matmul(&svd.vt.broadcast_mul(&s).unwrap()).unwrap();
+        let err: f32 = (rebuilt - &m)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_keepdim(0)
+            .unwrap()
+            .max(1)
+            .unwrap()
+            .squeeze(0)
+            .unwrap()
+            .to_scalar()
+            .unwrap();
+        assert!(err < 1e-5, "err={err}");
+    }
+}
@@ -1,7 +1,9 @@
 /// Jacobi symmetric eigendecomposition and rank-health metrics.
 ///
-/// All public functions take plain `Vec<f64>` / `&[f64]` so they have
-/// no dependency on candle and are easy to unit-test.
+/// The eigensolver and the metrics take plain `Vec<f64>` / `&[f64]` so they
+/// are easy to unit-test; `singular_values`, `rank_metrics` and
+/// `flatten_to_2d` are the thin wrappers over candle tensors. The SVD of a
+/// whole layer lives in [`crate::subspace`].
 use serde::{Deserialize, Serialize};
 
 /// Health classification of a LoRA layer's rank usage.
@@ -0,0 +1,338 @@
+use base64::Engine;
+use serde::Serialize;
+
+use crate::metadata::Metadata;
+use crate::{InspectorError, Result};
+
+/// Key of the cover image defined by the modelspec.
+pub const THUMBNAIL_KEY: &str = "modelspec.thumbnail";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Gif,
+    Unknown,
+}
+
+impl ImageFormat {
+    /// Detects the format from the file signature.
+    pub fn detect(bytes: &[u8]) -> ImageFormat {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            ImageFormat::Png
+        } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+            ImageFormat::Jpeg
+        } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            ImageFormat::WebP
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            ImageFormat::Gif
+        } else {
+            ImageFormat::Unknown
+        }
+    }
+
+    pub fn mime(&self) -> Option<&'static str> {
+        match self {
+            ImageFormat::Png => Some("image/png"),
+            ImageFormat::Jpeg => Some("image/jpeg"),
+            ImageFormat::WebP => Some("image/webp"),
+            ImageFormat::Gif => Some("image/gif"),
+            ImageFormat::Unknown => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::WebP => "webp",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Unknown => "bin",
+        }
+    }
+
+    /// Width and height read from the image header.
+    pub fn dimensions(&self, bytes: &[u8]) -> Option<(u32, u32)> {
+        match self {
+            ImageFormat::Png => png_dimensions(bytes),
+            ImageFormat::Jpeg => jpeg_dimensions(bytes),
+            ImageFormat::WebP => webp_dimensions(bytes),
+            ImageFormat::Gif => Some((le16(bytes, 6)? as u32, le16(bytes, 8)? as u32)),
+            ImageFormat::Unknown => None,
+        }
+    }
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn le16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn le24(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 3)?;
+    Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+}
+
+fn png_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    // Signature, then the IHDR chunk: length, "IHDR", width, height.
+    if bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    Some((
+        u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?),
+        u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?),
+    ))
+}
+
+fn jpeg_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut i = 2;
+    while i + 4 <= bytes.len() {
+        if bytes[i] != 0xff {
+            return None;
+        }
+        let marker = bytes[i + 1];
+        // Fill bytes and markers without a length.
+        if marker == 0xff {
+            i += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            i += 2;
+            continue;
+        }
+
+        let length = be16(bytes, i + 2)? as usize;
+        // Start of frame, except DHT (c4), JPG (c8) and DAC (cc).
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            let height = be16(bytes, i + 5)?;
+            let width = be16(bytes, i + 7)?;
+            return Some((width as u32, height as u32));
+        }
+        i += 2 + length;
+    }
+    None
+}
+
+fn webp_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    match bytes.get(12..16)? {
+        b"VP8 " => {
+            // Frame tag (3 bytes), start code (3 bytes), then 14 bit sizes.
+            if bytes.get(23..26)? != [0x9d, 0x01, 0x2a] {
+                return None;
+            }
+            Some((
+                (le16(bytes, 26)? & 0x3fff) as u32,
+                (le16(bytes, 28)? & 0x3fff) as u32,
+            ))
+        }
+        b"VP8L" => {
+            if *bytes.get(20)? != 0x2f {
+                return None;
+            }
+            let b = bytes.get(21..25)?;
+            let bits = u32::from_le_bytes(b.try_into().ok()?);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => Some((le24(bytes, 24)? + 1, le24(bytes, 27)? + 1)),
+        _ => None,
+    }
+}
+
+/// Decodes a base64 `data:` URI into its declared MIME type and bytes.
+pub fn decode_data_uri(uri: &str) -> Result<(String, Vec<u8>)> {
+    let rest = uri
+        .trim()
+        .strip_prefix("data:")
+        .ok_or_else(|| InspectorError::Msg("Not a data URI".to_string()))?;
+    let (header, data) = rest
+        .split_once(',')
+        .ok_or_else(|| InspectorError::Msg("Data URI has no data".to_string()))?;
+
+    let mut params = header.split(';');
+    let mime = params.next().unwrap_or_default().to_string();
+    if !params.any(|p| p == "base64") {
+        return Err(InspectorError::Msg(
+            "Only base64 data URIs are supported".to_string(),
+        ));
+    }
+
+    let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| InspectorError::Msg(format!("Invalid base64 in data URI: {e}")))?;
+
+    Ok((mime, bytes))
+}
+
+/// An image embedded in the metadata as a data URI.
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddedImage {
+    pub key: String,
+    /// MIME type from the data URI, which is not always accurate.
+    pub declared_mime: String,
+    pub format: ImageFormat,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub size: usize,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+impl EmbeddedImage {
+    pub fn from_data_uri(key: &str, uri: &str) -> Result<EmbeddedImage> {
+        let (declared_mime, data) = decode_data_uri(uri)?;
+        let format = ImageFormat::detect(&data);
+        let (width, height) = format.dimensions(&data).unzip();
+
+        Ok(EmbeddedImage {
+            key: key.to_string(),
+            declared_mime,
+            format,
+            width,
+            height,
+            size: data.len(),
+            data,
+        })
+    }
+
+    /// Detected MIME type, falling back to the declared one.
+    pub fn mime(&self) -> String {
+        self.format
+            .mime()
+            .map(|m| m.to_string())
+            .unwrap_or_else(|| self.declared_mime.clone())
+    }
+}
+
+/// Decodes the image stored under `key`.
+pub fn image(metadata: &Metadata, key: &str) -> Result<EmbeddedImage> {
+    let value = metadata
+        .metadata
+        .as_ref()
+        .and_then(|m| m.get(key))
+        .ok_or(InspectorError::NotFound)?;
+    EmbeddedImage::from_data_uri(key, value)
+}
+
+/// The modelspec thumbnail, if the file has one.
+pub fn cover_image(metadata: &Metadata) -> Option<Result<EmbeddedImage>> {
+    match image(metadata, THUMBNAIL_KEY) {
+        Err(InspectorError::NotFound) => None,
+        result => Some(result),
+    }
+}
+
+/// Every metadata value that decodes as an image data URI, with the modelspec
+/// thumbnail first.
+pub fn embedded_images(metadata: &Metadata) -> Vec<EmbeddedImage> {
+    let Some(map) = metadata.metadata.as_ref() else {
+        return vec![];
+    };
+
+    let mut keys: Vec<&String> = map
+        .iter()
+        .filter(|(_, v)| v.trim_start().starts_with("data:image/"))
+        .map(|(k, _)| k)
+        .collect();
+    keys.sort_by_key(|k| (k.as_str() != THUMBNAIL_KEY, k.as_str()));
+
+    keys.into_iter()
+        .filter_map(|k| EmbeddedImage::from_data_uri(k, &map[k]).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_uri(mime: &str, bytes: &[u8]) -> String {
+        format!(
+            "data:{mime};base64,{}",
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        )
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        bytes.extend(width.to_be_bytes());
+        bytes.extend(height.to_be_bytes());
+        bytes.extend([8, 6, 0, 0, 0]);
+        bytes
+    }
+
+    fn jpeg(width: u16, height: u16) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xd8];
+        // APP0 segment to skip over
+        bytes.extend([0xff, 0xe0, 0, 4, 0, 0]);
+        bytes.extend([0xff, 0xc0, 0, 11, 8]);
+        bytes.extend(height.to_be_bytes());
+        bytes.extend(width.to_be_bytes());
+        bytes.extend([1, 1, 0x11, 0]);
+        bytes
+    }
+
+    fn webp_vp8x(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\0\0\0\0".to_vec();
+        bytes.extend(&(width - 1).to_le_bytes()[0..3]);
+        bytes.extend(&(height - 1).to_le_bytes()[0..3]);
+        bytes
+    }
+
+    #[test]
+    fn detects_formats_and_dimensions() {
+        for (bytes, format, dims) in [
+            (png(640, 480), ImageFormat::Png, (640, 480)),
+            (jpeg(1024, 768), ImageFormat::Jpeg, (1024, 768)),
+            (webp_vp8x(512, 300), ImageFormat::WebP, (512, 300)),
+        ] {
+            assert_eq!(ImageFormat::detect(&bytes), format);
+            assert_eq!(format.dimensions(&bytes), Some(dims));
+        }
+        assert_eq!(ImageFormat::detect(b"hello"), ImageFormat::Unknown);
+    }
+
+    #[test]
+    fn decodes_thumbnail_from_metadata() {
+        let metadata = Metadata {
+            metadata: Some(
+                [
+                    (
+                        "ss_sample_image".to_string(),
+                        data_uri("image/jpeg", &jpeg(8, 4)),
+                    ),
+                    // Declared as jpeg but actually a png
+                    (
+                        THUMBNAIL_KEY.to_string(),
+                        data_uri("image/jpeg", &png(2, 3)),
+                    ),
+                    ("ss_output_name".to_string(), "boo".to_string()),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+        };
+
+        let cover = cover_image(&metadata).unwrap().unwrap();
+        assert_eq!(cover.mime(), "image/png");
+        assert_eq!(cover.declared_mime, "image/jpeg");
+        assert_eq!((cover.width, cover.height), (Some(2), Some(3)));
+
+        let keys: Vec<String> = embedded_images(&metadata)
+            .into_iter()
+            .map(|i| i.key)
+            .collect();
+        assert_eq!(keys, vec![THUMBNAIL_KEY, "ss_sample_image"]);
+    }
+
+    #[test]
+    fn rejects_bad_data_uris() {
+        assert!(decode_data_uri("https://example.com/a.png").is_err());
+        assert!(decode_data_uri("data:image/png,rawdata").is_err());
+        assert!(decode_data_uri("data:image/png;base64,!!!").is_err());
+        assert!(cover_image(&Metadata::default()).is_none());
+    }
+}
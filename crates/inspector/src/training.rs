@@ -0,0 +1,830 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::metadata::Metadata;
+use crate::network::{Architecture, NetworkModule};
+
+/// Placeholder written for required values the metadata does not record.
+pub const UNRECOVERED: &str = "<UNRECOVERED>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Trainer {
+    SdScripts,
+    MusubiTuner,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "value")]
+pub enum ConfigValue {
+    Bool(bool),
+    /// Kept as written in the metadata so `1e-4` doesn't turn into `0.0001`.
+    Number(String),
+    Str(String),
+    List(Vec<ConfigValue>),
+    Unrecovered,
+}
+
+impl ConfigValue {
+    /// Types a raw `ss_*` value. `None`, `null` and empty values are unset.
+    fn from_metadata(value: &str) -> Option<ConfigValue> {
+        match value.trim() {
+            "" | "None" | "null" => None,
+            "True" | "true" => Some(ConfigValue::Bool(true)),
+            "False" | "false" => Some(ConfigValue::Bool(false)),
+            v if v.parse::<f64>().is_ok_and(|n| n.is_finite()) => {
+                Some(ConfigValue::Number(v.to_string()))
+            }
+            v => Some(ConfigValue::Str(v.to_string())),
+        }
+    }
+
+    fn from_json(value: &Value) -> Option<ConfigValue> {
+        match value {
+            Value::Null => None,
+            Value::Bool(b) => Some(ConfigValue::Bool(*b)),
+            Value::Number(n) => Some(ConfigValue::Number(n.to_string())),
+            Value::String(s) => Some(ConfigValue::Str(s.clone())),
+            Value::Array(values) => Some(ConfigValue::List(
+                values.iter().filter_map(ConfigValue::from_json).collect(),
+            )),
+            Value::Object(_) => None,
+        }
+    }
+
+    fn to_toml(&self) -> String {
+        match self {
+            ConfigValue::Bool(b) => b.to_string(),
+            ConfigValue::Number(n) => n.clone(),
+            ConfigValue::Str(s) => toml_string(s),
+            ConfigValue::List(values) => format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(|v| v.to_toml())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ConfigValue::Unrecovered => toml_string(UNRECOVERED),
+        }
+    }
+
+    fn to_shell(&self) -> Vec<String> {
+        match self {
+            ConfigValue::Bool(_) => vec![],
+            ConfigValue::Number(n) => vec![n.clone()],
+            ConfigValue::Str(s) => vec![shell_quote(s)],
+            ConfigValue::List(values) => values.iter().flat_map(|v| v.to_shell()).collect(),
+            ConfigValue::Unrecovered => vec![shell_quote(UNRECOVERED)],
+        }
+    }
+}
+
+fn toml_string(s: &str) -> String {
+    // JSON string escapes are a subset of TOML basic string escapes.
+    serde_json::to_string(s).unwrap_or_else(|_| format!("\"{s}\""))
+}
+
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=,:+@".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigEntry {
+    pub name: String,
+    pub value: ConfigValue,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Unrecovered {
+    pub field: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DatasetConfig {
+    pub entries: Vec<ConfigEntry>,
+    pub subsets: Vec<Vec<ConfigEntry>>,
+}
+
+/// Training run reconstructed from the `ss_*` metadata of a LoRA.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrainingConfig {
+    pub trainer: Trainer,
+    pub script: String,
+    pub args: Vec<ConfigEntry>,
+    pub datasets: Vec<DatasetConfig>,
+    /// Fields that could not be read back from the metadata. Required ones are
+    /// also present in `args` or `datasets` as [`ConfigValue::Unrecovered`].
+    pub unrecovered: Vec<Unrecovered>,
+}
+
+/// Rendered files for the UI.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrainingFiles {
+    pub command: String,
+    pub config_toml: String,
+    pub dataset_toml: String,
+    pub unrecovered: Vec<Unrecovered>,
+}
+
+/// `ss_*` keys that map one to one onto a training argument. Booleans become
+/// store_true flags.
+const ARGS: &[(&str, &str)] = &[
+    ("ss_learning_rate", "learning_rate"),
+    ("ss_unet_lr", "unet_lr"),
+    ("ss_text_encoder_lr", "text_encoder_lr"),
+    ("ss_max_train_steps", "max_train_steps"),
+    ("ss_num_epochs", "max_train_epochs"),
+    ("ss_lr_scheduler", "lr_scheduler"),
+    ("ss_lr_warmup_steps", "lr_warmup_steps"),
+    ("ss_seed", "seed"),
+    ("ss_clip_skip", "clip_skip"),
+    ("ss_mixed_precision", "mixed_precision"),
+    ("ss_full_fp16", "full_fp16"),
+    ("ss_full_bf16", "full_bf16"),
+    ("ss_fp8_base", "fp8_base"),
+    ("ss_gradient_checkpointing", "gradient_checkpointing"),
+    (
+        "ss_gradient_accumulation_steps",
+        "gradient_accumulation_steps",
+    ),
+    ("ss_max_token_length", "max_token_length"),
+    ("ss_v2", "v2"),
+    ("ss_noise_offset", "noise_offset"),
+    ("ss_adaptive_noise_scale", "adaptive_noise_scale"),
+    ("ss_multires_noise_iterations", "multires_noise_iterations"),
+    ("ss_multires_noise_discount", "multires_noise_discount"),
+    ("ss_ip_noise_gamma", "ip_noise_gamma"),
+    ("ss_min_snr_gamma", "min_snr_gamma"),
+    ("ss_scale_weight_norms", "scale_weight_norms"),
+    ("ss_debiased_estimation", "debiased_estimation_loss"),
+    ("ss_zero_terminal_snr", "zero_terminal_snr"),
+    ("ss_max_grad_norm", "max_grad_norm"),
+    ("ss_prior_loss_weight", "prior_loss_weight"),
+    ("ss_caption_dropout_rate", "caption_dropout_rate"),
+    (
+        "ss_caption_dropout_every_n_epochs",
+        "caption_dropout_every_n_epochs",
+    ),
+    ("ss_caption_tag_dropout_rate", "caption_tag_dropout_rate"),
+    ("ss_network_dropout", "network_dropout"),
+    ("ss_loss_type", "loss_type"),
+    ("ss_huber_c", "huber_c"),
+    ("ss_huber_schedule", "huber_schedule"),
+    ("ss_timestep_sampling", "timestep_sampling"),
+    ("ss_sigmoid_scale", "sigmoid_scale"),
+    ("ss_model_prediction_type", "model_prediction_type"),
+    ("ss_discrete_flow_shift", "discrete_flow_shift"),
+    ("ss_guidance_scale", "guidance_scale"),
+    ("ss_apply_t5_attn_mask", "apply_t5_attn_mask"),
+    ("ss_weighting_scheme", "weighting_scheme"),
+    ("ss_training_comment", "training_comment"),
+];
+
+/// Keys kohya writes once for the whole run when it was not given a dataset
+/// config, mapped onto dataset (`true`) or subset (`false`) options.
+const DATASET_ARGS: &[(&str, &str, bool)] = &[
+    ("ss_resolution", "resolution", true),
+    ("ss_enable_bucket", "enable_bucket", true),
+    ("ss_min_bucket_reso", "min_bucket_reso", true),
+    ("ss_max_bucket_reso", "max_bucket_reso", true),
+    ("ss_bucket_no_upscale", "bucket_no_upscale", true),
+    ("ss_shuffle_caption", "shuffle_caption", false),
+    ("ss_keep_tokens", "keep_tokens", false),
+    ("ss_color_aug", "color_aug", false),
+    ("ss_flip_aug", "flip_aug", false),
+    ("ss_random_crop", "random_crop", false),
+];
+
+/// Subset keys stored in `ss_datasets`.
+const SUBSET_KEYS: &[&str] = &[
+    "image_dir",
+    "metadata_file",
+    "num_repeats",
+    "class_tokens",
+    "is_reg",
+    "shuffle_caption",
+    "keep_tokens",
+    "color_aug",
+    "flip_aug",
+    "random_crop",
+];
+
+/// Text encoder and VAE paths each script needs that kohya does not record.
+fn model_files(trainer: Trainer, architecture: Option<Architecture>) -> &'static [&'static str] {
+    match (trainer, architecture) {
+        (Trainer::MusubiTuner, _) => &["vae", "text_encoder"],
+        (_, Some(Architecture::Flux)) => &["clip_l", "t5xxl", "ae"],
+        (_, Some(Architecture::SD3)) => &["clip_l", "clip_g", "t5xxl"],
+        (_, Some(Architecture::Lumina)) => &["gemma2", "ae"],
+        _ => &[],
+    }
+}
+
+fn script(module: Option<&NetworkModule>, architecture: Option<Architecture>) -> (Trainer, String) {
+    match module {
+        Some(NetworkModule::MusubiTunerLoRAFlux2) => (
+            Trainer::MusubiTuner,
+            "src/musubi_tuner/flux_2_train_network.py".to_string(),
+        ),
+        Some(NetworkModule::MusubiTunerLoRAKrea2) => (
+            Trainer::MusubiTuner,
+            "src/musubi_tuner/krea2_train_network.py".to_string(),
+        ),
+        _ => {
+            let script = match architecture {
+                Some(Architecture::SDXL) => "sdxl_train_network.py",
+                Some(Architecture::SD3) => "sd3_train_network.py",
+                Some(Architecture::Flux) => "flux_train_network.py",
+                Some(Architecture::Lumina) => "lumina_train_network.py",
+                _ => "train_network.py",
+            };
+            (Trainer::SdScripts, script.to_string())
+        }
+    }
+}
+
+/// Splits `bitsandbytes.optim.adamw.AdamW8bit(weight_decay=0.1,betas=(0.9, 0.99))`
+/// into the optimizer type and its `key=value` arguments.
+fn parse_optimizer(optimizer: &str) -> (String, Vec<String>) {
+    let (path, args) = match optimizer.split_once('(') {
+        Some((path, args)) => (path, args.strip_suffix(')').unwrap_or(args)),
+        None => (optimizer, ""),
+    };
+    let name = path.rsplit('.').next().unwrap_or(path).trim().to_string();
+
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in args.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            _ => (),
+        }
+        if c == ',' && depth == 0 {
+            parts.push(current.trim().to_string());
+            current.clear();
+        } else {
+            current.push(c);
+        }
+    }
+    parts.push(current.trim().to_string());
+
+    (
+        name,
+        parts
+            .into_iter()
+            .filter(|p| !p.is_empty())
+            .map(|p| p.replace(' ', ""))
+            .collect(),
+    )
+}
+
+/// `(512, 512)`, `[512, 512]`, `512,512` or `512` as a `[w, h]` list.
+fn parse_resolution(value: &str) -> Option<ConfigValue> {
+    let sizes: Vec<ConfigValue> = value
+        .trim_matches(|c| "()[] ".contains(c))
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<usize>()
+                .map(|n| ConfigValue::Number(n.to_string()))
+        })
+        .collect::<std::result::Result<_, _>>()
+        .ok()?;
+
+    match sizes.len() {
+        1 => Some(ConfigValue::List(vec![sizes[0].clone(), sizes[0].clone()])),
+        2 => Some(ConfigValue::List(sizes)),
+        _ => None,
+    }
+}
+
+struct Builder<'a> {
+    metadata: &'a HashMap<String, String>,
+    args: Vec<ConfigEntry>,
+    unrecovered: Vec<Unrecovered>,
+}
+
+impl Builder<'_> {
+    fn get(&self, key: &str) -> Option<ConfigValue> {
+        self.metadata
+            .get(key)
+            .and_then(|v| ConfigValue::from_metadata(v))
+    }
+
+    fn arg(&mut self, name: &str, value: ConfigValue) {
+        self.args.push(ConfigEntry {
+            name: name.to_string(),
+            value,
+        });
+    }
+
+    fn missing(&mut self, field: &str, reason: &str, required: bool) {
+        if required {
+            self.arg(field, ConfigValue::Unrecovered);
+        }
+        self.unrecovered.push(Unrecovered {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+}
+
+/// Rebuilds the training arguments and dataset config from the metadata.
+pub fn training_config(metadata: &Metadata) -> TrainingConfig {
+    let empty = HashMap::new();
+    let map = metadata.metadata.as_ref().unwrap_or(&empty);
+    let module = metadata.network_module();
+    let architecture = metadata.architecture();
+    let (trainer, script) = script(module.as_ref(), architecture);
+
+    let mut b = Builder {
+        metadata: map,
+        args: vec![],
+        unrecovered: vec![],
+    };
+
+    let model_arg = match trainer {
+        Trainer::SdScripts => "pretrained_model_name_or_path",
+        Trainer::MusubiTuner => "dit",
+    };
+    match b.get("ss_sd_model_name") {
+        Some(name) => b.arg(model_arg, name),
+        None => b.missing(model_arg, "base model is not recorded", true),
+    }
+    if let Some(vae) = b.get("ss_vae_name") {
+        b.arg("vae", vae);
+    }
+    for file in model_files(trainer, architecture) {
+        if !b.args.iter().any(|a| a.name == *file) {
+            b.missing(file, "model file paths are not recorded", true);
+        }
+    }
+
+    b.arg(
+        "dataset_config",
+        ConfigValue::Str("dataset.toml".to_string()),
+    );
+    b.missing("output_dir", "output directory is not recorded", true);
+    if let Some(name) = b.get("ss_output_name") {
+        b.arg("output_name", name);
+    }
+    b.arg("save_model_as", ConfigValue::Str("safetensors".to_string()));
+
+    match map.get("ss_network_module").map(|m| m.as_str()) {
+        // Files from before kohya switched to import paths.
+        Some("kohya-ss/lora") => b.arg("network_module", ConfigValue::Str("networks.lora".into())),
+        Some(m) => b.arg("network_module", ConfigValue::Str(m.to_string())),
+        None => b.missing("network_module", "ss_network_module is not set", true),
+    }
+    for (key, name) in [
+        ("ss_network_dim", "network_dim"),
+        ("ss_network_alpha", "network_alpha"),
+    ] {
+        if let Some(v) = b.get(key) {
+            b.arg(name, v);
+        }
+    }
+    if let Some(Ok(Value::Object(args))) = map
+        .get("ss_network_args")
+        .map(|v| serde_json::from_str::<Value>(v))
+    {
+        let args: Vec<ConfigValue> = args
+            .iter()
+            .map(|(k, v)| {
+                let v = match v {
+                    Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                };
+                ConfigValue::Str(format!("{k}={v}"))
+            })
+            .collect();
+        if !args.is_empty() {
+            b.arg("network_args", ConfigValue::List(args));
+        }
+    }
+
+    if let Some(optimizer) = map.get("ss_optimizer") {
+        let (name, args) = parse_optimizer(optimizer);
+        b.arg("optimizer_type", ConfigValue::Str(name));
+        if !args.is_empty() {
+            b.arg(
+                "optimizer_args",
+                ConfigValue::List(args.into_iter().map(ConfigValue::Str).collect()),
+            );
+        }
+    } else {
+        b.missing("optimizer_type", "ss_optimizer is not set", false);
+    }
+
+    for (key, name) in ARGS {
+        match b.get(key) {
+            Some(ConfigValue::Bool(false)) | None => (),
+            Some(value) => b.arg(name, value),
+        }
+    }
+    if let Some(batch_size) = b
+        .get("ss_batch_size_per_device")
+        .or_else(|| b.get("ss_batch_size_per_gpu"))
+    {
+        b.arg("train_batch_size", batch_size);
+    }
+
+    let datasets = datasets(&mut b, trainer);
+
+    TrainingConfig {
+        trainer,
+        script,
+        args: b.args,
+        datasets,
+        unrecovered: b.unrecovered,
+    }
+}
+
+fn datasets(b: &mut Builder, trainer: Trainer) -> Vec<DatasetConfig> {
+    let image_dir = match trainer {
+        Trainer::SdScripts => "image_dir",
+        Trainer::MusubiTuner => "image_directory",
+    };
+
+    let parsed = b
+        .metadata
+        .get("ss_datasets")
+        .and_then(|v| serde_json::from_str::<Vec<Value>>(v).ok());
+
+    let mut datasets = match parsed {
+        Some(datasets) => datasets
+            .iter()
+            .map(|dataset| {
+                let mut config = DatasetConfig::default();
+                for (key, name) in [
+                    ("resolution", "resolution"),
+                    ("batch_size_per_device", "batch_size"),
+                    ("enable_bucket", "enable_bucket"),
+                    ("min_bucket_reso", "min_bucket_reso"),
+                    ("max_bucket_reso", "max_bucket_reso"),
+                    ("bucket_no_upscale", "bucket_no_upscale"),
+                ] {
+                    if let Some(value) = dataset.get(key).and_then(ConfigValue::from_json) {
+                        config.entries.push(entry(name, value));
+                    }
+                }
+
+                for subset in dataset
+                    .get("subsets")
+                    .and_then(|s| s.as_array())
+                    .into_iter()
+                    .flatten()
+                {
+                    let mut entries = vec![];
+                    for key in SUBSET_KEYS {
+                        if let Some(value) = subset.get(*key).and_then(ConfigValue::from_json) {
+                            let name = if *key == "image_dir" { image_dir } else { key };
+                            entries.push(entry(name, value));
+                        }
+                    }
+                    config.subsets.push(entries);
+                }
+                config
+            })
+            .collect(),
+        None => vec![legacy_dataset(b, image_dir)],
+    };
+
+    for dataset in datasets.iter_mut() {
+        if dataset.subsets.is_empty() {
+            dataset.subsets.push(vec![]);
+        }
+        for subset in dataset.subsets.iter_mut() {
+            let has_images = subset
+                .iter()
+                .any(|e| e.name == image_dir || e.name == "metadata_file");
+            if !has_images {
+                subset.insert(0, entry(image_dir, ConfigValue::Unrecovered));
+            }
+        }
+    }
+
+    if datasets
+        .iter()
+        .flat_map(|d| d.subsets.iter().flatten())
+        .any(|e| e.value == ConfigValue::Unrecovered)
+    {
+        b.unrecovered.push(Unrecovered {
+            field: image_dir.to_string(),
+            reason: "image directories are not recorded".to_string(),
+        });
+    }
+    b.unrecovered.push(Unrecovered {
+        field: "caption_extension".to_string(),
+        reason: "caption extension is not recorded".to_string(),
+    });
+    if trainer == Trainer::MusubiTuner {
+        b.unrecovered.push(Unrecovered {
+            field: "cache_directory".to_string(),
+            reason: "latent cache directory is not recorded".to_string(),
+        });
+    }
+
+    datasets
+}
+
+/// Dataset from the run-wide `ss_*` keys and `ss_dataset_dirs`, which is all
+/// older files and runs without a dataset config have.
+fn legacy_dataset(b: &Builder, image_dir: &str) -> DatasetConfig {
+    let mut config = DatasetConfig::default();
+    let mut subset_entries = vec![];
+
+    for (key, name, on_dataset) in DATASET_ARGS {
+        let value = match *key {
+            "ss_resolution" => b.metadata.get(*key).and_then(|v| parse_resolution(v)),
+            _ => b.get(key),
+        };
+        if let Some(value) = value {
+            if *on_dataset {
+                config.entries.push(entry(name, value));
+            } else {
+                subset_entries.push(entry(name, value));
+            }
+        }
+    }
+
+    let dirs = b
+        .metadata
+        .get("ss_dataset_dirs")
+        .and_then(|v| serde_json::from_str::<HashMap<String, Value>>(v).ok())
+        .unwrap_or_default();
+    let mut dirs: Vec<_> = dirs.into_iter().collect();
+    dirs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (dir, info) in dirs {
+        let mut entries = vec![entry(image_dir, ConfigValue::Str(dir))];
+        if let Some(repeats) = info.get("n_repeats").and_then(ConfigValue::from_json) {
+            entries.push(entry("num_repeats", repeats));
+        }
+        entries.extend(subset_entries.iter().cloned());
+        config.subsets.push(entries);
+    }
+
+    if config.subsets.is_empty() && !subset_entries.is_empty() {
+        config.subsets.push(subset_entries);
+    }
+
+    config
+}
+
+fn entry(name: &str, value: ConfigValue) -> ConfigEntry {
+    ConfigEntry {
+        name: name.to_string(),
+        value,
+    }
+}
+
+fn write_entries(out: &mut String, indent: &str, entries: &[ConfigEntry]) {
+    for e in entries {
+        let _ = write!(out, "{indent}{} = {}", e.name, e.value.to_toml());
+        if e.value == ConfigValue::Unrecovered {
+            out.push_str(" # not recorded in the metadata");
+        }
+        out.push('\n');
+    }
+}
+
+impl TrainingConfig {
+    /// Shell command for the training script, one argument per line.
+    pub fn command(&self) -> String {
+        let mut lines = vec![format!("accelerate launch {}", self.script)];
+        for arg in &self.args {
+            if arg.value == ConfigValue::Bool(false) {
+                continue;
+            }
+            let mut line = format!("--{}", arg.name);
+            for value in arg.value.to_shell() {
+                line.push(' ');
+                line.push_str(&value);
+            }
+            lines.push(line);
+        }
+
+        lines.join(" \\\n  ")
+    }
+
+    /// Arguments as a `--config_file` TOML, read the same way by sd-scripts
+    /// and musubi-tuner.
+    pub fn config_toml(&self) -> String {
+        let mut out = String::new();
+        for u in &self.unrecovered {
+            let _ = writeln!(out, "# unrecovered: {} ({})", u.field, u.reason);
+        }
+        write_entries(&mut out, "", &self.args);
+        out
+    }
+
+    /// Dataset config in the layout of the trainer. sd-scripts nests subsets
+    /// under each dataset, musubi-tuner has one flat entry per directory.
+    pub fn dataset_toml(&self) -> String {
+        let mut out = String::from("[general]\n");
+        let _ = writeln!(
+            out,
+            "# caption_extension = {} # not recorded in the metadata",
+            toml_string(UNRECOVERED)
+        );
+
+        match self.trainer {
+            Trainer::SdScripts => {
+                for dataset in &self.datasets {
+                    out.push_str("\n[[datasets]]\n");
+                    write_entries(&mut out, "", &dataset.entries);
+                    for subset in &dataset.subsets {
+                        out.push_str("\n  [[datasets.subsets]]\n");
+                        write_entries(&mut out, "  ", subset);
+                    }
+                }
+            }
+            Trainer::MusubiTuner => {
+                for dataset in &self.datasets {
+                    for subset in &dataset.subsets {
+                        out.push_str("\n[[datasets]]\n");
+                        write_entries(&mut out, "", &dataset.entries);
+                        write_entries(&mut out, "", subset);
+                        let _ = writeln!(
+                            out,
+                            "cache_directory = {} # not recorded in the metadata",
+                            toml_string(UNRECOVERED)
+                        );
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    pub fn files(&self) -> TrainingFiles {
+        TrainingFiles {
+            command: self.command(),
+            config_toml: self.config_toml(),
+            dataset_toml: self.dataset_toml(),
+            unrecovered: self.unrecovered.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(entries: &[(&str, &str)]) -> Metadata {
+        Metadata {
+            metadata: Some(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn arg<'a>(config: &'a TrainingConfig, name: &str) -> Option<&'a ConfigValue> {
+        config
+            .args
+            .iter()
+            .find(|a| a.name == name)
+            .map(|a| &a.value)
+    }
+
+    #[test]
+    fn rebuilds_sdxl_command() {
+        let config = training_config(&metadata(&[
+            ("ss_base_model_version", "sdxl_base_v1-0"),
+            ("ss_sd_model_name", "sd_xl_base_1.0.safetensors"),
+            ("ss_network_module", "networks.lora"),
+            ("ss_network_dim", "16"),
+            ("ss_network_alpha", "8.0"),
+            ("ss_network_args", r#"{"conv_dim": "4", "conv_alpha": "1"}"#),
+            (
+                "ss_optimizer",
+                "bitsandbytes.optim.adamw.AdamW8bit(weight_decay=0.1,betas=(0.9, 0.99))",
+            ),
+            ("ss_learning_rate", "1e-4"),
+            ("ss_gradient_checkpointing", "True"),
+            ("ss_full_fp16", "False"),
+            ("ss_max_token_length", "None"),
+            ("ss_training_comment", "it's mine"),
+        ]));
+
+        assert_eq!(config.trainer, Trainer::SdScripts);
+        assert_eq!(config.script, "sdxl_train_network.py");
+        assert_eq!(
+            arg(&config, "optimizer_type"),
+            Some(&ConfigValue::Str("AdamW8bit".to_string()))
+        );
+        assert_eq!(
+            arg(&config, "optimizer_args"),
+            Some(&ConfigValue::List(vec![
+                ConfigValue::Str("weight_decay=0.1".to_string()),
+                ConfigValue::Str("betas=(0.9,0.99)".to_string()),
+            ]))
+        );
+        assert_eq!(arg(&config, "full_fp16"), None);
+        assert_eq!(arg(&config, "max_token_length"), None);
+        assert_eq!(arg(&config, "output_dir"), Some(&ConfigValue::Unrecovered));
+
+        let command = config.command();
+        assert!(command.starts_with("accelerate launch sdxl_train_network.py"));
+        assert!(command.contains("--learning_rate 1e-4"));
+        assert!(command.contains("--gradient_checkpointing \\"));
+        assert!(command.contains("--network_args conv_alpha=1 conv_dim=4"));
+        assert!(command.contains("--output_dir '<UNRECOVERED>'"));
+        assert!(command.contains(r"--training_comment 'it'\''s mine'"));
+
+        let toml = config.config_toml();
+        assert!(toml.contains("# unrecovered: output_dir"));
+        assert!(toml.contains("network_dim = 16\n"));
+        assert!(toml.contains("gradient_checkpointing = true\n"));
+    }
+
+    #[test]
+    fn dataset_from_ss_datasets() {
+        let config = training_config(&metadata(&[
+            ("ss_network_module", "networks.lora"),
+            (
+                "ss_datasets",
+                r#"[{"is_dreambooth": true, "batch_size_per_device": 2, "resolution": [512, 768],
+                     "enable_bucket": true, "tag_frequency": {"a": {"b": 1}},
+                     "subsets": [{"img_count": 30, "num_repeats": 10, "image_dir": "10_boo",
+                                  "class_tokens": "boo", "is_reg": false, "flip_aug": true}]}]"#,
+            ),
+        ]));
+
+        let toml = config.dataset_toml();
+        assert!(toml.contains("resolution = [512, 768]\n"));
+        assert!(toml.contains("batch_size = 2\n"));
+        assert!(toml.contains("  [[datasets.subsets]]\n  image_dir = \"10_boo\"\n"));
+        assert!(toml.contains("  num_repeats = 10\n"));
+        assert!(toml.contains("  class_tokens = \"boo\"\n"));
+        assert!(!toml.contains("img_count"));
+        assert!(!toml.contains("tag_frequency"));
+    }
+
+    #[test]
+    fn legacy_dataset_dirs() {
+        let config = training_config(&metadata(&[
+            ("ss_network_module", "kohya-ss/lora"),
+            ("ss_resolution", "(512, 512)"),
+            ("ss_shuffle_caption", "True"),
+            (
+                "ss_dataset_dirs",
+                r#"{"10_boo": {"n_repeats": 10, "img_count": 30}}"#,
+            ),
+            ("ss_batch_size_per_gpu", "1"),
+        ]));
+
+        assert_eq!(
+            arg(&config, "network_module"),
+            Some(&ConfigValue::Str("networks.lora".to_string()))
+        );
+        assert_eq!(
+            arg(&config, "train_batch_size"),
+            Some(&ConfigValue::Number("1".to_string()))
+        );
+
+        let toml = config.dataset_toml();
+        assert!(toml.contains("resolution = [512, 512]\n"));
+        assert!(toml.contains("  image_dir = \"10_boo\"\n  num_repeats = 10\n"));
+        assert!(toml.contains("  shuffle_caption = true\n"));
+    }
+
+    #[test]
+    fn musubi_tuner_config() {
+        let config = training_config(&metadata(&[
+            ("ss_network_module", "networks.lora_flux_2"),
+            ("ss_network_dim", "32"),
+        ]));
+
+        assert_eq!(config.trainer, Trainer::MusubiTuner);
+        assert_eq!(arg(&config, "dit"), Some(&ConfigValue::Unrecovered));
+        assert_eq!(arg(&config, "vae"), Some(&ConfigValue::Unrecovered));
+
+        let toml = config.dataset_toml();
+        assert!(toml.contains("[[datasets]]\nimage_directory = \"<UNRECOVERED>\""));
+        assert!(toml.contains("cache_directory = \"<UNRECOVERED>\""));
+        assert!(config
+            .unrecovered
+            .iter()
+            .any(|u| u.field == "image_directory"));
+    }
+}
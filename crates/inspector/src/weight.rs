@@ -40,6 +40,33 @@ where
     }
 }
 
+/// LyCORIS keys of layers trained without a decomposition: `full` weight diffs
+/// and `train_norm` norm layers, each with its bias update.
+const FULL_KEYS: [&str; 4] = ["diff", "diff_b", "w_norm", "b_norm"];
+
+pub(crate) fn is_full_key(key: &str) -> bool {
+    key.rsplit('.')
+        .next()
+        .is_some_and(|suffix| FULL_KEYS.contains(&suffix))
+}
+
+/// Gets the first of `suffixes` saved for `base_name`.
+fn first_of<F>(get: F, base_name: &str, suffixes: &[&str]) -> Result<Tensor, candle_core::Error>
+where
+    F: Fn(&str) -> Result<Tensor, candle_core::Error>,
+{
+    let mut result = Err(candle_core::Error::CannotFindTensor {
+        path: format!("{base_name}.{}", suffixes.join(",")),
+    });
+    for suffix in suffixes {
+        result = get(&format!("{base_name}.{suffix}"));
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
 /// Converts tensor into DType thats compatible with candle
 /// We convert BF16 to F32
 fn to_compatible_dtype(tensor: &candle_core::Tensor) -> Result<Tensor, candle_core::Error> {
@@ -83,6 +110,11 @@ pub fn get_base_name(name: &str) -> String {
                     | "lokr_w2_a"
                     | "lokr_w2_b"
                     | "oft_blocks"
+                    | "on_input"
+                    | "diff"
+                    | "diff_b"
+                    | "w_norm"
+                    | "b_norm"
             )
         })
         .fold(String::new(), |acc, v| {
@@ -94,6 +126,29 @@ pub fn get_base_name(name: &str) -> String {
         })
 }
 
+/// Rescales `base_weight` by IA3 scaling vectors, returning the delta
+/// `W0 * weight`: per input column when trained `on_input`, per output row
+/// otherwise.
+pub fn apply_ia3(
+    scale: &Tensor,
+    on_input: bool,
+    base_weight: &Tensor,
+) -> Result<Tensor, candle_core::Error> {
+    let scale = scale.flatten_all()?.to_dtype(base_weight.dtype())?;
+    let dims = base_weight.dims();
+    let (rows, cols) = (dims[0], dims.get(1).copied().unwrap_or(1));
+
+    let rescaled = if on_input {
+        let weight = base_weight.reshape((rows, cols, ()))?;
+        weight.broadcast_mul(&scale.reshape((1, cols, 1))?)?
+    } else {
+        let weight = base_weight.reshape((rows, ()))?;
+        weight.broadcast_mul(&scale.reshape((rows, 1))?)?
+    };
+
+    rescaled.reshape(dims)
+}
+
 fn reshape_keep_first_dim(tensor: &Tensor) -> candle_core::Result<Tensor> {
     // Equivalent to tensor.reshape(tensor.size(0), -1)
     let shape = tensor.shape();
@@ -117,18 +172,30 @@ fn rebuild_tucker(t: &Tensor, wa: &Tensor, wb: &Tensor) -> candle_core::Result<T
     // wa shape: [i, p]
     // wb shape: [j, r]
     // output shape: [p, r, ...]
-
-    // Method 1: Using two sequential matrix multiplications
-    // Step 1: Contract wa with t along dimension i
-    // wa.T @ t: [p, i] @ [i, j, ...] -> [p, j, ...]
-    let wa_t = wa.transpose(0, 1)?; // [p, i]
-    let temp = wa_t.matmul(t)?; // [p, j, ...]
-
-    // Step 2: Contract temp with wb along dimension j
-    // temp @ wb: [p, j, ...] @ [j, r] -> [p, r, ...]
-    let result = temp.matmul(wb)?;
-
-    Ok(result)
+    let dims = t.dims();
+    let (i, j) = (dims[0], dims[1]);
+    let kernel = &dims[2..];
+    let k: usize = kernel.iter().product();
+    let p = wa.dim(1)?;
+    let r = wb.dim(1)?;
+
+    // Contract i: wa.T @ t.view(i, -1) -> [p, j, k]
+    let temp = wa.t()?.matmul(&t.reshape((i, j * k))?)?;
+
+    // Contract j: [p, k, j] @ [j, r] -> [p, k, r]
+    let temp = temp
+        .reshape((p, j, k))?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape((p * k, j))?
+        .matmul(wb)?;
+
+    let mut out_dims = vec![p, r];
+    out_dims.extend_from_slice(kernel);
+    temp.reshape((p, k, r))?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape(out_dims)
 }
 
 fn rebuild_lokr_w2(w2_a: &Tensor, w2_b: &Tensor) -> Result<Tensor, candle_core::Error> {
@@ -191,6 +258,10 @@ pub enum DType {
     F64,
     I64,
     U64,
+    /// fp8 with 4 exponent and 3 mantissa bits, no infinities
+    F8E4M3,
+    /// fp8 with 5 exponent and 2 mantissa bits
+    F8E5M2,
 }
 
 impl fmt::Display for DType {
@@ -209,6 +280,77 @@ impl fmt::Display for DType {
             DType::F64 => write!(f, "fp64"),
             DType::I64 => write!(f, "i64"),
             DType::U64 => write!(f, "u64"),
+            DType::F8E4M3 => write!(f, "fp8_e4m3"),
+            DType::F8E5M2 => write!(f, "fp8_e5m2"),
+        }
+    }
+}
+
+impl DType {
+    /// Size of a single element in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            DType::Bool | DType::U8 | DType::I8 | DType::F8E4M3 | DType::F8E5M2 => 1,
+            DType::I16 | DType::U16 | DType::F16 | DType::BF16 => 2,
+            DType::I32 | DType::U32 | DType::F32 => 4,
+            DType::F64 | DType::I64 | DType::U64 => 8,
+        }
+    }
+
+    /// The dtype tag used in the safetensors header JSON.
+    pub fn safetensors_name(&self) -> &'static str {
+        match self {
+            DType::Bool => "BOOL",
+            DType::U8 => "U8",
+            DType::I8 => "I8",
+            DType::I16 => "I16",
+            DType::U16 => "U16",
+            DType::F16 => "F16",
+            DType::BF16 => "BF16",
+            DType::I32 => "I32",
+            DType::U32 => "U32",
+            DType::F32 => "F32",
+            DType::F64 => "F64",
+            DType::I64 => "I64",
+            DType::U64 => "U64",
+            DType::F8E4M3 => "F8_E4M3",
+            DType::F8E5M2 => "F8_E5M2",
+        }
+    }
+
+    /// The dtype for a safetensors header tag, see `safetensors_name`.
+    pub fn from_safetensors_name(name: &str) -> Option<DType> {
+        match name {
+            "BOOL" => Some(DType::Bool),
+            "U8" => Some(DType::U8),
+            "I8" => Some(DType::I8),
+            "I16" => Some(DType::I16),
+            "U16" => Some(DType::U16),
+            "F16" => Some(DType::F16),
+            "BF16" => Some(DType::BF16),
+            "I32" => Some(DType::I32),
+            "U32" => Some(DType::U32),
+            "F32" => Some(DType::F32),
+            "F64" => Some(DType::F64),
+            "I64" => Some(DType::I64),
+            "U64" => Some(DType::U64),
+            "F8_E4M3" => Some(DType::F8E4M3),
+            "F8_E5M2" => Some(DType::F8E5M2),
+            _ => None,
+        }
+    }
+
+    /// The matching candle dtype, if candle can hold tensors of this type.
+    pub fn to_candle(self) -> Option<candle_core::DType> {
+        match self {
+            DType::U8 => Some(candle_core::DType::U8),
+            DType::U32 => Some(candle_core::DType::U32),
+            DType::I64 => Some(candle_core::DType::I64),
+            DType::F16 => Some(candle_core::DType::F16),
+            DType::BF16 => Some(candle_core::DType::BF16),
+            DType::F32 => Some(candle_core::DType::F32),
+            DType::F64 => Some(candle_core::DType::F64),
+            _ => None,
         }
     }
 }
@@ -298,6 +440,7 @@ impl WeightKey for BufferedLoRAWeight {
         let glora_b1 = &mut self.keys_by_key("b1");
         let glora_a2 = &mut self.keys_by_key("a2");
         let glora_b2 = &mut self.keys_by_key("b1");
+        let full = &mut self.keys().into_iter().filter(|k| is_full_key(k)).collect();
         let mut keys = self.keys_by_key("weight");
         keys.append(hada);
         keys.append(lokr);
@@ -307,6 +450,7 @@ impl WeightKey for BufferedLoRAWeight {
         keys.append(glora_b1);
         keys.append(glora_a2);
         keys.append(glora_b2);
+        keys.append(full);
 
         keys
     }
@@ -453,7 +597,7 @@ impl Weight for BufferedLoRAWeight {
         let w1 = self.get(&lokr_w1);
         let w2 = self.get(&lokr_w2);
 
-        let (mut w1, w2, scale) = match (w1, w2) {
+        let (w1, w2, scale) = match (w1, w2) {
             (Ok(w1), Ok(w2)) => Ok((w1, w2, 1.0)),
             (
                 Ok(w1),
@@ -471,7 +615,8 @@ impl Weight for BufferedLoRAWeight {
                     rebuild_lokr_w2(&w2_a, &w2_b)?
                 };
 
-                let dims = w2_a.dims();
+                // lokr_w2_b leads with the rank, with or without Tucker
+                let dims = w2_b.dims();
 
                 let scale = alpha
                     .to_dtype(candle_core::DType::F64)?
@@ -487,8 +632,10 @@ impl Weight for BufferedLoRAWeight {
                 Ok(w2),
             ) => {
                 let w1_a = self.get(&lokr_w1_a)?;
-                let w1 = w1_a.matmul(&self.get(&lokr_w1_b)?)?;
-                let dims = w1_a.dims();
+                let w1_b = self.get(&lokr_w1_b)?;
+                let w1 = w1_a.matmul(&w1_b)?;
+                // lokr_w1_b is (rank, in)
+                let dims = w1_b.dims();
 
                 let scale = alpha
                     .to_dtype(candle_core::DType::F64)?
@@ -506,7 +653,8 @@ impl Weight for BufferedLoRAWeight {
             ) => {
                 let w1_a = self.get(&lokr_w1_a)?;
                 // need to get w1 and w2
-                let w1 = w1_a.matmul(&self.get(&lokr_w1_b)?)?;
+                let w1_b = self.get(&lokr_w1_b)?;
+                let w1 = w1_a.matmul(&w1_b)?;
 
                 // need to get w2
                 let w2_a = self.get(&lokr_w2_a)?;
@@ -518,7 +666,8 @@ impl Weight for BufferedLoRAWeight {
                     rebuild_lokr_w2(&w2_a, &w2_b)?
                 };
 
-                let dims = w1_a.dims();
+                // lokr_w1_b is (rank, in)
+                let dims = w1_b.dims();
 
                 let scale = alpha
                     .to_dtype(candle_core::DType::F64)?
@@ -532,11 +681,6 @@ impl Weight for BufferedLoRAWeight {
             }),
         }?;
 
-        for _ in 0..w2.dims().len() - w1.dims().len() {
-            w1 = w1.unsqueeze(candle_core::D::Minus1)?;
-        }
-
-        // TODO: Need to handle different size dimensions between w1 and w2
         let rebuild = kron(&w1, &w2.contiguous()?)?;
 
         if scale != 1.0 {
@@ -573,6 +717,30 @@ impl Weight for BufferedLoRAWeight {
         w2b.matmul(&w1b)?.add(&w2a.matmul(&w1a)?)?.mul(scale)
     }
 
+    fn scale_ia3_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error> {
+        // IA3 are not scaled by alpha
+        self.get(&format!("{}.weight", base_name))?.flatten_all()
+    }
+
+    fn ia3_on_input(&self, base_name: &str) -> Result<bool, candle_core::Error> {
+        // Saved as a BOOL tensor, which candle can not load
+        match self.buffered.get(&format!("{}.on_input", base_name)) {
+            Ok(view) => Ok(view.data().iter().any(|b| *b != 0)),
+            Err(candle_core::Error::SafeTensor(safetensors::SafeTensorError::TensorNotFound(
+                _,
+            ))) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn scale_full_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error> {
+        first_of(|k| self.get(k), base_name, &["diff", "w_norm"])
+    }
+
+    fn bias_diff(&self, base_name: &str) -> Result<Tensor, candle_core::Error> {
+        first_of(|k| self.get(k), base_name, &["diff_b", "b_norm"])
+    }
+
     fn scale_diag_oft_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error> {
         // Diag OFT are not scaled by alpha
         let oft_diag_key = format!("{}.oft_diag", base_name);
@@ -733,6 +901,11 @@ impl BufferedLoRAWeight {
     pub fn load(&self, name: &str) -> Result<Tensor, candle_core::Error> {
         self.buffered.get(name)?.load(&self.device)
     }
+
+    /// The device the tensors are loaded on.
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
 }
 
 pub trait WeightKey {
@@ -779,8 +952,18 @@ pub trait Weight {
     fn scale_hada_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error>;
     #[allow(dead_code)]
     fn scale_lokr_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error>;
-
+    /// IA3 scaling vector. The delta needs the base weight, see `apply_ia3`.
+    fn scale_ia3_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error>;
+    fn ia3_on_input(&self, base_name: &str) -> Result<bool, candle_core::Error>;
+    /// LyCORIS `full` diff or `train_norm` weight update, not scaled by alpha.
+    fn scale_full_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error>;
+    /// Bias update of a `full` or norm layer.
+    fn bias_diff(&self, base_name: &str) -> Result<Tensor, candle_core::Error>;
+
+    /// Raw `oft_diag`/`oft_blocks` parameters; see `crate::oft` for the rotation.
+    #[cfg_attr(not(test), allow(dead_code))]
     fn scale_diag_oft_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error>;
+    #[cfg_attr(not(test), allow(dead_code))]
     fn scale_boft_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error>;
 
     fn alphas(&self) -> HashSet<Alpha>;
@@ -833,10 +1016,12 @@ impl WeightKey for LoRAWeight {
         let hada = &mut self.keys_by_key("hada_w1");
         let lokr = &mut self.keys_by_key("lokr_w1");
         let oft_diag = &mut self.keys_by_key("oft_diag");
+        let full = &mut self.keys().into_iter().filter(|k| is_full_key(k)).collect();
         let mut keys = self.keys_by_key("weight");
         keys.append(hada);
         keys.append(lokr);
         keys.append(oft_diag);
+        keys.append(full);
 
         keys
     }
@@ -1099,8 +1284,10 @@ impl Weight for LoRAWeight {
         let (w1, w2, scale) = match (w1, w2) {
             (None, Some(w2)) => {
                 let w1_a = self.get(&lokr_w1_a)?;
-                let w1 = w1_a.matmul(&self.get(&lokr_w1_b)?)?;
-                let dims = w1_a.dims();
+                let w1_b = self.get(&lokr_w1_b)?;
+                let w1 = w1_a.matmul(&w1_b)?;
+                // lokr_w1_b is (rank, in)
+                let dims = w1_b.dims();
 
                 let scale = alpha
                     .to_dtype(candle_core::DType::F64)?
@@ -1111,7 +1298,8 @@ impl Weight for LoRAWeight {
             (None, None) => {
                 let w1_a = self.get(&lokr_w1_a)?;
                 // need to get w1 and w2
-                let w1 = w1_a.matmul(&self.get(&lokr_w1_b)?)?;
+                let w1_b = self.get(&lokr_w1_b)?;
+                let w1 = w1_a.matmul(&w1_b)?;
 
                 // need to get w2
                 let w2_a = self.get(&lokr_w2_a)?;
@@ -1123,7 +1311,8 @@ impl Weight for LoRAWeight {
                     rebuild_lokr_w2(&w2_a, &w2_b)?
                 };
 
-                let dims = w1_a.dims();
+                // lokr_w1_b is (rank, in)
+                let dims = w1_b.dims();
 
                 let scale = alpha
                     .to_dtype(candle_core::DType::F64)?
@@ -1144,7 +1333,8 @@ impl Weight for LoRAWeight {
                     rebuild_lokr_w2(&w2_a, &w2_b)?
                 };
 
-                let dims = w2_a.dims();
+                // lokr_w2_b leads with the rank, with or without Tucker
+                let dims = w2_b.dims();
 
                 let scale = alpha
                     .to_dtype(candle_core::DType::F64)?
@@ -1155,7 +1345,6 @@ impl Weight for LoRAWeight {
             }
         };
 
-        // TODO: Need to handle different size dimensions between w1 and w2
         let rebuild = kron(&w1, &w2.contiguous()?)?;
 
         if scale != 1.0 {
@@ -1165,6 +1354,30 @@ impl Weight for LoRAWeight {
         }
     }
 
+    fn scale_ia3_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error> {
+        // IA3 are not scaled by alpha
+        self.get(&format!("{}.weight", base_name))?.flatten_all()
+    }
+
+    fn ia3_on_input(&self, base_name: &str) -> Result<bool, candle_core::Error> {
+        match self.tensors.get(&format!("{}.on_input", base_name)) {
+            Some(on_input) => Ok(on_input
+                .to_dtype(candle_core::DType::F64)?
+                .sum_all()?
+                .to_scalar::<f64>()?
+                != 0.0),
+            None => Ok(false),
+        }
+    }
+
+    fn scale_full_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error> {
+        first_of(|k| self.get(k), base_name, &["diff", "w_norm"])
+    }
+
+    fn bias_diff(&self, base_name: &str) -> Result<Tensor, candle_core::Error> {
+        first_of(|k| self.get(k), base_name, &["diff_b", "b_norm"])
+    }
+
     fn scale_diag_oft_weight(&self, base_name: &str) -> Result<Tensor, candle_core::Error> {
         // Diag OFT are not scaled by alpha
         let oft_diag_key = format!("{}.oft_diag", base_name);
@@ -1745,6 +1958,155 @@ mod tests {
         Ok(())
     }
 
+    fn values(dims: &[usize], offset: f32) -> Tensor {
+        let n: usize = dims.iter().product();
+        let values: Vec<f32> = (0..n).map(|v| (v as f32 * 0.37 + offset).sin()).collect();
+        Tensor::from_vec(values, dims, &Device::Cpu).unwrap()
+    }
+
+    fn lokr_conv_weight(tensors: &[(&str, &Tensor)]) -> BufferedLoRAWeight {
+        let mut writer = crate::writer::SafetensorsWriter::new();
+        for (suffix, tensor) in tensors {
+            writer
+                .insert_tensor(
+                    &format!("lora_unet_mid_block_resnets_0_conv1.{suffix}"),
+                    tensor,
+                )
+                .unwrap();
+        }
+        writer
+            .insert_tensor(
+                "lora_unet_mid_block_resnets_0_conv1.alpha",
+                &Tensor::new(1f32, &Device::Cpu).unwrap(),
+            )
+            .unwrap();
+        BufferedLoRAWeight::new(writer.serialize().unwrap(), &Device::Cpu).unwrap()
+    }
+
+    /// LyCORIS `make_kron` on a conv layer, written out element by element:
+    /// `delta[a * o + p, b * i + r, h, w] = w1[a, b] * w2[p, r, h, w] * scale`
+    fn reference_lokr_conv(w1: &Tensor, w2: &Tensor, scale: f32) -> Vec<f32> {
+        let w1_values: Vec<Vec<f32>> = w1.to_vec2().unwrap();
+        let w2_values: Vec<f32> = w2.flatten_all().unwrap().to_vec1().unwrap();
+        let (a_n, b_n) = w1.dims2().unwrap();
+        let (o, i, kh, kw) = w2.dims4().unwrap();
+
+        let mut out = vec![0.; a_n * o * b_n * i * kh * kw];
+        for (a, row) in w1_values.iter().enumerate() {
+            for (b, w1_value) in row.iter().enumerate() {
+                for p in 0..o {
+                    for r in 0..i {
+                        for k in 0..kh * kw {
+                            let row = a * o + p;
+                            let col = b * i + r;
+                            out[(row * b_n * i + col) * kh * kw + k] =
+                                w1_value * w2_values[(p * i + r) * kh * kw + k] * scale;
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn assert_close(result: &Tensor, expected: &[f32]) {
+        let result: Vec<f32> = result.flatten_all().unwrap().to_vec1().unwrap();
+        assert_eq!(result.len(), expected.len());
+        for (r, e) in result.iter().zip(expected) {
+            assert!((r - e).abs() < 1e-5, "{r} != {e}");
+        }
+    }
+
+    #[test]
+    fn buffered_scale_lokr_conv_weight() -> candle_core::Result<()> {
+        let base = "lora_unet_mid_block_resnets_0_conv1";
+        let w1 = values(&[2, 2], 0.);
+        let w2_a = values(&[3, 2], 1.);
+        let w2_b = values(&[2, 4, 3, 3], 2.);
+
+        let lora_weight =
+            lokr_conv_weight(&[("lokr_w1", &w1), ("lokr_w2_a", &w2_a), ("lokr_w2_b", &w2_b)]);
+        let scaled_tensor = lora_weight.scale_lokr_weight(base)?;
+        assert_eq!(scaled_tensor.dims(), &[6, 8, 3, 3]);
+
+        // w2 = w2_a @ w2_b.view(r, -1), scaled by alpha / rank
+        let w2 = w2_a
+            .matmul(&w2_b.reshape((2, 36))?)?
+            .reshape((3, 4, 3, 3))?;
+        assert_close(&scaled_tensor, &reference_lokr_conv(&w1, &w2, 0.5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_scale_lokr_tucker_weight() -> candle_core::Result<()> {
+        let base = "lora_unet_mid_block_resnets_0_conv1";
+        let w1_a = values(&[2, 2], 0.);
+        let w1_b = values(&[2, 2], 0.5);
+        let t2 = values(&[2, 2, 3, 3], 1.);
+        let w2_a = values(&[2, 3], 2.);
+        let w2_b = values(&[2, 4], 3.);
+
+        // decompose_both with a Tucker core for w2
+        let lora_weight = lokr_conv_weight(&[
+            ("lokr_w1_a", &w1_a),
+            ("lokr_w1_b", &w1_b),
+            ("lokr_t2", &t2),
+            ("lokr_w2_a", &w2_a),
+            ("lokr_w2_b", &w2_b),
+        ]);
+        let scaled_tensor = lora_weight.scale_lokr_weight(base)?;
+        assert_eq!(scaled_tensor.dims(), &[6, 8, 3, 3]);
+
+        // einsum("i j ..., i p, j r -> p r ...", t2, w2_a, w2_b)
+        let t2_values: Vec<f32> = t2.flatten_all()?.to_vec1()?;
+        let (wa, wb): (Vec<Vec<f32>>, Vec<Vec<f32>>) = (w2_a.to_vec2()?, w2_b.to_vec2()?);
+        let mut w2 = vec![0f32; 3 * 4 * 9];
+        for p in 0..3 {
+            for r in 0..4 {
+                for k in 0..9 {
+                    for i in 0..2 {
+                        for j in 0..2 {
+                            w2[(p * 4 + r) * 9 + k] +=
+                                t2_values[(i * 2 + j) * 9 + k] * wa[i][p] * wb[j][r];
+                        }
+                    }
+                }
+            }
+        }
+        let w2 = Tensor::from_vec(w2, (3, 4, 3, 3), &Device::Cpu)?;
+
+        let w1 = w1_a.matmul(&w1_b)?;
+        assert_close(&scaled_tensor, &reference_lokr_conv(&w1, &w2, 0.5));
+
+        Ok(())
+    }
+
+    /// LyCORIS `make_kron(w1, rebuild_tucker(t2, w2_a, w2_b), 1)`
+    #[test]
+    fn kron_of_tucker_rebuild() -> candle_core::Result<()> {
+        let t2 = Tensor::new(&[[[[1f32, 2.]]], [[[3., 4.]]]], &Device::Cpu)?;
+        let w2_a = Tensor::new(&[[1f32], [2.]], &Device::Cpu)?;
+        let w2_b = Tensor::new(&[[1f32, -1.]], &Device::Cpu)?;
+        let w1 = Tensor::new(&[[1f32], [2.]], &Device::Cpu)?;
+
+        let w2 = rebuild_tucker(&t2, &w2_a, &w2_b)?;
+        assert_eq!(w2.dims(), &[1, 2, 1, 2]);
+        assert_eq!(
+            w2.flatten_all()?.to_vec1::<f32>()?,
+            vec![7., 10., -7., -10.]
+        );
+
+        let rebuild = kron(&w1, &w2)?;
+        assert_eq!(rebuild.dims(), &[2, 2, 1, 2]);
+        assert_eq!(
+            rebuild.flatten_all()?.to_vec1::<f32>()?,
+            vec![7., 10., -7., -10., 14., 20., -14., -20.]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn buffered_scale_diag_oft_weight() -> candle_core::Result<()> {
         let buffer = load_test_diag_oft_file().unwrap();
@@ -1817,6 +2179,156 @@ mod tests {
         Ok(())
     }
 
+    // IA3
+    // -=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+
+    fn ia3_buffer(on_input: bool) -> Vec<u8> {
+        let base = "lora_unet_mid_block_attentions_0_transformer_blocks_0_attn1_to_k";
+        let mut writer = crate::writer::SafetensorsWriter::new();
+        writer
+            .insert_tensor(
+                &format!("{base}.weight"),
+                &Tensor::new(&[[0.5f32, -1., 2.]], &Device::Cpu).unwrap(),
+            )
+            .unwrap();
+        writer.insert(
+            &format!("{base}.on_input"),
+            crate::writer::TensorBytes {
+                dtype: DType::Bool,
+                shape: vec![],
+                data: vec![on_input as u8],
+            },
+        );
+        writer.insert_metadata("ss_network_module", "lycoris.kohya");
+        writer.insert_metadata("ss_network_args", r#"{"algo": "ia3"}"#);
+        writer.serialize().unwrap()
+    }
+
+    #[test]
+    fn get_base_name_strips_ia3_keys() {
+        let base_name = get_base_name(
+            "lora_unet_mid_block_attentions_0_transformer_blocks_0_attn1_to_k.weight",
+        );
+        assert_eq!(
+            base_name,
+            "lora_unet_mid_block_attentions_0_transformer_blocks_0_attn1_to_k"
+        );
+
+        let base_name = get_base_name(
+            "lora_unet_mid_block_attentions_0_transformer_blocks_0_attn1_to_k.on_input",
+        );
+        assert_eq!(
+            base_name,
+            "lora_unet_mid_block_attentions_0_transformer_blocks_0_attn1_to_k"
+        );
+    }
+
+    #[test]
+    fn buffered_scale_ia3_weight() -> candle_core::Result<()> {
+        let base = "lora_unet_mid_block_attentions_0_transformer_blocks_0_attn1_to_k";
+        let lora_weight = BufferedLoRAWeight::new(ia3_buffer(true), &Device::Cpu)?;
+
+        assert_eq!(lora_weight.base_names(), vec![base.to_string()]);
+        assert!(lora_weight.ia3_on_input(base)?);
+
+        let scaled_tensor = lora_weight.scale_ia3_weight(base)?;
+        assert_eq!(scaled_tensor.dims(), &[3]);
+        assert!((norms::l2::<f32>(&scaled_tensor).unwrap() - 5.25f32.sqrt()).abs() < 1e-6);
+
+        let lora_weight = BufferedLoRAWeight::new(ia3_buffer(false), &Device::Cpu)?;
+        assert!(!lora_weight.ia3_on_input(base)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_ia3_scales_columns_or_rows() -> candle_core::Result<()> {
+        let scale = Tensor::new(&[0.5f32, -1., 2.], &Device::Cpu)?;
+        let base_weight = Tensor::ones((3, 3), candle_core::DType::F32, &Device::Cpu)?;
+
+        let on_input = apply_ia3(&scale, true, &base_weight)?;
+        assert_eq!(on_input.to_vec2::<f32>()?[0], vec![0.5, -1., 2.]);
+
+        let on_output = apply_ia3(&scale, false, &base_weight)?;
+        assert_eq!(on_output.to_vec2::<f32>()?[0], vec![0.5, 0.5, 0.5]);
+
+        // Conv weights scale whole input channels
+        let conv = Tensor::ones((2, 3, 3, 3), candle_core::DType::F32, &Device::Cpu)?;
+        let rescaled = apply_ia3(&scale, true, &conv)?;
+        assert_eq!(rescaled.dims(), &[2, 3, 3, 3]);
+        assert_eq!(rescaled.sum_all()?.to_scalar::<f32>()?, 2. * 9. * 1.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ia3_file_reconstruction() {
+        let base = "lora_unet_mid_block_attentions_0_transformer_blocks_0_attn1_to_k";
+        let buffer = ia3_buffer(true);
+        let file = crate::file::LoRAFile::new_from_buffer(&buffer, "ia3.safetensors", &Device::Cpu);
+
+        assert_eq!(
+            file.metadata().and_then(|m| m.network_type()),
+            Some(crate::network::NetworkType::IA3)
+        );
+        assert_eq!(file.scale_weight(base).unwrap().dims(), &[3]);
+
+        let base_weight = Tensor::ones((2, 3), candle_core::DType::F32, &Device::Cpu).unwrap();
+        let delta = file.ia3_delta(base, &base_weight).unwrap().unwrap();
+        assert_eq!(delta.dims(), &[2, 3]);
+
+        let effective_scale = file.effective_scale_with_base(base, &base_weight).unwrap();
+        assert!((effective_scale.unwrap() - (2. * 5.25f64).sqrt()).abs() < 1e-6);
+
+        // On output the vector has to match the rows instead
+        assert!(file
+            .ia3_delta(
+                base,
+                &Tensor::ones((3, 2), candle_core::DType::F32, &Device::Cpu).unwrap()
+            )
+            .is_err());
+    }
+
+    // GLoKr
+    // -=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+
+    #[test]
+    fn glokr_file_reconstruction() -> candle_core::Result<()> {
+        let device = Device::Cpu;
+        let base = "lora_unet_mid_block_attentions_0_proj_in";
+        let w1 = Tensor::new(&[[1f32, 0.], [0., 2.]], &device)?;
+        let w2_a = Tensor::new(&[[1f32], [2.]], &device)?;
+        let w2_b = Tensor::new(&[[1f32, -1.]], &device)?;
+
+        let mut writer = crate::writer::SafetensorsWriter::new();
+        writer
+            .insert_tensor(&format!("{base}.lokr_w1"), &w1)
+            .unwrap();
+        writer
+            .insert_tensor(&format!("{base}.lokr_w2_a"), &w2_a)
+            .unwrap();
+        writer
+            .insert_tensor(&format!("{base}.lokr_w2_b"), &w2_b)
+            .unwrap();
+        writer
+            .insert_tensor(&format!("{base}.alpha"), &Tensor::new(0.5f32, &device)?)
+            .unwrap();
+        writer.insert_metadata("ss_network_module", "lycoris.kohya");
+        writer.insert_metadata("ss_network_args", r#"{"algo": "glokr"}"#);
+        let buffer = writer.serialize().unwrap();
+
+        let file = crate::file::LoRAFile::new_from_buffer(&buffer, "glokr.safetensors", &device);
+        assert_eq!(file.base_names(), vec![base.to_string()]);
+
+        let scaled_tensor = file.scale_weight(base).unwrap();
+        let expected = kron(&w1, &w2_a.matmul(&w2_b)?)?.affine(0.5, 0.)?;
+        assert_eq!(scaled_tensor.dims(), &[4, 4]);
+        assert_eq!(scaled_tensor.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+        assert!(file.effective_scale(base).unwrap().is_some());
+
+        Ok(())
+    }
+
     // #[test]
     // fn weight_norm_handles_scale_weight_lorafa() {
     //     // Arrange
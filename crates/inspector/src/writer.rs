@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, HashMap};
+
+use candle_core::Tensor;
+use safetensors::View;
+use serde_json::{json, Map, Value};
+
+use crate::weight::DType;
+use crate::{InspectorError, Result};
+
+/// Raw bytes of a single tensor as they will be laid out in a safetensors file.
+#[derive(Debug, Clone)]
+pub struct TensorBytes {
+    pub dtype: DType,
+    pub shape: Vec<usize>,
+    pub data: Vec<u8>,
+}
+
+impl TensorBytes {
+    /// Copies a candle tensor out in its current dtype.
+    pub fn from_tensor(tensor: &Tensor) -> Result<TensorBytes> {
+        let tensor = tensor.contiguous()?;
+        Ok(TensorBytes {
+            dtype: tensor.dtype().into(),
+            shape: tensor.dims().to_vec(),
+            data: View::data(&&tensor).into_owned(),
+        })
+    }
+
+    /// Keeps the first `len` entries along `dim`, working on the raw bytes so
+    /// any dtype can be sliced without decoding it.
+    pub fn narrow(&self, dim: usize, len: usize) -> Result<TensorBytes> {
+        let size = *self.shape.get(dim).ok_or_else(|| {
+            InspectorError::Msg(format!("dim {dim} out of range for {:?}", self.shape))
+        })?;
+        if len > size {
+            return Err(InspectorError::Msg(format!(
+                "cannot narrow dim {dim} of {:?} to {len}",
+                self.shape
+            )));
+        }
+
+        let inner = self.shape[dim + 1..].iter().product::<usize>() * self.dtype.size();
+        let data = self
+            .data
+            .chunks(size * inner)
+            .flat_map(|chunk| &chunk[..len * inner])
+            .copied()
+            .collect();
+
+        let mut shape = self.shape.clone();
+        shape[dim] = len;
+        Ok(TensorBytes {
+            dtype: self.dtype,
+            shape,
+            data,
+        })
+    }
+
+    /// Copies a candle tensor out, converting it to `dtype` first so rewritten
+    /// tensors keep the precision they were stored with.
+    pub fn from_tensor_as(tensor: &Tensor, dtype: DType) -> Result<TensorBytes> {
+        let candle_dtype = dtype.to_candle().ok_or_else(|| {
+            InspectorError::Msg(format!("cannot store a tensor as {dtype} with candle"))
+        })?;
+        TensorBytes::from_tensor(&tensor.to_dtype(candle_dtype)?)
+    }
+}
+
+/// Builds a safetensors file from raw tensor bytes and a metadata map.
+///
+/// Existing files are copied tensor-for-tensor, so operations that only touch
+/// the metadata (or a handful of layers) never re-encode the untouched payload.
+#[derive(Debug, Default, Clone)]
+pub struct SafetensorsWriter {
+    tensors: BTreeMap<String, TensorBytes>,
+    metadata: Option<HashMap<String, String>>,
+}
+
+impl SafetensorsWriter {
+    pub fn new() -> SafetensorsWriter {
+        SafetensorsWriter::default()
+    }
+
+    /// Copies every tensor and the `__metadata__` block from a full safetensors
+    /// buffer. Payloads are copied as raw bytes, so fp8 tensors survive even
+    /// though candle cannot load them.
+    pub fn from_buffer(buffer: &[u8]) -> Result<SafetensorsWriter> {
+        let (header, metadata) = crate::header::parse_header(buffer)?;
+        let data_start = header.data_start();
+
+        let tensors = header
+            .keys()
+            .into_iter()
+            .map(|name| {
+                let entry = header.entry(&name).expect("key from the same header");
+                let (start, end) = entry.data_offsets;
+                let data = buffer
+                    .get(data_start + start..data_start + end)
+                    .filter(|data| {
+                        data.len() == entry.shape.iter().product::<usize>() * entry.dtype.size()
+                    })
+                    .ok_or_else(|| {
+                        InspectorError::Msg(format!("invalid data offsets for tensor {name}"))
+                    })?;
+                Ok((
+                    name,
+                    TensorBytes {
+                        dtype: entry.dtype,
+                        shape: entry.shape.clone(),
+                        data: data.to_vec(),
+                    },
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(SafetensorsWriter { tensors, metadata })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.tensors.keys().cloned().collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TensorBytes> {
+        self.tensors.get(name)
+    }
+
+    pub fn insert(&mut self, name: &str, tensor: TensorBytes) {
+        self.tensors.insert(name.to_string(), tensor);
+    }
+
+    pub fn insert_tensor(&mut self, name: &str, tensor: &Tensor) -> Result<()> {
+        self.insert(name, TensorBytes::from_tensor(tensor)?);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<TensorBytes> {
+        self.tensors.remove(name)
+    }
+
+    pub fn metadata(&self) -> Option<&HashMap<String, String>> {
+        self.metadata.as_ref()
+    }
+
+    pub fn set_metadata(&mut self, metadata: Option<HashMap<String, String>>) {
+        self.metadata = metadata;
+    }
+
+    pub fn insert_metadata(&mut self, key: &str, value: &str) {
+        self.metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// Serializes to the safetensors layout: an 8-byte little-endian header
+    /// length, the header JSON padded to an 8-byte boundary, then the tensor
+    /// payloads in name order.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut header = Map::new();
+
+        if let Some(metadata) = self.metadata.as_ref() {
+            header.insert("__metadata__".to_string(), json!(metadata));
+        }
+
+        let mut offset = 0;
+        for (name, tensor) in self.tensors.iter() {
+            let expected = tensor.shape.iter().product::<usize>() * tensor.dtype.size();
+            if expected != tensor.data.len() {
+                return Err(InspectorError::Msg(format!(
+                    "tensor {name} has {} bytes but shape {:?} of {} needs {expected}",
+                    tensor.data.len(),
+                    tensor.shape,
+                    tensor.dtype
+                )));
+            }
+
+            header.insert(
+                name.clone(),
+                json!({
+                    "dtype": tensor.dtype.safetensors_name(),
+                    "shape": tensor.shape,
+                    "data_offsets": [offset, offset + tensor.data.len()],
+                }),
+            );
+            offset += tensor.data.len();
+        }
+
+        let mut header_bytes = serde_json::to_vec(&Value::Object(header))?;
+        let padding = (8 - header_bytes.len() % 8) % 8;
+        header_bytes.extend(std::iter::repeat_n(b' ', padding));
+
+        let mut buffer = Vec::with_capacity(8 + header_bytes.len() + offset);
+        buffer.extend_from_slice(&(header_bytes.len() as u64).to_le_bytes());
+        buffer.extend_from_slice(&header_bytes);
+        for tensor in self.tensors.values() {
+            buffer.extend_from_slice(&tensor.data);
+        }
+
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use crate::weight::{BufferedLoRAWeight, Weight, WeightKey};
+
+    use super::*;
+
+    #[test]
+    fn serialize_round_trips_tensors_and_metadata() -> crate::Result<()> {
+        let up = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+        let alpha = Tensor::new(4f32, &Device::Cpu)?.to_dtype(candle_core::DType::F16)?;
+
+        let mut writer = SafetensorsWriter::new();
+        writer.insert_tensor("lora_unet_proj.lora_up.weight", &up)?;
+        writer.insert_tensor("lora_unet_proj.alpha", &alpha)?;
+        writer.insert_metadata("ss_network_dim", "2");
+
+        let buffer = writer.serialize()?;
+        assert_eq!(u64::from_le_bytes(buffer[0..8].try_into().unwrap()) % 8, 0);
+
+        let metadata = crate::metadata::Metadata::new_from_buffer(&buffer)?;
+        assert_eq!(
+            metadata.metadata.unwrap().get("ss_network_dim"),
+            Some(&"2".to_string())
+        );
+
+        let weights = BufferedLoRAWeight::new(buffer.clone(), &Device::Cpu)?;
+        assert_eq!(weights.keys().len(), 2);
+        assert_eq!(
+            weights
+                .get("lora_unet_proj.lora_up.weight")?
+                .to_vec2::<f32>()?,
+            vec![vec![1., 2.], vec![3., 4.]]
+        );
+
+        let copy = SafetensorsWriter::from_buffer(&buffer)?.serialize()?;
+        assert_eq!(copy, buffer);
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_tensor_as_converts_dtype() -> crate::Result<()> {
+        let t = Tensor::new(&[1f32, -2.], &Device::Cpu)?;
+        let bytes = TensorBytes::from_tensor_as(&t, DType::BF16)?;
+
+        assert_eq!(bytes.dtype, DType::BF16);
+        assert_eq!(bytes.data.len(), 4);
+
+        Ok(())
+    }
+}
@@ -6,11 +6,48 @@ use web_sys::console;
 
 // extern crate console_panic_hook;
 
+use inspector::base;
+use inspector::blocks;
+use inspector::compat;
+use inspector::dora;
+use inspector::dylora;
 use inspector::file::LoRAFile;
+use inspector::filter::{self, FilterOptions};
+use inspector::lbw::{self, LbwLayout};
+use inspector::lint::{self, LintRule};
 use inspector::metadata::Metadata;
+use inspector::modelspec;
 use inspector::network::{NetworkModule, WeightDecomposition};
+use inspector::oft;
+use inspector::precision;
+use inspector::prune::{self, PruneOptions};
+use inspector::scrub::{self, ScrubProfile};
+use inspector::thumbnail::{self, EmbeddedImage};
+use inspector::training;
 use inspector::{norms, statistic, InspectorError};
 
+/// Decoded image bytes with the MIME type to build a `Blob` from.
+#[wasm_bindgen(getter_with_clone)]
+pub struct ImageData {
+    pub key: String,
+    pub mime: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub bytes: Vec<u8>,
+}
+
+impl From<EmbeddedImage> for ImageData {
+    fn from(image: EmbeddedImage) -> Self {
+        ImageData {
+            mime: image.mime(),
+            key: image.key,
+            width: image.width,
+            height: image.height,
+            bytes: image.data,
+        }
+    }
+}
+
 #[wasm_bindgen]
 pub struct LoraWorker {
     metadata: Metadata,
@@ -300,6 +337,16 @@ impl LoraWorker {
         serde_wasm_bindgen::to_value(&self.metadata.network_type())
     }
 
+    /// Network metadata that could not be parsed, as messages to show as
+    /// warnings.
+    pub fn parse_warnings(&self) -> Vec<String> {
+        self.metadata
+            .parse_warnings()
+            .iter()
+            .map(|w| w.to_string())
+            .collect()
+    }
+
     pub fn format(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
         serde_wasm_bindgen::to_value(&self.file.format())
     }
@@ -339,10 +386,251 @@ impl LoraWorker {
             .unwrap_or(Ok(JsValue::NULL))
     }
 
+    /// Layers grouped by block with the block learning rate weights they were
+    /// trained with, to show next to the block norms.
+    pub fn block_map(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
+        serde_wasm_bindgen::to_value(&self.file.block_map())
+    }
+
+    /// `block_dims` and `conv_block_dims` compared with the saved layers.
+    pub fn block_dims(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
+        serde_wasm_bindgen::to_value(&blocks::block_dims(&self.file))
+    }
+
+    /// Full diff, norm and bias layers, apart from the low-rank layers.
+    pub fn diff_layers(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
+        serde_wasm_bindgen::to_value(&self.file.diff_layers())
+    }
+
+    /// Deviation from identity and orthogonality of every OFT/BOFT rotation.
+    pub fn oft_layers(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
+        serde_wasm_bindgen::to_value(&oft::layers(&self.file))
+    }
+
+    /// DoRA magnitude statistics of every layer with a `dora_scale`.
+    pub fn dora_layers(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
+        serde_wasm_bindgen::to_value(&dora::layers(&self.file))
+    }
+
+    /// DoRA magnitudes compared with the base weights they rescale.
+    /// `base_buffer` is the base model checkpoint.
+    pub fn dora_layers_with_base(&self, base_buffer: &[u8]) -> Result<JsValue, JsValue> {
+        let mut base_model = base::BaseModel::new(std::io::Cursor::new(base_buffer))
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(serde_wasm_bindgen::to_value(&dora::layers_with_base(
+            &self.file,
+            &mut base_model,
+        ))?)
+    }
+
+    /// Effective scale, error and energy of every DyLoRA rank slice, per layer.
+    pub fn dylora_layers(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
+        serde_wasm_bindgen::to_value(&dylora::layers(&self.file))
+    }
+
+    pub fn dylora_summary(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
+        serde_wasm_bindgen::to_value(&dylora::summary(&dylora::layers(&self.file)))
+    }
+
+    /// Truncates the full file `buffer` to `rank`. The worker does not keep the
+    /// file bytes, so they are passed back in.
+    pub fn dylora_truncate(&self, buffer: &[u8], rank: usize) -> Result<Vec<u8>, JsValue> {
+        dylora::truncate(buffer, rank, dylora::unit(&self.metadata))
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Keeps the layers of the full file `buffer` that match `include` and
+    /// not `exclude`, see `filter::Selector` for the patterns.
+    pub fn filter(
+        &self,
+        buffer: &[u8],
+        include: Vec<String>,
+        exclude: Vec<String>,
+    ) -> Result<Vec<u8>, JsValue> {
+        let selectors = |patterns: Vec<String>| {
+            patterns
+                .iter()
+                .map(|p| p.parse())
+                .collect::<Result<Vec<filter::Selector>, InspectorError>>()
+        };
+        let options = FilterOptions {
+            include: selectors(include).map_err(|e| JsValue::from_str(&e.to_string()))?,
+            exclude: selectors(exclude).map_err(|e| JsValue::from_str(&e.to_string()))?,
+        };
+        filter::filter(buffer, &options)
+            .map(|(buffer, _)| buffer)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// LoRA Block Weight preset from the block norms. The layout is detected
+    /// when `layout` is `None`.
+    pub fn lbw_preset(
+        &self,
+        layout: Option<String>,
+        percentile: Option<f64>,
+        normalize: bool,
+    ) -> Result<JsValue, JsValue> {
+        let layout = match layout {
+            Some(layout) => layout
+                .parse::<LbwLayout>()
+                .map_err(|e| JsValue::from_str(&e.to_string()))?,
+            None => LbwLayout::detect(&self.file)
+                .ok_or_else(|| JsValue::from_str("Could not detect the block layout"))?,
+        };
+        let norms = lbw::slot_norms(&self.file).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let preset = lbw::preset(
+            layout,
+            &norms,
+            &lbw::PresetOptions {
+                percentile,
+                normalize,
+            },
+        );
+        serde_wasm_bindgen::to_value(&preset).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Bakes the block `weights` string into the full file `buffer`.
+    pub fn lbw_apply(
+        &self,
+        buffer: &[u8],
+        weights: &str,
+        layout: Option<String>,
+    ) -> Result<Vec<u8>, JsValue> {
+        let layout = layout
+            .map(|l| l.parse::<LbwLayout>())
+            .transpose()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        lbw::parse_weights(weights)
+            .and_then(|weights| {
+                lbw::apply(
+                    buffer,
+                    &weights,
+                    layout,
+                    inspector::rescale::ScaleMethod::Alpha,
+                )
+            })
+            .map(|(buffer, _)| buffer)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// What converting the full file `buffer` to `dtype` would lose, per tensor
+    /// and per layer.
+    pub fn precision_report(&self, buffer: &[u8], dtype: &str) -> Result<JsValue, JsValue> {
+        precision::parse_precision(dtype)
+            .and_then(|dtype| precision::simulate(buffer, dtype))
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+            .and_then(|report| {
+                serde_wasm_bindgen::to_value(&report).map_err(|e| JsValue::from_str(&e.to_string()))
+            })
+    }
+
+    /// The full file `buffer` with its tensors converted to `dtype`.
+    pub fn convert_dtype(&self, buffer: &[u8], dtype: &str) -> Result<Vec<u8>, JsValue> {
+        precision::parse_precision(dtype)
+            .and_then(|dtype| precision::convert(buffer, dtype))
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Drops weak or collapsed layers of the full file `buffer` and truncates
+    /// the rest to `energy` of their singular value energy.
+    pub fn prune(
+        &self,
+        buffer: &[u8],
+        min_scale: Option<f64>,
+        health: Vec<String>,
+        energy: Option<f64>,
+    ) -> Result<Vec<u8>, JsValue> {
+        let options = PruneOptions {
+            min_scale,
+            health: health
+                .iter()
+                .map(|h| prune::parse_health(h))
+                .collect::<Result<_, InspectorError>>()
+                .map_err(|e| JsValue::from_str(&e.to_string()))?,
+            energy,
+        };
+        prune::prune(buffer, &options)
+            .map(|(buffer, _)| buffer)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Update of every layer relative to the base model weight it applies to.
+    /// `base_buffer` is the base model checkpoint.
+    pub fn base_layers(&self, base_buffer: &[u8]) -> Result<JsValue, JsValue> {
+        let mut base_model = base::BaseModel::new(std::io::Cursor::new(base_buffer))
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(serde_wasm_bindgen::to_value(&base::analyze(
+            &self.file,
+            &mut base_model,
+        ))?)
+    }
+
+    /// Whether the LoRA fits the base model. `base_header` only needs the
+    /// header of the checkpoint, not its tensors.
+    pub fn compatibility(&self, base_header: &[u8]) -> Result<JsValue, JsValue> {
+        let report = compat::check(&self.file, &mut std::io::Cursor::new(base_header))
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(serde_wasm_bindgen::to_value(&report)?)
+    }
+
     pub fn tensor_info(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
         serde_wasm_bindgen::to_value(&self.file.tensor_info())
     }
 
+    /// Report of what `profile` (minimal, share-safe or strip-all) would
+    /// remove or redact from the metadata.
+    pub fn scrub_report(&self, profile: &str) -> Result<JsValue, JsValue> {
+        let profile = profile
+            .parse::<ScrubProfile>()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let (_cleaned, report) = scrub::scrub(&self.metadata, profile);
+        serde_wasm_bindgen::to_value(&report).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Runs the lint rules with the given ids, or every rule when `rules` is
+    /// empty. Tensor rules are skipped until the weights are loaded.
+    pub fn lint(&self, rules: Vec<String>, skip: Vec<String>) -> Result<JsValue, JsValue> {
+        let rules =
+            LintRule::select(&rules, &skip).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let report = lint::lint(&self.file, &rules);
+        serde_wasm_bindgen::to_value(&report).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Parsed modelspec with its spec violations and disagreements with the
+    /// `ss_*` keys.
+    pub fn modelspec(&self) -> Result<JsValue, JsValue> {
+        serde_wasm_bindgen::to_value(&modelspec::report(&self.metadata))
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// The modelspec thumbnail, if the file has a valid one.
+    pub fn cover_image(&self) -> Option<ImageData> {
+        match thumbnail::cover_image(&self.metadata)? {
+            Ok(image) => Some(image.into()),
+            Err(e) => {
+                console::error_1(&format!("cover image error: {e}").into());
+                None
+            }
+        }
+    }
+
+    /// Key, format and dimensions of every image embedded in the metadata.
+    pub fn embedded_images(&self) -> Result<JsValue, serde_wasm_bindgen::Error> {
+        serde_wasm_bindgen::to_value(&thumbnail::embedded_images(&self.metadata))
+    }
+
+    pub fn embedded_image(&self, key: &str) -> Result<ImageData, JsValue> {
+        thumbnail::image(&self.metadata, key)
+            .map(ImageData::from)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Training command, config and dataset TOML rebuilt from the metadata.
+    pub fn training_config(&self) -> Result<JsValue, JsValue> {
+        let config = training::training_config(&self.metadata);
+        serde_wasm_bindgen::to_value(&config.files()).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
     pub fn effective_scales_all(&self) -> Result<JsValue, JsValue> {
         console_error_panic_hook::set_once();
         let scales = self.file.effective_scales_all();
@@ -1,9 +1,18 @@
 use clap::{Parser, Subcommand};
 use inspector::metadata::compare_metadata;
-use inspector::{file, metadata, norms, statistic, InspectorError};
+use inspector::{
+    base, blocks, compat, dora, dylora, extract, file, filter, lbw, lint, metadata, modelspec,
+    norms, oft, precision, prune, rescale, scrub, statistic, thumbnail, training, writer,
+    InspectorError,
+};
 use serde::{Deserialize, Serialize};
 use std::io;
-use std::{collections::HashMap, fs::File, io::Read, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::Read,
+    path::PathBuf,
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about = "Inspect LoRA file weights and norms")]
@@ -34,155 +43,1543 @@ enum Command {
         #[clap(long)]
         file2: PathBuf,
     },
+
+    /// Remove private dataset details from the metadata
+    Scrub {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Scrub profile: minimal, share-safe or strip-all
+        #[clap(short, long, default_value = "share-safe")]
+        profile: String,
+
+        /// Where to write the cleaned file. Only reports when omitted.
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Check the metadata against the tensors for inconsistencies
+    Lint {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Rule ids to run, comma separated. Runs every rule when omitted.
+        #[clap(long, value_delimiter = ',')]
+        rules: Vec<String>,
+
+        /// Rule ids to skip, comma separated
+        #[clap(long, value_delimiter = ',')]
+        skip: Vec<String>,
+
+        /// List the available rules and exit
+        #[clap(long)]
+        list: bool,
+    },
+
+    /// Compare block_dims/block_alphas with the rank and alpha of the saved layers
+    BlockDims {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Also show blocks that match
+        #[clap(long)]
+        all: bool,
+    },
+
+    /// Analyze each DyLoRA rank slice and optionally export a truncated file
+    Dylora {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Show the slices of every layer, not just the summary
+        #[clap(long)]
+        layers: bool,
+
+        /// Rank to truncate to when writing --output
+        #[clap(long, requires = "output")]
+        rank: Option<usize>,
+
+        /// Where to write the truncated file
+        #[clap(long, requires = "rank")]
+        output: Option<PathBuf>,
+    },
+
+    /// Statistics of the DoRA magnitude vectors
+    Dora {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Base model checkpoint, to compare the magnitudes with the column
+        /// norms of the weights they rescale
+        #[clap(short, long)]
+        base: Option<PathBuf>,
+    },
+
+    /// Deviation from identity and orthogonality of OFT/BOFT rotations
+    Oft {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+    },
+
+    /// Compare each layer's update with the base model weight it applies to
+    Base {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Path to the base model checkpoint
+        #[clap(short, long)]
+        base: PathBuf,
+    },
+
+    /// Check whether the LoRA fits a base model, reading only the
+    /// checkpoint's header
+    Compat {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Path to the base model checkpoint
+        #[clap(short, long)]
+        base: PathBuf,
+    },
+
+    /// Extract a LoRA from the difference of a tuned and a base checkpoint
+    Extract {
+        /// Path to the base model checkpoint
+        #[clap(short, long)]
+        base: PathBuf,
+
+        /// Path to the tuned checkpoint
+        #[clap(short, long)]
+        tuned: PathBuf,
+
+        /// Where to write the LoRA
+        #[clap(short, long)]
+        output: PathBuf,
+
+        /// Rank of every layer, or the largest rank with --energy
+        #[clap(long, default_value_t = 32)]
+        rank: usize,
+
+        /// Keep the smallest rank that holds this fraction of each layer's
+        /// update energy, e.g. 0.9
+        #[clap(long)]
+        energy: Option<f64>,
+
+        /// Rank of 3x3 conv layers. Leaves them out when omitted.
+        #[clap(long)]
+        conv_rank: Option<usize>,
+
+        /// Save precision: fp16, bf16 or fp32
+        #[clap(long, default_value = "fp16")]
+        precision: String,
+    },
+
+    /// Rescale layers to a target effective scale
+    Normalize {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Layers to rescale: outliers, all, or a block like down_03 or double_07
+        #[clap(long, default_value = "outliers")]
+        layers: String,
+
+        /// Effective scale to rescale to. The median of the layers when omitted.
+        #[clap(long)]
+        target: Option<f64>,
+
+        /// How to rescale: alpha or factors
+        #[clap(long, default_value = "alpha")]
+        method: String,
+
+        /// Where to write the rescaled file. Only reports when omitted.
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Store a new alpha, rescaling the factors so the LoRA applies the same
+    Alpha {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// New alpha: a number, rank or sqrt-rank. The convention the file
+        /// was trained with when omitted, sqrt-rank for rsLoRA.
+        #[clap(long)]
+        alpha: Option<String>,
+
+        /// Where to write the file
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+
+    /// Rewrite the factors of each layer so up and down carry the same norm
+    Rebalance {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Where to write the file
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+
+    /// Write a file with only some of the layers
+    Filter {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Layers to keep: unet, te, te1, te2, block:down_03 or a glob over
+        /// base names like '*attn1*'. Every layer when omitted.
+        #[clap(long)]
+        include: Vec<String>,
+
+        /// Layers to drop, in the same patterns as --include
+        #[clap(long)]
+        exclude: Vec<String>,
+
+        /// Where to write the file
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+
+    /// Derive a LoRA Block Weight preset from the block norms, or apply one
+    Lbw {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Block layout: sd1, sd1-full, sdxl or flux. Detected when omitted.
+        #[clap(long)]
+        layout: Option<String>,
+
+        /// Zero the blocks with a norm below this percentile of the block norms
+        #[clap(long)]
+        percentile: Option<f64>,
+
+        /// Weigh blocks by their norm over the strongest block's
+        #[clap(long)]
+        normalize: bool,
+
+        /// Block weights to bake into the file, like 1,0,0,0,1,1,...
+        #[clap(long, requires = "output")]
+        apply: Option<String>,
+
+        /// How to scale the layers when applying: alpha or factors. 0 weights
+        /// always zero the factors, loaders read an alpha of 0 as the rank.
+        #[clap(long, default_value = "alpha")]
+        method: String,
+
+        /// Where to write the file with the block weights applied
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Write the file in another precision, or report what each precision
+    /// would lose
+    ConvertDtype {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// fp32, fp16, bf16, fp8_e4m3 or fp8_e5m2. Every precision is
+        /// reported when omitted.
+        #[clap(long)]
+        precision: Option<String>,
+
+        /// Largest relative change of an update still considered safe
+        #[clap(long, default_value_t = 1e-2)]
+        tolerance: f64,
+
+        /// Where to write the converted file. Only reports when omitted.
+        #[clap(short, long, requires = "precision")]
+        output: Option<PathBuf>,
+    },
+
+    /// Drop weak or collapsed layers and cut the tail of each layer's rank
+    Prune {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Drop layers with an effective scale below this
+        #[clap(long)]
+        min_scale: Option<f64>,
+
+        /// Drop layers with this rank health: collapsed, poor, weak or ok.
+        /// Can be given more than once.
+        #[clap(long)]
+        health: Vec<String>,
+
+        /// Keep the singular values carrying this fraction (0-1) of each
+        /// layer's energy
+        #[clap(long)]
+        energy: Option<f64>,
+
+        /// Where to write the file
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+
+    /// Rebuild the training command and dataset config from the metadata
+    TrainingConfig {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Directory to write config.toml and dataset.toml to. Prints them
+        /// when omitted.
+        #[clap(long)]
+        output_dir: Option<PathBuf>,
+    },
+
+    /// Show, validate and update the modelspec metadata
+    Modelspec {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Set a modelspec key, e.g. --set title="My LoRA". Repeatable.
+        #[clap(long)]
+        set: Vec<String>,
+
+        /// Fill missing modelspec keys from the kohya ss_* metadata
+        #[clap(long)]
+        fill: bool,
+
+        /// Where to write the updated file
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Write the images embedded in the metadata to disk
+    Images {
+        /// Path to the safetensors file
+        #[clap(short, long)]
+        file: PathBuf,
+
+        /// Directory to write the images to
+        #[clap(short, long, default_value = ".")]
+        output_dir: PathBuf,
+
+        /// Only write the image stored under this metadata key
+        #[clap(long)]
+        key: Option<String>,
+    },
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct WeightStatistics {
+    kind: file::LayerKind,
+    l1_norm: f64,
+    l2_norm: f64,
+    matrix_norm: f64,
+    max: f64,
+    min: f64,
+    std_dev: Option<f64>,
+    median: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct BlockData {
+    weights: HashMap<String, WeightStatistics>,
+    average_l2_norm: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct OutputData {
+    metadata: Option<HashMap<String, String>>,
+    base_names: Vec<String>,
+    norms: HashMap<String, WeightStatistics>,
+    blocks: HashMap<String, BlockData>,
+    /// Block learning rate weights from the network args, by U-Net block
+    block_lr_weights: BTreeMap<String, f64>,
+    non_block_weights: HashMap<String, WeightStatistics>,
+    /// Full diff, norm and bias layers, kept out of the block averages
+    diff_weights: HashMap<String, WeightStatistics>,
+    /// Network metadata that could not be parsed
+    warnings: Vec<String>,
+}
+
+pub type Result<T> = std::result::Result<T, LoraInspectorError>;
+
+#[derive(Debug)]
+pub enum LoraInspectorError {
+    Json(serde_json::Error),
+    Io(io::Error),
+    Inspector(InspectorError),
+}
+
+impl From<serde_json::Error> for LoraInspectorError {
+    fn from(err: serde_json::Error) -> LoraInspectorError {
+        LoraInspectorError::Json(err)
+    }
+}
+
+impl From<io::Error> for LoraInspectorError {
+    fn from(err: io::Error) -> LoraInspectorError {
+        LoraInspectorError::Io(err)
+    }
+}
+
+impl From<InspectorError> for LoraInspectorError {
+    fn from(err: InspectorError) -> LoraInspectorError {
+        LoraInspectorError::Inspector(err)
+    }
+}
+
+// Parses a weight name to determine if it belongs to a block and which one
+fn parse_block_info(weight_name: &str) -> Option<(String, String)> {
+    // Check for "blocks_XX" pattern
+    if let Some(blocks_idx) = weight_name.find("blocks_") {
+        // Find where the block number starts
+        let num_start = blocks_idx + "blocks_".len();
+
+        // Find where the block number ends (next underscore)
+        if let Some(num_end) = weight_name[num_start..].find('_') {
+            let block_num = &weight_name[num_start..num_start + num_end];
+
+            // Determine if it's a single or double block
+            let block_type = if weight_name.contains("single") {
+                "single"
+            } else if weight_name.contains("double") {
+                "double"
+            } else {
+                "unknown"
+            };
+
+            let block_key = format!("{}_{}", block_type, block_num);
+            return Some((block_key, weight_name.to_string()));
+        }
+    }
+
+    None
+}
+
+// Calculate average L2 norm for a block
+fn calculate_block_average_l2(norms: &HashMap<String, WeightStatistics>) -> f64 {
+    if norms.is_empty() {
+        return 0.0;
+    }
+
+    let sum: f64 = norms.values().map(|norm| norm.l2_norm).sum();
+    sum / (norms.len() as f64)
+}
+
+// Generate ASCII bar chart
+fn generate_ascii_bar(value: f64, max_value: f64, width: usize) -> String {
+    let bar_width = if max_value > 0.0 {
+        ((value / max_value) * width as f64).round() as usize
+    } else {
+        0
+    };
+
+    let bar = "█".repeat(bar_width);
+    let padding = " ".repeat(width - bar_width);
+    format!("[{}{}] {:.6}", bar, padding, value)
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::BlockWeights {
+            file,
+            output_format,
+        } => {
+            // Handle block weights command
+            println!("Block weights command with file: {}", file.display());
+            parse_block_weights(file, output_format.as_str())
+        }
+
+        Command::CompareMetadata { file1, file2 } => {
+            // Handle compare metadata command
+            println!(
+                "Compare metadata command with files: {} and {}",
+                file1.display(),
+                file2.display()
+            );
+            let diff = compare_metadata(&metadata_from_file(file1)?, &metadata_from_file(file2)?);
+
+            println!("Added:");
+            for (k, v) in diff.added.iter() {
+                println!("{}", k);
+                println!("{}", v);
+            }
+
+            println!("Removed:");
+            for (k, v) in diff.removed.iter() {
+                println!("{}", k);
+                println!("{}", v);
+            }
+
+            println!("Changed:");
+            for (k, v) in diff.changed.iter() {
+                println!("{}", k);
+                println!("old: {}", v.old);
+                println!("new: {}", v.new);
+            }
+            Ok(())
+        }
+
+        Command::Scrub {
+            file,
+            profile,
+            output,
+        } => scrub_metadata(file, &profile, output),
+
+        Command::Lint {
+            file,
+            rules,
+            skip,
+            list,
+        } => {
+            if list {
+                for rule in lint::LintRule::ALL {
+                    println!(
+                        "{:<30} {:<8} {}",
+                        rule.id(),
+                        rule.severity(),
+                        rule.description()
+                    );
+                }
+                return Ok(());
+            }
+            lint_file(file, &rules, &skip)
+        }
+
+        Command::BlockDims { file, all } => check_block_dims(file, all),
+
+        Command::Dylora {
+            file,
+            layers,
+            rank,
+            output,
+        } => analyze_dylora(file, layers, rank, output),
+
+        Command::Dora { file, base } => analyze_dora(file, base),
+
+        Command::Oft { file } => analyze_oft(file),
+
+        Command::Base { file, base } => analyze_base(file, base),
+
+        Command::Compat { file, base } => check_compat(file, base),
+
+        Command::Normalize {
+            file,
+            layers,
+            target,
+            method,
+            output,
+        } => normalize_file(file, &layers, target, &method, output),
+
+        Command::Alpha {
+            file,
+            alpha,
+            output,
+        } => reparameterize_alpha(file, alpha, output),
+
+        Command::Rebalance { file, output } => rebalance_file(file, output),
+
+        Command::Filter {
+            file,
+            include,
+            exclude,
+            output,
+        } => filter_file(file, include, exclude, output),
+
+        Command::Lbw {
+            file,
+            layout,
+            percentile,
+            normalize,
+            apply,
+            method,
+            output,
+        } => match (apply, output) {
+            (Some(weights), Some(output)) => {
+                apply_lbw(file, &weights, layout.as_deref(), &method, output)
+            }
+            _ => lbw_preset(file, layout.as_deref(), percentile, normalize),
+        },
+
+        Command::ConvertDtype {
+            file,
+            precision,
+            tolerance,
+            output,
+        } => convert_dtype(file, precision.as_deref(), tolerance, output),
+
+        Command::Prune {
+            file,
+            min_scale,
+            health,
+            energy,
+            output,
+        } => prune_file(file, min_scale, health, energy, output),
+
+        Command::Extract {
+            base,
+            tuned,
+            output,
+            rank,
+            energy,
+            conv_rank,
+            precision,
+        } => extract_lora(base, tuned, output, rank, energy, conv_rank, &precision),
+
+        Command::Modelspec {
+            file,
+            set,
+            fill,
+            output,
+        } => update_modelspec(file, &set, fill, output),
+
+        Command::Images {
+            file,
+            output_dir,
+            key,
+        } => write_images(file, output_dir, key),
+
+        Command::TrainingConfig { file, output_dir } => {
+            let config = training::training_config(&metadata_from_file(file)?);
+
+            println!("{}", config.command());
+
+            if let Some(output_dir) = output_dir {
+                std::fs::create_dir_all(&output_dir)?;
+                std::fs::write(output_dir.join("config.toml"), config.config_toml())?;
+                std::fs::write(output_dir.join("dataset.toml"), config.dataset_toml())?;
+                println!(
+                    "Wrote config.toml and dataset.toml to {}",
+                    output_dir.display()
+                );
+            } else {
+                println!("\n# config.toml\n{}", config.config_toml());
+                println!("# dataset.toml\n{}", config.dataset_toml());
+            }
+
+            if !config.unrecovered.is_empty() {
+                println!("Could not recover:");
+                for u in &config.unrecovered {
+                    println!("  {}: {}", u.field, u.reason);
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+fn scrub_metadata(file: PathBuf, profile: &str, output: Option<PathBuf>) -> Result<()> {
+    let profile = profile.parse::<scrub::ScrubProfile>()?;
+
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let metadata = metadata::Metadata::new_from_header_buffer(data.as_slice())?;
+    let (cleaned, report) = scrub::scrub(&metadata, profile);
+
+    if report.is_clean() {
+        println!("Nothing to scrub with profile {:?}", profile);
+    }
+
+    for finding in &report.findings {
+        match finding.action {
+            scrub::ScrubAction::Removed => {
+                println!("Remove {}: {}", finding.key, finding.reason)
+            }
+            scrub::ScrubAction::Redacted => {
+                println!("Redact {}: {}", finding.key, finding.reason);
+                for m in &finding.matches {
+                    println!("  {}", m);
+                }
+            }
+        }
+    }
+
+    if let Some(output) = output {
+        let mut writer = writer::SafetensorsWriter::from_buffer(&data)?;
+        writer.set_metadata(cleaned.metadata);
+        std::fs::write(&output, writer.serialize()?)?;
+        println!("Wrote {}", output.display());
+    }
+
+    Ok(())
+}
+
+fn update_modelspec(
+    file: PathBuf,
+    set: &[String],
+    fill: bool,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let mut metadata = metadata::Metadata::new_from_header_buffer(data.as_slice())?;
+
+    if fill {
+        modelspec::ModelSpec::from_metadata(&metadata)
+            .unwrap_or_default()
+            .or(modelspec::ModelSpec::from_ss_metadata(&metadata))
+            .apply(&mut metadata);
+    }
+
+    for pair in set {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| InspectorError::Msg(format!("Expected key=value, found {pair}")))?;
+        modelspec::set_key(&mut metadata, key, value)?;
+    }
+
+    let report = modelspec::report(&metadata);
+    match &report.spec {
+        Some(spec) => {
+            let mut keys: Vec<_> = spec.to_metadata().into_iter().collect();
+            keys.sort();
+            for (k, v) in keys {
+                // Thumbnails are data URIs, too long to print
+                let v: String = v.chars().take(80).collect();
+                println!("{k}: {v}");
+            }
+        }
+        None => println!("No modelspec metadata"),
+    }
+
+    for issue in &report.issues {
+        println!("Invalid {}: {}", issue.key, issue.message);
+    }
+
+    for r in report.reconciliation.iter().filter(|r| !r.agrees) {
+        println!(
+            "Mismatch {} = {} but {} = {}",
+            r.key, r.modelspec, r.ss_key, r.ss
+        );
+    }
+
+    if let Some(output) = output {
+        let mut writer = writer::SafetensorsWriter::from_buffer(&data)?;
+        writer.set_metadata(metadata.metadata);
+        std::fs::write(&output, writer.serialize()?)?;
+        println!("Wrote {}", output.display());
+    }
+
+    Ok(())
+}
+
+fn write_images(file: PathBuf, output_dir: PathBuf, key: Option<String>) -> Result<()> {
+    let metadata = metadata_from_file(file.clone())?;
+
+    let images = match key {
+        Some(key) => vec![thumbnail::image(&metadata, &key)?],
+        None => thumbnail::embedded_images(&metadata),
+    };
+
+    if images.is_empty() {
+        println!("No embedded images in {}", file.display());
+        return Ok(());
+    }
+
+    let stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    std::fs::create_dir_all(&output_dir)?;
+
+    for image in images {
+        let name = format!(
+            "{stem}_{}.{}",
+            image.key.replace(|c: char| !c.is_ascii_alphanumeric(), "_"),
+            image.format.extension()
+        );
+        let path = output_dir.join(name);
+        std::fs::write(&path, &image.data)?;
+
+        match (image.width, image.height) {
+            (Some(w), Some(h)) => println!(
+                "{} {} {w}x{h} -> {}",
+                image.key,
+                image.mime(),
+                path.display()
+            ),
+            _ => println!("{} {} -> {}", image.key, image.mime(), path.display()),
+        }
+    }
+
+    Ok(())
+}
+
+fn lint_file(file: PathBuf, rules: &[String], skip: &[String]) -> Result<()> {
+    let rules = lint::LintRule::select(rules, skip)?;
+
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let filename = file.display().to_string();
+    let lora_file = file::LoRAFile::new_from_buffer(&data, &filename, &candle_core::Device::Cpu);
+    let report = lint::lint(&lora_file, &rules);
+
+    for finding in &report.findings {
+        println!(
+            "{}[{}]: {}",
+            finding.severity, finding.rule, finding.message
+        );
+        for layer in &finding.layers {
+            println!("  {}", layer);
+        }
+    }
+
+    for skipped in &report.skipped {
+        println!("skipped[{}]: {}", skipped.rule, skipped.reason);
+    }
+
+    if report.findings.is_empty() {
+        println!("No problems found in {}", filename);
+    }
+
+    Ok(())
+}
+
+fn check_block_dims(file: PathBuf, all: bool) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let filename = file.display().to_string();
+    let lora_file = file::LoRAFile::new_from_buffer(&data, &filename, &candle_core::Device::Cpu);
+    let report = blocks::block_dims(&lora_file);
+
+    if report.is_empty() {
+        println!("No block_dims or conv_block_dims in {}", filename);
+        return Ok(());
+    }
+
+    for block in &report {
+        if !all && block.status == blocks::BlockDimStatus::Match {
+            continue;
+        }
+
+        println!(
+            "{:2} {:8}{} dim {} alpha {}: {:?}",
+            block.index,
+            block
+                .id
+                .as_ref()
+                .map_or("-".to_string(), |id| id.to_string()),
+            if block.conv { " (conv)" } else { "" },
+            block.declared_dim,
+            block.declared_alpha,
+            block.status
+        );
+        for layer in &block.layers {
+            if all || !layer.matches {
+                println!(
+                    "  {} rank {} alpha {}",
+                    layer.base_name,
+                    layer.rank.map_or("?".to_string(), |r| r.to_string()),
+                    layer.alpha.map_or("?".to_string(), |a| a.to_string())
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn analyze_dylora(
+    file: PathBuf,
+    show_layers: bool,
+    rank: Option<usize>,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let filename = file.display().to_string();
+    let lora_file = file::LoRAFile::new_from_buffer(&data, &filename, &candle_core::Device::Cpu);
+    let unit = lora_file.metadata().map(dylora::unit).unwrap_or(1);
+
+    if lora_file.metadata().and_then(|m| m.network_type())
+        != Some(inspector::network::NetworkType::DyLoRA)
+    {
+        println!("{} is not a DyLoRA, slicing it anyway", filename);
+    }
+
+    let layers = dylora::layers(&lora_file);
+    println!("unit {}", unit);
+    println!(
+        "{:>6} {:>7} {:>12} {:>12} {:>10}",
+        "rank", "layers", "mean error", "max error", "energy"
+    );
+    for slice in dylora::summary(&layers) {
+        println!(
+            "{:>6} {:>7} {:>12.4} {:>12.4} {:>10.4}",
+            slice.rank,
+            slice.layers,
+            slice.mean_relative_error,
+            slice.max_relative_error,
+            slice.mean_energy
+        );
+    }
+
+    if show_layers {
+        for layer in &layers {
+            println!(
+                "\n{} (rank {}, alpha {})",
+                layer.base_name, layer.rank, layer.alpha
+            );
+            for slice in &layer.slices {
+                println!(
+                    "  {:>4} scale {:.4} error {:.4} energy {:.4}",
+                    slice.rank, slice.effective_scale, slice.relative_error, slice.energy
+                );
+            }
+        }
+    }
+
+    if let (Some(rank), Some(output)) = (rank, output) {
+        std::fs::write(&output, dylora::truncate(&data, rank, unit)?)?;
+        println!("Wrote rank {} to {}", rank, output.display());
+    }
+
+    Ok(())
+}
+
+fn analyze_dora(file: PathBuf, base_file: Option<PathBuf>) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let filename = file.display().to_string();
+    let lora_file = file::LoRAFile::new_from_buffer(&data, &filename, &candle_core::Device::Cpu);
+    let layers = match &base_file {
+        Some(base_file) => {
+            let mut base_model = base::BaseModel::new(File::open(base_file)?)?;
+            dora::layers_with_base(&lora_file, &mut base_model)
+        }
+        None => dora::layers(&lora_file),
+    };
+
+    if layers.is_empty() {
+        println!("No dora_scale tensors in {}", filename);
+        return Ok(());
+    }
+
+    println!(
+        "{:60} {:>9} {:>9} {:>9} {:>9} {:>9} {:>10}",
+        "layer", "mean", "std", "min", "max", "min/max", "direction"
+    );
+    for layer in &layers {
+        let m = &layer.magnitude;
+        println!(
+            "{:60} {:>9.4} {:>9.4} {:>9.4} {:>9.4} {:>9.4} {:>10.4}",
+            layer.base_name,
+            m.mean,
+            m.std_dev,
+            m.min,
+            m.max,
+            m.min_max_ratio,
+            layer.direction_update
+        );
+    }
+
+    if base_file.is_some() {
+        println!(
+            "\n{:60} {:>10} {:>10} {:>10}",
+            "layer", "deviation", "magnitude", "effective"
+        );
+        for layer in &layers {
+            match &layer.base {
+                Some(base) => println!(
+                    "{:60} {:>10.4} {:>10.4} {:>10.4}{}",
+                    layer.base_name,
+                    base.max_deviation,
+                    base.magnitude_update,
+                    base.effective_scale,
+                    if base.magnitude_dominates {
+                        " magnitude dominates"
+                    } else {
+                        ""
+                    }
+                ),
+                None => println!("{:60} not in the base model", layer.base_name),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn analyze_oft(file: PathBuf) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let filename = file.display().to_string();
+    let lora_file = file::LoRAFile::new_from_buffer(&data, &filename, &candle_core::Device::Cpu);
+    let layers = oft::layers(&lora_file);
+
+    if layers.is_empty() {
+        println!("No OFT blocks in {}", filename);
+        return Ok(());
+    }
+
+    println!(
+        "{:60} {:>6} {:>6} {:>10} {:>10} {:>12}",
+        "layer", "blocks", "size", "||R - I||", "max block", "orthogonal"
+    );
+    for layer in &layers {
+        let m = &layer.metrics;
+        println!(
+            "{:60} {:>6} {:>6} {:>10.4} {:>10.4} {:>12.2e}",
+            layer.base_name,
+            m.blocks * m.stages,
+            m.block_size,
+            m.identity_deviation,
+            m.max_block_deviation,
+            m.orthogonality_error
+        );
+    }
+
+    Ok(())
 }
 
-#[derive(Serialize, Clone, Debug)]
-struct WeightStatistics {
-    l1_norm: f64,
-    l2_norm: f64,
-    matrix_norm: f64,
-    max: f64,
-    min: f64,
-    std_dev: Option<f64>,
-    median: Option<f64>,
+fn analyze_base(file: PathBuf, base_file: PathBuf) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let filename = file.display().to_string();
+    let lora_file = file::LoRAFile::new_from_buffer(&data, &filename, &candle_core::Device::Cpu);
+    // Only the tensors the LoRA applies to are read from the checkpoint
+    let mut base_model = base::BaseModel::new(File::open(&base_file)?)?;
+    let layers = base::analyze(&lora_file, &mut base_model);
+
+    println!(
+        "{:60} {:>12} {:>10} {:>10}",
+        "layer", "||dW||/||W||", "alignment", "directions"
+    );
+    for layer in &layers {
+        match (&layer.metrics, &layer.issue) {
+            (Some(m), _) => println!(
+                "{:60} {:>12.6} {:>10} {:>10}",
+                layer.base_name,
+                m.relative_magnitude,
+                m.alignment
+                    .map(|a| format!("{:.4}", a))
+                    .unwrap_or("-".to_string()),
+                m.directions
+            ),
+            (None, Some(base::LayerIssue::NotInBase)) => {
+                println!("{:60} not in {}", layer.base_name, base_file.display())
+            }
+            (None, Some(base::LayerIssue::ShapeMismatch { delta, base })) => println!(
+                "{:60} shape {:?} does not fit the base weight {:?}",
+                layer.base_name, delta, base
+            ),
+            (None, Some(base::LayerIssue::Failed(e))) => println!("{:60} {}", layer.base_name, e),
+            (None, None) => (),
+        }
+    }
+
+    let issues = layers.iter().filter(|l| l.issue.is_some()).count();
+    println!(
+        "{} of {} layers compared with the base model",
+        layers.len() - issues,
+        layers.len()
+    );
+
+    Ok(())
 }
 
-#[derive(Serialize, Debug, Clone)]
-struct BlockData {
-    weights: HashMap<String, WeightStatistics>,
-    average_l2_norm: f64,
+fn check_compat(file: PathBuf, base_file: PathBuf) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let filename = file.display().to_string();
+    let lora_file = file::LoRAFile::new_from_buffer(&data, &filename, &candle_core::Device::Cpu);
+    let report = compat::check(&lora_file, &mut File::open(&base_file)?)?;
+
+    let target = |t: &compat::ModelTarget| {
+        format!(
+            "{} {}",
+            t.architecture
+                .map(|a| format!("{:?}", a))
+                .unwrap_or("unknown".to_string()),
+            t.variant.map(|v| format!("({:?})", v)).unwrap_or_default()
+        )
+    };
+    println!("LoRA:       {}", target(&report.lora));
+    println!("Base model: {}", target(&report.base));
+    println!("Matched layers: {}", report.matched);
+
+    if !report.missing.is_empty() {
+        println!("Not in the base model:");
+        for base_name in &report.missing {
+            println!("  {}", base_name);
+        }
+    }
+
+    if !report.shape_mismatches.is_empty() {
+        println!("Shape mismatches:");
+        for m in &report.shape_mismatches {
+            println!(
+                "  {:60} {:?} does not fit {} {:?}",
+                m.base_name, m.lora_shape, m.base_key, m.base_shape
+            );
+        }
+    }
+
+    println!("Verdict: {:?}", report.verdict);
+
+    Ok(())
 }
 
-#[derive(Serialize, Debug)]
-struct OutputData {
-    metadata: Option<HashMap<String, String>>,
-    base_names: Vec<String>,
-    norms: HashMap<String, WeightStatistics>,
-    blocks: HashMap<String, BlockData>,
-    non_block_weights: HashMap<String, WeightStatistics>,
+fn extract_lora(
+    base_file: PathBuf,
+    tuned_file: PathBuf,
+    output: PathBuf,
+    rank: usize,
+    energy: Option<f64>,
+    conv_rank: Option<usize>,
+    precision: &str,
+) -> Result<()> {
+    let selection = |rank: usize| match energy {
+        Some(threshold) => extract::RankSelection::Energy {
+            threshold,
+            max_rank: rank,
+        },
+        None => extract::RankSelection::Fixed(rank),
+    };
+    let dtype = match precision {
+        "fp16" => candle_core::DType::F16,
+        "bf16" => candle_core::DType::BF16,
+        "fp32" => candle_core::DType::F32,
+        other => {
+            return Err(InspectorError::Msg(format!(
+                "unknown precision {}, expected fp16, bf16 or fp32",
+                other
+            ))
+            .into())
+        }
+    };
+
+    let file_name = |path: &PathBuf| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+    };
+    let options = extract::ExtractOptions {
+        rank: selection(rank),
+        conv_rank: conv_rank.map(selection),
+        dtype,
+        base_model: file_name(&base_file),
+        tuned_model: file_name(&tuned_file),
+        ..Default::default()
+    };
+
+    // Both checkpoints are read one tensor at a time
+    let mut base_model = base::BaseModel::new(File::open(&base_file)?)?;
+    let mut tuned_model = base::BaseModel::new(File::open(&tuned_file)?)?;
+    let (buffer, report) = extract::extract(&mut base_model, &mut tuned_model, &options)?;
+
+    println!(
+        "{:60} {:>5} {:>8} {:>10}",
+        "layer", "rank", "energy", "||dW||/||W||"
+    );
+    for layer in &report.layers {
+        println!(
+            "{:60} {:>5} {:>8.4} {:>10.6}",
+            layer.lora_name, layer.rank, layer.energy, layer.relative_diff
+        );
+    }
+    println!(
+        "Extracted {} layers, skipped {}",
+        report.layers.len(),
+        report.skipped.len()
+    );
+
+    std::fs::write(&output, buffer)?;
+    println!("Wrote {}", output.display());
+
+    Ok(())
 }
 
-pub type Result<T> = std::result::Result<T, LoraInspectorError>;
+fn normalize_file(
+    file: PathBuf,
+    layers: &str,
+    target: Option<f64>,
+    method: &str,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
 
-#[derive(Debug)]
-pub enum LoraInspectorError {
-    Json(serde_json::Error),
-    Io(io::Error),
-    Inspector(InspectorError),
+    let options = rescale::NormalizeOptions {
+        selection: layers.parse()?,
+        target: target.map_or(rescale::ScaleTarget::Median, rescale::ScaleTarget::Value),
+        method: method.parse()?,
+    };
+    let (buffer, report) = rescale::normalize(&data, &options)?;
+
+    if report.layers.is_empty() {
+        println!("No layers to rescale in {}", file.display());
+    } else {
+        println!(
+            "{:60} {:>10} {:>10} {:>8} {:>8}",
+            "layer", "before", "after", "factor", "method"
+        );
+        for layer in &report.layers {
+            println!(
+                "{:60} {:>10.4} {:>10.4} {:>8.4} {:>8?}",
+                layer.base_name, layer.before, layer.after, layer.factor, layer.method
+            );
+        }
+    }
+    for base_name in &report.skipped {
+        println!("Skipped {}, its update does not scale linearly", base_name);
+    }
+
+    if let Some(output) = output {
+        std::fs::write(&output, buffer)?;
+        println!("Wrote {}", output.display());
+    }
+
+    Ok(())
 }
 
-impl From<serde_json::Error> for LoraInspectorError {
-    fn from(err: serde_json::Error) -> LoraInspectorError {
-        LoraInspectorError::Json(err)
+fn reparameterize_alpha(file: PathBuf, alpha: Option<String>, output: PathBuf) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let target = match alpha {
+        Some(alpha) => alpha.parse()?,
+        None => {
+            let lora_file = file::LoRAFile::new_from_header_buffer(&data, "")?;
+            rescale::AlphaTarget::for_file(&lora_file)
+        }
+    };
+    let (buffer, report) = rescale::reparameterize_alpha(&data, target)?;
+
+    println!(
+        "{:60} {:>6} {:>10} {:>10} {:>10}",
+        "layer", "rank", "alpha", "new alpha", "error"
+    );
+    for layer in &report.layers {
+        println!(
+            "{:60} {:>6} {:>10} {:>10} {:>10.2e}",
+            layer.base_name, layer.rank, layer.before, layer.after, layer.error
+        );
+    }
+    for base_name in &report.skipped {
+        println!(
+            "Skipped {}, it has no alpha or lora_up/lora_down pair",
+            base_name
+        );
     }
+
+    std::fs::write(&output, buffer)?;
+    println!("Wrote {}", output.display());
+
+    Ok(())
 }
 
-impl From<io::Error> for LoraInspectorError {
-    fn from(err: io::Error) -> LoraInspectorError {
-        LoraInspectorError::Io(err)
+fn rebalance_file(file: PathBuf, output: PathBuf) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let (buffer, report) = rescale::rebalance(&data)?;
+
+    println!(
+        "{:60} {:>6} {:>10} {:>10} {:>10}",
+        "layer", "rank", "before", "after", "error"
+    );
+    for layer in &report.layers {
+        println!(
+            "{:60} {:>6} {:>10.4} {:>10.4} {:>10.2e}",
+            layer.base_name, layer.rank, layer.before, layer.after, layer.error
+        );
     }
+    for base_name in &report.skipped {
+        println!(
+            "Skipped {}, it has a Tucker core or too large a rank",
+            base_name
+        );
+    }
+    for base_name in &report.zero {
+        println!("Skipped {}, its update is zero", base_name);
+    }
+
+    std::fs::write(&output, buffer)?;
+    println!("Wrote {}", output.display());
+
+    Ok(())
 }
 
-impl From<InspectorError> for LoraInspectorError {
-    fn from(err: InspectorError) -> LoraInspectorError {
-        LoraInspectorError::Inspector(err)
+fn filter_file(
+    file: PathBuf,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    output: PathBuf,
+) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let options = filter::FilterOptions {
+        include: include
+            .iter()
+            .map(|p| p.parse())
+            .collect::<inspector::Result<_>>()?,
+        exclude: exclude
+            .iter()
+            .map(|p| p.parse())
+            .collect::<inspector::Result<_>>()?,
+    };
+    let (buffer, report) = filter::filter(&data, &options)?;
+
+    println!(
+        "Kept {} layers, removed {}",
+        report.kept.len(),
+        report.removed.len()
+    );
+    for base_name in &report.kept {
+        println!("  {}", base_name);
     }
+
+    std::fs::write(&output, buffer)?;
+    println!("Wrote {}", output.display());
+
+    Ok(())
 }
 
-// Parses a weight name to determine if it belongs to a block and which one
-fn parse_block_info(weight_name: &str) -> Option<(String, String)> {
-    // Check for "blocks_XX" pattern
-    if let Some(blocks_idx) = weight_name.find("blocks_") {
-        // Find where the block number starts
-        let num_start = blocks_idx + "blocks_".len();
+fn lbw_preset(
+    file: PathBuf,
+    layout: Option<&str>,
+    percentile: Option<f64>,
+    normalize: bool,
+) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
 
-        // Find where the block number ends (next underscore)
-        if let Some(num_end) = weight_name[num_start..].find('_') {
-            let block_num = &weight_name[num_start..num_start + num_end];
+    let lora_file = file::LoRAFile::new_from_buffer(&data, "", &candle_core::Device::Cpu);
+    let layout = match layout {
+        Some(layout) => layout.parse()?,
+        None => lbw::LbwLayout::detect(&lora_file).ok_or_else(|| {
+            InspectorError::Msg("Could not detect the block layout, use --layout".to_string())
+        })?,
+    };
 
-            // Determine if it's a single or double block
-            let block_type = if weight_name.contains("single") {
-                "single"
-            } else if weight_name.contains("double") {
-                "double"
-            } else {
-                "unknown"
-            };
+    let norms = lbw::slot_norms(&lora_file)?;
+    let preset = lbw::preset(
+        layout,
+        &norms,
+        &lbw::PresetOptions {
+            percentile,
+            normalize,
+        },
+    );
 
-            let block_key = format!("{}_{}", block_type, block_num);
-            return Some((block_key, weight_name.to_string()));
-        }
+    let max_norm = norms.values().copied().fold(0.0_f64, f64::max);
+    for (slot, weight) in preset.slots.iter().zip(&preset.weights) {
+        let norm = norms.get(slot).copied().unwrap_or_default();
+        println!(
+            "{:6} {} {:.4} -> {}",
+            slot.to_string(),
+            generate_ascii_bar(norm, max_norm, 40),
+            norm,
+            weight
+        );
     }
+    println!("\n{:?}: {}", layout, preset);
 
-    None
+    Ok(())
 }
 
-// Calculate average L2 norm for a block
-fn calculate_block_average_l2(norms: &HashMap<String, WeightStatistics>) -> f64 {
-    if norms.is_empty() {
-        return 0.0;
+fn apply_lbw(
+    file: PathBuf,
+    weights: &str,
+    layout: Option<&str>,
+    method: &str,
+    output: PathBuf,
+) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let layout = layout.map(|l| l.parse()).transpose()?;
+    let weights = lbw::parse_weights(weights)?;
+    let (buffer, report) = lbw::apply(&data, &weights, layout, method.parse()?)?;
+
+    println!("{:?} block weights", report.layout);
+    for layer in &report.layers {
+        println!(
+            "{:6} x{:<6} {} ({:?})",
+            layer.slot.to_string(),
+            layer.weight,
+            layer.base_name,
+            layer.method
+        );
+    }
+    for base_name in &report.skipped {
+        println!("Skipped {}, its update does not scale linearly", base_name);
+    }
+    if !report.unmatched.is_empty() {
+        println!(
+            "{} layers are outside the blocks of the layout and were left as they are",
+            report.unmatched.len()
+        );
     }
 
-    let sum: f64 = norms.values().map(|norm| norm.l2_norm).sum();
-    sum / (norms.len() as f64)
+    std::fs::write(&output, buffer)?;
+    println!("Wrote {}", output.display());
+
+    Ok(())
 }
 
-// Generate ASCII bar chart
-fn generate_ascii_bar(value: f64, max_value: f64, width: usize) -> String {
-    let bar_width = if max_value > 0.0 {
-        ((value / max_value) * width as f64).round() as usize
-    } else {
-        0
-    };
+fn convert_dtype(
+    file: PathBuf,
+    precision: Option<&str>,
+    tolerance: f64,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
 
-    let bar = "█".repeat(bar_width);
-    let padding = " ".repeat(width - bar_width);
-    format!("[{}{}] {:.6}", bar, padding, value)
-}
+    let targets = match precision {
+        Some(precision) => vec![precision::parse_precision(precision)?],
+        None => precision::TARGETS.to_vec(),
+    };
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+    println!(
+        "{:10} {:>12} {:>10} {:>14} {:>6}",
+        "precision", "max error", "overflow", "flush to zero", "safe"
+    );
+    let mut reports = vec![];
+    for dtype in targets {
+        let report = precision::simulate(&data, dtype)?;
+        println!(
+            "{:10} {:>12.2e} {:>10} {:>14} {:>6}",
+            report.dtype,
+            report.max_error(),
+            report.overflow(),
+            report.flush_to_zero(),
+            if report.is_safe(tolerance) {
+                "yes"
+            } else {
+                "no"
+            }
+        );
+        reports.push((dtype, report));
+    }
 
-    match cli.command {
-        Command::BlockWeights {
-            file,
-            output_format,
-        } => {
-            // Handle block weights command
-            println!("Block weights command with file: {}", file.display());
-            parse_block_weights(file, output_format.as_str())
+    for (_, report) in &reports {
+        let mut layers: Vec<_> = report
+            .layers
+            .iter()
+            .filter(|l| l.error > tolerance)
+            .collect();
+        if layers.is_empty() {
+            continue;
+        }
+        layers.sort_by(|a, b| b.error.total_cmp(&a.error));
+        println!("\n{} layers over {:.0e}:", report.dtype, tolerance);
+        for layer in layers.iter().take(10) {
+            println!("  {:60} {:.2e}", layer.base_name, layer.error);
         }
+        if layers.len() > 10 {
+            println!("  and {} more", layers.len() - 10);
+        }
+    }
 
-        Command::CompareMetadata { file1, file2 } => {
-            // Handle compare metadata command
-            println!(
-                "Compare metadata command with files: {} and {}",
-                file1.display(),
-                file2.display()
-            );
-            let diff = compare_metadata(&metadata_from_file(file1)?, &metadata_from_file(file2)?);
+    if let (Some(output), Some((dtype, _))) = (output, reports.first()) {
+        std::fs::write(&output, precision::convert(&data, *dtype)?)?;
+        println!("\nWrote {} as {}", output.display(), dtype);
+    }
 
-            println!("Added:");
-            for (k, v) in diff.added.iter() {
-                println!("{}", k);
-                println!("{}", v);
-            }
+    Ok(())
+}
 
-            println!("Removed:");
-            for (k, v) in diff.removed.iter() {
-                println!("{}", k);
-                println!("{}", v);
-            }
+fn prune_file(
+    file: PathBuf,
+    min_scale: Option<f64>,
+    health: Vec<String>,
+    energy: Option<f64>,
+    output: PathBuf,
+) -> Result<()> {
+    if let Some(energy) = energy {
+        if energy <= 0.0 || energy > 1.0 {
+            return Err(InspectorError::Msg(format!(
+                "--energy must be within (0, 1], got {energy}"
+            ))
+            .into());
+        }
+    }
 
-            println!("Changed:");
-            for (k, v) in diff.changed.iter() {
-                println!("{}", k);
-                println!("old: {}", v.old);
-                println!("new: {}", v.new);
-            }
-            Ok(())
-        } // Handle other subcommands...
+    let mut f = File::open(&file)?;
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    let options = prune::PruneOptions {
+        min_scale,
+        health: health
+            .iter()
+            .map(|h| prune::parse_health(h))
+            .collect::<inspector::Result<_>>()?,
+        energy,
+    };
+    let (buffer, report) = prune::prune(&data, &options)?;
+
+    for layer in &report.layers {
+        let action = match &layer.action {
+            prune::PruneAction::Kept => continue,
+            prune::PruneAction::DroppedScale => "dropped, scale".to_string(),
+            prune::PruneAction::DroppedHealth(health) => format!("dropped, {health:?}"),
+            prune::PruneAction::Truncated { from, to } => format!("rank {from} -> {to}"),
+            prune::PruneAction::Skipped(reason) => format!("not truncated, {reason}"),
+        };
+        println!(
+            "{:60} {:24} {:>7.2}%",
+            layer.base_name,
+            action,
+            layer.retained / layer.energy.max(f64::MIN_POSITIVE) * 100.0
+        );
     }
+
+    println!(
+        "\nDropped {} of {} layers, {:.2}% of the delta energy retained",
+        report.dropped(),
+        report.layers.len(),
+        report.retained_energy() * 100.0
+    );
+    println!(
+        "{:.2} MB -> {:.2} MB",
+        report.size_before as f64 / 1e6,
+        report.size_after as f64 / 1e6
+    );
+
+    std::fs::write(&output, buffer)?;
+    println!("Wrote {}", output.display());
+
+    Ok(())
 }
 
 fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
@@ -198,7 +1595,7 @@ fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
     f.read_to_end(&mut data)?;
 
     // Parse metadata
-    let metadata = metadata::Metadata::new_from_buffer(data.as_slice())
+    let metadata = metadata::Metadata::new_from_header_buffer(data.as_slice())
         .map_err(|e| e.to_string())
         .ok();
 
@@ -223,6 +1620,7 @@ fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
     // Maps to store our organized data
     let mut blocks: HashMap<String, HashMap<String, WeightStatistics>> = HashMap::new();
     let mut non_block_weights: HashMap<String, WeightStatistics> = HashMap::new();
+    let mut diff_weights: HashMap<String, WeightStatistics> = HashMap::new();
 
     for base_name in &base_names {
         pb.set_message(format!("Processing: {}", base_name));
@@ -247,6 +1645,7 @@ fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
         norms_map.insert(
             base_name.to_string(),
             WeightStatistics {
+                kind: file.layer_kind(base_name),
                 l1_norm: l1,
                 l2_norm: l2,
                 matrix_norm: matrix,
@@ -258,7 +1657,12 @@ fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
         );
 
         // Organize weights into blocks or non-blocks
-        if let Some((block_key, weight_name)) = parse_block_info(base_name) {
+        if file.layer_kind(base_name) != file::LayerKind::LowRank {
+            diff_weights.insert(
+                base_name.to_string(),
+                norms_map.get(base_name).expect("To get norm map").clone(),
+            );
+        } else if let Some((block_key, weight_name)) = parse_block_info(base_name) {
             blocks.entry(block_key).or_default().insert(
                 weight_name,
                 norms_map
@@ -278,6 +1682,12 @@ fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
 
     pb.finish_with_message("Completed norm calculations");
 
+    let block_lr_weights: BTreeMap<String, f64> = file
+        .block_map()
+        .into_iter()
+        .filter_map(|block| block.lr_weight.map(|w| (block.id.to_string(), w)))
+        .collect();
+
     // Create final block data with average L2 norms
     let blocks_data: HashMap<String, BlockData> = blocks
         .into_iter()
@@ -293,13 +1703,21 @@ fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
         })
         .collect();
 
+    let warnings: Vec<String> = metadata
+        .as_ref()
+        .map(|m| m.parse_warnings().iter().map(|w| w.to_string()).collect())
+        .unwrap_or_default();
+
     // Create output data structure
     let output = OutputData {
+        warnings,
         metadata: metadata.map(|m| m.metadata).expect("No metadata"),
         base_names,
         norms: norms_map,
         blocks: blocks_data.clone(),
+        block_lr_weights,
         non_block_weights,
+        diff_weights,
     };
 
     // Output based on format
@@ -308,6 +1726,9 @@ fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
             println!("{}", serde_json::to_string_pretty(&output)?);
         }
         "text" => {
+            for warning in &output.warnings {
+                println!("Warning: {}", warning);
+            }
             println!("Metadata: {:?}", output.metadata);
             println!("Base Names: {:?}", output.base_names);
             println!("Norms:");
@@ -373,11 +1794,26 @@ fn parse_block_weights(file: PathBuf, output_format: &str) -> Result<()> {
                 );
             }
 
+            if !output.block_lr_weights.is_empty() {
+                println!("\nBlock LR Weights:");
+                for (block, weight) in &output.block_lr_weights {
+                    println!("  {block:10} x{weight:.2}");
+                }
+            }
+
             println!("\nNon-Block Weights:");
             for (name, norm) in &output.non_block_weights {
                 println!("  {name}:");
                 println!("    L2 Norm: {}", norm.l2_norm);
             }
+
+            if !output.diff_weights.is_empty() {
+                println!("\nFull Diff, Norm and Bias Weights:");
+                for (name, norm) in &output.diff_weights {
+                    println!("  {name} ({:?}):", norm.kind);
+                    println!("    L2 Norm: {}", norm.l2_norm);
+                }
+            }
         }
         _ => {
             eprintln!("Unsupported format: {}. Using JSON instead.", output_format);